@@ -0,0 +1,1556 @@
+use crate::{
+    audit::{AuditAction, AuditEntry, AuditLogFilter},
+    error::AuthError,
+    filter::{FilterAction, HeldMessage, RoomFilter},
+    http::AppState,
+    metrics::metrics,
+    messages::{
+        GetPrivateMessagesRequest, MessageReplyContext, MessageReport, MessageType, PrivateMessageResponse, ReactionDetail, ReactionSummary,
+        ReportAction, ReportStatus, Room, RoomMessageResponse, RoomSanction, SanctionKind, SendPrivateMessageRequest,
+        SendRoomMessageRequest,
+    },
+    users::{ApiScope, AuthResponse, CreateUserRequest, LoginRequest, Presence, User, UserBlock},
+    webhooks::{IncomingWebhookPayload, WebhookAttachment},
+    websocket::WsServerMessage,
+};
+use axum::{
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    IntoParams, Modify, OpenApi, ToSchema,
+};
+
+/// An error returned as `{"error": "..."}` with a matching status code.
+pub(crate) struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    pub(crate) fn not_found(message: &str) -> Self {
+        Self { status: StatusCode::NOT_FOUND, message: message.to_string() }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(error: AuthError) -> Self {
+        metrics().record_error(&error);
+        let status = match error {
+            AuthError::InvalidCredentials | AuthError::InvalidSession | AuthError::InvalidToken => StatusCode::UNAUTHORIZED,
+            AuthError::PermissionDenied
+            | AuthError::Forbidden(_)
+            | AuthError::EmailNotVerified
+            | AuthError::RegistrationClosed
+            | AuthError::PendingApproval
+            | AuthError::AccountDeactivated => StatusCode::FORBIDDEN,
+            AuthError::UserNotFound => StatusCode::NOT_FOUND,
+            AuthError::UserExists => StatusCode::CONFLICT,
+            AuthError::InvalidInput(_) | AuthError::InvalidInviteCode => StatusCode::BAD_REQUEST,
+            AuthError::ContentRejected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AuthError::MessageHeld { .. } => StatusCode::ACCEPTED,
+            AuthError::Provider(_) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        Self { status, message: error.to_string() }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(serde_json::json!({ "error": self.message }))).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<Json<T>, ApiError>;
+
+/// The raw token from an `Authorization: Bearer` header.
+pub(crate) struct BearerToken(String);
+
+impl<S: Send + Sync> FromRequestParts<S> for BearerToken {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| Self(token.trim().to_string()))
+            .ok_or_else(|| AuthError::InvalidSession.into())
+    }
+}
+
+/// The user behind the request's `Authorization: Bearer` token. Bots using
+/// API tokens are limited to their scopes, the same as over WebSocket.
+pub(crate) struct Caller {
+    user: User,
+    scopes: Option<Vec<ApiScope>>,
+}
+
+impl Caller {
+    fn allow(&self, scope: Option<ApiScope>) -> Result<(), ApiError> {
+        match &self.scopes {
+            Some(scopes) if !scope.is_some_and(|s| scopes.contains(&s)) => Err(AuthError::PermissionDenied.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for Caller {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let BearerToken(token) = BearerToken::from_request_parts(parts, state).await?;
+        let (user, scopes) = state.auth.lock().await.validate_token(&token)?;
+        Ok(Self { user, scopes })
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct CreateRoomBody {
+    name: String,
+    #[serde(default)]
+    desc: String,
+}
+
+/// Fields left out are unchanged.
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct UpdateRoomBody {
+    name: Option<String>,
+    desc: Option<String>,
+    topic: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub(crate) struct KickBody {
+    reason: Option<String>,
+}
+
+/// Without a duration the ban or mute lasts until lifted.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub(crate) struct SanctionBody {
+    reason: Option<String>,
+    duration_minutes: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct ReportBody {
+    message_id: String,
+    reason: String,
+}
+
+/// `duration_minutes` applies to mutes and bans, which otherwise last until lifted.
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct ResolveReportBody {
+    action: ReportAction,
+    note: Option<String>,
+    duration_minutes: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub(crate) struct DismissReportBody {
+    note: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct SendMessageBody {
+    content: String,
+    reply_to_message_id: Option<String>,
+}
+
+/// Returned with 202 when a filter holds a message for moderator review.
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct HeldResponse {
+    held_id: String,
+    reason: String,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub(crate) struct RejectHeldBody {
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct EditMessageBody {
+    content: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub(crate) struct ReactionBody {
+    emoji: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct UnreadCount {
+    count: i64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub(crate) struct RoomListQuery {
+    /// Also list archived rooms
+    #[serde(default)]
+    include_archived: bool,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub(crate) struct Pagination {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// Room managers must pass one of their rooms; admins may leave it out.
+#[derive(Debug, Deserialize, IntoParams)]
+pub(crate) struct ReportQuery {
+    room_id: Option<String>,
+    status: Option<ReportStatus>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// Room managers must pass one of their rooms; admins may leave it out.
+#[derive(Debug, Deserialize, IntoParams)]
+pub(crate) struct AuditLogQuery {
+    room_id: Option<String>,
+    actor_id: Option<String>,
+    target_id: Option<String>,
+    action: Option<AuditAction>,
+    /// Inclusive, RFC 3339
+    since: Option<DateTime<Utc>>,
+    /// Exclusive, RFC 3339
+    until: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+impl AuditLogQuery {
+    fn filter(&self) -> AuditLogFilter {
+        AuditLogFilter {
+            room_id: self.room_id.clone(),
+            actor_id: self.actor_id.clone(),
+            target_id: self.target_id.clone(),
+            action: self.action,
+            since: self.since,
+            until: self.until,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub(crate) struct DirectMessageQuery {
+    /// Only the conversation with this username
+    with_user: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    #[serde(default)]
+    unread_only: bool,
+}
+
+#[utoipa::path(post, path = "/api/auth/register", tag = "auth",
+    request_body = CreateUserRequest, responses((status = 200, body = AuthResponse), (status = 409)))]
+async fn register(State(state): State<AppState>, Json(body): Json<CreateUserRequest>) -> ApiResult<AuthResponse> {
+    Ok(Json(state.auth.lock().await.register(body)?))
+}
+
+#[utoipa::path(post, path = "/api/auth/login", tag = "auth",
+    request_body = LoginRequest, responses((status = 200, body = AuthResponse), (status = 401)))]
+async fn login(State(state): State<AppState>, Json(body): Json<LoginRequest>) -> ApiResult<AuthResponse> {
+    Ok(Json(state.auth.lock().await.login(body)?))
+}
+
+#[utoipa::path(post, path = "/api/auth/logout", tag = "auth", security(("bearer" = [])),
+    responses((status = 204), (status = 401)))]
+async fn logout(State(state): State<AppState>, BearerToken(token): BearerToken) -> Result<StatusCode, ApiError> {
+    state.auth.lock().await.logout(&token)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Checks a session or API token, returning the user it belongs to.
+#[utoipa::path(get, path = "/api/auth/validate", tag = "auth", security(("bearer" = [])),
+    responses((status = 200, body = User), (status = 401)))]
+async fn validate(caller: Caller) -> ApiResult<User> {
+    Ok(Json(caller.user))
+}
+
+#[utoipa::path(get, path = "/api/me", tag = "users", security(("bearer" = [])),
+    responses((status = 200, body = User)))]
+async fn me(caller: Caller) -> ApiResult<User> {
+    Ok(Json(caller.user))
+}
+
+#[utoipa::path(get, path = "/api/blocks", tag = "users", security(("bearer" = [])),
+    responses((status = 200, body = Vec<UserBlock>)))]
+async fn blocked_users(State(state): State<AppState>, caller: Caller) -> ApiResult<Vec<UserBlock>> {
+    caller.allow(None)?;
+    Ok(Json(state.message_service.lock().await.get_blocked_users(&caller.user.id)?))
+}
+
+#[utoipa::path(put, path = "/api/blocks/{user_id}", tag = "users", security(("bearer" = [])),
+    params(("user_id" = String, Path)), responses((status = 200, body = UserBlock), (status = 400), (status = 404)))]
+async fn block_user(State(state): State<AppState>, caller: Caller, Path(user_id): Path<String>) -> ApiResult<UserBlock> {
+    caller.allow(None)?;
+    Ok(Json(state.message_service.lock().await.block_user(&caller.user.id, &user_id)?))
+}
+
+#[utoipa::path(delete, path = "/api/blocks/{user_id}", tag = "users", security(("bearer" = [])),
+    params(("user_id" = String, Path)), responses((status = 204), (status = 400)))]
+async fn unblock_user(State(state): State<AppState>, caller: Caller, Path(user_id): Path<String>) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    state.message_service.lock().await.unblock_user(&caller.user.id, &user_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/rooms", tag = "rooms", security(("bearer" = [])),
+    params(RoomListQuery), responses((status = 200, body = Vec<Room>)))]
+async fn list_rooms(State(state): State<AppState>, caller: Caller, Query(query): Query<RoomListQuery>) -> ApiResult<Vec<Room>> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    Ok(Json(state.message_service.lock().await.get_all_rooms(query.include_archived)?))
+}
+
+#[utoipa::path(post, path = "/api/rooms", tag = "rooms", security(("bearer" = [])),
+    request_body = CreateRoomBody, responses((status = 200, body = Room)))]
+async fn create_room(State(state): State<AppState>, caller: Caller, Json(body): Json<CreateRoomBody>) -> ApiResult<Room> {
+    caller.allow(None)?;
+    Ok(Json(state.message_service.lock().await.create_room(&caller.user.id, &body.name, &body.desc)?))
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}", tag = "rooms", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 200, body = Room), (status = 404)))]
+async fn get_room(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> ApiResult<Room> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    let room = state.message_service.lock().await.get_room(&room_id)?;
+    room.map(Json).ok_or_else(|| ApiError::not_found("Room not found"))
+}
+
+#[utoipa::path(patch, path = "/api/rooms/{room_id}", tag = "rooms", security(("bearer" = [])),
+    params(("room_id" = String, Path)), request_body = UpdateRoomBody, responses((status = 200, body = Room), (status = 403)))]
+async fn update_room(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    Json(body): Json<UpdateRoomBody>,
+) -> ApiResult<Room> {
+    caller.allow(None)?;
+    let room = state.message_service.lock().await
+        .update_room(&room_id, &caller.user.id, body.name.as_deref(), body.desc.as_deref(), body.topic.as_deref())?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::RoomUpdated { room: room.clone().into() });
+    Ok(Json(room))
+}
+
+#[utoipa::path(delete, path = "/api/rooms/{room_id}", tag = "rooms", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 204), (status = 403)))]
+async fn delete_room(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    state.message_service.lock().await.delete_room(&room_id, &caller.user.id)?;
+
+    let mut conns = state.connections.write().await;
+    conns.broadcast_to_room(&room_id, WsServerMessage::RoomDeleted { room_id: room_id.clone() });
+    conns.close_room(&room_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(put, path = "/api/rooms/{room_id}/archive", tag = "rooms", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 204), (status = 403)))]
+async fn archive_room(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    let archived_at = state.message_service.lock().await.archive_room(&room_id, &caller.user.id)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::RoomArchived {
+        room_id: room_id.clone(),
+        archived_at: archived_at.to_rfc3339(),
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(delete, path = "/api/rooms/{room_id}/archive", tag = "rooms", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 204), (status = 403)))]
+async fn unarchive_room(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    state.message_service.lock().await.unarchive_room(&room_id, &caller.user.id)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::RoomUnarchived { room_id: room_id.clone() });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(post, path = "/api/rooms/{room_id}/members/{user_id}/kick", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("user_id" = String, Path)), request_body = KickBody, responses((status = 204), (status = 403)))]
+async fn kick_member(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, user_id)): Path<(String, String)>,
+    body: Option<Json<KickBody>>,
+) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    let reason = body.unwrap_or_default().0.reason;
+    let target = state.message_service.lock().await.kick_member(&room_id, &caller.user.id, &user_id, reason.as_deref())?;
+
+    let mut conns = state.connections.write().await;
+    conns.broadcast_to_room(&room_id, WsServerMessage::MemberKicked {
+        room_id: room_id.clone(),
+        user_id: target.id.clone(),
+        username: target.username,
+        kicked_by: caller.user.id.clone(),
+        reason,
+    });
+    conns.remove_member(&room_id, &target.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(post, path = "/api/reports", tag = "moderation", security(("bearer" = [])),
+    request_body = ReportBody, responses((status = 200, body = MessageReport), (status = 400)))]
+async fn report_message(State(state): State<AppState>, caller: Caller, Json(body): Json<ReportBody>) -> ApiResult<MessageReport> {
+    caller.allow(None)?;
+    let (report, moderators) = state.message_service.lock().await.report_message(&caller.user.id, &body.message_id, &body.reason)?;
+
+    let conns = state.connections.read().await;
+    for moderator_id in moderators {
+        let _ = conns.send_to_user(&moderator_id, WsServerMessage::ReportCreated { report: report.clone() });
+    }
+    Ok(Json(report))
+}
+
+#[utoipa::path(get, path = "/api/reports", tag = "moderation", security(("bearer" = [])),
+    params(ReportQuery), responses((status = 200, body = Vec<MessageReport>), (status = 403)))]
+async fn reports(State(state): State<AppState>, caller: Caller, Query(query): Query<ReportQuery>) -> ApiResult<Vec<MessageReport>> {
+    caller.allow(None)?;
+    let reports = state.message_service.lock().await.get_reports(
+        &caller.user.id,
+        query.room_id.as_deref(),
+        query.status,
+        query.limit.unwrap_or(50),
+        query.offset.unwrap_or(0),
+    )?;
+    Ok(Json(reports))
+}
+
+#[utoipa::path(post, path = "/api/reports/{report_id}/resolve", tag = "moderation", security(("bearer" = [])),
+    params(("report_id" = String, Path)), request_body = ResolveReportBody,
+    responses((status = 200, body = MessageReport), (status = 403)))]
+async fn resolve_report(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(report_id): Path<String>,
+    Json(body): Json<ResolveReportBody>,
+) -> ApiResult<MessageReport> {
+    caller.allow(None)?;
+    let (report, sanction) = state.message_service.lock().await
+        .resolve_report(&report_id, &caller.user.id, body.action, body.note.as_deref(), body.duration_minutes)?;
+
+    state.connections.write().await.broadcast_report_outcome(&caller.user.id, &report, sanction);
+    Ok(Json(report))
+}
+
+#[utoipa::path(post, path = "/api/reports/{report_id}/dismiss", tag = "moderation", security(("bearer" = [])),
+    params(("report_id" = String, Path)), request_body = DismissReportBody,
+    responses((status = 200, body = MessageReport), (status = 403)))]
+async fn dismiss_report(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(report_id): Path<String>,
+    body: Option<Json<DismissReportBody>>,
+) -> ApiResult<MessageReport> {
+    caller.allow(None)?;
+    let note = body.unwrap_or_default().0.note;
+    Ok(Json(state.message_service.lock().await.dismiss_report(&report_id, &caller.user.id, note.as_deref())?))
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}/filter", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 200, body = RoomFilter), (status = 403)))]
+async fn room_filter(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> ApiResult<RoomFilter> {
+    caller.allow(None)?;
+    Ok(Json(state.message_service.lock().await.get_room_filter(&room_id, &caller.user.id)?))
+}
+
+#[utoipa::path(put, path = "/api/rooms/{room_id}/filter", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path)), request_body = RoomFilter,
+    responses((status = 200, body = RoomFilter), (status = 400), (status = 403)))]
+async fn set_room_filter(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    Json(body): Json<RoomFilter>,
+) -> ApiResult<RoomFilter> {
+    caller.allow(None)?;
+    Ok(Json(state.message_service.lock().await.set_room_filter(&room_id, &caller.user.id, body)?))
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}/held", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path), Pagination), responses((status = 200, body = Vec<HeldMessage>), (status = 403)))]
+async fn held_messages(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    Query(page): Query<Pagination>,
+) -> ApiResult<Vec<HeldMessage>> {
+    caller.allow(None)?;
+    let messages = state.message_service.lock().await
+        .get_held_messages(&room_id, &caller.user.id, page.limit.unwrap_or(50), page.offset.unwrap_or(0))?;
+    Ok(Json(messages))
+}
+
+#[utoipa::path(post, path = "/api/rooms/{room_id}/held/{held_id}/approve", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("held_id" = String, Path)),
+    responses((status = 200, body = RoomMessageResponse), (status = 403)))]
+async fn approve_held_message(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((_room_id, held_id)): Path<(String, String)>,
+) -> ApiResult<RoomMessageResponse> {
+    caller.allow(None)?;
+    let (message, audience) = state.message_service.lock().await.approve_held_message(&held_id, &caller.user.id)?;
+
+    state.connections.read().await.broadcast_new_message(&message, &audience);
+    Ok(Json(message))
+}
+
+#[utoipa::path(delete, path = "/api/rooms/{room_id}/held/{held_id}", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("held_id" = String, Path)), request_body = RejectHeldBody,
+    responses((status = 204), (status = 403)))]
+async fn reject_held_message(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((_room_id, held_id)): Path<(String, String)>,
+    body: Option<Json<RejectHeldBody>>,
+) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    let reason = body.unwrap_or_default().0.reason;
+    let held = state.message_service.lock().await.reject_held_message(&held_id, &caller.user.id, reason.as_deref())?;
+
+    let _ = state.connections.read().await.send_to_user(&held.sender_id, WsServerMessage::HeldMessageRejected {
+        held_id: held.id,
+        room_id: held.room_id,
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/audit-log", tag = "moderation", security(("bearer" = [])),
+    params(AuditLogQuery), responses((status = 200, body = Vec<AuditEntry>), (status = 403)))]
+async fn audit_log(State(state): State<AppState>, caller: Caller, Query(query): Query<AuditLogQuery>) -> ApiResult<Vec<AuditEntry>> {
+    caller.allow(None)?;
+    let entries = state.message_service.lock().await.get_audit_log(
+        &caller.user.id,
+        &query.filter(),
+        query.limit.unwrap_or(50),
+        query.offset.unwrap_or(0),
+    )?;
+    Ok(Json(entries))
+}
+
+/// Every matching entry as JSON lines; `limit` and `offset` are ignored.
+#[utoipa::path(get, path = "/api/audit-log/export", tag = "moderation", security(("bearer" = [])),
+    params(AuditLogQuery), responses((status = 200, content_type = "application/x-ndjson", body = String), (status = 403)))]
+async fn export_audit_log(State(state): State<AppState>, caller: Caller, Query(query): Query<AuditLogQuery>) -> Result<Response, ApiError> {
+    caller.allow(None)?;
+    let lines = state.message_service.lock().await.export_audit_log(&caller.user.id, &query.filter())?;
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], lines).into_response())
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}/bans", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 200, body = Vec<RoomSanction>), (status = 403)))]
+async fn room_bans(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> ApiResult<Vec<RoomSanction>> {
+    caller.allow(None)?;
+    Ok(Json(state.message_service.lock().await.get_room_sanctions(SanctionKind::Ban, &room_id, &caller.user.id)?))
+}
+
+#[utoipa::path(put, path = "/api/rooms/{room_id}/bans/{user_id}", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("user_id" = String, Path)), request_body = SanctionBody,
+    responses((status = 200, body = RoomSanction), (status = 403)))]
+async fn ban_member(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, user_id)): Path<(String, String)>,
+    body: Option<Json<SanctionBody>>,
+) -> ApiResult<RoomSanction> {
+    caller.allow(None)?;
+    let body = body.unwrap_or_default().0;
+    let ban = state.message_service.lock().await
+        .ban_member(&room_id, &caller.user.id, &user_id, body.reason.as_deref(), body.duration_minutes)?;
+
+    let mut conns = state.connections.write().await;
+    conns.broadcast_to_room(&room_id, WsServerMessage::MemberBanned { room_id: room_id.clone(), ban: ban.clone() });
+    conns.remove_member(&room_id, &user_id);
+    Ok(Json(ban))
+}
+
+#[utoipa::path(delete, path = "/api/rooms/{room_id}/bans/{user_id}", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("user_id" = String, Path)), responses((status = 204), (status = 403)))]
+async fn unban_member(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, user_id)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    state.message_service.lock().await.lift_sanction(SanctionKind::Ban, &room_id, &caller.user.id, &user_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}/mutes", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 200, body = Vec<RoomSanction>), (status = 403)))]
+async fn room_mutes(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> ApiResult<Vec<RoomSanction>> {
+    caller.allow(None)?;
+    Ok(Json(state.message_service.lock().await.get_room_sanctions(SanctionKind::Mute, &room_id, &caller.user.id)?))
+}
+
+#[utoipa::path(put, path = "/api/rooms/{room_id}/mutes/{user_id}", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("user_id" = String, Path)), request_body = SanctionBody,
+    responses((status = 200, body = RoomSanction), (status = 403)))]
+async fn mute_member(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, user_id)): Path<(String, String)>,
+    body: Option<Json<SanctionBody>>,
+) -> ApiResult<RoomSanction> {
+    caller.allow(None)?;
+    let body = body.unwrap_or_default().0;
+    let mute = state.message_service.lock().await
+        .mute_member(&room_id, &caller.user.id, &user_id, body.reason.as_deref(), body.duration_minutes)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::MemberMuted { room_id: room_id.clone(), mute: mute.clone() });
+    Ok(Json(mute))
+}
+
+#[utoipa::path(delete, path = "/api/rooms/{room_id}/mutes/{user_id}", tag = "moderation", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("user_id" = String, Path)), responses((status = 204), (status = 403)))]
+async fn unmute_member(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, user_id)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    state.message_service.lock().await.lift_sanction(SanctionKind::Mute, &room_id, &caller.user.id, &user_id)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::MemberUnmuted { room_id: room_id.clone(), user_id });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(post, path = "/api/rooms/{room_id}/join", tag = "rooms", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 200, body = Room), (status = 404)))]
+async fn join_room(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> ApiResult<Room> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    let msg_service = state.message_service.lock().await;
+    let room = msg_service.get_room(&room_id)?.ok_or_else(|| ApiError::not_found("Room not found"))?;
+    msg_service.join_room(&caller.user.id, &room_id)?;
+
+    let mut conns = state.connections.write().await;
+    // Only succeeds if the user also has a WebSocket open
+    let _ = conns.join_room(&caller.user.id, room_id.clone());
+    conns.broadcast_to_room(&room_id, WsServerMessage::UserJoined {
+        room_id: room_id.clone(),
+        user_id: caller.user.id.clone(),
+        username: caller.user.username.clone(),
+    });
+
+    let announcement = SendRoomMessageRequest {
+        room_id: room_id.clone(),
+        content: format!("{} has joined the room", caller.user.username),
+        reply_to_message_id: None,
+    };
+    if let Ok(message) = msg_service.send_room_announcement(&caller.user.id, announcement) {
+        conns.broadcast_to_room(&room_id, WsServerMessage::NewMessage { room_id: room_id.clone(), message });
+    }
+
+    Ok(Json(room))
+}
+
+#[utoipa::path(post, path = "/api/rooms/{room_id}/leave", tag = "rooms", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 204)))]
+async fn leave_room(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> Result<StatusCode, ApiError> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    let msg_service = state.message_service.lock().await;
+    msg_service.leave_room(&caller.user.id, &room_id)?;
+
+    let mut conns = state.connections.write().await;
+    let _ = conns.leave_room(&caller.user.id, room_id.clone());
+    conns.broadcast_to_room(&room_id, WsServerMessage::UserLeft {
+        room_id: room_id.clone(),
+        user_id: caller.user.id.clone(),
+        username: caller.user.username.clone(),
+    });
+
+    let announcement = SendRoomMessageRequest {
+        room_id: room_id.clone(),
+        content: format!("{} has left the room", caller.user.username),
+        reply_to_message_id: None,
+    };
+    if let Ok(message) = msg_service.send_room_announcement(&caller.user.id, announcement) {
+        conns.broadcast_to_room(&room_id, WsServerMessage::NewMessage { room_id: room_id.clone(), message });
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}/members", tag = "rooms", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 200, body = Vec<User>)))]
+async fn room_members(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> ApiResult<Vec<User>> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    Ok(Json(state.message_service.lock().await.get_room_members(&room_id)?))
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}/messages", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path), Pagination), responses((status = 200, body = Vec<RoomMessageResponse>)))]
+async fn room_history(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    Query(page): Query<Pagination>,
+) -> ApiResult<Vec<RoomMessageResponse>> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    let messages = state.message_service.lock().await
+        .get_room_messages(&room_id, &caller.user.id, page.limit.unwrap_or(50), page.offset.unwrap_or(0))?;
+    Ok(Json(messages))
+}
+
+#[utoipa::path(post, path = "/api/rooms/{room_id}/messages", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path)), request_body = SendMessageBody,
+    responses((status = 200, body = RoomMessageResponse), (status = 202, body = HeldResponse), (status = 422)))]
+async fn send_message(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(room_id): Path<String>,
+    Json(body): Json<SendMessageBody>,
+) -> Result<Response, ApiError> {
+    caller.allow(Some(ApiScope::PostMessages))?;
+    let request = SendRoomMessageRequest {
+        room_id,
+        content: body.content,
+        reply_to_message_id: body.reply_to_message_id,
+    };
+    let msg_service = state.message_service.lock().await;
+    let (message, audience) = match msg_service.send_room_message(&caller.user.id, request) {
+        Ok(sent) => sent,
+        Err(AuthError::MessageHeld { held_id, reason }) => {
+            let (held, moderators) = msg_service.held_message_moderators(&held_id)?;
+            let conns = state.connections.read().await;
+            for moderator_id in moderators {
+                let _ = conns.send_to_user(&moderator_id, WsServerMessage::HeldMessageCreated { message: held.clone() });
+            }
+            return Ok((StatusCode::ACCEPTED, Json(HeldResponse { held_id, reason })).into_response());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    drop(msg_service);
+
+    state.connections.read().await.broadcast_new_message(&message, &audience);
+    Ok(Json(message).into_response())
+}
+
+#[utoipa::path(patch, path = "/api/rooms/{room_id}/messages/{message_id}", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("message_id" = String, Path)), request_body = EditMessageBody,
+    responses((status = 204)))]
+async fn edit_message(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, message_id)): Path<(String, String)>,
+    Json(body): Json<EditMessageBody>,
+) -> Result<StatusCode, ApiError> {
+    caller.allow(Some(ApiScope::PostMessages))?;
+    let new_content = state.message_service.lock().await.edit_message(&caller.user.id, &room_id, &message_id, &body.content)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::MessageEdited {
+        room_id: room_id.clone(),
+        message_id,
+        new_content,
+        edited_at: Utc::now().to_rfc3339(),
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(delete, path = "/api/rooms/{room_id}/messages/{message_id}", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("message_id" = String, Path)), responses((status = 204), (status = 404)))]
+async fn delete_message(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, message_id)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    caller.allow(Some(ApiScope::PostMessages))?;
+    let msg_service = state.message_service.lock().await;
+    if msg_service.message_room_id(&message_id)?.as_deref() != Some(room_id.as_str()) {
+        return Err(ApiError::not_found("Message not found"));
+    }
+    msg_service.delete_message(&caller.user.id, &message_id)?;
+    drop(msg_service);
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::MessageDeleted {
+        room_id: room_id.clone(),
+        message_id,
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}/messages/{message_id}/reactions", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("message_id" = String, Path)), responses((status = 200, body = Vec<ReactionDetail>)))]
+async fn reaction_details(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, message_id)): Path<(String, String)>,
+) -> ApiResult<Vec<ReactionDetail>> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    let details = state.message_service.lock().await
+        .get_reaction_details(&room_id, &message_id, &caller.user.id)?;
+    Ok(Json(details))
+}
+
+#[utoipa::path(post, path = "/api/rooms/{room_id}/messages/{message_id}/reactions", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("message_id" = String, Path)), request_body = ReactionBody,
+    responses((status = 200, body = Vec<ReactionSummary>)))]
+async fn add_reaction(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, message_id)): Path<(String, String)>,
+    Json(body): Json<ReactionBody>,
+) -> ApiResult<Vec<ReactionSummary>> {
+    caller.allow(Some(ApiScope::React))?;
+    let reactions = state.message_service.lock().await
+        .add_reaction(&message_id, &caller.user.id, &body.emoji)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::ReactionAdded {
+        room_id: room_id.clone(),
+        message_id,
+        emoji: body.emoji,
+        user_id: caller.user.id.clone(),
+        username: caller.user.username.clone(),
+        reactions: reactions.clone(),
+    });
+    Ok(Json(reactions))
+}
+
+#[utoipa::path(delete, path = "/api/rooms/{room_id}/messages/{message_id}/reactions/{emoji}", tag = "messages",
+    security(("bearer" = [])),
+    params(("room_id" = String, Path), ("message_id" = String, Path), ("emoji" = String, Path)),
+    responses((status = 200, body = Vec<ReactionSummary>)))]
+async fn remove_reaction(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, message_id, emoji)): Path<(String, String, String)>,
+) -> ApiResult<Vec<ReactionSummary>> {
+    caller.allow(Some(ApiScope::React))?;
+    let reactions = state.message_service.lock().await.remove_reaction(&message_id, &caller.user.id, &emoji)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::ReactionRemoved {
+        room_id: room_id.clone(),
+        message_id,
+        emoji,
+        user_id: caller.user.id.clone(),
+        reactions: reactions.clone(),
+    });
+    Ok(Json(reactions))
+}
+
+#[utoipa::path(get, path = "/api/rooms/{room_id}/pins", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path)), responses((status = 200, body = Vec<RoomMessageResponse>)))]
+async fn pinned_messages(State(state): State<AppState>, caller: Caller, Path(room_id): Path<String>) -> ApiResult<Vec<RoomMessageResponse>> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    Ok(Json(state.message_service.lock().await.get_pinned_messages(&room_id, &caller.user.id)?))
+}
+
+#[utoipa::path(put, path = "/api/rooms/{room_id}/messages/{message_id}/pin", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("message_id" = String, Path)), responses((status = 204)))]
+async fn pin_message(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, message_id)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    let pinned_at = state.message_service.lock().await.pin_message(&room_id, &message_id, &caller.user.id)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::MessagePinned {
+        room_id: room_id.clone(),
+        message_id,
+        pinned_by: caller.user.id.clone(),
+        pinned_at: pinned_at.to_rfc3339(),
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(delete, path = "/api/rooms/{room_id}/messages/{message_id}/pin", tag = "messages", security(("bearer" = [])),
+    params(("room_id" = String, Path), ("message_id" = String, Path)), responses((status = 204)))]
+async fn unpin_message(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((room_id, message_id)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    caller.allow(None)?;
+    state.message_service.lock().await.unpin_message(&room_id, &message_id, &caller.user.id)?;
+
+    state.connections.read().await.broadcast_to_room(&room_id, WsServerMessage::MessageUnpinned {
+        room_id: room_id.clone(),
+        message_id,
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/mentions", tag = "mentions", security(("bearer" = [])),
+    params(Pagination), responses((status = 200, body = Vec<RoomMessageResponse>)))]
+async fn mentions(State(state): State<AppState>, caller: Caller, Query(page): Query<Pagination>) -> ApiResult<Vec<RoomMessageResponse>> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    let mentions = state.message_service.lock().await
+        .get_user_mentions(&caller.user.id, page.limit.unwrap_or(50), page.offset.unwrap_or(0))?;
+    Ok(Json(mentions))
+}
+
+#[utoipa::path(get, path = "/api/mentions/unread", tag = "mentions", security(("bearer" = [])),
+    responses((status = 200, body = UnreadCount)))]
+async fn unread_mentions(State(state): State<AppState>, caller: Caller) -> ApiResult<UnreadCount> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    let count = state.message_service.lock().await.get_unread_mentions_count(&caller.user.id)?;
+    Ok(Json(UnreadCount { count }))
+}
+
+#[utoipa::path(post, path = "/api/mentions/{message_id}/read", tag = "mentions", security(("bearer" = [])),
+    params(("message_id" = String, Path)), responses((status = 204)))]
+async fn mark_mention_read(State(state): State<AppState>, caller: Caller, Path(message_id): Path<String>) -> Result<StatusCode, ApiError> {
+    caller.allow(Some(ApiScope::ReadRooms))?;
+    state.message_service.lock().await.mark_mention_as_read(&caller.user.id, &message_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/dms", tag = "direct messages", security(("bearer" = [])),
+    params(DirectMessageQuery), responses((status = 200, body = Vec<PrivateMessageResponse>)))]
+async fn direct_messages(
+    State(state): State<AppState>,
+    caller: Caller,
+    Query(query): Query<DirectMessageQuery>,
+) -> ApiResult<Vec<PrivateMessageResponse>> {
+    caller.allow(None)?;
+    let request = GetPrivateMessagesRequest {
+        with_user: query.with_user,
+        limit: query.limit.or(Some(100)),
+        offset: query.offset.or(Some(0)),
+        unread_only: query.unread_only,
+    };
+    Ok(Json(state.message_service.lock().await.get_private_messages(&caller.user.id, request)?))
+}
+
+#[utoipa::path(post, path = "/api/dms", tag = "direct messages", security(("bearer" = [])),
+    request_body = SendPrivateMessageRequest, responses((status = 200, body = PrivateMessageResponse)))]
+async fn send_direct_message(
+    State(state): State<AppState>,
+    caller: Caller,
+    Json(body): Json<SendPrivateMessageRequest>,
+) -> ApiResult<PrivateMessageResponse> {
+    caller.allow(None)?;
+    Ok(Json(state.message_service.lock().await.send_private_message(&caller.user.id, body)?))
+}
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// Bearer tokens are session tokens from login, or a bot's API token.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "SpaRk HTTP API"),
+    paths(
+        register, login, logout, validate, me, blocked_users, block_user, unblock_user, list_rooms, create_room, get_room, update_room, delete_room, archive_room,
+        unarchive_room, join_room, leave_room, room_members, kick_member, room_bans, ban_member, unban_member, room_mutes,
+        mute_member, unmute_member, report_message, reports, resolve_report, dismiss_report, room_filter, set_room_filter,
+        held_messages, approve_held_message, reject_held_message, audit_log, export_audit_log,
+        room_history, send_message, edit_message, delete_message, reaction_details, add_reaction, remove_reaction,
+        pinned_messages, pin_message, unpin_message, mentions, unread_mentions, mark_mention_read,
+        direct_messages, send_direct_message, crate::http::incoming_webhook,
+    ),
+    components(schemas(
+        CreateUserRequest, LoginRequest, AuthResponse, User, UserBlock, Presence, Room, MessageType, RoomMessageResponse, MessageReplyContext, ReactionSummary, ReactionDetail,
+        PrivateMessageResponse, SendPrivateMessageRequest, CreateRoomBody, UpdateRoomBody, KickBody, SanctionBody, RoomSanction, SendMessageBody, EditMessageBody,
+        ReportBody, ResolveReportBody, DismissReportBody, MessageReport, ReportStatus, ReportAction, AuditEntry, AuditAction,
+        RoomFilter, FilterAction, HeldMessage, HeldResponse, RejectHeldBody,
+        ReactionBody, UnreadCount, IncomingWebhookPayload, WebhookAttachment,
+    )),
+    modifiers(&BearerAuth),
+)]
+pub struct ApiDoc;
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/openapi.json", get(openapi))
+        .route("/api/auth/register", post(register))
+        .route("/api/auth/login", post(login))
+        .route("/api/auth/logout", post(logout))
+        .route("/api/auth/validate", get(validate))
+        .route("/api/me", get(me))
+        .route("/api/blocks", get(blocked_users))
+        .route("/api/blocks/{user_id}", put(block_user).delete(unblock_user))
+        .route("/api/rooms", get(list_rooms).post(create_room))
+        .route("/api/rooms/{room_id}", get(get_room).patch(update_room).delete(delete_room))
+        .route("/api/rooms/{room_id}/archive", put(archive_room).delete(unarchive_room))
+        .route("/api/rooms/{room_id}/join", post(join_room))
+        .route("/api/rooms/{room_id}/leave", post(leave_room))
+        .route("/api/rooms/{room_id}/members", get(room_members))
+        .route("/api/rooms/{room_id}/members/{user_id}/kick", post(kick_member))
+        .route("/api/rooms/{room_id}/bans", get(room_bans))
+        .route("/api/rooms/{room_id}/bans/{user_id}", put(ban_member).delete(unban_member))
+        .route("/api/rooms/{room_id}/mutes", get(room_mutes))
+        .route("/api/rooms/{room_id}/mutes/{user_id}", put(mute_member).delete(unmute_member))
+        .route("/api/reports", get(reports).post(report_message))
+        .route("/api/reports/{report_id}/resolve", post(resolve_report))
+        .route("/api/reports/{report_id}/dismiss", post(dismiss_report))
+        .route("/api/rooms/{room_id}/filter", get(room_filter).put(set_room_filter))
+        .route("/api/rooms/{room_id}/held", get(held_messages))
+        .route("/api/rooms/{room_id}/held/{held_id}/approve", post(approve_held_message))
+        .route("/api/rooms/{room_id}/held/{held_id}", axum::routing::delete(reject_held_message))
+        .route("/api/audit-log", get(audit_log))
+        .route("/api/audit-log/export", get(export_audit_log))
+        .route("/api/rooms/{room_id}/messages", get(room_history).post(send_message))
+        .route("/api/rooms/{room_id}/messages/{message_id}", axum::routing::patch(edit_message).delete(delete_message))
+        .route("/api/rooms/{room_id}/messages/{message_id}/reactions", get(reaction_details).post(add_reaction))
+        .route("/api/rooms/{room_id}/messages/{message_id}/reactions/{emoji}", axum::routing::delete(remove_reaction))
+        .route("/api/rooms/{room_id}/messages/{message_id}/pin", put(pin_message).delete(unpin_message))
+        .route("/api/rooms/{room_id}/pins", get(pinned_messages))
+        .route("/api/mentions", get(mentions))
+        .route("/api/mentions/unread", get(unread_mentions))
+        .route("/api/mentions/{message_id}/read", post(mark_mention_read))
+        .route("/api/dms", get(direct_messages).post(send_direct_message))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        network::{AuthService, MessageService},
+        storage::Storage,
+        users::{ApiScope, AuthResponse, CreateUserRequest, UserRole},
+        webhooks::WebhookService,
+        websocket::ConnectionManager,
+        Database, HttpServer,
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::sync::{Mutex, RwLock};
+
+    /// Runs the HTTP server on a fresh database. Returns the base URL, the
+    /// auth service and the database path.
+    async fn spawn_server() -> (String, Arc<Mutex<AuthService>>, std::path::PathBuf) {
+        let db_path = std::env::temp_dir().join(format!("spark-api-{}.db", uuid::Uuid::new_v4()));
+        let auth = Arc::new(Mutex::new(AuthService::new(Database::new(&db_path).unwrap())));
+        let message_service = Arc::new(Mutex::new(MessageService::new(Database::new(&db_path).unwrap())));
+        let webhooks = Arc::new(Mutex::new(WebhookService::new(Database::new(&db_path).unwrap())));
+        let connections = Arc::new(RwLock::new(ConnectionManager::new()));
+
+        let server = HttpServer::new(Arc::clone(&auth), message_service, connections, webhooks, String::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let router = server.router();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        (base, auth, db_path)
+    }
+
+    /// Signs up `name` with a `name@test.com` address.
+    async fn register(auth: &Mutex<AuthService>, name: &str) -> AuthResponse {
+        auth.lock().await.register(CreateUserRequest {
+            username: name.to_string(),
+            email: format!("{}@test.com", name),
+            password: "test_password_123".to_string(),
+            invite_code: None,
+        }).unwrap()
+    }
+
+    /// Sends a request with an optional bearer token, returning the status and JSON body.
+    async fn call(method: &'static str, url: String, token: Option<String>, body: Option<Value>) -> (u16, Value) {
+        tokio::task::spawn_blocking(move || {
+            let mut request = ureq::request(method, &url);
+            if let Some(token) = token {
+                request = request.set("Authorization", &format!("Bearer {}", token));
+            }
+            let result = match body {
+                Some(body) => request.send_json(body),
+                None => request.call(),
+            };
+            let response = match result {
+                Ok(response) => response,
+                Err(ureq::Error::Status(_, response)) => response,
+                Err(e) => panic!("request failed: {}", e),
+            };
+            let status = response.status();
+            (status, response.into_json().unwrap_or(Value::Null))
+        }).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rest_api_with_bearer_tokens() {
+        let (base, auth, db_path) = spawn_server().await;
+        let session = register(&auth, "alice").await;
+        let token = Some(session.token.clone());
+
+        let (status, _) = call("GET", format!("{}/api/rooms", base), None, None).await;
+        assert_eq!(status, 401);
+        let (status, _) = call("GET", format!("{}/api/rooms", base), Some("bogus".to_string()), None).await;
+        assert_eq!(status, 401);
+
+        let (status, me) = call("GET", format!("{}/api/me", base), token.clone(), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(me["username"], "alice");
+
+        let (status, room) = call("POST", format!("{}/api/rooms", base), token.clone(), Some(json!({ "name": "General" }))).await;
+        assert_eq!(status, 200);
+        let room_id = room["id"].as_str().unwrap().to_string();
+
+        let (status, _) = call("POST", format!("{}/api/rooms/{}/join", base, room_id), token.clone(), None).await;
+        assert_eq!(status, 200);
+
+        let (status, message) = call("POST", format!("{}/api/rooms/{}/messages", base, room_id), token.clone(),
+            Some(json!({ "content": "hello over http" }))).await;
+        assert_eq!(status, 200);
+        let message_id = message["id"].as_str().unwrap().to_string();
+
+        let (status, reactions) = call("POST", format!("{}/api/rooms/{}/messages/{}/reactions", base, room_id, message_id),
+            token.clone(), Some(json!({ "emoji": "👍" }))).await;
+        assert_eq!(status, 200);
+        assert_eq!(reactions[0]["count"], 1);
+
+        let (status, history) = call("GET", format!("{}/api/rooms/{}/messages?limit=10", base, room_id), token.clone(), None).await;
+        assert_eq!(status, 200);
+        assert!(history.as_array().unwrap().iter().any(|m| m["content"] == "hello over http"));
+
+        let (status, _) = call("GET", format!("{}/api/rooms/does-not-exist", base), token.clone(), None).await;
+        assert_eq!(status, 404);
+
+        // A bot token only reaches the routes its scopes allow
+        let bot_token = {
+            let auth = auth.lock().await;
+            let bot = auth.create_bot(&session.token, "reader-bot").unwrap();
+            auth.create_api_token(&session.token, &bot.id, "ci", vec![ApiScope::ReadRooms]).unwrap().token
+        };
+        let (status, _) = call("GET", format!("{}/api/rooms", base), Some(bot_token.clone()), None).await;
+        assert_eq!(status, 200);
+        let (status, _) = call("POST", format!("{}/api/rooms/{}/messages", base, room_id), Some(bot_token),
+            Some(json!({ "content": "not allowed" }))).await;
+        assert_eq!(status, 403);
+
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_room_management_is_limited_to_the_creator() {
+        let (base, auth, db_path) = spawn_server().await;
+        let owner = Some(register(&auth, "owner").await.token);
+        let member = Some(register(&auth, "member").await.token);
+
+        let (_, room) = call("POST", format!("{}/api/rooms", base), owner.clone(), Some(json!({ "name": "General" }))).await;
+        let room_url = format!("{}/api/rooms/{}", base, room["id"].as_str().unwrap());
+        call("POST", format!("{}/join", room_url), member.clone(), None).await;
+
+        let (status, _) = call("PATCH", room_url.clone(), member.clone(), Some(json!({ "name": "Mine now" }))).await;
+        assert_eq!(status, 403);
+        let (status, updated) = call("PATCH", room_url.clone(), owner.clone(), Some(json!({ "topic": "Launch day" }))).await;
+        assert_eq!(status, 200);
+        assert_eq!(updated["name"], "General");
+        assert_eq!(updated["topic"], "Launch day");
+
+        let (status, _) = call("PUT", format!("{}/archive", room_url), owner.clone(), None).await;
+        assert_eq!(status, 204);
+        let (status, _) = call("POST", format!("{}/messages", room_url), member.clone(), Some(json!({ "content": "hello?" }))).await;
+        assert_eq!(status, 400);
+        let (_, rooms) = call("GET", format!("{}/api/rooms", base), member.clone(), None).await;
+        assert!(rooms.as_array().unwrap().is_empty());
+        let (_, rooms) = call("GET", format!("{}/api/rooms?include_archived=true", base), member.clone(), None).await;
+        assert!(rooms[0]["archived_at"].is_string());
+
+        let (status, _) = call("DELETE", format!("{}/archive", room_url), owner.clone(), None).await;
+        assert_eq!(status, 204);
+        let (status, _) = call("POST", format!("{}/messages", room_url), member.clone(), Some(json!({ "content": "back" }))).await;
+        assert_eq!(status, 200);
+
+        let (status, _) = call("DELETE", room_url.clone(), member.clone(), None).await;
+        assert_eq!(status, 403);
+        let (status, _) = call("DELETE", room_url.clone(), owner.clone(), None).await;
+        assert_eq!(status, 204);
+        let (status, _) = call("GET", room_url, owner, None).await;
+        assert_eq!(status, 404);
+
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_only_the_sender_edits_a_message() {
+        let (base, auth, db_path) = spawn_server().await;
+        let owner = Some(register(&auth, "owner").await.token);
+        let member = Some(register(&auth, "member").await.token);
+
+        let (_, room) = call("POST", format!("{}/api/rooms", base), owner.clone(), Some(json!({ "name": "General" }))).await;
+        let (_, other_room) = call("POST", format!("{}/api/rooms", base), owner.clone(), Some(json!({ "name": "Other" }))).await;
+        let room_url = format!("{}/api/rooms/{}", base, room["id"].as_str().unwrap());
+        let other_url = format!("{}/api/rooms/{}", base, other_room["id"].as_str().unwrap());
+        call("POST", format!("{}/join", room_url), owner.clone(), None).await;
+        call("POST", format!("{}/join", room_url), member.clone(), None).await;
+
+        let (_, message) = call("POST", format!("{}/messages", room_url), owner.clone(), Some(json!({ "content": "original" }))).await;
+        let message_id = message["id"].as_str().unwrap();
+
+        let (status, _) = call("PATCH", format!("{}/messages/{}", room_url, message_id), member.clone(),
+            Some(json!({ "content": "rewritten" }))).await;
+        assert_eq!(status, 403);
+        let (status, _) = call("PATCH", format!("{}/messages/{}", other_url, message_id), owner.clone(),
+            Some(json!({ "content": "rewritten" }))).await;
+        assert_eq!(status, 400);
+
+        let (status, _) = call("PATCH", format!("{}/messages/{}", room_url, message_id), owner.clone(),
+            Some(json!({ "content": "fixed typo" }))).await;
+        assert_eq!(status, 204);
+        let (_, history) = call("GET", format!("{}/messages", room_url), member, None).await;
+        assert_eq!(history[0]["content"], "fixed typo");
+
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_messages_are_deleted_through_their_own_room() {
+        let (base, auth, db_path) = spawn_server().await;
+        let admin_session = register(&auth, "admin").await;
+        Database::new(&db_path).unwrap().set_user_role(&admin_session.user.id, UserRole::Admin).unwrap();
+        let admin = Some(admin_session.token);
+        let member = Some(register(&auth, "member").await.token);
+
+        let (_, room) = call("POST", format!("{}/api/rooms", base), member.clone(), Some(json!({ "name": "General" }))).await;
+        let (_, other_room) = call("POST", format!("{}/api/rooms", base), admin.clone(), Some(json!({ "name": "Other" }))).await;
+        let room_url = format!("{}/api/rooms/{}", base, room["id"].as_str().unwrap());
+        let other_url = format!("{}/api/rooms/{}", base, other_room["id"].as_str().unwrap());
+        call("POST", format!("{}/join", room_url), member.clone(), None).await;
+        let (_, message) = call("POST", format!("{}/messages", room_url), member.clone(), Some(json!({ "content": "hello" }))).await;
+        let message_id = message["id"].as_str().unwrap();
+
+        let (status, _) = call("DELETE", format!("{}/messages/{}", other_url, message_id), admin.clone(), None).await;
+        assert_eq!(status, 404);
+        let listed = |history: &Value| history.as_array().unwrap().iter().any(|m| m["id"] == message["id"]);
+        let (_, history) = call("GET", format!("{}/messages", room_url), member.clone(), None).await;
+        assert!(listed(&history));
+
+        let (status, _) = call("DELETE", format!("{}/messages/{}", room_url, message_id), admin, None).await;
+        assert_eq!(status, 204);
+        let (_, history) = call("GET", format!("{}/messages", room_url), member, None).await;
+        assert!(!listed(&history));
+
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kick_ban_and_mute() {
+        let (base, auth, db_path) = spawn_server().await;
+        let owner_session = register(&auth, "owner").await;
+        let member_session = register(&auth, "member").await;
+        let (owner, member) = (Some(owner_session.token), Some(member_session.token));
+        let member_id = member_session.user.id;
+
+        let (_, room) = call("POST", format!("{}/api/rooms", base), owner.clone(), Some(json!({ "name": "General" }))).await;
+        let room_url = format!("{}/api/rooms/{}", base, room["id"].as_str().unwrap());
+        call("POST", format!("{}/join", room_url), member.clone(), None).await;
+
+        let (status, _) = call("PUT", format!("{}/bans/{}", room_url, owner_session.user.id), member.clone(), None).await;
+        assert_eq!(status, 403);
+
+        let (_, welcome) = call("POST", format!("{}/messages", room_url), owner.clone(), Some(json!({ "content": "welcome" }))).await;
+        let welcome_reactions = format!("{}/messages/{}/reactions", room_url, welcome["id"].as_str().unwrap());
+
+        let (status, _) = call("POST", format!("{}/members/{}/kick", room_url, member_id), owner.clone(), None).await;
+        assert_eq!(status, 204);
+        // Former members can't react to what they can no longer see
+        let (status, _) = call("POST", welcome_reactions.clone(), member.clone(), Some(json!({ "emoji": "👎" }))).await;
+        assert_eq!(status, 400);
+        let (status, _) = call("POST", format!("{}/join", room_url), member.clone(), None).await;
+        assert_eq!(status, 200);
+
+        let (status, ban) = call("PUT", format!("{}/bans/{}", room_url, member_id), owner.clone(),
+            Some(json!({ "reason": "spam", "duration_minutes": 60 }))).await;
+        assert_eq!(status, 200);
+        assert_eq!(ban["username"], "member");
+        assert!(ban["expires_at"].is_string());
+        let (status, error) = call("POST", format!("{}/join", room_url), member.clone(), None).await;
+        assert_eq!(status, 403);
+        assert!(error["error"].as_str().unwrap().contains("banned"));
+        let (_, bans) = call("GET", format!("{}/bans", room_url), owner.clone(), None).await;
+        assert_eq!(bans[0]["reason"], "spam");
+
+        let (status, _) = call("POST", welcome_reactions, member.clone(), Some(json!({ "emoji": "👎" }))).await;
+        assert_eq!(status, 400);
+
+        let (status, _) = call("DELETE", format!("{}/bans/{}", room_url, member_id), owner.clone(), None).await;
+        assert_eq!(status, 204);
+        let (status, _) = call("POST", format!("{}/join", room_url), member.clone(), None).await;
+        assert_eq!(status, 200);
+
+        let (_, message) = call("POST", format!("{}/messages", room_url), owner.clone(), Some(json!({ "content": "hi" }))).await;
+        let (_, own) = call("POST", format!("{}/messages", room_url), member.clone(), Some(json!({ "content": "mine" }))).await;
+        let pin_url = format!("{}/messages/{}/pin", room_url, message["id"].as_str().unwrap());
+        let (status, _) = call("PUT", pin_url.clone(), owner.clone(), None).await;
+        assert_eq!(status, 204);
+        let (status, _) = call("PUT", format!("{}/mutes/{}", room_url, member_id), owner.clone(), None).await;
+        assert_eq!(status, 200);
+
+        // Muted members can't change what the room sees in other ways either
+        let (status, _) = call("PATCH", format!("{}/messages/{}", room_url, own["id"].as_str().unwrap()), member.clone(),
+            Some(json!({ "content": "edited while muted" }))).await;
+        assert_eq!(status, 403);
+        let (status, _) = call("DELETE", pin_url.clone(), member.clone(), None).await;
+        assert_eq!(status, 403);
+        let (status, _) = call("PUT", format!("{}/messages/{}/pin", room_url, own["id"].as_str().unwrap()), member.clone(), None).await;
+        assert_eq!(status, 403);
+        let (status, _) = call("POST", format!("{}/messages", room_url), member.clone(), Some(json!({ "content": "hello?" }))).await;
+        assert_eq!(status, 403);
+        let (status, _) = call("POST", format!("{}/messages/{}/reactions", room_url, message["id"].as_str().unwrap()),
+            member.clone(), Some(json!({ "emoji": "👍" }))).await;
+        assert_eq!(status, 403);
+        let (status, history) = call("GET", format!("{}/messages", room_url), member.clone(), None).await;
+        assert_eq!(status, 200);
+        assert!(!history.as_array().unwrap().is_empty());
+
+        let (status, _) = call("DELETE", format!("{}/mutes/{}", room_url, member_id), owner.clone(), None).await;
+        assert_eq!(status, 204);
+        let (status, _) = call("POST", format!("{}/messages", room_url), member, Some(json!({ "content": "thanks" }))).await;
+        assert_eq!(status, 200);
+
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reports_and_moderation_queue() {
+        let (base, auth, db_path) = spawn_server().await;
+        let owner = Some(register(&auth, "owner").await.token);
+        let troll_session = register(&auth, "troll").await;
+        let (troll, troll_id) = (Some(troll_session.token), troll_session.user.id);
+        let witness = Some(register(&auth, "witness").await.token);
+
+        let (_, room) = call("POST", format!("{}/api/rooms", base), owner.clone(), Some(json!({ "name": "General" }))).await;
+        let room_id = room["id"].as_str().unwrap().to_string();
+        let room_url = format!("{}/api/rooms/{}", base, room_id);
+        call("POST", format!("{}/join", room_url), troll.clone(), None).await;
+        call("POST", format!("{}/join", room_url), witness.clone(), None).await;
+        let (_, first) = call("POST", format!("{}/messages", room_url), troll.clone(), Some(json!({ "content": "rude" }))).await;
+        let (_, second) = call("POST", format!("{}/messages", room_url), troll.clone(), Some(json!({ "content": "ruder" }))).await;
+
+        let reports_url = format!("{}/api/reports", base);
+        let (status, report) = call("POST", reports_url.clone(), witness.clone(),
+            Some(json!({ "message_id": first["id"], "reason": "insulting" }))).await;
+        assert_eq!(status, 200);
+        assert_eq!(report["content"], "rude");
+        assert_eq!(report["status"], "open");
+        let (status, _) = call("POST", reports_url.clone(), witness.clone(), Some(json!({ "message_id": first["id"], "reason": "again" }))).await;
+        assert_eq!(status, 400);
+        let (status, _) = call("POST", reports_url.clone(), troll.clone(), Some(json!({ "message_id": first["id"], "reason": "me" }))).await;
+        assert_eq!(status, 400);
+
+        // The queue is for the room's moderators
+        let queue_url = format!("{}?room_id={}&status=open", reports_url, room_id);
+        let (status, _) = call("GET", queue_url.clone(), witness.clone(), None).await;
+        assert_eq!(status, 403);
+        let (status, queue) = call("GET", queue_url.clone(), owner.clone(), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(queue.as_array().unwrap().len(), 1);
+
+        let report_url = format!("{}/{}", reports_url, report["id"].as_str().unwrap());
+        let (status, resolved) = call("POST", format!("{}/resolve", report_url), owner.clone(),
+            Some(json!({ "action": "delete_message", "note": "removed" }))).await;
+        assert_eq!(status, 200);
+        assert_eq!(resolved["status"], "resolved");
+        assert_eq!(resolved["action"], "delete_message");
+        let (_, history) = call("GET", format!("{}/messages", room_url), owner.clone(), None).await;
+        assert!(history.as_array().unwrap().iter().all(|m| m["id"] != first["id"]));
+        let (status, _) = call("POST", format!("{}/dismiss", report_url), owner.clone(), None).await;
+        assert_eq!(status, 400);
+
+        let (_, report) = call("POST", reports_url.clone(), witness.clone(), Some(json!({ "message_id": second["id"], "reason": "still rude" }))).await;
+        let report_url = format!("{}/{}", reports_url, report["id"].as_str().unwrap());
+        let (status, _) = call("POST", format!("{}/resolve", report_url), owner.clone(), Some(json!({ "action": "mute", "duration_minutes": 10 }))).await;
+        assert_eq!(status, 200);
+        let (status, _) = call("POST", format!("{}/messages", room_url), troll.clone(), Some(json!({ "content": "hello?" }))).await;
+        assert_eq!(status, 403);
+        let (_, mutes) = call("GET", format!("{}/mutes", room_url), owner.clone(), None).await;
+        assert_eq!(mutes[0]["user_id"], troll_id.as_str());
+        assert_eq!(mutes[0]["reason"], "still rude");
+
+        let (_, queue) = call("GET", queue_url, owner.clone(), None).await;
+        assert!(queue.as_array().unwrap().is_empty());
+        let (_, entries) = call("GET", format!("{}/api/audit-log?room_id={}", base, room_id), owner, None).await;
+        let actions: Vec<&str> = entries.as_array().unwrap().iter().map(|e| e["action"].as_str().unwrap()).collect();
+        assert_eq!(actions, vec!["report.resolved", "member.muted", "report.resolved", "message.deleted"]);
+
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_room_content_filter() {
+        let (base, auth, db_path) = spawn_server().await;
+        let owner = Some(register(&auth, "owner").await.token);
+        let member_session = register(&auth, "member").await;
+        let (member, member_id) = (Some(member_session.token), member_session.user.id);
+
+        let (_, room) = call("POST", format!("{}/api/rooms", base), owner.clone(), Some(json!({ "name": "General" }))).await;
+        let room_url = format!("{}/api/rooms/{}", base, room["id"].as_str().unwrap());
+        call("POST", format!("{}/join", room_url), member.clone(), None).await;
+        let messages_url = format!("{}/messages", room_url);
+        let filter_url = format!("{}/filter", room_url);
+
+        let (status, _) = call("PUT", filter_url.clone(), member.clone(), Some(json!({ "blocked_words": ["darn"], "action": "reject" }))).await;
+        assert_eq!(status, 403);
+        let (status, _) = call("PUT", filter_url.clone(), owner.clone(), Some(json!({ "blocked_words": ["/(oops/"], "action": "reject" }))).await;
+        assert_eq!(status, 400);
+
+        // Reject
+        let (status, filter) = call("PUT", filter_url.clone(), owner.clone(), Some(json!({ "blocked_words": ["darn", " "], "action": "reject" }))).await;
+        assert_eq!(status, 200);
+        assert_eq!(filter["blocked_words"], json!(["darn"]));
+        let (status, error) = call("POST", messages_url.clone(), member.clone(), Some(json!({ "content": "well Darn" }))).await;
+        assert_eq!(status, 422);
+        assert_eq!(error["error"], "Message rejected: contains a blocked word");
+        let (status, clean) = call("POST", messages_url.clone(), member.clone(), Some(json!({ "content": "well then" }))).await;
+        assert_eq!(status, 200);
+        let (status, _) = call("PATCH", format!("{}/{}", messages_url, clean["id"].as_str().unwrap()), member.clone(),
+            Some(json!({ "content": "well darn" }))).await;
+        assert_eq!(status, 422);
+
+        // Mask
+        call("PUT", filter_url.clone(), owner.clone(), Some(json!({ "blocked_words": ["darn"], "action": "mask" }))).await;
+        let (status, masked) = call("POST", messages_url.clone(), member.clone(), Some(json!({ "content": "well darn it" }))).await;
+        assert_eq!(status, 200);
+        assert_eq!(masked["content"], "well **** it");
+
+        // Room link lists, under the same action as the words
+        let (status, filter) = call("PUT", filter_url.clone(), owner.clone(),
+            Some(json!({ "blocked_words": [], "action": "reject", "denied_link_domains": ["evil.example"] }))).await;
+        assert_eq!(status, 200);
+        assert_eq!(filter["denied_link_domains"], json!(["evil.example"]));
+        let (status, error) = call("POST", messages_url.clone(), member.clone(),
+            Some(json!({ "content": "see https://cdn.evil.example/x" }))).await;
+        assert_eq!(status, 422);
+        assert_eq!(error["error"], "Message rejected: links to cdn.evil.example are not allowed");
+        let (status, _) = call("POST", messages_url.clone(), member.clone(), Some(json!({ "content": "see https://docs.rs" }))).await;
+        assert_eq!(status, 200);
+
+        // Hold, then approve one and reject another
+        call("PUT", filter_url.clone(), owner.clone(), Some(json!({ "blocked_words": ["darn"], "action": "hold" }))).await;
+        let (status, held) = call("POST", messages_url.clone(), member.clone(), Some(json!({ "content": "darn, first" }))).await;
+        assert_eq!(status, 202);
+        assert_eq!(held["reason"], "contains a blocked word");
+        let (_, second) = call("POST", messages_url.clone(), member.clone(), Some(json!({ "content": "darn, second" }))).await;
+
+        let (status, _) = call("GET", format!("{}/held", room_url), member.clone(), None).await;
+        assert_eq!(status, 403);
+        let (_, queue) = call("GET", format!("{}/held", room_url), owner.clone(), None).await;
+        let queued: Vec<&str> = queue.as_array().unwrap().iter().map(|m| m["content"].as_str().unwrap()).collect();
+        assert_eq!(queued, vec!["darn, first", "darn, second"]);
+
+        let held_url = format!("{}/held/{}", room_url, held["held_id"].as_str().unwrap());
+        let (status, posted) = call("POST", format!("{}/approve", held_url), owner.clone(), None).await;
+        assert_eq!(status, 200);
+        assert_eq!(posted["content"], "darn, first");
+        assert_eq!(posted["sender_username"], "member");
+        let (status, _) = call("POST", format!("{}/approve", held_url), owner.clone(), None).await;
+        assert_eq!(status, 400);
+        let (status, _) = call("DELETE", format!("{}/held/{}", room_url, second["held_id"].as_str().unwrap()), owner.clone(),
+            Some(json!({ "reason": "no" }))).await;
+        assert_eq!(status, 204);
+
+        let (_, queue) = call("GET", format!("{}/held", room_url), owner.clone(), None).await;
+        assert!(queue.as_array().unwrap().is_empty());
+        let (_, history) = call("GET", messages_url.clone(), member.clone(), None).await;
+        let contents: Vec<&str> = history.as_array().unwrap().iter().map(|m| m["content"].as_str().unwrap()).collect();
+        assert!(contents.contains(&"darn, first"));
+        assert!(!contents.contains(&"darn, second"));
+
+        let (_, entries) = call("GET", format!("{}/api/audit-log?room_id={}", base, room["id"].as_str().unwrap()), owner.clone(), None).await;
+        let actions: Vec<&str> = entries.as_array().unwrap().iter().map(|e| e["action"].as_str().unwrap()).take(2).collect();
+        assert_eq!(actions, vec!["held_message.rejected", "held_message.approved"]);
+
+        // Approval rechecks that the sender may still post
+        let (_, third) = call("POST", messages_url, member.clone(), Some(json!({ "content": "darn, third" }))).await;
+        let approve_url = format!("{}/held/{}/approve", room_url, third["held_id"].as_str().unwrap());
+        call("PUT", format!("{}/mutes/{}", room_url, member_id), owner.clone(), None).await;
+        let (status, _) = call("POST", approve_url.clone(), owner.clone(), None).await;
+        assert_eq!(status, 403);
+        call("DELETE", format!("{}/mutes/{}", room_url, member_id), owner.clone(), None).await;
+        call("POST", format!("{}/leave", room_url), member, None).await;
+        let (status, _) = call("POST", approve_url, owner.clone(), None).await;
+        assert_eq!(status, 400);
+        let (_, queue) = call("GET", format!("{}/held", room_url), owner, None).await;
+        assert_eq!(queue.as_array().unwrap().len(), 1);
+
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audit_log() {
+        let (base, auth, db_path) = spawn_server().await;
+        let owner_session = register(&auth, "owner").await;
+        let member_session = register(&auth, "member").await;
+        let (owner, member) = (Some(owner_session.token), Some(member_session.token));
+        let member_id = member_session.user.id;
+
+        let (_, room) = call("POST", format!("{}/api/rooms", base), owner.clone(), Some(json!({ "name": "General" }))).await;
+        let room_id = room["id"].as_str().unwrap().to_string();
+        let room_url = format!("{}/api/rooms/{}", base, room_id);
+        call("POST", format!("{}/join", room_url), member.clone(), None).await;
+        call("POST", format!("{}/members/{}/kick", room_url, member_id), owner.clone(), Some(json!({ "reason": "off topic" }))).await;
+        call("PUT", format!("{}/bans/{}", room_url, member_id), owner.clone(), Some(json!({ "reason": "spam" }))).await;
+        call("PATCH", room_url.clone(), owner.clone(), Some(json!({ "topic": "Rules first" }))).await;
+
+        let log_url = format!("{}/api/audit-log?room_id={}", base, room_id);
+        let (status, entries) = call("GET", log_url.clone(), owner.clone(), None).await;
+        assert_eq!(status, 200);
+        let actions: Vec<&str> = entries.as_array().unwrap().iter().map(|e| e["action"].as_str().unwrap()).collect();
+        assert_eq!(actions, vec!["room.updated", "member.banned", "member.kicked"]);
+        assert_eq!(entries[2]["reason"], "off topic");
+        assert_eq!(entries[2]["target_id"], member_id.as_str());
+
+        let (_, entries) = call("GET", format!("{}&action=member.banned", log_url), owner.clone(), None).await;
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        let (_, entries) = call("GET", format!("{}&limit=1&offset=1", log_url), owner.clone(), None).await;
+        assert_eq!(entries[0]["action"], "member.banned");
+
+        // Only admins may read the log across rooms
+        let (status, _) = call("GET", log_url.clone(), member.clone(), None).await;
+        assert_eq!(status, 403);
+        let (status, _) = call("GET", format!("{}/api/audit-log", base), owner.clone(), None).await;
+        assert_eq!(status, 403);
+
+        let (_, message) = call("POST", format!("{}/messages", room_url), owner.clone(), Some(json!({ "content": "Read the rules" }))).await;
+        let pin_url = format!("{}/messages/{}/pin", room_url, message["id"].as_str().unwrap());
+        call("PUT", pin_url.clone(), owner.clone(), None).await;
+        call("DELETE", pin_url, owner.clone(), None).await;
+        let (_, entries) = call("GET", format!("{}&action=message.pinned", log_url), owner.clone(), None).await;
+        assert_eq!(entries[0]["target_id"], message["id"]);
+        let (_, entries) = call("GET", format!("{}&action=message.unpinned", log_url), owner.clone(), None).await;
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+
+        let export_url = format!("{}/api/audit-log/export?room_id={}", base, room_id);
+        let (content_type, body) = tokio::task::spawn_blocking(move || {
+            let response = ureq::get(&export_url).set("Authorization", &format!("Bearer {}", owner.unwrap())).call().unwrap();
+            (response.content_type().to_string(), response.into_string().unwrap())
+        }).await.unwrap();
+        assert_eq!(content_type, "application/x-ndjson");
+        let lines: Vec<Value> = body.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0]["action"], "message.unpinned");
+
+        let _ = std::fs::remove_file(db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_openapi_document() {
+        let (base, _, db_path) = spawn_server().await;
+
+        let (status, doc) = call("GET", format!("{}/api/openapi.json", base), None, None).await;
+        assert_eq!(status, 200);
+        assert!(doc["paths"]["/api/rooms/{room_id}/messages"]["post"].is_object());
+        assert!(doc["components"]["schemas"]["RoomMessageResponse"].is_object());
+        assert!(doc["components"]["securitySchemes"]["bearer"].is_object());
+
+        let _ = std::fs::remove_file(db_path);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{
+    config::Argon2Config,
+    error::{AuthError, Result},
+    users::User,
+    storage::Storage,
+};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version
+};
+
+/// Stored as the password hash of accounts without a local password: those
+/// provisioned from an external provider, and bots. It never parses as a PHC
+/// string, so it can't be logged into locally.
+pub const EXTERNAL_PASSWORD_HASH: &str = "!external";
+
+/// A user as asserted by an external identity provider.
+#[derive(Debug, Clone)]
+pub struct ExternalIdentity {
+    pub provider: String,
+    /// Stable identifier for the user at the provider (OIDC `sub`, LDAP DN, ...)
+    pub subject: String,
+    pub username: String,
+    pub email: Option<String>,
+    /// Whether the provider vouches that `email` belongs to this user
+    pub email_verified: bool,
+}
+
+pub enum Identity {
+    Local(User),
+    External(ExternalIdentity),
+}
+
+/// Where to send the user's browser to start a redirect-based sign in.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct AuthorizationRequest {
+    pub provider: String,
+    pub url: String,
+    pub state: String,
+}
+
+/// A source of user authentication. Password-based providers implement
+/// `authenticate`, redirect-based ones implement the authorization pair.
+pub trait AuthProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Checks a username and password. `Ok(None)` means the provider doesn't
+    /// know the user and the next provider should be tried.
+    fn authenticate(&self, _db: &dyn Storage, _username: &str, _password: &str) -> Result<Option<Identity>> {
+        Ok(None)
+    }
+
+    fn begin_authorization(&self) -> Result<AuthorizationRequest> {
+        Err(AuthError::InvalidInput(format!("{} does not support browser sign in", self.name())))
+    }
+
+    fn complete_authorization(&self, _state: &str, _code: &str) -> Result<ExternalIdentity> {
+        Err(AuthError::InvalidInput(format!("{} does not support browser sign in", self.name())))
+    }
+}
+
+/// The built-in provider backed by Argon2 hashes in the `users` table.
+pub struct LocalProvider {
+    argon2: Argon2Config,
+}
+
+impl LocalProvider {
+    pub fn new(argon2: Argon2Config) -> Self {
+        Self { argon2 }
+    }
+}
+
+impl AuthProvider for LocalProvider {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn authenticate(&self, db: &dyn Storage, username: &str, password: &str) -> Result<Option<Identity>> {
+        let user = match db.get_user_by_username(username)? {
+            Some(user) if !user.is_bot && user.password_hash != EXTERNAL_PASSWORD_HASH => user,
+            _ => return Ok(None),
+        };
+
+        if !verify_password(password, &user.password_hash)? {
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        // The plaintext is only available here, so upgrade outdated hashes now
+        if needs_rehash(&self.argon2, &user.password_hash)? {
+            let password_hash = hash_password(&self.argon2, password)?;
+            db.update_user_password(&user.id, &password_hash)?;
+        }
+
+        Ok(Some(Identity::Local(user)))
+    }
+}
+
+fn argon2_with(config: &Argon2Config) -> Result<Argon2<'static>> {
+    let params = Params::new(config.memory_kib, config.iterations, config.parallelism, None)
+        .map_err(|e| AuthError::PasswordHash(e.to_string()))?;
+
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+pub fn hash_password(config: &Argon2Config, password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+
+    argon2_with(config)?
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AuthError::PasswordHash(e.to_string()))
+}
+
+pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
+    let parsed_hash = PasswordHash::new(hash)
+        .map_err(|e| AuthError::PasswordHash(e.to_string()))?;
+
+    Ok(Argon2::default()
+    .verify_password(password.as_bytes(), &parsed_hash)
+    .is_ok())
+}
+
+/// True when a stored hash wasn't produced with the current algorithm and
+/// cost parameters.
+pub fn needs_rehash(config: &Argon2Config, hash: &str) -> Result<bool> {
+    let parsed_hash = PasswordHash::new(hash)
+        .map_err(|e| AuthError::PasswordHash(e.to_string()))?;
+
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident() {
+        return Ok(true);
+    }
+
+    let params = Params::try_from(&parsed_hash)
+        .map_err(|e| AuthError::PasswordHash(e.to_string()))?;
+
+    Ok(params.m_cost() != config.memory_kib
+        || params.t_cost() != config.iterations
+        || params.p_cost() != config.parallelism)
+}
+
+/// Smallest memory cost worth recommending, and where the search starts
+const MIN_RECOMMENDED_MEMORY_KIB: u32 = 8 * 1024;
+
+/// Finds the largest Argon2id memory cost whose hash time stays under
+/// `target` on this host, doubling from 8 MiB. Iterations and parallelism
+/// are kept at their defaults. Fails if even 8 MiB is too slow.
+pub fn recommend_argon2_params(target: std::time::Duration) -> Result<Argon2Config> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let mut candidate = Argon2Config {
+        memory_kib: Params::MIN_M_COST.max(MIN_RECOMMENDED_MEMORY_KIB),
+        ..Argon2Config::default()
+    };
+    let mut best = None;
+
+    loop {
+        let argon2 = argon2_with(&candidate)?;
+        let started = std::time::Instant::now();
+        argon2
+            .hash_password(b"spark-benchmark-password", &salt)
+            .map_err(|e| AuthError::PasswordHash(e.to_string()))?;
+        let elapsed = started.elapsed();
+
+        if elapsed > target {
+            return best.ok_or_else(|| AuthError::InvalidInput(format!(
+                "even {} KiB takes {}ms to hash, over the {}ms target",
+                candidate.memory_kib,
+                elapsed.as_millis(),
+                target.as_millis(),
+            )));
+        }
+
+        best = Some(candidate);
+        match candidate.memory_kib.checked_mul(2) {
+            // Stop at 4 GiB, well past anything sensible for a login
+            Some(memory_kib) if memory_kib <= 4 * 1024 * 1024 => candidate.memory_kib = memory_kib,
+            _ => return Ok(candidate),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recommendation_fails_when_the_minimum_is_too_slow() {
+        assert!(matches!(recommend_argon2_params(std::time::Duration::ZERO), Err(AuthError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,421 @@
+use crate::websocket::ConnectionManager;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tracing::{error, info, warn};
+
+/// Redis channel every node publishes to and subscribes on.
+const CHANNEL: &str = "spark:backplane";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Events buffered per subscriber before a slow node starts missing them.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Something one node tells the others, so clients connected anywhere see it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BackplaneEvent {
+    /// A room broadcast, already encoded for clients
+    RoomBroadcast {
+        origin: String,
+        room_id: String,
+        /// Members sent their own copy instead, by a `MembersBroadcast`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        except: Vec<String>,
+        ephemeral: bool,
+        message: String,
+    },
+    /// A broadcast to only some of a room's members, e.g. a new message
+    /// flagged for the users who blocked its sender
+    MembersBroadcast {
+        origin: String,
+        room_id: String,
+        user_ids: Vec<String>,
+        ephemeral: bool,
+        message: String,
+    },
+    /// Typing lists are merged per node rather than forwarded, since each node
+    /// only knows who is typing among its own clients.
+    Typing {
+        origin: String,
+        room_id: String,
+        user_id: String,
+        username: String,
+        is_typing: bool,
+    },
+    /// A deleted room, for nodes to drop their clients' subscriptions to it
+    RoomClosed {
+        origin: String,
+        room_id: String,
+    },
+    /// A member kicked or banned from a room, to unsubscribe wherever they
+    /// are connected
+    MemberRemoved {
+        origin: String,
+        room_id: String,
+        user_id: String,
+    },
+    /// A user whose sessions were ended, to close their connection
+    UserDisconnected {
+        origin: String,
+        user_id: String,
+        reason: String,
+    },
+    /// A message for every connected client, already encoded
+    GlobalBroadcast {
+        origin: String,
+        ephemeral: bool,
+        message: String,
+    },
+}
+
+impl BackplaneEvent {
+    pub fn origin(&self) -> &str {
+        match self {
+            BackplaneEvent::RoomBroadcast { origin, .. }
+            | BackplaneEvent::MembersBroadcast { origin, .. }
+            | BackplaneEvent::Typing { origin, .. }
+            | BackplaneEvent::RoomClosed { origin, .. }
+            | BackplaneEvent::MemberRemoved { origin, .. }
+            | BackplaneEvent::UserDisconnected { origin, .. }
+            | BackplaneEvent::GlobalBroadcast { origin, .. } => origin,
+        }
+    }
+}
+
+/// Pub/sub shared by every server node. Each node publishes what happens on
+/// it and applies what the others publish.
+pub trait Backplane: Send + Sync {
+    /// Hands an event to the other nodes. Called with the connection manager
+    /// locked, so must not block.
+    fn publish(&self, event: BackplaneEvent);
+
+    /// Events published by every node, this one included.
+    fn subscribe(&self) -> broadcast::Receiver<BackplaneEvent>;
+}
+
+/// Backplane for nodes running in the same process, mainly for tests and
+/// embedding. Clones share the same channel.
+#[derive(Clone)]
+pub struct InProcessBackplane {
+    events: broadcast::Sender<BackplaneEvent>,
+}
+
+impl InProcessBackplane {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        Self { events }
+    }
+}
+
+impl Default for InProcessBackplane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backplane for InProcessBackplane {
+    fn publish(&self, event: BackplaneEvent) {
+        let _ = self.events.send(event);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BackplaneEvent> {
+        self.events.subscribe()
+    }
+}
+
+/// Backplane over Redis pub/sub. Publishing goes through a background task
+/// on an auto-reconnecting connection; the subscription is re-established
+/// if the server goes away. Events published while disconnected are lost.
+pub struct RedisBackplane {
+    outgoing: mpsc::UnboundedSender<BackplaneEvent>,
+    incoming: broadcast::Sender<BackplaneEvent>,
+}
+
+impl RedisBackplane {
+    /// Connects and subscribes, so a bad URL or unreachable server fails
+    /// startup rather than the first broadcast. Must be called from within
+    /// a Tokio runtime.
+    pub async fn connect(url: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let mut publisher = client.get_connection_manager().await?;
+        let pubsub = subscribe(&client).await?;
+
+        let (incoming, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        tokio::spawn(forward_messages(client, pubsub, incoming.clone()));
+
+        let (outgoing, mut receiver) = mpsc::unbounded_channel::<BackplaneEvent>();
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let payload = serde_json::to_string(&event).unwrap();
+                let published: redis::RedisResult<usize> = redis::AsyncCommands::publish(&mut publisher, CHANNEL, payload).await;
+                if let Err(e) = published {
+                    warn!(error = %e, "failed to publish to backplane");
+                }
+            }
+        });
+
+        Ok(Self { outgoing, incoming })
+    }
+}
+
+impl Backplane for RedisBackplane {
+    fn publish(&self, event: BackplaneEvent) {
+        let _ = self.outgoing.send(event);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BackplaneEvent> {
+        self.incoming.subscribe()
+    }
+}
+
+async fn subscribe(client: &redis::Client) -> redis::RedisResult<redis::aio::PubSub> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(CHANNEL).await?;
+    Ok(pubsub)
+}
+
+async fn forward_messages(client: redis::Client, mut pubsub: redis::aio::PubSub, incoming: broadcast::Sender<BackplaneEvent>) {
+    loop {
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let event = message.get_payload::<String>()
+                .map_err(|e| e.to_string())
+                .and_then(|payload| serde_json::from_str::<BackplaneEvent>(&payload).map_err(|e| e.to_string()));
+            match event {
+                Ok(event) => {
+                    let _ = incoming.send(event);
+                }
+                Err(e) => warn!(error = %e, "ignoring malformed backplane message"),
+            }
+        }
+        drop(messages);
+
+        warn!("backplane subscription lost, reconnecting");
+        pubsub = loop {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            match subscribe(&client).await {
+                Ok(pubsub) => break pubsub,
+                Err(e) => warn!(error = %e, "backplane reconnect failed"),
+            }
+        };
+        info!("backplane subscription restored");
+    }
+}
+
+/// Connects a node's connection manager to the backplane: local broadcasts
+/// are published, and a background task applies events from other nodes.
+/// Must be called from within a Tokio runtime, before the connections are
+/// in use.
+pub(crate) fn attach(connections: &Arc<RwLock<ConnectionManager>>, backplane: Arc<dyn Backplane>) {
+    let mut events = backplane.subscribe();
+    connections.try_write()
+        .expect("backplane must be attached before the server starts")
+        .set_backplane(backplane);
+
+    let connections = Arc::clone(connections);
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => connections.write().await.apply_remote(event),
+                Err(RecvError::Lagged(skipped)) => warn!(skipped, "fell behind on backplane events"),
+                Err(RecvError::Closed) => {
+                    error!("backplane closed, cross-node delivery has stopped");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutboundQueueConfig;
+    use crate::outbound::{self, ClientReceiver};
+    use crate::websocket::WsServerMessage;
+
+    async fn next_json(rx: &mut ClientReceiver) -> serde_json::Value {
+        let text = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        serde_json::from_str(text.as_str()).unwrap()
+    }
+
+    fn node(backplane: &InProcessBackplane, user_id: &str, room_id: &str) -> (Arc<RwLock<ConnectionManager>>, ClientReceiver) {
+        let connections = Arc::new(RwLock::new(ConnectionManager::new()));
+        attach(&connections, Arc::new(backplane.clone()));
+
+        let (tx, rx) = outbound::channel(&OutboundQueueConfig::default());
+        let mut conns = connections.try_write().unwrap();
+        conns.add_client(user_id.to_string(), user_id.to_string(), tx);
+        conns.join_room(user_id, room_id.to_string()).unwrap();
+        drop(conns);
+        (connections, rx)
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_reach_clients_on_other_nodes() {
+        let backplane = InProcessBackplane::new();
+        let (node_a, mut alice) = node(&backplane, "alice", "room-1");
+        let (_node_b, mut bob) = node(&backplane, "bob", "room-1");
+
+        node_a.read().await.broadcast_to_room("room-1", WsServerMessage::MessageDeleted {
+            room_id: "room-1".to_string(),
+            message_id: "m1".to_string(),
+        });
+
+        for rx in [&mut alice, &mut bob] {
+            let received = next_json(rx).await;
+            assert_eq!(received["type"], "MessageDeleted");
+            assert_eq!(received["message_id"], "m1");
+        }
+        // Alice's node must not deliver its own broadcast a second time
+        assert!(tokio::time::timeout(Duration::from_millis(100), alice.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blockers_on_other_nodes_get_flagged_messages() {
+        use crate::messages::{MessageAudience, RoomMessageResponse};
+
+        let backplane = InProcessBackplane::new();
+        let (node_a, _pest) = node(&backplane, "pest", "room-1");
+        let (_node_b, mut bob) = node(&backplane, "bob", "room-1");
+        let (_node_c, mut carol) = node(&backplane, "carol", "room-1");
+
+        let message: RoomMessageResponse = serde_json::from_value(serde_json::json!({
+            "id": "m1",
+            "sender_username": "pest",
+            "message_type": "Room",
+            "room_id": "room-1",
+            "room_name": "Room",
+            "content": "hi",
+            "sent_at": "2024-01-01T00:00:00Z",
+            "is_edited": false,
+            "edited_at": null,
+            "mentions": [],
+            "reply_to": null,
+        })).unwrap();
+        let audience = MessageAudience { notify_user_ids: Vec::new(), blocked_by: vec!["bob".to_string()] };
+        node_a.read().await.broadcast_new_message(&message, &audience);
+
+        let received = next_json(&mut bob).await;
+        assert_eq!(received["type"], "NewMessage");
+        assert_eq!(received["message"]["sender_blocked"], true);
+        let received = next_json(&mut carol).await;
+        assert_eq!(received["message"]["sender_blocked"], false);
+        // One copy each
+        assert!(tokio::time::timeout(Duration::from_millis(100), bob.recv()).await.is_err());
+        assert!(tokio::time::timeout(Duration::from_millis(100), carol.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_typing_lists_merge_across_nodes() {
+        let backplane = InProcessBackplane::new();
+        let (node_a, _alice) = node(&backplane, "alice", "room-1");
+        let (node_b, mut bob) = node(&backplane, "bob", "room-1");
+
+        node_a.write().await.set_typing("alice", "room-1", true).unwrap();
+        let received = next_json(&mut bob).await;
+        assert_eq!(received["type"], "TypingStatusChanged");
+        assert_eq!(received["typing_users"][0]["username"], "alice");
+
+        node_b.write().await.set_typing("bob", "room-1", true).unwrap();
+        let mut typing = node_b.write().await.get_typing_users("room-1");
+        typing.sort();
+        assert_eq!(typing.len(), 2);
+        assert_eq!(typing[0].0, "alice");
+        assert_eq!(typing[1].0, "bob");
+    }
+
+    #[tokio::test]
+    async fn test_deleted_rooms_are_closed_on_every_node() {
+        let backplane = InProcessBackplane::new();
+        let (node_a, mut alice) = node(&backplane, "alice", "room-1");
+        let (node_b, mut bob) = node(&backplane, "bob", "room-1");
+
+        let mut conns = node_a.write().await;
+        conns.broadcast_to_room("room-1", WsServerMessage::RoomDeleted { room_id: "room-1".to_string() });
+        conns.close_room("room-1");
+        drop(conns);
+
+        for rx in [&mut alice, &mut bob] {
+            assert_eq!(next_json(rx).await["type"], "RoomDeleted");
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node_b.read().await.room_subscriber_counts().next().is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert!(node_a.read().await.room_subscriber_counts().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_removed_members_are_unsubscribed_on_their_node() {
+        let backplane = InProcessBackplane::new();
+        let (node_a, _alice) = node(&backplane, "alice", "room-1");
+        let (node_b, mut bob) = node(&backplane, "bob", "room-1");
+
+        node_a.write().await.remove_member("room-1", "bob");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node_b.read().await.room_subscriber_counts().next().is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+
+        node_a.read().await.broadcast_to_room("room-1", WsServerMessage::MessageDeleted {
+            room_id: "room-1".to_string(),
+            message_id: "m1".to_string(),
+        });
+        assert!(tokio::time::timeout(Duration::from_millis(100), bob.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_announcements_and_disconnects_reach_other_nodes() {
+        let backplane = InProcessBackplane::new();
+        let (node_a, mut alice) = node(&backplane, "alice", "room-1");
+        let (_node_b, mut bob) = node(&backplane, "bob", "room-2");
+
+        node_a.read().await.broadcast_to_all(WsServerMessage::ServerAnnouncement {
+            content: "maintenance at noon".to_string(),
+            sent_at: "2026-01-01T00:00:00Z".to_string(),
+        });
+        for rx in [&mut alice, &mut bob] {
+            let received = next_json(rx).await;
+            assert_eq!(received["type"], "ServerAnnouncement");
+            assert_eq!(received["content"], "maintenance at noon");
+        }
+
+        node_a.read().await.disconnect_user("bob", "Logged out by an administrator");
+        let received = next_json(&mut bob).await;
+        assert_eq!(received["type"], "SessionRevoked");
+        assert!(tokio::time::timeout(Duration::from_millis(100), alice.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a local redis-server"]
+    async fn test_redis_backplane_against_local_server() {
+        let url = std::env::var("SPARK_TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        let node_a = RedisBackplane::connect(&url).await.unwrap();
+        let node_b = RedisBackplane::connect(&url).await.unwrap();
+        let mut events = node_b.subscribe();
+
+        node_a.publish(BackplaneEvent::RoomBroadcast {
+            origin: "node-a".to_string(),
+            room_id: "room-1".to_string(),
+            except: Vec::new(),
+            ephemeral: false,
+            message: r#"{"type":"RoomLeft","room_id":"room-1"}"#.to_string(),
+        });
+
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event.origin(), "node-a");
+        match event {
+            BackplaneEvent::RoomBroadcast { room_id, message, .. } => {
+                assert_eq!(room_id, "room-1");
+                assert!(message.contains("RoomLeft"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+}
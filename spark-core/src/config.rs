@@ -0,0 +1,559 @@
+use crate::filter::FilterAction;
+use crate::logging::REDACTED;
+use crate::mailer::{FileMailer, Mailer, SmtpMailer};
+use chrono::Duration;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Comma-separated list, with blank entries dropped.
+fn env_list(key: &str) -> Option<Vec<String>> {
+    env::var(key)
+        .ok()
+        .map(|v| v.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect())
+}
+
+/// Listener address that can be switched off with an empty value or `off`.
+fn optional_addr(key: &str, default: Option<String>) -> Option<String> {
+    match env::var(key) {
+        Ok(v) if v.is_empty() || v.eq_ignore_ascii_case("off") => None,
+        Ok(v) => Some(v),
+        Err(_) => default,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationPolicy {
+    Open,
+    InviteOnly,
+    AdminApproval,
+    Disabled,
+}
+
+impl FromStr for RegistrationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "open" => Ok(Self::Open),
+            "invite" | "invite_only" => Ok(Self::InviteOnly),
+            "approval" | "admin_approval" => Ok(Self::AdminApproval),
+            "disabled" | "closed" => Ok(Self::Disabled),
+            other => Err(format!("Unknown registration policy: {}", other)),
+        }
+    }
+}
+
+/// Argon2id cost parameters used for new password hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Config {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Argon2Config {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            memory_kib: env_or("SPARK_ARGON2_MEMORY_KIB", default.memory_kib),
+            iterations: env_or("SPARK_ARGON2_ITERATIONS", default.iterations),
+            parallelism: env_or("SPARK_ARGON2_PARALLELISM", default.parallelism),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    /// e.g. `ldap://127.0.0.1:389` or `ldaps://ldap.example.com`
+    pub url: String,
+    /// Bind DN with a `{username}` placeholder, e.g. `uid={username},ou=people,dc=example,dc=org`
+    pub bind_dn_template: String,
+    pub email_attribute: String,
+}
+
+impl LdapConfig {
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            url: env::var("SPARK_LDAP_URL").ok()?,
+            bind_dn_template: env::var("SPARK_LDAP_BIND_DN").ok()?,
+            email_attribute: env::var("SPARK_LDAP_EMAIL_ATTR").unwrap_or("mail".to_string()),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct OidcConfig {
+    /// Provider name clients use to pick this provider
+    pub name: String,
+    /// Issuer URL; endpoints are read from `{issuer}/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+}
+
+impl fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OidcConfig")
+            .field("name", &self.name)
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &self.client_secret.as_ref().map(|_| REDACTED))
+            .field("redirect_uri", &self.redirect_uri)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl OidcConfig {
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            name: env::var("SPARK_OIDC_NAME").unwrap_or("oidc".to_string()),
+            issuer: env::var("SPARK_OIDC_ISSUER").ok()?,
+            client_id: env::var("SPARK_OIDC_CLIENT_ID").ok()?,
+            client_secret: env::var("SPARK_OIDC_CLIENT_SECRET").ok(),
+            redirect_uri: env::var("SPARK_OIDC_REDIRECT_URI").ok()?,
+            scopes: env::var("SPARK_OIDC_SCOPES").unwrap_or("openid email profile".to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub session_ttl: Duration,
+    pub reset_token_ttl: Duration,
+    /// New accounts start unverified and can't post until they confirm their email
+    pub require_email_verification: bool,
+    pub verification_token_ttl: Duration,
+    pub registration_policy: RegistrationPolicy,
+    /// Existing accounts given the server admin role at startup
+    pub admin_usernames: Vec<String>,
+    pub argon2: Argon2Config,
+    pub ldap: Option<LdapConfig>,
+    pub oidc: Option<OidcConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            session_ttl: Duration::days(30),
+            reset_token_ttl: Duration::minutes(60),
+            require_email_verification: false,
+            verification_token_ttl: Duration::hours(48),
+            registration_policy: RegistrationPolicy::Open,
+            admin_usernames: Vec::new(),
+            argon2: Argon2Config::default(),
+            ldap: None,
+            oidc: None,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            session_ttl: Duration::days(env_or("SPARK_SESSION_TTL_DAYS", default.session_ttl.num_days())),
+            reset_token_ttl: Duration::minutes(env_or("SPARK_RESET_TOKEN_TTL_MINUTES", default.reset_token_ttl.num_minutes())),
+            require_email_verification: env_or("SPARK_REQUIRE_EMAIL_VERIFICATION", default.require_email_verification),
+            verification_token_ttl: Duration::hours(env_or("SPARK_VERIFICATION_TOKEN_TTL_HOURS", default.verification_token_ttl.num_hours())),
+            registration_policy: env_or("SPARK_REGISTRATION_POLICY", default.registration_policy),
+            admin_usernames: env_list("SPARK_ADMIN_USERS").unwrap_or(default.admin_usernames),
+            argon2: Argon2Config::from_env(),
+            ldap: LdapConfig::from_env(),
+            oidc: OidcConfig::from_env(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MailTransport {
+    File { outbox: PathBuf },
+    Smtp { addr: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    pub from: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: MailTransport::File { outbox: PathBuf::from("outbox") },
+            from: "noreply@spark.local".to_string(),
+        }
+    }
+}
+
+impl MailConfig {
+    /// `SPARK_SMTP_ADDR` switches to SMTP delivery, otherwise mail goes to
+    /// `SPARK_MAIL_OUTBOX` (default `./outbox`).
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let transport = match env::var("SPARK_SMTP_ADDR") {
+            Ok(addr) => MailTransport::Smtp { addr },
+            Err(_) => match env::var("SPARK_MAIL_OUTBOX") {
+                Ok(outbox) => MailTransport::File { outbox: PathBuf::from(outbox) },
+                Err(_) => default.transport,
+            },
+        };
+
+        Self {
+            transport,
+            from: env::var("SPARK_MAIL_FROM").unwrap_or(default.from),
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn Mailer> {
+        match &self.transport {
+            MailTransport::File { outbox } => Box::new(FileMailer::new(outbox, &self.from)),
+            MailTransport::Smtp { addr } => Box::new(SmtpMailer::new(addr, &self.from)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" | "pretty" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(format!("Unknown log format: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// `EnvFilter` directives, e.g. `info` or `spark_core=debug,hyper=warn`
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl LogConfig {
+    /// `SPARK_LOG` takes precedence over `RUST_LOG`.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            filter: env::var("SPARK_LOG").or_else(|_| env::var("RUST_LOG")).unwrap_or(default.filter),
+            format: env_or("SPARK_LOG_FORMAT", default.format),
+        }
+    }
+}
+
+/// WebSocket keepalive. The server pings every `interval` and drops clients
+/// it hasn't heard anything from, pongs included, for `timeout`.
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    pub interval: std::time::Duration,
+    pub timeout: std::time::Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: std::time::Duration::from_secs(30),
+            timeout: std::time::Duration::from_secs(75),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            interval: std::time::Duration::from_secs(env_or("SPARK_HEARTBEAT_INTERVAL_SECS", default.interval.as_secs())),
+            timeout: std::time::Duration::from_secs(env_or("SPARK_HEARTBEAT_TIMEOUT_SECS", default.timeout.as_secs())),
+        }
+    }
+}
+
+/// Per-connection WebSocket send queue. Once `shed_ephemeral_at` messages
+/// are waiting, typing and presence updates are dropped; a client whose
+/// queue fills up entirely is disconnected as a slow consumer.
+#[derive(Debug, Clone)]
+pub struct OutboundQueueConfig {
+    pub capacity: usize,
+    pub shed_ephemeral_at: usize,
+}
+
+impl Default for OutboundQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            shed_ephemeral_at: 192,
+        }
+    }
+}
+
+impl OutboundQueueConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let capacity = env_or("SPARK_WS_QUEUE_CAPACITY", default.capacity).max(1);
+        Self {
+            capacity,
+            shed_ephemeral_at: env_or("SPARK_WS_SHED_EPHEMERAL_AT", capacity * 3 / 4).min(capacity),
+        }
+    }
+}
+
+/// Pub/sub backplane for running several nodes behind a load balancer.
+/// Without a URL the server runs as a single node.
+#[derive(Clone, Default)]
+pub struct BackplaneConfig {
+    /// `redis://` URL, which may carry a password
+    pub url: Option<String>,
+}
+
+impl fmt::Debug for BackplaneConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackplaneConfig")
+            .field("url", &self.url.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+impl BackplaneConfig {
+    pub fn from_env() -> Self {
+        Self { url: env::var("SPARK_BACKPLANE_URL").ok().filter(|url| !url.is_empty()) }
+    }
+}
+
+/// Where the server keeps its data. Without a URL everything lives in the
+/// SQLite database at `db_path`.
+#[derive(Clone, Default)]
+pub struct StorageConfig {
+    /// `postgres://` URL, which may carry a password
+    pub postgres_url: Option<String>,
+}
+
+impl fmt::Debug for StorageConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StorageConfig")
+            .field("postgres_url", &self.postgres_url.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+impl StorageConfig {
+    pub fn from_env() -> Self {
+        Self { postgres_url: env::var("SPARK_DATABASE_URL").ok().filter(|url| !url.is_empty()) }
+    }
+}
+
+/// How long a SIGINT/SIGTERM shutdown may take, and when clients are told
+/// to reconnect.
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    pub deadline: std::time::Duration,
+    pub reconnect_after: std::time::Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            deadline: std::time::Duration::from_secs(10),
+            reconnect_after: std::time::Duration::from_secs(5),
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            deadline: std::time::Duration::from_secs(env_or("SPARK_SHUTDOWN_DEADLINE_SECS", default.deadline.as_secs())),
+            reconnect_after: std::time::Duration::from_millis(env_or("SPARK_RECONNECT_AFTER_MS", default.reconnect_after.as_millis() as u64)),
+        }
+    }
+}
+
+/// Delivery settings for outgoing webhooks. Failed deliveries are retried
+/// with exponential backoff and dead-lettered after `max_attempts`.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub max_attempts: u32,
+    pub initial_backoff: std::time::Duration,
+    pub max_backoff: std::time::Duration,
+    pub timeout: std::time::Duration,
+    /// Hosts webhooks may be delivered to even though they resolve to a
+    /// loopback, private or link-local address
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: std::time::Duration::from_secs(1),
+            max_backoff: std::time::Duration::from_secs(60),
+            timeout: std::time::Duration::from_secs(10),
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_attempts: env_or("SPARK_WEBHOOK_MAX_ATTEMPTS", default.max_attempts),
+            initial_backoff: std::time::Duration::from_millis(env_or("SPARK_WEBHOOK_BACKOFF_MS", default.initial_backoff.as_millis() as u64)),
+            max_backoff: std::time::Duration::from_millis(env_or("SPARK_WEBHOOK_MAX_BACKOFF_MS", default.max_backoff.as_millis() as u64)),
+            timeout: std::time::Duration::from_secs(env_or("SPARK_WEBHOOK_TIMEOUT_SECS", default.timeout.as_secs())),
+            allowed_hosts: env_list("SPARK_WEBHOOK_ALLOWED_HOSTS").unwrap_or(default.allowed_hosts),
+        }
+    }
+}
+
+/// Server-wide content filter for room messages, applied on send and edit.
+/// Rooms can block more words on top of these.
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    /// Whole words, matched case-insensitively, or `/regex/` patterns
+    pub blocked_words: Vec<String>,
+    pub word_action: FilterAction,
+    /// When non-empty, links to any other domain are filtered as well
+    pub allowed_link_domains: Vec<String>,
+    pub denied_link_domains: Vec<String>,
+    pub link_action: FilterAction,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            blocked_words: Vec::new(),
+            word_action: FilterAction::Reject,
+            allowed_link_domains: Vec::new(),
+            denied_link_domains: Vec::new(),
+            link_action: FilterAction::Reject,
+        }
+    }
+}
+
+impl FilterConfig {
+    /// `SPARK_FILTER_WORDS_FILE` names a file with one entry per line, for
+    /// patterns that contain commas; `#` starts a comment line.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let mut blocked_words = env_list("SPARK_FILTER_BLOCKED_WORDS").unwrap_or(default.blocked_words);
+        if let Ok(path) = env::var("SPARK_FILTER_WORDS_FILE") {
+            match std::fs::read_to_string(&path) {
+                Ok(contents) => blocked_words.extend(
+                    contents
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(str::to_string),
+                ),
+                Err(e) => tracing::warn!(path = %path, error = %e, "could not read filter words file"),
+            }
+        }
+
+        Self {
+            blocked_words,
+            word_action: env_or("SPARK_FILTER_WORD_ACTION", default.word_action),
+            allowed_link_domains: env_list("SPARK_FILTER_ALLOWED_DOMAINS").unwrap_or(default.allowed_link_domains),
+            denied_link_domains: env_list("SPARK_FILTER_DENIED_DOMAINS").unwrap_or(default.denied_link_domains),
+            link_action: env_or("SPARK_FILTER_LINK_ACTION", default.link_action),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub db_path: String,
+    pub storage: StorageConfig,
+    /// Legacy line-delimited JSON auth listener
+    pub tcp_addr: Option<String>,
+    /// Standalone WebSocket listener; the HTTP listener also serves `/ws`
+    pub ws_addr: Option<String>,
+    pub http_addr: String,
+    pub auth: AuthConfig,
+    pub mail: MailConfig,
+    pub webhooks: WebhookConfig,
+    pub filter: FilterConfig,
+    pub log: LogConfig,
+    pub shutdown: ShutdownConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub outbound: OutboundQueueConfig,
+    pub backplane: BackplaneConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            db_path: "spark.db".to_string(),
+            storage: StorageConfig::default(),
+            tcp_addr: Some("127.0.0.1:8080".to_string()),
+            ws_addr: Some("127.0.0.1:8081".to_string()),
+            http_addr: "127.0.0.1:8082".to_string(),
+            auth: AuthConfig::default(),
+            mail: MailConfig::default(),
+            webhooks: WebhookConfig::default(),
+            filter: FilterConfig::default(),
+            log: LogConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            outbound: OutboundQueueConfig::default(),
+            backplane: BackplaneConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            db_path: env_or("SPARK_DB_PATH", default.db_path),
+            storage: StorageConfig::from_env(),
+            tcp_addr: optional_addr("SPARK_TCP_ADDR", default.tcp_addr),
+            ws_addr: optional_addr("SPARK_WS_ADDR", default.ws_addr),
+            http_addr: env_or("SPARK_HTTP_ADDR", default.http_addr),
+            auth: AuthConfig::from_env(),
+            mail: MailConfig::from_env(),
+            webhooks: WebhookConfig::from_env(),
+            filter: FilterConfig::from_env(),
+            log: LogConfig::from_env(),
+            shutdown: ShutdownConfig::from_env(),
+            heartbeat: HeartbeatConfig::from_env(),
+            outbound: OutboundQueueConfig::from_env(),
+            backplane: BackplaneConfig::from_env(),
+        }
+    }
+}
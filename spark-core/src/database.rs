@@ -1,11 +1,18 @@
 use crate::{
-    AuthError, error::Result, messages::{Message, MessageType, ReactionSummary, Room}, users::{Presence, Session, User}
+    audit::{AuditAction, AuditEntry, AuditLogFilter}, error::Result, filter::{FilterAction, HeldMessage, RoomFilter}, metrics::metrics, messages::{Message, MessageReport, MessageType, ReactionDetail, ReactionSummary, ReportAction, ReportStatus, Room, RoomSanction, SanctionKind}, users::{ApiScope, ApiToken, EmailVerification, InviteCode, PasswordReset, Presence, Session, User, UserBlock, UserRole, UserSearch}, webhooks::{IncomingWebhook, Webhook, WebhookDeadLetter}
 };
 use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection};
+use rusqlite::{params, trace::{TraceEvent, TraceEventCodes}, Connection};
+use std::collections::HashMap;
 use std::path::Path;
 use uuid::Uuid;
-use regex::Regex;
+use crate::storage::{everyone_mentioned, extract_mentions, like_pattern, summarize_reactions, Storage};
+
+fn profile_statement(event: TraceEvent<'_>) {
+    if let TraceEvent::Profile(stmt, duration) = event {
+        metrics().record_db_query(&stmt.sql(), duration);
+    }
+}
 
 pub struct Database {
     conn: Connection,
@@ -22,6 +29,114 @@ fn parse_presence(s: &str) -> Presence {
         }
     }
 
+const USER_COLUMNS: &str =
+    "u.id, u.username, u.email, u.password_hash, u.created_at, u.last_login, u.presence, u.status, u.email_verified, u.approved, u.is_bot, u.bot_owner_id, u.role, u.deactivated_at";
+
+/// Maps a row selected with `USER_COLUMNS` (from `users u`) to a `User`.
+fn row_to_user(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        email: row.get(2)?,
+        password_hash: row.get(3)?,
+        created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+        last_login: row.get::<_, Option<String>>(5)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+        presence: parse_presence(&row.get::<_, String>(6)?),
+        status: row.get(7)?,
+        email_verified: row.get::<_, i32>(8)? != 0,
+        approved: row.get::<_, i32>(9)? != 0,
+        is_bot: row.get::<_, i32>(10)? != 0,
+        bot_owner_id: row.get(11)?,
+        role: UserRole::parse(&row.get::<_, String>(12)?),
+        deactivated_at: row.get::<_, Option<String>>(13)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+    })
+}
+
+const ROOM_COLUMNS: &str = "r.id, r.name, r.desc, r.topic, r.created_by, r.created_at, r.archived_at";
+
+/// Maps a row selected with `ROOM_COLUMNS` (from `rooms r`) to a `Room`.
+fn row_to_room(row: &rusqlite::Row) -> rusqlite::Result<Room> {
+    Ok(Room {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        desc: row.get(2)?,
+        topic: row.get(3)?,
+        created_by: row.get(4)?,
+        created_at: row.get::<_, String>(5)?.parse::<DateTime<Utc>>().unwrap(),
+        archived_at: row.get::<_, Option<String>>(6)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+    })
+}
+
+const SANCTION_COLUMNS: &str = "s.room_id, s.user_id, u.username, s.reason, s.issued_by, s.created_at, s.expires_at";
+
+/// Maps a row selected with `SANCTION_COLUMNS` (from `room_sanctions s`
+/// joined to `users u`) to a `RoomSanction`.
+fn row_to_sanction(row: &rusqlite::Row) -> rusqlite::Result<RoomSanction> {
+    Ok(RoomSanction {
+        room_id: row.get(0)?,
+        user_id: row.get(1)?,
+        username: row.get(2)?,
+        reason: row.get(3)?,
+        issued_by: row.get(4)?,
+        created_at: row.get::<_, String>(5)?.parse::<DateTime<Utc>>().unwrap(),
+        expires_at: row.get::<_, Option<String>>(6)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+    })
+}
+
+const HELD_COLUMNS: &str = "id, room_id, sender_id, content, reply_to_message_id, reason, created_at";
+
+/// Maps a row selected with `HELD_COLUMNS` (from `held_messages`) to a `HeldMessage`.
+fn row_to_held_message(row: &rusqlite::Row) -> rusqlite::Result<HeldMessage> {
+    Ok(HeldMessage {
+        id: row.get(0)?,
+        room_id: row.get(1)?,
+        sender_id: row.get(2)?,
+        content: row.get(3)?,
+        reply_to_message_id: row.get(4)?,
+        reason: row.get(5)?,
+        created_at: row.get::<_, String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
+
+const REPORT_COLUMNS: &str =
+    "id, room_id, message_id, sender_id, content, reporter_id, reason, status, created_at, resolved_by, resolved_at, action, resolution_note";
+
+/// Maps a row selected with `REPORT_COLUMNS` (from `message_reports`) to a `MessageReport`.
+fn row_to_report(row: &rusqlite::Row) -> rusqlite::Result<MessageReport> {
+    Ok(MessageReport {
+        id: row.get(0)?,
+        room_id: row.get(1)?,
+        message_id: row.get(2)?,
+        sender_id: row.get(3)?,
+        content: row.get(4)?,
+        reporter_id: row.get(5)?,
+        reason: row.get(6)?,
+        status: ReportStatus::parse(&row.get::<_, String>(7)?),
+        created_at: row.get::<_, String>(8)?.parse::<DateTime<Utc>>().unwrap(),
+        resolved_by: row.get(9)?,
+        resolved_at: row.get::<_, Option<String>>(10)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+        action: row.get::<_, Option<String>>(11)?.and_then(|s| ReportAction::parse(&s)),
+        resolution_note: row.get(12)?,
+    })
+}
+
+const AUDIT_COLUMNS: &str = "id, actor_id, action, target_id, room_id, reason, created_at";
+
+/// Maps a row selected with `AUDIT_COLUMNS` (from `audit_log`) to an `AuditEntry`.
+fn row_to_audit_entry(row: &rusqlite::Row) -> rusqlite::Result<AuditEntry> {
+    let action: String = row.get(2)?;
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        actor_id: row.get(1)?,
+        action: AuditAction::parse(&action).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, format!("unknown audit action {}", action).into())
+        })?,
+        target_id: row.get(3)?,
+        room_id: row.get(4)?,
+        reason: row.get(5)?,
+        created_at: row.get::<_, String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+    })
+}
 
 impl Database {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
@@ -39,6 +154,10 @@ impl Database {
     }
 
     fn init(&self) -> Result<()> {
+        self.conn.trace_v2(TraceEventCodes::SQLITE_TRACE_PROFILE, Some(profile_statement));
+        // Deleting rooms and users relies on the cascades below
+        self.conn.pragma_update(None, "foreign_keys", true)?;
+
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS users (
                 id TEXT PRIMARY KEY,
@@ -48,11 +167,106 @@ impl Database {
                 created_at TEXT NOT NULL,
                 last_login TEXT,
                 presence TEXT NOT NULL DEFAULT 'Offline',
-                status TEXT
+                status TEXT,
+                email_verified INTEGER NOT NULL DEFAULT 1,
+                approved INTEGER NOT NULL DEFAULT 1,
+                invite_code TEXT,
+                is_bot INTEGER NOT NULL DEFAULT 0,
+                bot_owner_id TEXT,
+                role TEXT NOT NULL DEFAULT 'user',
+                deactivated_at TEXT
             )",
             [],
         )?;
 
+        // Accounts created before email verification existed count as verified
+        self.add_column_if_missing("users", "email_verified", "INTEGER NOT NULL DEFAULT 1")?;
+        self.add_column_if_missing("users", "approved", "INTEGER NOT NULL DEFAULT 1")?;
+        self.add_column_if_missing("users", "invite_code", "TEXT")?;
+        self.add_column_if_missing("users", "is_bot", "INTEGER NOT NULL DEFAULT 0")?;
+        self.add_column_if_missing("users", "bot_owner_id", "TEXT")?;
+        self.add_column_if_missing("users", "role", "TEXT NOT NULL DEFAULT 'user'")?;
+        self.add_column_if_missing("users", "deactivated_at", "TEXT")?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS webhooks (
+                id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                url TEXT NOT NULL,
+                secret TEXT NOT NULL,
+                events TEXT NOT NULL,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS webhook_dead_letters (
+                id TEXT PRIMARY KEY,
+                webhook_id TEXT NOT NULL,
+                event TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                attempts INTEGER NOT NULL,
+                last_error TEXT NOT NULL,
+                failed_at TEXT NOT NULL,
+                FOREIGN KEY (webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS incoming_webhooks (
+                id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                token_hash TEXT UNIQUE NOT NULL,
+                bot_user_id TEXT NOT NULL,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+                FOREIGN KEY (bot_user_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+        self.migrate_incoming_webhook_tokens()?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS api_tokens (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                token_hash TEXT UNIQUE NOT NULL,
+                scopes TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                last_used_at TEXT,
+                revoked INTEGER NOT NULL DEFAULT 0,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS user_identities (
+                provider TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (provider, subject),
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS invite_codes (
+                code TEXT PRIMARY KEY,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                max_uses INTEGER,
+                uses INTEGER NOT NULL DEFAULT 0,
+                revoked INTEGER NOT NULL DEFAULT 0,
+                FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS sessions (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -65,17 +279,33 @@ impl Database {
             [],
         )?;
 
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS user_blocks (
+                blocker_id TEXT NOT NULL,
+                blocked_id TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (blocker_id, blocked_id),
+                FOREIGN KEY (blocker_id) REFERENCES users(id) ON DELETE CASCADE,
+                FOREIGN KEY (blocked_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS rooms (
                 id TEXT PRIMARY KEY,
                 name TEXT NOT NULL,
                 desc TEXT,
+                topic TEXT NOT NULL DEFAULT '',
                 created_by TEXT NOT NULL,
                 created_at TEXT NOT NULL,
+                archived_at TEXT,
                 FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE CASCADE
             )", [],
         )?;
 
+        self.add_column_if_missing("rooms", "topic", "TEXT NOT NULL DEFAULT ''")?;
+        self.add_column_if_missing("rooms", "archived_at", "TEXT")?;
+
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS room_members (
                 room_id TEXT NOT NULL,
@@ -87,6 +317,21 @@ impl Database {
             )", [],
         )?;
 
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS room_sanctions (
+                room_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                reason TEXT,
+                issued_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                PRIMARY KEY (room_id, user_id, kind),
+                FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
         self.conn.execute(
             "CREATE TABLE IF NOT EXISTS messages (
                 id TEXT PRIMARY KEY,
@@ -101,10 +346,10 @@ impl Database {
                 is_edited INTEGER NOT NULL DEFAULT 0,
                 edited_at TEXT,
                 reply_to_message_id TEXT,
-                reactions TEXT DEFAULT '[]',
                 is_pinned INTEGER NOT NULL DEFAULT 0,
                 pinned_at TEXT,
                 pinned_by TEXT,
+                display_name TEXT,
                 FOREIGN KEY (sender_id) REFERENCES users(id) ON DELETE CASCADE,
                 FOREIGN KEY (receiver_id) REFERENCES users(id) ON DELETE CASCADE,
                 FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
@@ -117,6 +362,7 @@ impl Database {
                 )
             )", [],
         )?;
+        self.add_column_if_missing("messages", "display_name", "TEXT")?;
 
         self.conn.execute("
             CREATE TABLE IF NOT EXISTS message_mentions (
@@ -132,6 +378,107 @@ impl Database {
             )",[]
         )?;
 
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_reactions (
+                message_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                PRIMARY KEY (message_id, user_id, emoji),
+                FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+        self.migrate_reactions_column()?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS email_verifications (
+                token TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                used_at TEXT,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS password_resets (
+                token TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                used_at TEXT,
+                FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS room_filters (
+                room_id TEXT PRIMARY KEY,
+                blocked_words TEXT NOT NULL DEFAULT '[]',
+                action TEXT NOT NULL,
+                FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE
+            )", [],
+        )?;
+        self.add_column_if_missing("room_filters", "allowed_link_domains", "TEXT NOT NULL DEFAULT '[]'")?;
+        self.add_column_if_missing("room_filters", "denied_link_domains", "TEXT NOT NULL DEFAULT '[]'")?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS held_messages (
+                id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                reply_to_message_id TEXT,
+                reason TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+                FOREIGN KEY (sender_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
+        // message_id has no foreign key so reports outlive deleted messages
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_reports (
+                id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                reporter_id TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'open',
+                created_at TEXT NOT NULL,
+                resolved_by TEXT,
+                resolved_at TEXT,
+                action TEXT,
+                resolution_note TEXT,
+                FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+                FOREIGN KEY (reporter_id) REFERENCES users(id) ON DELETE CASCADE
+            )", [],
+        )?;
+
+        // No foreign keys: entries must outlive the rooms and users they mention
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id TEXT PRIMARY KEY,
+                actor_id TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target_id TEXT,
+                room_id TEXT,
+                reason TEXT,
+                created_at TEXT NOT NULL
+            )", [],
+        )?;
+
+        self.conn.execute_batch(
+            "CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;
+            CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;",
+        )?;
+
         // Indices
 
         self.conn.execute(
@@ -189,23 +536,222 @@ impl Database {
             [],
         )?;
 
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_held_messages_room ON held_messages(room_id, created_at)",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_reports_open ON message_reports(message_id, reporter_id) WHERE status = 'open'",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_reports_room ON message_reports(room_id, status, created_at)",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_audit_log_room ON audit_log(room_id, created_at)",
+            [],
+        )?;
+
+        Ok(())
+    }
+
+    fn has_column(&self, table: &str, column: &str) -> Result<bool> {
+        let mut stmt = self.conn.prepare(&format!("PRAGMA table_info({})", table))?;
+        let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+
+        for existing in columns {
+            if existing? == column {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+        if !self.has_column(table, column)? {
+            self.conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+        }
+        Ok(())
+    }
+
+    /// Incoming webhook tokens used to be stored as-is in `token`. Renames
+    /// the column and replaces each token with its hash.
+    fn migrate_incoming_webhook_tokens(&self) -> Result<()> {
+        if !self.has_column("incoming_webhooks", "token")? {
+            return Ok(());
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        let tokens = {
+            let mut stmt = tx.prepare("SELECT id, token FROM incoming_webhooks")?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            rows.collect::<std::result::Result<Vec<_>, _>>()?
+        };
+
+        tx.execute("ALTER TABLE incoming_webhooks RENAME COLUMN token TO token_hash", [])?;
+        for (id, token) in tokens {
+            tx.execute("UPDATE incoming_webhooks SET token_hash = ?1 WHERE id = ?2", params![crate::webhooks::hash_token(&token), id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Reactions used to be a JSON array of `ReactionSummary` on each message.
+    /// Moves them into `message_reactions`, dated by the message since the
+    /// original times weren't kept, and drops the column.
+    fn migrate_reactions_column(&self) -> Result<()> {
+        if !self.has_column("messages", "reactions")? {
+            return Ok(());
+        }
+
+        self.conn.execute_batch(
+            "BEGIN;
+            INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, created_at)
+                SELECT m.id, u.value, json_extract(r.value, '$.emoji'), m.sent_at
+                FROM messages m, json_each(m.reactions) r, json_each(r.value, '$.user_ids') u
+                WHERE json_valid(m.reactions);
+            ALTER TABLE messages DROP COLUMN reactions;
+            COMMIT;"
+        )?;
+        Ok(())
+    }
+
+    /// Fills in `reactions` for a page of messages with a single query.
+    fn attach_reactions(&self, messages: &mut [Message]) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let ids = serde_json::to_string(&messages.iter().map(|m| &m.id).collect::<Vec<_>>()).unwrap();
+        let mut stmt = self.conn.prepare(
+            "SELECT mr.message_id, mr.emoji, mr.user_id, u.username, mr.created_at
+            FROM message_reactions mr
+            JOIN users u ON u.id = mr.user_id
+            WHERE mr.message_id IN (SELECT value FROM json_each(?1))
+            ORDER BY mr.created_at, mr.rowid"
+        )?;
+
+        let rows = stmt.query_map(params![ids], |row| {
+            Ok((row.get::<_, String>(0)?, ReactionDetail {
+                emoji: row.get(1)?,
+                user_id: row.get(2)?,
+                username: row.get(3)?,
+                created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+            }))
+        })?;
+
+        let mut by_message: HashMap<String, Vec<ReactionDetail>> = HashMap::new();
+        for row in rows {
+            let (message_id, detail) = row?;
+            by_message.entry(message_id).or_default().push(detail);
+        }
+
+        for message in messages.iter_mut() {
+            if let Some(details) = by_message.get(&message.id) {
+                message.reactions = summarize_reactions(details);
+            }
+        }
         Ok(())
     }
 
+    fn query_api_tokens(&self, condition: &str, param: &str) -> Result<Vec<ApiToken>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, user_id, name, scopes, created_at, last_used_at, revoked
+            FROM api_tokens WHERE {} ORDER BY created_at", condition
+        ))?;
+
+        let tokens = stmt.query_map(params![param], |row| {
+            Ok(ApiToken {
+                id: row.get(0)?,
+                user_id: row.get(1)?,
+                name: row.get(2)?,
+                scopes: row.get::<_, String>(3)?.split(',').filter_map(ApiScope::parse).collect(),
+                created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap(),
+                last_used_at: row.get::<_, Option<String>>(5)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                revoked: row.get::<_, i32>(6)? != 0,
+            })
+        })?;
+
+        let mut result = Vec::new();
+        for token in tokens {
+            result.push(token?);
+        }
+        Ok(result)
+    }
+
+    fn query_webhooks(&self, condition: &str, param: &str) -> Result<Vec<Webhook>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, room_id, url, secret, events, created_by, created_at
+            FROM webhooks WHERE {} ORDER BY created_at", condition
+        ))?;
+
+        let webhooks = stmt.query_map(params![param], |row| {
+            Ok(Webhook {
+                id: row.get(0)?,
+                room_id: row.get(1)?,
+                url: row.get(2)?,
+                secret: row.get(3)?,
+                events: row.get::<_, String>(4)?.split(',').filter(|e| !e.is_empty()).map(String::from).collect(),
+                created_by: row.get(5)?,
+                created_at: row.get::<_, String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+
+        let mut result = Vec::new();
+        for webhook in webhooks {
+            result.push(webhook?);
+        }
+        Ok(result)
+    }
+
+    fn query_incoming_webhooks(&self, condition: &str, param: &str) -> Result<Vec<IncomingWebhook>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, room_id, name, bot_user_id, created_by, created_at
+            FROM incoming_webhooks WHERE {} ORDER BY created_at", condition
+        ))?;
+
+        let webhooks = stmt.query_map(params![param], |row| {
+            Ok(IncomingWebhook {
+                id: row.get(0)?,
+                room_id: row.get(1)?,
+                name: row.get(2)?,
+                bot_user_id: row.get(3)?,
+                created_by: row.get(4)?,
+                created_at: row.get::<_, String>(5)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+
+        let mut result = Vec::new();
+        for webhook in webhooks {
+            result.push(webhook?);
+        }
+        Ok(result)
+    }
+
+}
+
+impl Storage for Database {
     // User Methods
 
-    pub fn create_user(
+    fn create_user_with_status(
         &self,
         username: &str,
         email: &str,
         password_hash: &str,
+        approved: bool,
+        email_verified: bool,
     ) -> Result<User> {
         let now = Utc::now();
         let id = Uuid::new_v4().to_string();
 
         self.conn.execute(
-            "INSERT INTO users (id, username, email, password_hash, created_at, presence) VALUES (?1, ?2, ?3, ?4, ?5, 'Offline')",
-            params![id, username, email, password_hash, now.to_rfc3339()],
+            "INSERT INTO users (id, username, email, password_hash, created_at, presence, approved, email_verified)
+            VALUES (?1, ?2, ?3, ?4, ?5, 'Offline', ?6, ?7)",
+            params![id, username, email, password_hash, now.to_rfc3339(), approved as i32, email_verified as i32],
         )?;
 
         Ok(User {
@@ -217,28 +763,31 @@ impl Database {
             last_login: None,
             presence: Presence::Offline,
             status: None,
+            email_verified,
+            approved,
+            is_bot: false,
+            bot_owner_id: None,
+            role: UserRole::User,
+            deactivated_at: None,
         })
     }
 
-
-    pub fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, username, email, password_hash, created_at, last_login, presence, status
-            FROM users WHERE username = ?1"
+    fn create_bot_user(&self, username: &str, email: &str, password_hash: &str, owner_id: &str) -> Result<User> {
+        let user = self.create_user(username, email, password_hash)?;
+        self.conn.execute(
+            "UPDATE users SET is_bot = 1, bot_owner_id = ?1 WHERE id = ?2",
+            params![owner_id, user.id],
         )?;
 
-        let user = stmt.query_row(params![username], |row| {
-            Ok(User {
-                id: row.get(0)?,
-                username: row.get(1)?,
-                email: row.get(2)?,
-                password_hash: row.get(3)?,
-                created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap(),
-                last_login: row.get::<_, Option<String>>(5)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                presence: parse_presence(&row.get::<_, String>(6)?),
-                status: row.get(7)?,
-            })
-        });
+        Ok(User { is_bot: true, bot_owner_id: Some(owner_id.to_string()), ..user })
+    }
+
+    fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM users u WHERE u.username = ?1", USER_COLUMNS
+        ))?;
+
+        let user = stmt.query_row(params![username], row_to_user);
 
         match user {
             Ok(u) => Ok(Some(u)),
@@ -247,24 +796,12 @@ impl Database {
         }
     }
 
-    pub fn get_user_by_id(&self, user_id: String) -> Result<Option<User>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, username, email, password_hash, created_at, last_login, presence, status
-            FROM users WHERE id = ?1"
-        )?;
+    fn get_user_by_id(&self, user_id: String) -> Result<Option<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM users u WHERE u.id = ?1", USER_COLUMNS
+        ))?;
 
-        let user = stmt.query_row(params![user_id], |row| {
-            Ok(User {
-                id: row.get(0)?,
-                username: row.get(1)?,
-                email: row.get(2)?,
-                password_hash: row.get(3)?,
-                created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap(),
-                last_login: row.get::<_, Option<String>>(5)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                presence: parse_presence(&row.get::<_, String>(6)?),
-                status: row.get(7)?,
-            })
-        });
+        let user = stmt.query_row(params![user_id], row_to_user);
 
         match user {
             Ok(u) => Ok(Some(u)),
@@ -273,37 +810,246 @@ impl Database {
         }
     }
 
-    pub fn update_last_login(&self, user_id: String) -> Result<()> {
-        let now = Utc::now();
-        self.conn.execute(
-            "UPDATE users SET last_login = ?1 WHERE id = ?2",
-            params!{now.to_rfc3339(), user_id},
-        )?;
-        Ok(())
-    }
+    fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM users u WHERE u.email = ?1", USER_COLUMNS
+        ))?;
 
-    pub fn update_user_presence(&self, user_id: &str, presence: &Presence) -> Result<()> {
-        let presence_str = match presence {
-            Presence::Online => "Online",
-            Presence::Offline => "Offline",
-            Presence::Away => "Away",
-            Presence::DoNotDisturb => "DND",
-            Presence::AppearOffline => "AppearOffline",
-        };
+        let user = stmt.query_row(params![email], row_to_user);
+
+        match user {
+            Ok(u) => Ok(Some(u)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_user_by_identity(&self, provider: &str, subject: &str) -> Result<Option<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM users u
+            JOIN user_identities ui ON u.id = ui.user_id
+            WHERE ui.provider = ?1 AND ui.subject = ?2", USER_COLUMNS
+        ))?;
+
+        let user = stmt.query_row(params![provider, subject], row_to_user);
+
+        match user {
+            Ok(u) => Ok(Some(u)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn link_user_identity(&self, user_id: &str, provider: &str, subject: &str) -> Result<()> {
+        let now = Utc::now();
+        self.conn.execute(
+            "INSERT INTO user_identities (provider, subject, user_id, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![provider, subject, user_id, now.to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    fn set_email_verified(&self, user_id: &str, verified: bool) -> Result<()> {
+        self.conn.execute(
+            "UPDATE users SET email_verified = ?1 WHERE id = ?2",
+            params![verified as i32, user_id],
+        )?;
+        Ok(())
+    }
+
+    fn set_user_approved(&self, user_id: &str, approved: bool) -> Result<()> {
+        self.conn.execute(
+            "UPDATE users SET approved = ?1 WHERE id = ?2",
+            params![approved as i32, user_id],
+        )?;
+        Ok(())
+    }
+
+    fn get_pending_users(&self) -> Result<Vec<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM users u WHERE u.approved = 0 ORDER BY u.created_at", USER_COLUMNS
+        ))?;
+
+        let users = stmt.query_map([], row_to_user)?;
+
+        let mut result = Vec::new();
+        for user in users {
+            result.push(user?);
+        }
+        Ok(result)
+    }
+
+    fn delete_user(&self, user_id: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM user_blocks WHERE blocker_id = ?1 OR blocked_id = ?1", params![user_id])?;
+        self.conn.execute("DELETE FROM users WHERE id = ?1", params![user_id])?;
+        Ok(())
+    }
+
+    fn set_user_invite_code(&self, user_id: &str, code: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE users SET invite_code = ?1 WHERE id = ?2",
+            params![code, user_id],
+        )?;
+        Ok(())
+    }
+
+    fn update_user_password(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+            params![password_hash, user_id],
+        )?;
+        Ok(())
+    }
+
+    fn update_last_login(&self, user_id: String) -> Result<()> {
+        let now = Utc::now();
+        self.conn.execute(
+            "UPDATE users SET last_login = ?1 WHERE id = ?2",
+            params!{now.to_rfc3339(), user_id},
+        )?;
+        Ok(())
+    }
+
+    fn update_user_presence(&self, user_id: &str, presence: &Presence) -> Result<()> {
+        let presence_str = match presence {
+            Presence::Online => "Online",
+            Presence::Offline => "Offline",
+            Presence::Away => "Away",
+            Presence::DoNotDisturb => "DND",
+            Presence::AppearOffline => "AppearOffline",
+        };
 
         self.conn.execute("UPDATE users SET presence = ?1 WHERE id = ?2", params![presence_str, user_id])?;
 
         Ok(())
     }
 
-    pub fn update_user_status(&self, user_id: &str, status: Option<&str>) -> Result<()> {
+    fn update_user_status(&self, user_id: &str, status: Option<&str>) -> Result<()> {
         self.conn.execute("UPDATE users SET status = ?1 WHERE id = ?2", params![status, user_id])?;
         Ok(())
     }
 
+    fn get_bots_by_owner(&self, owner_id: &str) -> Result<Vec<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM users u WHERE u.is_bot = 1 AND u.bot_owner_id = ?1 ORDER BY u.created_at", USER_COLUMNS
+        ))?;
+
+        let users = stmt.query_map(params![owner_id], row_to_user)?;
+
+        let mut result = Vec::new();
+        for user in users {
+            result.push(user?);
+        }
+        Ok(result)
+    }
+
+    fn set_user_role(&self, user_id: &str, role: UserRole) -> Result<()> {
+        self.conn.execute("UPDATE users SET role = ?1 WHERE id = ?2", params![role.as_str(), user_id])?;
+        Ok(())
+    }
+
+    fn set_user_deactivated(&self, user_id: &str, deactivated_at: Option<DateTime<Utc>>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE users SET deactivated_at = ?1 WHERE id = ?2",
+            params![deactivated_at.map(|t| t.to_rfc3339()), user_id],
+        )?;
+        Ok(())
+    }
+
+    fn search_users(&self, search: &UserSearch, limit: usize, offset: usize) -> Result<Vec<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM users u
+            WHERE (?1 IS NULL OR u.username LIKE ?1 ESCAPE '\\' OR u.email LIKE ?1 ESCAPE '\\')
+            AND (?2 IS NULL OR u.role = ?2)
+            AND (?3 IS NULL OR (u.deactivated_at IS NOT NULL) = ?3)
+            ORDER BY u.username
+            LIMIT ?4 OFFSET ?5",
+            USER_COLUMNS
+        ))?;
+
+        let users = stmt.query_map(
+            params![
+                search.query.as_deref().map(like_pattern),
+                search.role.map(|r| r.as_str()),
+                search.deactivated,
+                limit as i64,
+                offset as i64,
+            ],
+            row_to_user,
+        )?;
+
+        let mut result = Vec::new();
+        for user in users {
+            result.push(user?);
+        }
+        Ok(result)
+    }
+
+    // Block Methods
+
+    fn block_user(&self, blocker_id: &str, blocked_id: &str) -> Result<bool> {
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO user_blocks (blocker_id, blocked_id, created_at) VALUES (?1, ?2, ?3)",
+            params![blocker_id, blocked_id, Utc::now().to_rfc3339()],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    fn unblock_user(&self, blocker_id: &str, blocked_id: &str) -> Result<bool> {
+        let removed = self.conn.execute(
+            "DELETE FROM user_blocks WHERE blocker_id = ?1 AND blocked_id = ?2",
+            params![blocker_id, blocked_id],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn is_user_blocked(&self, blocker_id: &str, blocked_id: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM user_blocks WHERE blocker_id = ?1 AND blocked_id = ?2",
+            params![blocker_id, blocked_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    fn get_blocked_users(&self, blocker_id: &str) -> Result<Vec<UserBlock>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT b.blocked_id, u.username, b.created_at
+            FROM user_blocks b
+            JOIN users u ON u.id = b.blocked_id
+            WHERE b.blocker_id = ?1
+            ORDER BY b.created_at DESC",
+        )?;
+
+        let blocks = stmt.query_map(params![blocker_id], |row| {
+            Ok(UserBlock {
+                user_id: row.get(0)?,
+                username: row.get(1)?,
+                created_at: row.get::<_, String>(2)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+
+        let mut result = Vec::new();
+        for block in blocks {
+            result.push(block?);
+        }
+        Ok(result)
+    }
+
+    fn get_blocker_ids(&self, blocked_id: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("SELECT blocker_id FROM user_blocks WHERE blocked_id = ?1")?;
+        let ids = stmt.query_map(params![blocked_id], |row| row.get(0))?;
+
+        let mut result = Vec::new();
+        for id in ids {
+            result.push(id?);
+        }
+        Ok(result)
+    }
+
     // Session Methods
 
-    pub fn create_session(&self, user_id: String, token: &str, expires_at: DateTime<Utc>) -> Result<Session> {
+    fn create_session(&self, user_id: String, token: &str, expires_at: DateTime<Utc>) -> Result<Session> {
         let now = Utc::now();
         
         self.conn.execute(
@@ -322,7 +1068,7 @@ impl Database {
         })
     }
 
-    pub fn get_session_by_token(&self, token: &str) -> Result<Option<Session>> {
+    fn get_session_by_token(&self, token: &str) -> Result<Option<Session>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, user_id, token, created_at, expires_at
             FROM sessions WHERE token = ?1"
@@ -345,12 +1091,12 @@ impl Database {
         }
     }
 
-    pub fn delete_session(&self, token: &str) -> Result<()> {
+    fn delete_session(&self, token: &str) -> Result<()> {
         self.conn.execute("DELETE FROM sessions WHERE token = ?1", params![token])?;
         Ok(())
     }
 
-    pub fn delete_expired_sessions(&self) -> Result<()> {
+    fn delete_expired_sessions(&self) -> Result<()> {
         let now = Utc::now();
         self.conn.execute(
             "DELETE FROM sessions WHERE expires_at < ?1",
@@ -359,9 +1105,17 @@ impl Database {
         Ok(())
     }
 
+    fn delete_user_sessions(&self, user_id: &str, keep_token: Option<&str>) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM sessions WHERE user_id = ?1 AND (?2 IS NULL OR token != ?2)",
+            params![user_id, keep_token],
+        )?;
+        Ok(())
+    }
+
     // Room Methods
 
-    pub fn create_room(&self, name: &str, desc: &str, created_by: &str) -> Result<Room> {
+    fn create_room(&self, name: &str, desc: &str, created_by: &str) -> Result<Room> {
         let id = Uuid::new_v4().to_string();
         let now = Utc::now();
 
@@ -376,25 +1130,20 @@ impl Database {
             id,
             name: name.to_string(),
             desc: desc.to_string(),
+            topic: String::new(),
             created_by: created_by.to_string(),
             created_at: now,
+            archived_at: None,
         })
     }
 
-    pub fn get_all_rooms(&self) -> Result<Vec<Room>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, name, desc, created_by, created_at FROM rooms ORDER BY created_at DESC"
-        )?;
+    fn get_all_rooms(&self, include_archived: bool) -> Result<Vec<Room>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM rooms r WHERE ?1 OR r.archived_at IS NULL ORDER BY r.created_at DESC",
+            ROOM_COLUMNS
+        ))?;
 
-        let rooms = stmt.query_map([], |row| {
-            Ok(Room {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                desc: row.get(2)?,
-                created_by: row.get(3)?,
-                created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap(),
-            })
-        })?;
+        let rooms = stmt.query_map(params![include_archived], row_to_room)?;
 
         let mut result = Vec::new();
         for room in rooms {
@@ -404,20 +1153,10 @@ impl Database {
         Ok(result)
     }
 
-    pub fn get_room_by_id(&self, room_id: &str) -> Result<Option<Room>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, name, desc, created_by, created_at FROM rooms where id = ?1"
-        )?;
+    fn get_room_by_id(&self, room_id: &str) -> Result<Option<Room>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM rooms r WHERE r.id = ?1", ROOM_COLUMNS))?;
 
-        let room = stmt.query_row(params![room_id], |row| {
-            Ok(Room {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                desc: row.get(2)?,
-                created_by: row.get(3)?,
-                created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap()
-            })
-        });
+        let room = stmt.query_row(params![room_id], row_to_room);
 
         match room {
             Ok(r) => Ok(Some(r)),
@@ -426,7 +1165,31 @@ impl Database {
         }
     }
 
-    pub fn add_user_to_room(&self, room_id: &str, user_id: &str) -> Result<()> {
+    fn update_room(&self, room_id: &str, name: &str, desc: &str, topic: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE rooms SET name = ?1, desc = ?2, topic = ?3 WHERE id = ?4",
+            params![name, desc, topic, room_id],
+        )?;
+        Ok(())
+    }
+
+    fn set_room_archived(&self, room_id: &str, archived: bool) -> Result<Option<DateTime<Utc>>> {
+        let archived_at = archived.then(Utc::now);
+        self.conn.execute(
+            "UPDATE rooms SET archived_at = ?1 WHERE id = ?2",
+            params![archived_at.map(|t| t.to_rfc3339()), room_id],
+        )?;
+        Ok(archived_at)
+    }
+
+    /// Members, messages, webhooks and the rest of the room's rows go with
+    /// it through `ON DELETE CASCADE`.
+    fn delete_room(&self, room_id: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM rooms WHERE id = ?1", params![room_id])?;
+        Ok(())
+    }
+
+    fn add_user_to_room(&self, room_id: &str, user_id: &str) -> Result<()> {
         let now = Utc::now();
         self.conn.execute(
             "INSERT OR IGNORE INTO room_members (room_id, user_id, joined_at) VALUES (?1, ?2, ?3)",
@@ -435,7 +1198,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn remove_user_from_room(&self, room_id: &str, user_id: &str) -> Result<()> {
+    fn remove_user_from_room(&self, room_id: &str, user_id: &str) -> Result<()> {
         self.conn.execute(
             "DELETE FROM room_members WHERE room_id = ?1 AND user_id = ?2",
             params![room_id, user_id],
@@ -443,7 +1206,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn is_user_in_room(&self, room_id: &str, user_id: &str) -> Result<bool> {
+    fn is_user_in_room(&self, room_id: &str, user_id: &str) -> Result<bool> {
         let count: i64 = self.conn.query_row(
             "SELECT COUNT(*) FROM room_members WHERE room_id = ?1 AND user_id = ?2",
             params![room_id, user_id],
@@ -452,31 +1215,110 @@ impl Database {
         Ok(count > 0)
     }
 
-    pub fn get_user_rooms(&self, user_id: &str) -> Result<Vec<Room>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT r.id, r.name, r.desc, r.created_by, r.created_at
+    fn get_user_rooms(&self, user_id: &str) -> Result<Vec<Room>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}
             FROM rooms r
             JOIN room_members rm ON r.id = rm.room_id
             WHERE rm.user_id = ?1
             ORDER BY rm.joined_at DESC",
-        )?;
+            ROOM_COLUMNS
+        ))?;
 
-        let rooms = stmt.query_map(params![user_id], |row| {
-            Ok(Room {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                desc: row.get(2)?,
-                created_by: row.get(3)?,
-                created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap(),
-            })
-        })?;
+        let rooms = stmt.query_map(params![user_id], row_to_room)?;
 
         let mut result = Vec::new();
         for room in rooms { result.push(room?); }
         Ok(result)
     }
 
-    pub fn create_room_message(
+    fn get_room_members(&self, room_id: &str) -> Result<Vec<User>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM users u
+            JOIN room_members rm ON u.id = rm.user_id
+            WHERE rm.room_id = ?1
+            ORDER BY u.username", // This will need to be changed to role once user roles are added
+            USER_COLUMNS
+        ))?;
+
+        let members = stmt.query_map(params![room_id], row_to_user)?;
+
+        let mut result = Vec::new();
+        for member in members {
+            result.push(member?);
+        }
+
+        Ok(result)
+    }
+
+    // Ban and Mute Methods
+
+    fn add_room_sanction(&self, kind: SanctionKind, sanction: &RoomSanction) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO room_sanctions (room_id, user_id, kind, reason, issued_by, created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                sanction.room_id,
+                sanction.user_id,
+                kind.as_str(),
+                sanction.reason,
+                sanction.issued_by,
+                sanction.created_at.to_rfc3339(),
+                sanction.expires_at.map(|t| t.to_rfc3339()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove_room_sanction(&self, kind: SanctionKind, room_id: &str, user_id: &str) -> Result<bool> {
+        let removed = self.conn.execute(
+            "DELETE FROM room_sanctions WHERE room_id = ?1 AND user_id = ?2 AND kind = ?3",
+            params![room_id, user_id, kind.as_str()],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn get_room_sanction(&self, kind: SanctionKind, room_id: &str, user_id: &str) -> Result<Option<RoomSanction>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM room_sanctions s
+            JOIN users u ON u.id = s.user_id
+            WHERE s.room_id = ?1 AND s.user_id = ?2 AND s.kind = ?3
+                AND (s.expires_at IS NULL OR s.expires_at > ?4)",
+            SANCTION_COLUMNS
+        ))?;
+
+        match stmt.query_row(params![room_id, user_id, kind.as_str(), Utc::now().to_rfc3339()], row_to_sanction) {
+            Ok(sanction) => Ok(Some(sanction)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_room_sanctions(&self, kind: SanctionKind, room_id: &str) -> Result<Vec<RoomSanction>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM room_sanctions s
+            JOIN users u ON u.id = s.user_id
+            WHERE s.room_id = ?1 AND s.kind = ?2
+                AND (s.expires_at IS NULL OR s.expires_at > ?3)
+            ORDER BY s.created_at DESC",
+            SANCTION_COLUMNS
+        ))?;
+
+        let sanctions = stmt.query_map(params![room_id, kind.as_str(), Utc::now().to_rfc3339()], row_to_sanction)?;
+
+        let mut result = Vec::new();
+        for sanction in sanctions {
+            result.push(sanction?);
+        }
+        Ok(result)
+    }
+
+    // Room Message Methods
+
+    fn create_room_message(
         &self, 
         sender_id: &str, 
         room_id: &str, 
@@ -488,8 +1330,8 @@ impl Database {
 
         self.conn.execute(
             "INSERT INTO messages (id, sender_id, message_type, room_id, content, 
-                sent_at, is_read, is_edited, reply_to_message_id, reactions, is_pinned)
-            VALUES (?1, ?2, 'room', ?3, ?4, ?5, 0, 0, ?6, '[]', 0)",
+                sent_at, is_read, is_edited, reply_to_message_id, is_pinned)
+            VALUES (?1, ?2, 'room', ?3, ?4, ?5, 0, 0, ?6, 0)",
             params![id, sender_id, room_id, content, now.to_rfc3339(), reply_to_message_id],
         )?;
 
@@ -510,13 +1352,22 @@ impl Database {
             is_pinned: false,
             pinned_at: None,
             pinned_by: None,
+            display_name: None,
         })
     }
 
-    pub fn get_room_messages(&self, room_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>> {
+    fn set_message_display_name(&self, message_id: &str, display_name: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE messages SET display_name = ?1 WHERE id = ?2",
+            params![display_name, message_id],
+        )?;
+        Ok(())
+    }
+
+    fn get_room_messages(&self, room_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, sender_id, message_type, room_id, content, sent_at, is_edited, edited_at, 
-                reply_to_message_id, reactions, is_pinned, pinned_at, pinned_by
+                reply_to_message_id, is_pinned, pinned_at, pinned_by, display_name
             FROM messages
             WHERE (message_type = 'room' OR message_type = 'server')  AND room_id = ?1
             ORDER BY sent_at DESC
@@ -524,9 +1375,6 @@ impl Database {
         )?;
 
         let messages = stmt.query_map(params![room_id, limit, offset], |row| {
-            let reactions_json: String = row.get(9)?;
-            let reactions: Vec<ReactionSummary> = serde_json::from_str(&reactions_json).unwrap_or_default();
-
             Ok(Message {
                 id: row.get(0)?,
                 sender_id: row.get(1)?,
@@ -543,38 +1391,132 @@ impl Database {
                 is_edited: row.get(6)?,
                 edited_at: row.get::<_, Option<String>>(7)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
                 reply_to_message_id: row.get(8)?,
-                reactions,
-                is_pinned: row.get::<_, i32>(10)? != 0,
-                pinned_at: row.get::<_, Option<String>>(11)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                pinned_by: row.get(12)?,
+                reactions: Vec::new(),
+                is_pinned: row.get::<_, i32>(9)? != 0,
+                pinned_at: row.get::<_, Option<String>>(10)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                pinned_by: row.get(11)?,
+                display_name: row.get(12)?,
             })
         })?;
 
         let mut result = Vec::new();
         for message in messages { result.push(message?); }
+        self.attach_reactions(&mut result)?;
         Ok(result)
     }
 
-    // Private Message Methods
+    fn get_message_by_id(&self, message_id: &str) -> Result<Option<Message>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, sender_id, message_type, room_id, receiver_id, content, sent_at,
+                read_at, is_read, is_edited, edited_at, reply_to_message_id, is_pinned, pinned_at, pinned_by, display_name
+            FROM messages
+            WHERE id = ?1"
+        )?;
 
-    pub fn create_private_message(&self, sender_id: &str, receiver_id: &str, content: &str) ->  Result<Message> {
-        let id = Uuid::new_v4().to_string();
+        let message = stmt.query_row(params![message_id], |row| {
+            Ok(Message {
+                id: row.get(0)?,
+                sender_id: row.get(1)?,
+                message_type: match row.get::<_, String>(2)?.as_str() {
+                    "private" => MessageType::Private,
+                    "server" => MessageType::Server,
+                    _ => MessageType::Room,
+                },
+                room_id: row.get(3)?,
+                receiver_id: row.get(4)?,
+                content: row.get(5)?,
+                sent_at: row.get::<_, String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+                read_at: row.get::<_, Option<String>>(7)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                is_read: row.get(8)?,
+                is_edited: row.get(9)?,
+                edited_at: row.get::<_, Option<String>>(10)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                reply_to_message_id: row.get(11)?,
+                reactions: Vec::new(),
+                is_pinned: row.get::<_,i32>(12)? != 0,
+                pinned_at: row.get::<_, Option<String>>(13)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                pinned_by: row.get(14)?,
+                display_name: row.get(15)?,
+            })
+        });
+
+        match message {
+            Ok(mut msg) => {
+                msg.reactions = self.get_reactions(&msg.id)?;
+                Ok(Some(msg))
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn edit_message(&self, message_id: &str, content: &str) -> Result<()> {
         let now = Utc::now();
-        
         self.conn.execute(
-            "INSERT INTO messages (id, sender_id, message_type, receiver_id, content, sent_at, is_read, is_edited, reactions, is_pinned)
-            VALUES (?1, ?2, 'private', ?3, ?4, ?5, 0, 0, '[]', 0)",
-            params![id, sender_id, receiver_id, content, now.to_rfc3339()],
+            "UPDATE messages SET content = ?1, is_edited = 1, edited_at = ?2 WHERE id = ?3", 
+            params![content, now.to_rfc3339(), message_id]
+        )?;
+        Ok(())
+    }
+
+    fn delete_message(&self, message_id: &str, user1_id: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM MESSAGES WHERE id = ?1 AND sender_id = ?2", 
+            params![message_id, user1_id]
+        )?;
+        Ok(())
+    }
+
+    fn room_announcement(&self, room_id: &str, content: &str, sender_id: &str) -> Result<Message> {
+        let now = Utc::now();
+        let id = Uuid::new_v4().to_string();
+
+        self.conn.execute(
+            "INSERT INTO messages (id, sender_id, message_type, room_id, content, sent_at, is_read, is_edited, is_pinned)
+            VALUES (?1, ?2, 'server', ?3, ?4, ?5, 0, 0, 0)",
+            params![id, sender_id, room_id, content, now.to_rfc3339()],
         )?;
 
         Ok(Message {
             id,
             sender_id: sender_id.to_string(),
-            message_type: MessageType::Private,
-            room_id: None,
-            receiver_id: Some(receiver_id.to_string()),
-            content: content.to_string(),
-            sent_at: now,
+            message_type: MessageType::Server,
+            room_id: Some(room_id.to_string()),
+            receiver_id: None,
+            content: content.to_string(),
+            sent_at: now,
+            read_at: None,
+            is_read: false,
+            is_edited: false,
+            edited_at: None,
+            reply_to_message_id: None,
+            reactions: Vec::new(),
+            is_pinned: false,
+            pinned_at: None,
+            pinned_by: None,
+            display_name: None,
+        })
+    }
+
+    // Private Message Methods
+
+    fn create_private_message(&self, sender_id: &str, receiver_id: &str, content: &str) ->  Result<Message> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        
+        self.conn.execute(
+            "INSERT INTO messages (id, sender_id, message_type, receiver_id, content, sent_at, is_read, is_edited, is_pinned)
+            VALUES (?1, ?2, 'private', ?3, ?4, ?5, 0, 0, 0)",
+            params![id, sender_id, receiver_id, content, now.to_rfc3339()],
+        )?;
+
+        Ok(Message {
+            id,
+            sender_id: sender_id.to_string(),
+            message_type: MessageType::Private,
+            room_id: None,
+            receiver_id: Some(receiver_id.to_string()),
+            content: content.to_string(),
+            sent_at: now,
             read_at: None,
             is_read: false,
             is_edited: false,
@@ -584,13 +1526,14 @@ impl Database {
             is_pinned: false,
             pinned_at: None,
             pinned_by: None,
+            display_name: None,
         })
     }
 
-    pub fn get_private_messages_between_users(&self, user1_id: &str, user2_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>> {
+    fn get_private_messages_between_users(&self, user1_id: &str, user2_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, sender_id, receiver_id, content, sent_at, read_at, is_read, 
-                is_edited, edited_at, reactions, is_pinned, pinned_at, pinned_by
+                is_edited, edited_at, is_pinned, pinned_at, pinned_by
             FROM messages
             WHERE message_type = 'private'
                 AND ((sender_id = ?1 AND receiver_id = ?2) OR (sender_id = ?2 AND receiver_id = ?1))
@@ -599,9 +1542,6 @@ impl Database {
         )?;
 
         let messages = stmt.query_map(params![user1_id, user2_id, limit, offset], |row| {
-            let reactions_json: String = row.get(9)?;
-            let reactions: Vec<ReactionSummary> = serde_json::from_str(&reactions_json).unwrap_or_default();
-
             Ok(Message {
                 id: row.get(0)?,
                 sender_id: row.get(1)?,
@@ -615,10 +1555,11 @@ impl Database {
                 is_edited: row.get(7)?,
                 edited_at: row.get::<_, Option<String>>(8)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
                 reply_to_message_id: None,
-                reactions,
-                is_pinned: row.get::<_, i32>(10)? != 0,
-                pinned_at: row.get::<_, Option<String>>(11)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                pinned_by: row.get(12)?,
+                reactions: Vec::new(),
+                is_pinned: row.get::<_, i32>(9)? != 0,
+                pinned_at: row.get::<_, Option<String>>(10)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                pinned_by: row.get(11)?,
+                display_name: None,
             })
         })?;
 
@@ -626,18 +1567,19 @@ impl Database {
         for message in messages {
             result.push(message?);
         }
+        self.attach_reactions(&mut result)?;
         Ok(result)
     }
 
-    pub fn get_received_private_messages(&self, receiver_id: &str, unread_only: bool, limit: usize, offset: usize) -> Result<Vec<Message>> {
+    fn get_received_private_messages(&self, receiver_id: &str, unread_only: bool, limit: usize, offset: usize) -> Result<Vec<Message>> {
         let query = if unread_only {
-            "SELECT id, sender_id, receiver_id, content, sent_at, read_at, is_read, is_edited, edited_at, reactions, is_pinned, pinned_at, pinned_by
+            "SELECT id, sender_id, receiver_id, content, sent_at, read_at, is_read, is_edited, edited_at, is_pinned, pinned_at, pinned_by
             FROM messages
             WHERE message_type = 'private' AND receiver_id = ?1 AND is_read = 0
             ORDER BY sent_at DESC
             LIMIT ?2 OFFSET ?3"
         } else {
-            "SELECT id, sender_id, receiver_id, content, sent_at, read_at, is_read, is_edited, edited_at, reactions, is_pinned, pinned_at, pinned_by
+            "SELECT id, sender_id, receiver_id, content, sent_at, read_at, is_read, is_edited, edited_at, is_pinned, pinned_at, pinned_by
             FROM messages
             WHERE message_type = 'private' AND receiver_id = ?1
             ORDER BY sent_at DESC
@@ -647,9 +1589,6 @@ impl Database {
         let mut stmt = self.conn.prepare(query)?;
 
         let messages = stmt.query_map(params![receiver_id, limit, offset], |row| {
-            let reactions_string: String = row.get(9)?;
-            let reactions = serde_json::from_str(&reactions_string).unwrap_or_default();
-
             Ok(Message {
                 id: row.get(0)?,
                 sender_id: row.get(1)?,
@@ -663,10 +1602,11 @@ impl Database {
                 is_edited: row.get(7)?,
                 edited_at: row.get::<_, Option<String>>(8)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
                 reply_to_message_id: None,
-                reactions,
-                is_pinned: row.get::<_, i32>(10)? != 0,
-                pinned_at: row.get::<_, Option<String>>(11)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                pinned_by: row.get(12)?,
+                reactions: Vec::new(),
+                is_pinned: row.get::<_, i32>(9)? != 0,
+                pinned_at: row.get::<_, Option<String>>(10)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                pinned_by: row.get(11)?,
+                display_name: None,
             })
         })?;
 
@@ -674,10 +1614,11 @@ impl Database {
         for message in messages {
             result.push(message?);
         }
+        self.attach_reactions(&mut result)?;
         Ok(result)
     }
 
-    pub fn mark_private_message_as_read(&self, message_id: &str) -> Result<()> {
+    fn mark_private_message_as_read(&self, message_id: &str) -> Result<()> {
         let now = Utc::now();
         self.conn.execute(
             "UPDATE messages SET is_read = 1, read_at = ?1
@@ -687,7 +1628,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn mark_private_conversation_as_read(&self, receiver_id: &str, sender_id: &str) -> Result<()> {
+    fn mark_private_conversation_as_read(&self, receiver_id: &str, sender_id: &str) -> Result<()> {
         let now = Utc::now();
         self.conn.execute(
             "UPDATE messages SET is_read = 1, read_at = ?1
@@ -697,7 +1638,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn get_unread_private_message_count(&self, user_id: &str) -> Result<i64> {
+    fn get_unread_private_message_count(&self, user_id: &str) -> Result<i64> {
         let count: i64 = self.conn.query_row(
             "SELECT COUNT(*) FROM messages
             WHERE message_type = 'private' AND receiver_id = ?1 AND is_read = 0",
@@ -708,99 +1649,13 @@ impl Database {
         Ok(count)
     }
 
-    pub fn delete_message(&self, message_id: &str, user1_id: &str) -> Result<()> {
-        self.conn.execute(
-            "DELETE FROM MESSAGES WHERE id = ?1 AND sender_id = ?2", 
-            params![message_id, user1_id]
-        )?;
-        Ok(())
-    }
-    pub fn edit_message(&self, message_id: &str, content: &str) -> Result<()> {
-        let now = Utc::now();
-        self.conn.execute(
-            "UPDATE messages SET content = ?1, is_edited = 1, edited_at = ?2 WHERE id = ?3", 
-            params![content, now.to_rfc3339(), message_id]
-        )?;
-        Ok(())
-    }
-
-    pub fn room_announcement(&self, room_id: &str, content: &str, sender_id: &str) -> Result<Message> {
-        let now = Utc::now();
-        let id = Uuid::new_v4().to_string();
+    // Mention Methods
 
-        self.conn.execute(
-            "INSERT INTO messages (id, sender_id, message_type, room_id, content, sent_at, is_read, is_edited, reactions, is_pinned)
-            VALUES (?1, ?2, 'server', ?3, ?4, ?5, 0, 0, '[]', 0)",
-            params![id, sender_id, room_id, content, now.to_rfc3339()],
-        )?;
-
-        Ok(Message {
-            id,
-            sender_id: sender_id.to_string(),
-            message_type: MessageType::Server,
-            room_id: Some(room_id.to_string()),
-            receiver_id: None,
-            content: content.to_string(),
-            sent_at: now,
-            read_at: None,
-            is_read: false,
-            is_edited: false,
-            edited_at: None,
-            reply_to_message_id: None,
-            reactions: Vec::new(),
-            is_pinned: false,
-            pinned_at: None,
-            pinned_by: None,
-        })
-    }
-
-    pub fn get_room_members(&self, room_id: &str) -> Result<Vec<User>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT u.id, u.username, u.email, u.password_hash, u.created_at, u.last_login, u.presence, u.status
-            FROM users u
-            JOIN room_members rm ON u.id = rm.user_id
-            WHERE rm.room_id = ?1
-            ORDER BY u.username" // This will need to be changed to role once user roles are added
-        )?;
-
-        let members = stmt.query_map(params![room_id], |row| {
-            Ok(User {
-                id: row.get(0)?,
-                username: row.get(1)?,
-                email: row.get(2)?,
-                password_hash: row.get(3)?,
-                created_at: row.get::<_, String>(4)?.parse::<DateTime<Utc>>().unwrap(),
-                last_login: row.get::<_, Option<String>>(5)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                presence: parse_presence(&row.get::<_, String>(6)?),
-                status: row.get(7)?,
-            })
-        })?;
-
-        let mut result = Vec::new();
-        for member in members {
-            result.push(member?);
-        }
-
-        Ok(result)
-    }
-
-    pub fn extract_mentions(&self, content: &str) -> Vec<String> {
-        let re = Regex::new(r"@(\w+)").unwrap();
-        re.captures_iter(content)
-            .filter_map(|cap| cap.get(1).map(|m| m.as_str().to_string()))
-            .filter(|username| username.to_lowercase() != "everyone")
-            .collect()
-    }
-
-    pub fn everyone_mentioned(&self, content: &str) -> bool {
-        content.to_lowercase().contains("@everyone")
-    }
-
-    pub fn save_message_mentions(&self, message_id: &str, sender_id: &str, content: &str, room_id: &str) -> Result<Vec<String>> {
+    fn save_message_mentions(&self, message_id: &str, sender_id: &str, content: &str, room_id: &str) -> Result<Vec<String>> {
         let mut notified_user_ids = Vec::new();
         let now = Utc::now();
 
-        if self.everyone_mentioned(content) {
+        if everyone_mentioned(content) {
             let members = self.get_room_members(room_id)?;
 
             for member in members {
@@ -815,7 +1670,7 @@ impl Database {
                 }
             }
         } else {
-            let mentioned_usernames = self.extract_mentions(content);
+            let mentioned_usernames = extract_mentions(content);
 
             for username in mentioned_usernames {
                 if let Ok(Some(user)) = self.get_user_by_username(&username) {
@@ -834,9 +1689,9 @@ impl Database {
         Ok(notified_user_ids)
     }
 
-    pub fn get_message_mentions(&self, message_id: &str) -> Result<Vec<String>> {
+    fn get_message_mentions(&self, message_id: &str) -> Result<Vec<String>> {
         let mut stmt = self.conn.prepare(
-            "SELECT mentioned_user_ids FROM message_mentions WHERE message_id = ?1",
+            "SELECT mentioned_user_id FROM message_mentions WHERE message_id = ?1",
         )?;
 
         let user_ids = stmt.query_map(params![message_id], |row| {
@@ -850,7 +1705,7 @@ impl Database {
         Ok(result)
     }
 
-    pub fn get_unread_mentions_count(&self, user_id: &str) -> Result<i64> {
+    fn get_unread_mentions_count(&self, user_id: &str) -> Result<i64> {
         let count: i64 = self.conn.query_row(
             "SELECT COUNT(*) FROM message_mentions WHERE mentioned_user_id = ?1 AND is_read = 0",
             params![user_id],
@@ -859,7 +1714,7 @@ impl Database {
         Ok(count)
     }
 
-    pub fn mark_mention_as_read(&self, user_id: &str, message_id: &str) -> Result<()> {
+    fn mark_mention_as_read(&self, user_id: &str, message_id: &str) -> Result<()> {
         let now = Utc::now().to_rfc3339();
         self.conn.execute(
             "UPDATE message_mentions SET is_read = 1, read_at = ?1
@@ -869,7 +1724,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn mark_room_mentions_as_read(&self, user_id: &str, room_id: &str) -> Result<()> {
+    fn mark_room_mentions_as_read(&self, user_id: &str, room_id: &str) -> Result<()> {
         let now = Utc::now().to_rfc3339();
         self.conn.execute(
             "UPDATE message_mentions SET is_read = 1, read_at = ?1
@@ -884,10 +1739,10 @@ impl Database {
         Ok(())
     }
 
-    pub fn get_all_user_mentions(&self, user_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>> {
+    fn get_all_user_mentions(&self, user_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>> {
         let mut stmt = self.conn.prepare(
             "SELECT m.id, m.sender_id, m.message_type, m.room_id, m.content, m.sent_at, 
-                m.is_edited, m.edited_at, m.reply_to_message_id, m.reactions, m.is_pinned, m.pinned_at, m.pinned_by
+                m.is_edited, m.edited_at, m.reply_to_message_id, m.is_pinned, m.pinned_at, m.pinned_by
             FROM messages m
             JOIN message_mentions mm ON m.id = mm.message_id
             WHERE mm.mentioned_user_id = ?1
@@ -896,9 +1751,6 @@ impl Database {
         )?;
 
         let messages = stmt.query_map(params![user_id, limit, offset], |row| {
-            let reactions_string: String = row.get(9)?;
-            let reactions = serde_json::from_str(&reactions_string).unwrap_or_default();
-
             Ok(Message {
                 id: row.get(0)?,
                 sender_id: row.get(1)?,
@@ -916,10 +1768,11 @@ impl Database {
                 is_edited: row.get(6)?,
                 edited_at: row.get::<_, Option<String>>(7)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
                 reply_to_message_id: row.get(8)?,
-                reactions,
-                is_pinned: row.get::<_, i32>(10)? != 0,
-                pinned_at: row.get::<_, Option<String>>(11)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                pinned_by: row.get(12)?,
+                reactions: Vec::new(),
+                is_pinned: row.get::<_, i32>(9)? != 0,
+                pinned_at: row.get::<_, Option<String>>(10)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                pinned_by: row.get(11)?,
+                display_name: None,
             })
         })?;
 
@@ -927,120 +1780,54 @@ impl Database {
         for message in messages {
             result.push(message?);
         }
+        self.attach_reactions(&mut result)?;
         Ok(result)
     }
 
-    pub fn get_message_by_id(&self, message_id: &str) -> Result<Option<Message>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, sender_id, message_type, room_id, receiver_id, content, sent_at,
-                read_at, is_read, is_edited, edited_at, reply_to_message_id, reactions, is_pinned, pinned_at, pinned_by
-            FROM messages
-            WHERE id = ?1"
-        )?;
+    // Reaction and Pin Methods
 
-        let message = stmt.query_row(params![message_id], |row| {
-            let reactions_string: String = row.get(12)?;
-            let reactions = serde_json::from_str(&reactions_string).unwrap_or_default();
-
-            Ok(Message {
-                id: row.get(0)?,
-                sender_id: row.get(1)?,
-                message_type: match row.get::<_, String>(2)?.as_str() {
-                    "private" => MessageType::Private,
-                    "server" => MessageType::Server,
-                    _ => MessageType::Room,
-                },
-                room_id: row.get(3)?,
-                receiver_id: row.get(4)?,
-                content: row.get(5)?,
-                sent_at: row.get::<_, String>(6)?.parse::<DateTime<Utc>>().unwrap(),
-                read_at: row.get::<_, Option<String>>(7)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                is_read: row.get(8)?,
-                is_edited: row.get(9)?,
-                edited_at: row.get::<_, Option<String>>(10)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                reply_to_message_id: row.get(11)?,
-                reactions,
-                is_pinned: row.get::<_,i32>(13)? != 0,
-                pinned_at: row.get::<_, Option<String>>(14)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                pinned_by: row.get(15)?,
-            })
-        });
-
-        match message {
-            Ok(msg) => Ok(Some(msg)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e.into()),
-        }
-    }
-
-    pub fn add_reaction(&self, message_id: &str, user_id: &str, username: &str, emoji: &str) -> Result<Vec<ReactionSummary>> {
-        let current_reactions: String = self.conn.query_row(
-            "SELECT COALESCE(reactions, '[]') FROM messages WHERE id = ?1", 
-            params![message_id],
-            |row| row.get(0),
+    fn add_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<Vec<ReactionSummary>> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![message_id, user_id, emoji, Utc::now().to_rfc3339()],
         )?;
+        self.get_reactions(message_id)
+    }
 
-        let mut reactions: Vec<ReactionSummary> = serde_json::from_str(&current_reactions)
-            .map_err(|e| AuthError::InvalidInput(format!("Failed to parse reactions: {}", e)))?;
-
-        if let Some(reaction) = reactions.iter_mut().find(|r| r.emoji == emoji) {
-            if !reaction.user_ids.contains(&user_id.to_string()) {
-                reaction.user_ids.push(user_id.to_string());
-                reaction.usernames.push(username.to_string());
-                reaction.count += 1;
-            } 
-        } else {
-            reactions.push(ReactionSummary { 
-                emoji: emoji.to_string(), 
-                count: 1, 
-                user_ids: vec![user_id.to_string()], 
-                usernames: vec![username.to_string()], 
-            });
-        }
-
-        let reactions_json = serde_json::to_string(&reactions)
-            .map_err(|e| AuthError::InvalidInput(format!("Failed to serialize reactions: {}", e)))?;
+    fn remove_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<Vec<ReactionSummary>> {
         self.conn.execute(
-            "UPDATE messages SET reactions = ?1 WHERE id = ?2",
-            params![reactions_json, message_id]
+            "DELETE FROM message_reactions WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3",
+            params![message_id, user_id, emoji],
         )?;
-
-        Ok(reactions)
+        self.get_reactions(message_id)
     }
 
-    pub fn remove_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<Vec<ReactionSummary>> {
-        let current_reactions: String = self.conn.query_row(
-            "SELET COALESCE(reactions, '[]') FROM messages WHERE id = ?1", 
-            params![message_id], 
-            |row| row.get(0),
+    fn get_reaction_details(&self, message_id: &str) -> Result<Vec<ReactionDetail>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT mr.emoji, mr.user_id, u.username, mr.created_at
+            FROM message_reactions mr
+            JOIN users u ON u.id = mr.user_id
+            WHERE mr.message_id = ?1
+            ORDER BY mr.created_at, mr.rowid"
         )?;
 
-        let mut reactions: Vec<ReactionSummary> = serde_json::from_str(&current_reactions)
-            .map_err(|e| AuthError::InvalidInput(format!("Failed to parse reactions: {}", e)))?;
-
-        for reaction in reactions.iter_mut() {
-            if reaction.emoji == emoji {
-                if let Some(pos) = reaction.user_ids.iter().position(|id| id == user_id) {
-                    reaction.user_ids.remove(pos);
-                    reaction.usernames.remove(pos);
-                    reaction.count = reaction.count.saturating_sub(1);
-                }
-            }
-        } 
-
-        reactions.retain(|r| r.count > 0);
-
-        let reactions_json = serde_json::to_string(&reactions)
-            .map_err(|e| AuthError::InvalidInput(format!("Failed to serialize reactions: {}", e)))?;
-        self.conn.execute(
-            "UPDATE messages SET reactions = ?1 WHERE id = ?2", 
-            params![reactions_json, message_id],
-        )?;
+        let details = stmt.query_map(params![message_id], |row| {
+            Ok(ReactionDetail {
+                emoji: row.get(0)?,
+                user_id: row.get(1)?,
+                username: row.get(2)?,
+                created_at: row.get::<_, String>(3)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
 
-        Ok(reactions)
+        let mut result = Vec::new();
+        for detail in details {
+            result.push(detail?);
+        }
+        Ok(result)
     }
 
-    pub fn pin_message(&self, message_id: &str, user_id: &str) -> Result<()> {
+    fn pin_message(&self, message_id: &str, user_id: &str) -> Result<()> {
         let now = Utc::now().to_rfc3339();
         self.conn.execute(
             "UPDATE messages SET is_pinned = 1, pinned_at = ?1, pinned_by = ?2 WHERE id = ?3", 
@@ -1049,7 +1836,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn unpin_message(&self, message_id: &str) -> Result<()> {
+    fn unpin_message(&self, message_id: &str) -> Result<()> {
         self.conn.execute(
             "UPDATE messages SET is_pinned = 0, pinned_at = NULL, pinned_by = NULL WHERE id = ?1", 
             [message_id],
@@ -1057,19 +1844,16 @@ impl Database {
         Ok(())
     }
 
-    pub fn get_pinned_messages(&self, room_id: &str) -> Result<Vec<Message>> {
+    fn get_pinned_messages(&self, room_id: &str) -> Result<Vec<Message>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, sender_id, message_type, room_id, content, sent_at, is_edited, edited_at,
-                reply_to_message_id, reactions, is_pinned, pinned_at, pinned_by
+                reply_to_message_id, is_pinned, pinned_at, pinned_by, display_name
             FROM messages
             WHERE room_id = ?1 AND is_pinned = 1
             ORDER BY pinned_at DESC"
         )?;
 
         let messages = stmt.query_map(params![room_id], |row| {
-            let reactions_string: String = row.get(9)?;
-            let reactions: Vec<ReactionSummary> = serde_json::from_str(&reactions_string).unwrap_or_default();
-
             Ok(Message {
                 id: row.get(0)?,
                 sender_id: row.get(1)?,
@@ -1087,10 +1871,11 @@ impl Database {
                 is_edited: row.get(6)?,
                 edited_at: row.get::<_, Option<String>>(7)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
                 reply_to_message_id: row.get(8)?,
-                reactions,
-                is_pinned: row.get::<_, i32>(10)? != 0,
-                pinned_at: row.get::<_, Option<String>>(11)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
-                pinned_by: row.get(12)?,
+                reactions: Vec::new(),
+                is_pinned: row.get::<_, i32>(9)? != 0,
+                pinned_at: row.get::<_, Option<String>>(10)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                pinned_by: row.get(11)?,
+                display_name: row.get(12)?,
             })
         })?;
 
@@ -1098,7 +1883,641 @@ impl Database {
         for message in messages {
             result.push(message?);
         }
+        self.attach_reactions(&mut result)?;
+        Ok(result)
+    }
+
+    // Content Filter Methods
+
+    fn get_room_filter(&self, room_id: &str) -> Result<Option<RoomFilter>> {
+        let result = self.conn.query_row(
+            "SELECT blocked_words, action, allowed_link_domains, denied_link_domains FROM room_filters WHERE room_id = ?1",
+            params![room_id],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?)),
+        );
+
+        match result {
+            Ok((blocked_words, action, allowed, denied)) => Ok(Some(RoomFilter {
+                blocked_words: serde_json::from_str(&blocked_words).unwrap_or_default(),
+                action: action.parse().unwrap_or(FilterAction::Reject),
+                allowed_link_domains: serde_json::from_str(&allowed).unwrap_or_default(),
+                denied_link_domains: serde_json::from_str(&denied).unwrap_or_default(),
+            })),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_room_filter(&self, room_id: &str, filter: &RoomFilter) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO room_filters (room_id, blocked_words, action, allowed_link_domains, denied_link_domains)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                room_id,
+                serde_json::to_string(&filter.blocked_words).unwrap(),
+                filter.action.as_str(),
+                serde_json::to_string(&filter.allowed_link_domains).unwrap(),
+                serde_json::to_string(&filter.denied_link_domains).unwrap(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn hold_message(&self, message: &HeldMessage) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO held_messages (id, room_id, sender_id, content, reply_to_message_id, reason, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                message.id,
+                message.room_id,
+                message.sender_id,
+                message.content,
+                message.reply_to_message_id,
+                message.reason,
+                message.created_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_held_message(&self, held_id: &str) -> Result<Option<HeldMessage>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM held_messages WHERE id = ?1", HELD_COLUMNS))?;
+
+        match stmt.query_row(params![held_id], row_to_held_message) {
+            Ok(message) => Ok(Some(message)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_held_messages(&self, room_id: &str, limit: usize, offset: usize) -> Result<Vec<HeldMessage>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM held_messages
+            WHERE room_id = ?1
+            ORDER BY created_at ASC
+            LIMIT ?2 OFFSET ?3",
+            HELD_COLUMNS
+        ))?;
+
+        let messages = stmt.query_map(params![room_id, limit as i64, offset as i64], row_to_held_message)?;
+
+        let mut result = Vec::new();
+        for message in messages {
+            result.push(message?);
+        }
+        Ok(result)
+    }
+
+    fn remove_held_message(&self, held_id: &str) -> Result<bool> {
+        let removed = self.conn.execute("DELETE FROM held_messages WHERE id = ?1", params![held_id])?;
+        Ok(removed > 0)
+    }
+
+    // Report Methods
+
+    fn create_report(&self, report: &MessageReport) -> Result<bool> {
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO message_reports (id, room_id, message_id, sender_id, content, reporter_id, reason, status, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                report.id,
+                report.room_id,
+                report.message_id,
+                report.sender_id,
+                report.content,
+                report.reporter_id,
+                report.reason,
+                report.status.as_str(),
+                report.created_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    fn get_report(&self, report_id: &str) -> Result<Option<MessageReport>> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM message_reports WHERE id = ?1", REPORT_COLUMNS))?;
+
+        match stmt.query_row(params![report_id], row_to_report) {
+            Ok(report) => Ok(Some(report)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_reports(&self, room_id: Option<&str>, status: Option<ReportStatus>, limit: usize, offset: usize) -> Result<Vec<MessageReport>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM message_reports
+            WHERE (?1 IS NULL OR room_id = ?1) AND (?2 IS NULL OR status = ?2)
+            ORDER BY created_at DESC
+            LIMIT ?3 OFFSET ?4",
+            REPORT_COLUMNS
+        ))?;
+
+        let reports = stmt.query_map(
+            params![room_id, status.map(|s| s.as_str()), limit as i64, offset as i64],
+            row_to_report,
+        )?;
+
+        let mut result = Vec::new();
+        for report in reports {
+            result.push(report?);
+        }
+        Ok(result)
+    }
+
+    fn close_report(&self, report: &MessageReport) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE message_reports
+            SET status = ?1, resolved_by = ?2, resolved_at = ?3, action = ?4, resolution_note = ?5
+            WHERE id = ?6 AND status = 'open'",
+            params![
+                report.status.as_str(),
+                report.resolved_by,
+                report.resolved_at.map(|t| t.to_rfc3339()),
+                report.action.map(|a| a.as_str()),
+                report.resolution_note,
+                report.id,
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // Audit Log Methods
+
+    fn append_audit_entry(&self, entry: &AuditEntry) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO audit_log (id, actor_id, action, target_id, room_id, reason, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                entry.id,
+                entry.actor_id,
+                entry.action.as_str(),
+                entry.target_id,
+                entry.room_id,
+                entry.reason,
+                entry.created_at.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_audit_log(&self, filter: &AuditLogFilter, limit: usize, offset: usize) -> Result<Vec<AuditEntry>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM audit_log
+            WHERE (?1 IS NULL OR room_id = ?1)
+                AND (?2 IS NULL OR actor_id = ?2)
+                AND (?3 IS NULL OR target_id = ?3)
+                AND (?4 IS NULL OR action = ?4)
+                AND (?5 IS NULL OR created_at >= ?5)
+                AND (?6 IS NULL OR created_at < ?6)
+            ORDER BY created_at DESC, rowid DESC
+            LIMIT ?7 OFFSET ?8",
+            AUDIT_COLUMNS
+        ))?;
+
+        let entries = stmt.query_map(
+            params![
+                filter.room_id,
+                filter.actor_id,
+                filter.target_id,
+                filter.action.map(|a| a.as_str()),
+                filter.since.map(|t| t.to_rfc3339()),
+                filter.until.map(|t| t.to_rfc3339()),
+                limit as i64,
+                offset as i64,
+            ],
+            row_to_audit_entry,
+        )?;
+
+        let mut result = Vec::new();
+        for entry in entries {
+            result.push(entry?);
+        }
+        Ok(result)
+    }
+
+    // Invites
+
+    fn create_invite_code(&self, code: &str, created_by: &str, max_uses: Option<u32>, expires_at: Option<DateTime<Utc>>) -> Result<InviteCode> {
+        let now = Utc::now();
+
+        self.conn.execute(
+            "INSERT INTO invite_codes (code, created_by, created_at, expires_at, max_uses) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![code, created_by, now.to_rfc3339(), expires_at.map(|e| e.to_rfc3339()), max_uses],
+        )?;
+
+        Ok(InviteCode {
+            code: code.to_string(),
+            created_by: created_by.to_string(),
+            created_at: now,
+            expires_at,
+            max_uses,
+            uses: 0,
+            revoked: false,
+            used_by: Vec::new(),
+        })
+    }
+
+    fn get_invite_codes(&self) -> Result<Vec<InviteCode>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT code, created_by, created_at, expires_at, max_uses, uses, revoked
+            FROM invite_codes ORDER BY created_at DESC"
+        )?;
+
+        let codes = stmt.query_map([], |row| {
+            Ok(InviteCode {
+                code: row.get(0)?,
+                created_by: row.get(1)?,
+                created_at: row.get::<_, String>(2)?.parse::<DateTime<Utc>>().unwrap(),
+                expires_at: row.get::<_, Option<String>>(3)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+                max_uses: row.get(4)?,
+                uses: row.get(5)?,
+                revoked: row.get::<_, i32>(6)? != 0,
+                used_by: Vec::new(),
+            })
+        })?;
+
+        let mut result = Vec::new();
+        for code in codes {
+            let mut code = code?;
+            let mut stmt = self.conn.prepare("SELECT username FROM users WHERE invite_code = ?1 ORDER BY created_at")?;
+            let usernames = stmt.query_map(params![code.code], |row| row.get::<_, String>(0))?;
+            for username in usernames {
+                code.used_by.push(username?);
+            }
+            result.push(code);
+        }
         Ok(result)
     }
 
-}
\ No newline at end of file
+    fn redeem_invite_code(&self, code: &str) -> Result<bool> {
+        let now = Utc::now();
+        let updated = self.conn.execute(
+            "UPDATE invite_codes SET uses = uses + 1
+            WHERE code = ?1 AND revoked = 0
+                AND (expires_at IS NULL OR expires_at > ?2)
+                AND (max_uses IS NULL OR uses < max_uses)",
+            params![code, now.to_rfc3339()],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn revoke_invite_code(&self, code: &str) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE invite_codes SET revoked = 1 WHERE code = ?1",
+            params![code],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // API tokens
+
+    fn create_api_token(&self, user_id: &str, name: &str, token_hash: &str, scopes: &[ApiScope]) -> Result<ApiToken> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let scope_list = scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(",");
+
+        self.conn.execute(
+            "INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![id, user_id, name, token_hash, scope_list, now.to_rfc3339()],
+        )?;
+
+        Ok(ApiToken {
+            id,
+            user_id: user_id.to_string(),
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+            created_at: now,
+            last_used_at: None,
+            revoked: false,
+        })
+    }
+
+    fn get_api_token(&self, id: &str) -> Result<Option<ApiToken>> {
+        Ok(self.query_api_tokens("id = ?1", id)?.into_iter().next())
+    }
+
+    fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        Ok(self.query_api_tokens("token_hash = ?1", token_hash)?.into_iter().next())
+    }
+
+    fn get_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>> {
+        self.query_api_tokens("user_id = ?1", user_id)
+    }
+
+    fn touch_api_token(&self, id: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE api_tokens SET last_used_at = ?1 WHERE id = ?2",
+            params![Utc::now().to_rfc3339(), id],
+        )?;
+        Ok(())
+    }
+
+    fn revoke_api_token(&self, id: &str) -> Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE api_tokens SET revoked = 1 WHERE id = ?1",
+            params![id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // Account emails
+
+    fn create_email_verification(&self, user_id: &str, token: &str, expires_at: DateTime<Utc>) -> Result<EmailVerification> {
+        let now = Utc::now();
+
+        self.conn.execute(
+            "INSERT INTO email_verifications (token, user_id, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![token, user_id, now.to_rfc3339(), expires_at.to_rfc3339()],
+        )?;
+
+        Ok(EmailVerification {
+            token: token.to_string(),
+            user_id: user_id.to_string(),
+            created_at: now,
+            expires_at,
+            used_at: None,
+        })
+    }
+
+    fn get_email_verification(&self, token: &str) -> Result<Option<EmailVerification>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT token, user_id, created_at, expires_at, used_at FROM email_verifications WHERE token = ?1"
+        )?;
+
+        let verification = stmt.query_row(params![token], |row| {
+            Ok(EmailVerification {
+                token: row.get(0)?,
+                user_id: row.get(1)?,
+                created_at: row.get::<_, String>(2)?.parse::<DateTime<Utc>>().unwrap(),
+                expires_at: row.get::<_, String>(3)?.parse::<DateTime<Utc>>().unwrap(),
+                used_at: row.get::<_, Option<String>>(4)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+            })
+        });
+
+        match verification {
+            Ok(v) => Ok(Some(v)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn consume_email_verification(&self, token: &str) -> Result<bool> {
+        let now = Utc::now();
+        let updated = self.conn.execute(
+            "UPDATE email_verifications SET used_at = ?1 WHERE token = ?2 AND used_at IS NULL",
+            params![now.to_rfc3339(), token],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn delete_expired_email_verifications(&self) -> Result<()> {
+        let now = Utc::now();
+        self.conn.execute(
+            "DELETE FROM email_verifications WHERE expires_at < ?1",
+            params![now.to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+
+    fn create_password_reset(&self, user_id: &str, token: &str, expires_at: DateTime<Utc>) -> Result<PasswordReset> {
+        let now = Utc::now();
+
+        self.conn.execute(
+            "INSERT INTO password_resets (token, user_id, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![token, user_id, now.to_rfc3339(), expires_at.to_rfc3339()],
+        )?;
+
+        Ok(PasswordReset {
+            token: token.to_string(),
+            user_id: user_id.to_string(),
+            created_at: now,
+            expires_at,
+            used_at: None,
+        })
+    }
+
+    fn get_password_reset(&self, token: &str) -> Result<Option<PasswordReset>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT token, user_id, created_at, expires_at, used_at FROM password_resets WHERE token = ?1"
+        )?;
+
+        let reset = stmt.query_row(params![token], |row| {
+            Ok(PasswordReset {
+                token: row.get(0)?,
+                user_id: row.get(1)?,
+                created_at: row.get::<_, String>(2)?.parse::<DateTime<Utc>>().unwrap(),
+                expires_at: row.get::<_, String>(3)?.parse::<DateTime<Utc>>().unwrap(),
+                used_at: row.get::<_, Option<String>>(4)?.and_then(|s| s.parse::<DateTime<Utc>>().ok()),
+            })
+        });
+
+        match reset {
+            Ok(r) => Ok(Some(r)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn consume_password_reset(&self, token: &str) -> Result<bool> {
+        let now = Utc::now();
+        let updated = self.conn.execute(
+            "UPDATE password_resets SET used_at = ?1 WHERE token = ?2 AND used_at IS NULL",
+            params![now.to_rfc3339(), token],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn delete_expired_password_resets(&self) -> Result<()> {
+        let now = Utc::now();
+        self.conn.execute(
+            "DELETE FROM password_resets WHERE expires_at < ?1",
+            params![now.to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    // Webhooks
+
+    fn create_webhook(&self, room_id: &str, url: &str, secret: &str, events: &[String], created_by: &str) -> Result<Webhook> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+
+        self.conn.execute(
+            "INSERT INTO webhooks (id, room_id, url, secret, events, created_by, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![id, room_id, url, secret, events.join(","), created_by, now.to_rfc3339()],
+        )?;
+
+        Ok(Webhook {
+            id,
+            room_id: room_id.to_string(),
+            url: url.to_string(),
+            secret: secret.to_string(),
+            events: events.to_vec(),
+            created_by: created_by.to_string(),
+            created_at: now,
+        })
+    }
+
+    fn get_webhook(&self, id: &str) -> Result<Option<Webhook>> {
+        Ok(self.query_webhooks("id = ?1", id)?.into_iter().next())
+    }
+
+    fn get_room_webhooks(&self, room_id: &str) -> Result<Vec<Webhook>> {
+        self.query_webhooks("room_id = ?1", room_id)
+    }
+
+    fn delete_webhook(&self, id: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM webhooks WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    fn create_webhook_dead_letter(&self, webhook_id: &str, event: &str, payload: &str, attempts: u32, last_error: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO webhook_dead_letters (id, webhook_id, event, payload, attempts, last_error, failed_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![Uuid::new_v4().to_string(), webhook_id, event, payload, attempts, last_error, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    fn get_webhook_dead_letters(&self, room_id: &str) -> Result<Vec<WebhookDeadLetter>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT d.id, d.webhook_id, d.event, d.payload, d.attempts, d.last_error, d.failed_at
+            FROM webhook_dead_letters d
+            JOIN webhooks w ON d.webhook_id = w.id
+            WHERE w.room_id = ?1
+            ORDER BY d.failed_at DESC"
+        )?;
+
+        let dead_letters = stmt.query_map(params![room_id], |row| {
+            Ok(WebhookDeadLetter {
+                id: row.get(0)?,
+                webhook_id: row.get(1)?,
+                event: row.get(2)?,
+                payload: row.get(3)?,
+                attempts: row.get(4)?,
+                last_error: row.get(5)?,
+                failed_at: row.get::<_, String>(6)?.parse::<DateTime<Utc>>().unwrap(),
+            })
+        })?;
+
+        let mut result = Vec::new();
+        for dead_letter in dead_letters {
+            result.push(dead_letter?);
+        }
+        Ok(result)
+    }
+
+    fn create_incoming_webhook(&self, room_id: &str, name: &str, token_hash: &str, bot_user_id: &str, created_by: &str) -> Result<IncomingWebhook> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+
+        self.conn.execute(
+            "INSERT INTO incoming_webhooks (id, room_id, name, token_hash, bot_user_id, created_by, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![id, room_id, name, token_hash, bot_user_id, created_by, now.to_rfc3339()],
+        )?;
+
+        Ok(IncomingWebhook {
+            id,
+            room_id: room_id.to_string(),
+            name: name.to_string(),
+            bot_user_id: bot_user_id.to_string(),
+            created_by: created_by.to_string(),
+            created_at: now,
+        })
+    }
+
+    fn get_incoming_webhook(&self, id: &str) -> Result<Option<IncomingWebhook>> {
+        Ok(self.query_incoming_webhooks("id = ?1", id)?.into_iter().next())
+    }
+
+    fn get_incoming_webhook_by_token_hash(&self, token_hash: &str) -> Result<Option<IncomingWebhook>> {
+        Ok(self.query_incoming_webhooks("token_hash = ?1", token_hash)?.into_iter().next())
+    }
+
+    fn get_room_incoming_webhooks(&self, room_id: &str) -> Result<Vec<IncomingWebhook>> {
+        self.query_incoming_webhooks("room_id = ?1", room_id)
+    }
+
+    fn delete_incoming_webhook(&self, id: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM incoming_webhooks WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        f()?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.conn.query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrates_legacy_reactions_column() {
+        let db = Database::in_memory().unwrap();
+        let ann = db.create_user("ann", "ann@test.com", "hash").unwrap();
+        let bob = db.create_user("bob", "bob@test.com", "hash").unwrap();
+        let room = db.create_room("legacy", "", &ann.id).unwrap();
+        let message = db.create_room_message(&ann.id, &room.id, "old", None).unwrap();
+
+        let legacy = serde_json::json!([
+            {"emoji": "👍", "count": 2, "user_ids": [ann.id, bob.id], "usernames": ["ann", "bob"]},
+            {"emoji": "🎉", "count": 1, "user_ids": [bob.id], "usernames": ["bob"]},
+        ]);
+        db.conn.execute("ALTER TABLE messages ADD COLUMN reactions TEXT DEFAULT '[]'", []).unwrap();
+        db.conn.execute("UPDATE messages SET reactions = ?1 WHERE id = ?2", params![legacy.to_string(), message.id]).unwrap();
+
+        db.migrate_reactions_column().unwrap();
+        assert!(!db.has_column("messages", "reactions").unwrap());
+
+        let reactions = db.get_reactions(&message.id).unwrap();
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions[0].emoji, "👍");
+        assert_eq!(reactions[0].usernames, vec!["ann", "bob"]);
+        assert_eq!(reactions[1].user_ids, vec![bob.id.clone()]);
+
+        // Already migrated databases are left alone
+        db.migrate_reactions_column().unwrap();
+        assert_eq!(db.get_reaction_details(&message.id).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_migrates_plaintext_incoming_webhook_tokens() {
+        let db = Database::in_memory().unwrap();
+        let ann = db.create_user("ann", "ann@test.com", "hash").unwrap();
+        let room = db.create_room("legacy", "", &ann.id).unwrap();
+        let hook = db.create_incoming_webhook(&room.id, "CI", "plain-token", &ann.id, &ann.id).unwrap();
+        db.conn.execute("ALTER TABLE incoming_webhooks RENAME COLUMN token_hash TO token", []).unwrap();
+
+        db.migrate_incoming_webhook_tokens().unwrap();
+        assert!(!db.has_column("incoming_webhooks", "token").unwrap());
+
+        let found = db.get_incoming_webhook_by_token_hash(&crate::webhooks::hash_token("plain-token")).unwrap().unwrap();
+        assert_eq!(found.id, hook.id);
+        assert!(db.get_incoming_webhook_by_token_hash("plain-token").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_audit_log_is_append_only() {
+        let db = Database::in_memory().unwrap();
+        db.append_audit_entry(&AuditEntry::new("admin", AuditAction::RoomDeleted).with_room("gone")).unwrap();
+
+        assert!(db.conn.execute("UPDATE audit_log SET reason = 'oops'", []).is_err());
+        assert!(db.conn.execute("DELETE FROM audit_log", []).is_err());
+        assert_eq!(db.get_audit_log(&AuditLogFilter::default(), 10, 0).unwrap().len(), 1);
+    }
+}
@@ -5,6 +5,9 @@ pub enum AuthError {
     #[error("Database Error: {0}")]
     Database(#[from] rusqlite::Error),
 
+    #[error("Database Error: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+
     #[error("Password hashing error: {0}")]
     PasswordHash(String),
 
@@ -22,6 +25,71 @@ pub enum AuthError {
 
     #[error("Invalid input: {0}")]
     InvalidInput(String),
+
+    #[error("Token is invalid, expired or already used")]
+    InvalidToken,
+
+    #[error("Email address has not been verified")]
+    EmailNotVerified,
+
+    #[error("Registration is currently closed")]
+    RegistrationClosed,
+
+    #[error("Invite code is invalid, expired or fully used")]
+    InvalidInviteCode,
+
+    #[error("Account is awaiting administrator approval")]
+    PendingApproval,
+
+    #[error("Account has been deactivated")]
+    AccountDeactivated,
+
+    #[error("Permission denied")]
+    PermissionDenied,
+
+    #[error("Permission denied: {0}")]
+    Forbidden(String),
+
+    #[error("Message rejected: {0}")]
+    ContentRejected(String),
+
+    /// Not a failure as such: the message was stored for a moderator to
+    /// approve instead of being posted.
+    #[error("Message held for moderator review: {reason}")]
+    MessageHeld { held_id: String, reason: String },
+
+    #[error("Authentication provider error: {0}")]
+    Provider(String),
+
+    #[error("Mail delivery error: {0}")]
+    Mail(String),
+}
+
+impl AuthError {
+    /// Stable snake_case name of the variant, used as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuthError::Database(_) | AuthError::Postgres(_) => "database",
+            AuthError::PasswordHash(_) => "password_hash",
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::UserExists => "user_exists",
+            AuthError::UserNotFound => "user_not_found",
+            AuthError::InvalidSession => "invalid_session",
+            AuthError::InvalidInput(_) => "invalid_input",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::EmailNotVerified => "email_not_verified",
+            AuthError::RegistrationClosed => "registration_closed",
+            AuthError::InvalidInviteCode => "invalid_invite_code",
+            AuthError::PendingApproval => "pending_approval",
+            AuthError::AccountDeactivated => "account_deactivated",
+            AuthError::PermissionDenied => "permission_denied",
+            AuthError::Forbidden(_) => "forbidden",
+            AuthError::ContentRejected(_) => "content_rejected",
+            AuthError::MessageHeld { .. } => "message_held",
+            AuthError::Provider(_) => "provider",
+            AuthError::Mail(_) => "mail",
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, AuthError>;
\ No newline at end of file
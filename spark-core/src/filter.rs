@@ -0,0 +1,389 @@
+use crate::{
+    config::FilterConfig,
+    error::{AuthError, Result},
+};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+/// What happens to a message that trips a filter. When several filters
+/// match, reject wins over hold, and hold over mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    /// Refuse the message and tell the sender why
+    Reject,
+    /// Replace the matched text with asterisks and send the rest
+    Mask,
+    /// Keep the message back until a room moderator approves it
+    Hold,
+}
+
+impl FilterAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterAction::Reject => "reject",
+            FilterAction::Mask => "mask",
+            FilterAction::Hold => "hold",
+        }
+    }
+}
+
+impl FromStr for FilterAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "mask" => Ok(Self::Mask),
+            "hold" => Ok(Self::Hold),
+            other => Err(format!("Unknown filter action: {}", other)),
+        }
+    }
+}
+
+/// Text a filter objected to, as a byte range of the message.
+#[derive(Debug, Clone)]
+pub struct FilterMatch {
+    pub action: FilterAction,
+    /// Shown to the sender, so it shouldn't repeat the blocked text
+    pub reason: String,
+    pub span: Range<usize>,
+}
+
+/// One stage of the pipeline. Implement this to plug in other checks.
+pub trait ContentFilter: Send + Sync {
+    fn check(&self, content: &str) -> Vec<FilterMatch>;
+}
+
+/// Blocks whole words, case-insensitively, and `/regex/` patterns, which
+/// are used as written.
+pub struct WordFilter {
+    patterns: Vec<Regex>,
+    action: FilterAction,
+}
+
+impl WordFilter {
+    pub fn new(entries: &[String], action: FilterAction) -> Result<Self> {
+        let mut patterns = Vec::new();
+        for entry in entries.iter().map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let pattern = match entry.strip_prefix('/').and_then(|e| e.strip_suffix('/')) {
+                Some(pattern) if !pattern.is_empty() => pattern.to_string(),
+                _ => format!(r"(?i)\b{}\b", regex::escape(entry)),
+            };
+            let regex = Regex::new(&pattern)
+                .map_err(|e| AuthError::InvalidInput(format!("Invalid filter pattern {}: {}", entry, e)))?;
+            patterns.push(regex);
+        }
+        Ok(Self { patterns, action })
+    }
+}
+
+impl ContentFilter for WordFilter {
+    fn check(&self, content: &str) -> Vec<FilterMatch> {
+        self.patterns
+            .iter()
+            .flat_map(|pattern| pattern.find_iter(content))
+            .filter(|m| !m.is_empty())
+            .map(|m| FilterMatch {
+                action: self.action,
+                reason: "contains a blocked word".to_string(),
+                span: m.range(),
+            })
+            .collect()
+    }
+}
+
+/// Checks the host of every `http(s)://` or `www.` link. A domain entry
+/// also covers its subdomains.
+pub struct LinkFilter {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+    action: FilterAction,
+    links: Regex,
+}
+
+impl LinkFilter {
+    /// With a non-empty allow list, links to anywhere else are filtered too.
+    pub fn new(allowed: &[String], denied: &[String], action: FilterAction) -> Self {
+        let normalize = |domains: &[String]| -> Vec<String> {
+            domains
+                .iter()
+                .map(|d| d.trim().trim_start_matches("*.").trim_end_matches('.').to_lowercase())
+                .filter(|d| !d.is_empty())
+                .collect()
+        };
+        Self {
+            allowed: normalize(allowed),
+            denied: normalize(denied),
+            action,
+            links: Regex::new(r#"(?i)\b(?:https?://|www\.)[^\s<>"']+"#).unwrap(),
+        }
+    }
+
+    fn covers(domains: &[String], host: &str) -> bool {
+        domains.iter().any(|d| host == d || host.ends_with(&format!(".{}", d)))
+    }
+}
+
+impl ContentFilter for LinkFilter {
+    fn check(&self, content: &str) -> Vec<FilterMatch> {
+        let mut matches = Vec::new();
+        for link in self.links.find_iter(content) {
+            let text = link.as_str();
+            let url = if text.to_lowercase().starts_with("www.") {
+                format!("http://{}", text)
+            } else {
+                text.to_string()
+            };
+            let Some(host) = url::Url::parse(&url).ok().and_then(|u| u.host_str().map(str::to_lowercase)) else {
+                continue;
+            };
+
+            let blocked = Self::covers(&self.denied, &host)
+                || (!self.allowed.is_empty() && !Self::covers(&self.allowed, &host));
+            if blocked {
+                matches.push(FilterMatch {
+                    action: self.action,
+                    reason: format!("links to {} are not allowed", host),
+                    span: link.range(),
+                });
+            }
+        }
+        matches
+    }
+}
+
+/// The result of running a message through the pipeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterOutcome {
+    /// The content to store, masked if any filter asked for it
+    Pass(String),
+    Hold(String),
+    Reject(String),
+}
+
+/// Runs every filter over a message and settles on one outcome.
+#[derive(Default)]
+pub struct FilterPipeline {
+    filters: Vec<Box<dyn ContentFilter>>,
+}
+
+impl FilterPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The server-wide word and link filters.
+    pub fn from_config(config: &FilterConfig) -> Result<Self> {
+        let mut pipeline = Self::new();
+        if !config.blocked_words.is_empty() {
+            pipeline = pipeline.with_filter(WordFilter::new(&config.blocked_words, config.word_action)?);
+        }
+        if !config.allowed_link_domains.is_empty() || !config.denied_link_domains.is_empty() {
+            pipeline = pipeline.with_filter(LinkFilter::new(
+                &config.allowed_link_domains,
+                &config.denied_link_domains,
+                config.link_action,
+            ));
+        }
+        Ok(pipeline)
+    }
+
+    pub fn with_filter(mut self, filter: impl ContentFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// `extra` holds per-room filters, run after the server's own.
+    pub fn apply(&self, content: &str, extra: &[&dyn ContentFilter]) -> FilterOutcome {
+        let matches: Vec<FilterMatch> = self
+            .filters
+            .iter()
+            .map(|f| f.as_ref())
+            .chain(extra.iter().copied())
+            .flat_map(|f| f.check(content))
+            .collect();
+
+        for action in [FilterAction::Reject, FilterAction::Hold] {
+            if let Some(m) = matches.iter().find(|m| m.action == action) {
+                return match action {
+                    FilterAction::Reject => FilterOutcome::Reject(m.reason.clone()),
+                    _ => FilterOutcome::Hold(m.reason.clone()),
+                };
+            }
+        }
+
+        let masked = content
+            .char_indices()
+            .map(|(i, c)| if matches.iter().any(|m| m.span.contains(&i)) { '*' } else { c })
+            .collect();
+        FilterOutcome::Pass(masked)
+    }
+}
+
+/// A room's own blocked words and links, checked after the server-wide
+/// filters. `action` applies to both.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct RoomFilter {
+    /// Whole words, matched case-insensitively, or `/regex/` patterns
+    pub blocked_words: Vec<String>,
+    pub action: FilterAction,
+    /// When set, links to any other domain are filtered
+    #[serde(default)]
+    pub allowed_link_domains: Vec<String>,
+    #[serde(default)]
+    pub denied_link_domains: Vec<String>,
+}
+
+impl Default for RoomFilter {
+    fn default() -> Self {
+        Self {
+            blocked_words: Vec::new(),
+            action: FilterAction::Reject,
+            allowed_link_domains: Vec::new(),
+            denied_link_domains: Vec::new(),
+        }
+    }
+}
+
+impl RoomFilter {
+    /// Builds the filters to pass to `FilterPipeline::apply`.
+    pub fn compile(&self) -> Result<Vec<Box<dyn ContentFilter>>> {
+        let mut filters: Vec<Box<dyn ContentFilter>> = Vec::new();
+        if !self.blocked_words.is_empty() {
+            filters.push(Box::new(WordFilter::new(&self.blocked_words, self.action)?));
+        }
+        if !self.allowed_link_domains.is_empty() || !self.denied_link_domains.is_empty() {
+            filters.push(Box::new(LinkFilter::new(&self.allowed_link_domains, &self.denied_link_domains, self.action)));
+        }
+        Ok(filters)
+    }
+}
+
+/// A room's filters, ready for `FilterPipeline::apply`.
+pub type CompiledRoomFilter = Arc<Vec<Box<dyn ContentFilter>>>;
+
+/// Compiled room filters, kept until the room's stored filter changes so
+/// patterns aren't recompiled on every message.
+#[derive(Default)]
+pub struct RoomFilterCache {
+    rooms: Mutex<HashMap<String, (RoomFilter, CompiledRoomFilter)>>,
+}
+
+impl RoomFilterCache {
+    /// The compiled form of `filter`, reused if the room's filter is unchanged.
+    pub fn get(&self, room_id: &str, filter: &RoomFilter) -> Result<CompiledRoomFilter> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some((cached, compiled)) = rooms.get(room_id) {
+            if cached == filter {
+                return Ok(Arc::clone(compiled));
+            }
+        }
+
+        let compiled = Arc::new(filter.compile()?);
+        rooms.insert(room_id.to_string(), (filter.clone(), Arc::clone(&compiled)));
+        Ok(compiled)
+    }
+
+    pub fn invalidate(&self, room_id: &str) {
+        self.rooms.lock().unwrap().remove(room_id);
+    }
+}
+
+/// A room message a filter kept back for a moderator to approve or reject.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct HeldMessage {
+    pub id: String,
+    pub room_id: String,
+    pub sender_id: String,
+    pub content: String,
+    pub reply_to_message_id: Option<String>,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(entries: &[&str], action: FilterAction) -> WordFilter {
+        WordFilter::new(&entries.iter().map(|e| e.to_string()).collect::<Vec<_>>(), action).unwrap()
+    }
+
+    fn domains(list: &[&str]) -> Vec<String> {
+        list.iter().map(|d| d.to_string()).collect()
+    }
+
+    #[test]
+    fn test_reject_action() {
+        let pipeline = FilterPipeline::new().with_filter(words(&["darn"], FilterAction::Reject));
+        assert_eq!(pipeline.apply("well DARN it", &[]), FilterOutcome::Reject("contains a blocked word".to_string()));
+        // Whole words only
+        assert_eq!(pipeline.apply("darnedest thing", &[]), FilterOutcome::Pass("darnedest thing".to_string()));
+    }
+
+    #[test]
+    fn test_mask_action() {
+        let pipeline = FilterPipeline::new().with_filter(words(&["heck", r"/fr[e3]{2}\s+money/"], FilterAction::Mask));
+        assert_eq!(pipeline.apply("what the heck, fr33  money!", &[]), FilterOutcome::Pass("what the ****, ***********!".to_string()));
+        assert_eq!(pipeline.apply("héck heck", &[]), FilterOutcome::Pass("héck ****".to_string()));
+    }
+
+    #[test]
+    fn test_hold_action_and_precedence() {
+        let pipeline = FilterPipeline::new()
+            .with_filter(words(&["spoiler"], FilterAction::Hold))
+            .with_filter(words(&["heck"], FilterAction::Mask));
+        assert!(matches!(pipeline.apply("spoiler: heck", &[]), FilterOutcome::Hold(_)));
+
+        let room = words(&["banned"], FilterAction::Reject);
+        assert!(matches!(pipeline.apply("spoiler: banned", &[&room]), FilterOutcome::Reject(_)));
+        assert_eq!(pipeline.apply("heck", &[&room]), FilterOutcome::Pass("****".to_string()));
+    }
+
+    #[test]
+    fn test_link_deny_and_allow_lists() {
+        let deny = LinkFilter::new(&[], &domains(&["evil.example"]), FilterAction::Reject);
+        let pipeline = FilterPipeline::new().with_filter(deny);
+        assert_eq!(
+            pipeline.apply("see https://cdn.Evil.example/x", &[]),
+            FilterOutcome::Reject("links to cdn.evil.example are not allowed".to_string())
+        );
+        assert!(matches!(pipeline.apply("www.evil.example", &[]), FilterOutcome::Reject(_)));
+        assert!(matches!(pipeline.apply("https://notevil.example", &[]), FilterOutcome::Pass(_)));
+
+        let allow = LinkFilter::new(&domains(&["docs.rs"]), &[], FilterAction::Mask);
+        let pipeline = FilterPipeline::new().with_filter(allow);
+        assert_eq!(pipeline.apply("https://docs.rs/regex ok", &[]), FilterOutcome::Pass("https://docs.rs/regex ok".to_string()));
+        assert_eq!(pipeline.apply("http://x.io/a b", &[]), FilterOutcome::Pass("************* b".to_string()));
+    }
+
+    #[test]
+    fn test_room_filters_are_compiled_once_per_change() {
+        let cache = RoomFilterCache::default();
+        let filter = RoomFilter { blocked_words: vec!["darn".to_string()], ..RoomFilter::default() };
+
+        let first = cache.get("room", &filter).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get("room", &filter).unwrap()));
+
+        // A filter changed elsewhere, e.g. on another node, is recompiled
+        let changed = RoomFilter { denied_link_domains: domains(&["evil.example"]), ..filter.clone() };
+        let second = cache.get("room", &changed).unwrap();
+        assert_eq!(second.len(), 2);
+        assert!(!Arc::ptr_eq(&first, &second));
+
+        cache.invalidate("room");
+        assert!(!Arc::ptr_eq(&second, &cache.get("room", &changed).unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_pattern_is_an_error() {
+        assert!(WordFilter::new(&["/(unclosed/".to_string()], FilterAction::Reject).is_err());
+    }
+}
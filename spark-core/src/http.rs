@@ -0,0 +1,544 @@
+use crate::{
+    api::{self, ApiError},
+    error::AuthError,
+    metrics::{metrics, Metrics},
+    network::{AuthService, MessageService},
+    shutdown::Shutdown,
+    webhooks::{IncomingWebhookPayload, WebhookService},
+    websocket::{ConnectionManager, WebSocketServer},
+};
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Path, Request, State},
+    middleware::{self, Next},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use hyper_util::rt::TokioIo;
+use tracing::{debug, info, info_span, warn, Instrument};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, RwLock};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role},
+    WebSocketStream,
+};
+
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub(crate) auth: Arc<Mutex<AuthService>>,
+    pub(crate) message_service: Arc<Mutex<MessageService>>,
+    pub(crate) connections: Arc<RwLock<ConnectionManager>>,
+    pub(crate) webhooks: Arc<Mutex<WebhookService>>,
+    websocket: Option<WebSocketServer>,
+    /// Listener readiness and what `/metrics` serves
+    metrics: &'static Metrics,
+}
+
+/// HTTP listener for the REST API, health checks and incoming webhooks.
+/// With `with_websocket` it also upgrades `/ws` to the chat protocol, so a
+/// single port is enough behind a reverse proxy.
+pub struct HttpServer {
+    state: AppState,
+    addr: String,
+    shutdown: Shutdown,
+}
+
+impl HttpServer {
+    pub fn new(
+        auth: Arc<Mutex<AuthService>>,
+        message_service: Arc<Mutex<MessageService>>,
+        connections: Arc<RwLock<ConnectionManager>>,
+        webhooks: Arc<Mutex<WebhookService>>,
+        addr: String,
+    ) -> Self {
+        Self {
+            state: AppState { auth, message_service, connections, webhooks, websocket: None, metrics: metrics() },
+            addr,
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    pub fn with_websocket(mut self, websocket: WebSocketServer) -> Self {
+        self.state.websocket = Some(websocket);
+        self
+    }
+
+    /// Tracks readiness in `metrics` instead of the process-wide registry.
+    #[cfg(test)]
+    pub(crate) fn with_metrics(mut self, metrics: &'static Metrics) -> Self {
+        self.state.metrics = metrics;
+        self
+    }
+
+    /// Stops accepting connections once `shutdown` is triggered and lets
+    /// in-flight requests finish.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn router(&self) -> Router {
+        api::routes()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .route("/metrics", get(prometheus_metrics))
+            .route("/ws", get(websocket_upgrade))
+            .route("/hooks/{token}", post(incoming_webhook))
+            .layer(middleware::from_fn(trace_request))
+            .with_state(self.state.clone())
+    }
+
+    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        info!(addr = %self.addr, "HTTP server listening");
+        self.state.metrics.listener_up("http");
+
+        let shutdown = self.shutdown.clone();
+        axum::serve(listener, self.router())
+            .with_graceful_shutdown(async move { shutdown.wait().await })
+            .await?;
+        Ok(())
+    }
+}
+
+/// Wraps each request in a span. The matched route template is logged
+/// rather than the URI, so tokens in paths like `/hooks/{token}` stay out of
+/// the logs.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let span = info_span!("http_request", method = %request.method(), route = %route);
+
+    async move {
+        let response = next.run(request).await;
+        debug!(status = response.status().as_u16(), "request handled");
+        response
+    }.instrument(span).await
+}
+
+/// Liveness: the process is up and serving HTTP.
+async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: the database answers and every configured listener has bound.
+async fn readyz(State(state): State<AppState>) -> Response {
+    let database = state.message_service.lock().await.ping();
+    let pending_listeners = state.metrics.pending_listeners();
+    let ready = database.is_ok() && pending_listeners.is_empty();
+
+    let body = serde_json::json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "database": match database {
+            Ok(()) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        },
+        "pending_listeners": pending_listeners,
+    });
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(body)).into_response()
+}
+
+async fn prometheus_metrics(State(state): State<AppState>) -> Response {
+    let body = state.metrics.render(&*state.connections.read().await);
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+/// Completes the WebSocket handshake and hands the upgraded connection to
+/// the chat server once hyper releases it.
+async fn websocket_upgrade(State(state): State<AppState>, request: Request) -> Response {
+    let Some(websocket) = state.websocket.clone() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let headers = request.headers();
+    let is_upgrade = headers.get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let is_version_13 = headers.get(header::SEC_WEBSOCKET_VERSION).is_some_and(|v| v == "13");
+    let key = match headers.get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_upgrade && is_version_13 => key,
+        _ => return (StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade").into_response(),
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    let on_upgrade = hyper::upgrade::on(request);
+    tokio::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!(error = %e, "WebSocket upgrade failed");
+                return;
+            }
+        };
+
+        let ws_stream = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+        if let Err(e) = websocket.serve(ws_stream).await {
+            warn!(error = %e, "WebSocket connection failed");
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Posts a message into the webhook's room. The token in the path is the
+/// only credential, so an unknown token is reported as not found.
+#[utoipa::path(post, path = "/hooks/{token}", tag = "webhooks",
+    params(("token" = String, Path)), request_body = IncomingWebhookPayload,
+    responses((status = 200, description = "Message posted"), (status = 404)))]
+pub(crate) async fn incoming_webhook(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(payload): Json<IncomingWebhookPayload>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let webhook = state.webhooks.lock().await
+        .find_incoming_webhook(&token)
+        .map_err(|e| match e {
+            AuthError::InvalidToken => ApiError::not_found("Unknown webhook"),
+            e => e.into(),
+        })?;
+
+    let content = payload.render()?;
+    let (message, audience) = state.message_service.lock().await
+        .send_webhook_message(&webhook.bot_user_id, &webhook.room_id, content, payload.username)?;
+
+    state.connections.read().await.broadcast_new_message(&message, &audience);
+    Ok(Json(serde_json::json!({ "message_id": message.id })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OutboundQueueConfig;
+    use crate::outbound;
+    use crate::messages::Room;
+    use crate::storage::Storage;
+    use crate::users::{AuthResponse, CreateUserRequest, Presence};
+    use crate::Database;
+    use futures_util::{SinkExt, StreamExt};
+    use std::net::SocketAddr;
+    use tokio_tungstenite::tungstenite::Message;
+
+    type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// A running server, as returned by `spawn_with_room`.
+    struct TestServer {
+        addr: SocketAddr,
+        base: String,
+        auth: Arc<Mutex<AuthService>>,
+        connections: Arc<RwLock<ConnectionManager>>,
+        webhooks: Arc<Mutex<WebhookService>>,
+        owner: AuthResponse,
+        room: Room,
+        db_path: std::path::PathBuf,
+    }
+
+    /// Serves the API and websocket on a fresh database, with a registered
+    /// owner who has created and joined a room. `configure` adjusts the
+    /// websocket server before it starts.
+    async fn spawn_with_room(
+        metrics: &'static Metrics,
+        configure: impl FnOnce(WebSocketServer) -> WebSocketServer,
+    ) -> TestServer {
+        let db_path = std::env::temp_dir().join(format!("spark-http-{}.db", uuid::Uuid::new_v4()));
+        let auth = Arc::new(Mutex::new(AuthService::new(Database::new(&db_path).unwrap())));
+        let owner = register(&auth, "owner").await;
+        let db = Database::new(&db_path).unwrap();
+        let room = db.create_room("Room", "", &owner.user.id).unwrap();
+        db.add_user_to_room(&room.id, &owner.user.id).unwrap();
+
+        let message_service = Arc::new(Mutex::new(MessageService::new(db)));
+        let webhooks = Arc::new(Mutex::new(WebhookService::new(Database::new(&db_path).unwrap())));
+        let ws_server = configure(WebSocketServer::new(Arc::clone(&auth), Arc::clone(&message_service), String::new()));
+        let connections = ws_server.connections();
+        let server = HttpServer::new(Arc::clone(&auth), message_service, Arc::clone(&connections), Arc::clone(&webhooks), String::new())
+            .with_websocket(ws_server)
+            .with_metrics(metrics);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = server.router();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        TestServer { addr, base: format!("http://{}", addr), auth, connections, webhooks, owner, room, db_path }
+    }
+
+    async fn register(auth: &Mutex<AuthService>, name: &str) -> AuthResponse {
+        auth.lock().await.register(CreateUserRequest {
+            username: name.to_string(),
+            email: format!("{}@test.com", name),
+            password: "test_password_123".to_string(),
+            invite_code: None,
+        }).unwrap()
+    }
+
+    /// Opens a websocket and signs in with `token`.
+    async fn connect(addr: SocketAddr, token: &str) -> Socket {
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+        let authenticate = serde_json::json!({ "type": "Authenticate", "token": token }).to_string();
+        socket.send(Message::Text(authenticate.into())).await.unwrap();
+        socket
+    }
+
+    async fn next_of_type(socket: &mut Socket, kind: &str) -> serde_json::Value {
+        loop {
+            if let Message::Text(text) = socket.next().await.unwrap().unwrap() {
+                let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                if message["type"] == kind {
+                    return message;
+                }
+            }
+        }
+    }
+
+    async fn get(url: String) -> (u16, String) {
+        tokio::task::spawn_blocking(move || match ureq::get(&url).call() {
+            Ok(response) => (response.status(), response.into_string().unwrap()),
+            Err(ureq::Error::Status(status, response)) => (status, response.into_string().unwrap()),
+            Err(e) => panic!("request failed: {}", e),
+        }).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_incoming_webhook_posts_and_broadcasts() {
+        let server = spawn_with_room(metrics(), |ws| ws).await;
+        let (owner, room) = (&server.owner.user, &server.room);
+        let hook = server.webhooks.lock().await.create_incoming_webhook(&owner.id, &room.id, "CI").unwrap();
+
+        let (tx, mut rx) = outbound::channel(&OutboundQueueConfig::default());
+        {
+            let mut conns = server.connections.write().await;
+            conns.add_client(owner.id.clone(), owner.username.clone(), tx);
+            conns.join_room(&owner.id, room.id.clone()).unwrap();
+        }
+
+        let post = |path: String, body: serde_json::Value| {
+            tokio::task::spawn_blocking(move || match ureq::post(&path).send_json(body) {
+                Ok(response) => response.status(),
+                Err(ureq::Error::Status(status, _)) => status,
+                Err(e) => panic!("request failed: {}", e),
+            })
+        };
+
+        let status = post(format!("{}/hooks/{}", server.base, hook.token), serde_json::json!({
+            "text": "Build #42 passed",
+            "username": "Jenkins",
+            "attachments": [{ "url": "https://ci.example.com/42", "title": "Logs" }],
+        })).await.unwrap();
+        assert_eq!(status, 200);
+
+        let received: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_str()).unwrap();
+        assert_eq!(received["type"], "NewMessage");
+        assert_eq!(received["room_id"], room.id);
+        assert_eq!(received["message"]["content"], "Build #42 passed\nLogs: https://ci.example.com/42");
+        assert_eq!(received["message"]["display_name"], "Jenkins");
+        assert!(received["message"]["sender_username"].as_str().unwrap().starts_with("webhook-"));
+
+        // Mentions notify just like messages sent by people
+        let status = post(format!("{}/hooks/{}", server.base, hook.token), serde_json::json!({ "text": "@owner deploy failed" })).await.unwrap();
+        assert_eq!(status, 200);
+        let received: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_str()).unwrap();
+        assert_eq!(received["type"], "NewMessage");
+        let received: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_str()).unwrap();
+        assert_eq!(received["type"], "MentionNotification");
+        assert_eq!(received["content"], "@owner deploy failed");
+
+        let status = post(format!("{}/hooks/not-a-token", server.base), serde_json::json!({ "text": "hi" })).await.unwrap();
+        assert_eq!(status, 404);
+
+        let _ = std::fs::remove_file(server.db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_auth_and_chat_share_one_port() {
+        let server = spawn_with_room(metrics(), |ws| ws).await;
+
+        let base = server.base.clone();
+        let (health, session) = tokio::task::spawn_blocking(move || {
+            let health: serde_json::Value = ureq::get(&format!("{}/healthz", base)).call().unwrap().into_json().unwrap();
+            let session: serde_json::Value = ureq::post(&format!("{}/api/auth/register", base))
+                .send_json(serde_json::json!({
+                    "username": "alice",
+                    "email": "alice@test.com",
+                    "password": "test_password_123",
+                }))
+                .unwrap()
+                .into_json()
+                .unwrap();
+            (health, session)
+        }).await.unwrap();
+        assert_eq!(health["status"], "ok");
+
+        let mut socket = connect(server.addr, session["token"].as_str().unwrap()).await;
+        let reply = next_of_type(&mut socket, "Authenticated").await;
+        assert_eq!(reply["username"], "alice");
+
+        let _ = std::fs::remove_file(server.db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_live_messages_are_flagged_for_blockers() {
+        let server = spawn_with_room(metrics(), |ws| ws).await;
+        let (bob, pest) = (register(&server.auth, "bob").await, register(&server.auth, "pest").await);
+        let db = Database::new(&server.db_path).unwrap();
+        for session in [&bob, &pest] {
+            db.add_user_to_room(&server.room.id, &session.user.id).unwrap();
+        }
+        db.block_user(&server.owner.user.id, &pest.user.id).unwrap();
+
+        let mut sockets = Vec::new();
+        for session in [&server.owner, &bob, &pest] {
+            let mut socket = connect(server.addr, &session.token).await;
+            next_of_type(&mut socket, "RoomJoined").await;
+            sockets.push(socket);
+        }
+
+        let send = serde_json::json!({ "type": "SendMessage", "room_id": server.room.id, "content": "hello" }).to_string();
+        sockets[2].send(Message::Text(send.into())).await.unwrap();
+
+        let mut flags = Vec::new();
+        for socket in &mut sockets {
+            let message = next_of_type(socket, "NewMessage").await;
+            assert_eq!(message["message"]["content"], "hello");
+            flags.push(message["message"]["sender_blocked"].as_bool().unwrap());
+        }
+        // Only the blocker's copy is flagged
+        assert_eq!(flags, vec![true, false, false]);
+
+        let _ = std::fs::remove_file(server.db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_readiness_waits_for_listeners() {
+        // A registry of its own, so other tests never see the pending listener
+        let registry: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+        let server = spawn_with_room(registry, |ws| ws).await;
+
+        let (status, _) = get(format!("{}/readyz", server.base)).await;
+        assert_eq!(status, 200);
+
+        registry.expect_listener("test-never-bound");
+        let (status, body) = get(format!("{}/readyz", server.base)).await;
+        assert_eq!(status, 503);
+        assert!(body.contains("test-never-bound"));
+
+        registry.listener_up("test-never-bound");
+        let (status, _) = get(format!("{}/readyz", server.base)).await;
+        assert_eq!(status, 200);
+
+        let _ = std::fs::remove_file(server.db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_metrics_endpoint() {
+        // Errors and query timings are always recorded globally
+        let server = spawn_with_room(metrics(), |ws| ws).await;
+        let (owner, room) = (&server.owner.user, &server.room);
+        let (tx, _rx) = outbound::channel(&OutboundQueueConfig::default());
+        {
+            let mut conns = server.connections.write().await;
+            conns.add_client(owner.id.clone(), owner.username.clone(), tx);
+            conns.join_room(&owner.id, room.id.clone()).unwrap();
+        }
+
+        // Unauthenticated API call counts as an invalid_session error
+        let (status, _) = get(format!("{}/api/me", server.base)).await;
+        assert_eq!(status, 401);
+
+        let (status, body) = get(format!("{}/metrics", server.base)).await;
+        assert_eq!(status, 200);
+        assert!(body.contains(&format!("spark_room_subscribers{{room_id=\"{}\"}} 1", room.id)));
+        assert!(body.contains("spark_errors_total{kind=\"invalid_session\"}"));
+        assert!(body.contains("spark_db_query_duration_seconds_count{statement=\"insert rooms\"}"));
+        assert!(body.contains("spark_connected_clients"));
+
+        let _ = std::fs::remove_file(server.db_path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown_notifies_clients_and_marks_them_offline() {
+        let shutdown = Shutdown::new();
+        let server = spawn_with_room(metrics(), |ws| {
+            ws.with_shutdown(shutdown.clone(), std::time::Duration::from_millis(1500))
+        }).await;
+
+        let mut socket = connect(server.addr, &server.owner.token).await;
+        next_of_type(&mut socket, "Authenticated").await;
+        assert_eq!(server.connections.read().await.client_count(), 1);
+
+        shutdown.trigger();
+
+        let mut notice = None;
+        while let Some(Ok(frame)) = socket.next().await {
+            match frame {
+                Message::Text(text) => {
+                    let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    if message["type"] == "ServerShuttingDown" {
+                        notice = Some(message);
+                    }
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        assert_eq!(notice.unwrap()["reconnect_after_ms"], 1500);
+
+        for _ in 0..100 {
+            if server.connections.read().await.client_count() == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(server.connections.read().await.client_count(), 0);
+        let user = Database::new(&server.db_path).unwrap().get_user_by_id(server.owner.user.id.clone()).unwrap().unwrap();
+        assert!(matches!(user.presence, Presence::Offline));
+
+        let _ = std::fs::remove_file(server.db_path);
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_clients_are_disconnected() {
+        use crate::config::HeartbeatConfig;
+        use std::time::Duration;
+
+        let server = spawn_with_room(metrics(), |ws| {
+            ws.with_heartbeat(HeartbeatConfig { interval: Duration::from_millis(100), timeout: Duration::from_millis(400) })
+        }).await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", server.addr)).await.unwrap();
+        let ping = serde_json::json!({ "type": "Ping", "nonce": "abc" }).to_string();
+        socket.send(Message::Text(ping.into())).await.unwrap();
+        let pong = next_of_type(&mut socket, "Pong").await;
+        assert_eq!(pong["nonce"], "abc");
+
+        let authenticate = serde_json::json!({ "type": "Authenticate", "token": server.owner.token }).to_string();
+        socket.send(Message::Text(authenticate.into())).await.unwrap();
+        while server.connections.read().await.client_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Stop reading, so the client never answers the server's pings
+        for _ in 0..100 {
+            if server.connections.read().await.client_count() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(server.connections.read().await.client_count(), 0);
+        let user = Database::new(&server.db_path).unwrap().get_user_by_id(server.owner.user.id.clone()).unwrap().unwrap();
+        assert!(matches!(user.presence, Presence::Offline));
+
+        drop(socket);
+        let _ = std::fs::remove_file(server.db_path);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{
+    auth_provider::{AuthProvider, ExternalIdentity, Identity},
+    config::LdapConfig,
+    error::{AuthError, Result},
+    storage::Storage,
+};
+use ldap3::{dn_escape, LdapConn, Scope, SearchEntry};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// LDAP result code for a failed simple bind
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Authenticates by binding to an LDAP directory as the user.
+pub struct LdapProvider {
+    config: LdapConfig,
+}
+
+impl LdapProvider {
+    pub fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    fn bind_and_lookup(&self, username: &str, password: &str) -> Result<Option<ExternalIdentity>> {
+        let dn = self.config.bind_dn_template.replace("{username}", &dn_escape(username));
+        let mut conn = LdapConn::new(&self.config.url).map_err(|e| AuthError::Provider(e.to_string()))?;
+
+        let bind = conn.simple_bind(&dn, password).map_err(|e| AuthError::Provider(e.to_string()))?;
+        if bind.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bind.success().map_err(|e| AuthError::Provider(e.to_string()))?;
+
+        let (entries, _) = conn
+            .search(&dn, Scope::Base, "(objectClass=*)", vec![self.config.email_attribute.as_str()])
+            .and_then(|result| result.success())
+            .map_err(|e| AuthError::Provider(e.to_string()))?;
+
+        let email = entries.into_iter()
+            .next()
+            .map(SearchEntry::construct)
+            .and_then(|entry| entry.attrs.get(&self.config.email_attribute).and_then(|v| v.first().cloned()));
+
+        let _ = conn.unbind();
+
+        Ok(Some(ExternalIdentity {
+            provider: self.name().to_string(),
+            subject: dn,
+            username: username.to_string(),
+            email,
+            // The address comes from the server's own directory
+            email_verified: true,
+        }))
+    }
+}
+
+impl AuthProvider for LdapProvider {
+    fn name(&self) -> &str {
+        "ldap"
+    }
+
+    /// A rejected bind is treated as "unknown user" so other providers still
+    /// get a chance; an empty password is never sent since it would be an
+    /// unauthenticated bind.
+    fn authenticate(&self, _db: &dyn Storage, username: &str, password: &str) -> Result<Option<Identity>> {
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        // LdapConn drives its own runtime, which can't be started from a
+        // runtime worker thread without handing the thread over first
+        let identity = match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| self.bind_and_lookup(username, password))
+            }
+            _ => self.bind_and_lookup(username, password),
+        }?;
+
+        Ok(identity.map(Identity::External))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Database;
+
+    // Expects a running slapd with a user entry, e.g.
+    // SPARK_TEST_LDAP_URL=ldap://127.0.0.1:389
+    // SPARK_TEST_LDAP_BIND_DN=uid={username},ou=people,dc=example,dc=org
+    // SPARK_TEST_LDAP_USER=alice SPARK_TEST_LDAP_PASSWORD=secret
+    #[test]
+    #[ignore = "requires a local slapd"]
+    fn test_ldap_bind_against_local_slapd() {
+        let var = |key: &str| std::env::var(key).unwrap_or_else(|_| panic!("{} not set", key));
+        let provider = LdapProvider::new(LdapConfig {
+            url: var("SPARK_TEST_LDAP_URL"),
+            bind_dn_template: var("SPARK_TEST_LDAP_BIND_DN"),
+            email_attribute: "mail".to_string(),
+        });
+        let db = Database::in_memory().unwrap();
+        let username = var("SPARK_TEST_LDAP_USER");
+
+        match provider.authenticate(&db, &username, &var("SPARK_TEST_LDAP_PASSWORD")).unwrap() {
+            Some(Identity::External(identity)) => assert_eq!(identity.username, username),
+            _ => panic!("expected an LDAP identity"),
+        }
+
+        assert!(provider.authenticate(&db, &username, "definitely-wrong").unwrap().is_none());
+    }
+}
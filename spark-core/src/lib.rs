@@ -1,10 +1,27 @@
+pub mod config;
 pub mod database;
+pub mod storage;
+pub mod postgres;
 pub mod error;
 pub mod users;
 pub mod messages;
 pub mod server;
 pub mod network;
 pub mod websocket;
+pub mod mailer;
+pub mod auth_provider;
+pub mod ldap;
+pub mod oidc;
+pub mod webhooks;
+pub mod http;
+pub mod api;
+pub mod metrics;
+pub mod logging;
+pub mod shutdown;
+pub mod outbound;
+pub mod backplane;
+pub mod audit;
+pub mod filter;
 
 
 pub use database::Database;
@@ -13,4 +30,8 @@ pub use users::{User, Session};
 pub use server::TcpServer;
 pub use network::{AuthService};
 pub use websocket::WebSocketServer;
+pub use http::HttpServer;
+pub use config::ServerConfig;
+pub use mailer::{Mailer, FileMailer, SmtpMailer};
+pub use auth_provider::AuthProvider;
 
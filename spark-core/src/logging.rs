@@ -0,0 +1,88 @@
+use crate::config::{LogConfig, LogFormat};
+use sha2::{Digest, Sha256};
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+/// Stands in for passwords, tokens and secrets in `Debug` output and log fields.
+pub const REDACTED: &str = "[redacted]";
+
+/// Short, stable identifier for a secret so log lines about the same token
+/// can be correlated without the token itself ever being written.
+pub fn fingerprint(secret: &str) -> String {
+    hex::encode(&Sha256::digest(secret.as_bytes())[..4])
+}
+
+fn build_subscriber<W>(config: &LogConfig, writer: W) -> Result<Box<dyn Subscriber + Send + Sync>, String>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::try_new(&config.filter).map_err(|e| format!("Invalid log filter: {}", e))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer);
+
+    Ok(match config.format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().with_current_span(true).with_span_list(true).finish()),
+    })
+}
+
+/// Installs the process-wide subscriber, writing to stderr.
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let subscriber = build_subscriber(config, std::io::stderr)?;
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::{CreateUserRequest, LoginRequest};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_logs_carry_span_fields_without_secrets() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let config = LogConfig { filter: "debug".to_string(), format: LogFormat::Json };
+        let subscriber = build_subscriber(&config, move || writer.clone()).unwrap();
+
+        let login = LoginRequest { username: "alice".to_string(), password: "hunter2hunter2".to_string() };
+        let register = CreateUserRequest {
+            username: "bob".to_string(),
+            email: "bob@test.com".to_string(),
+            password: "correct-horse".to_string(),
+            invite_code: None,
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("ws_request", user_id = "user-1", request_type = "SendMessage");
+            let _entered = span.enter();
+            tracing::info!(?login, ?register, "handling request");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        assert_eq!(line["span"]["user_id"], "user-1");
+        assert_eq!(line["span"]["request_type"], "SendMessage");
+        assert!(output.contains("alice"));
+        assert!(!output.contains("hunter2hunter2"));
+        assert!(!output.contains("correct-horse"));
+    }
+
+    #[test]
+    fn test_invalid_filter_is_rejected() {
+        let config = LogConfig { filter: "spark_core=loud".to_string(), format: LogFormat::Text };
+        assert!(build_subscriber(&config, std::io::sink).is_err());
+    }
+}
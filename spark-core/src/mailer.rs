@@ -0,0 +1,227 @@
+use crate::error::{AuthError, Result};
+use chrono::Utc;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers outgoing account mail (password resets, verification links, ...).
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<()>;
+}
+
+/// Writes each email to its own `.eml` file in an outbox directory. Useful for
+/// local development and tests where no SMTP server is running.
+pub struct FileMailer {
+    from: String,
+    outbox: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new<P: Into<PathBuf>>(outbox: P, from: &str) -> Self {
+        Self {
+            from: from.to_string(),
+            outbox: outbox.into(),
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<()> {
+        let message = format_message(&self.from, email)?;
+        fs::create_dir_all(&self.outbox).map_err(|e| AuthError::Mail(e.to_string()))?;
+
+        let path = self.outbox.join(format!("{}-{}.eml", Utc::now().timestamp_millis(), Uuid::new_v4()));
+        fs::write(&path, message).map_err(|e| AuthError::Mail(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Minimal plain-text SMTP client meant for a relay on the local network
+/// (no TLS or AUTH).
+pub struct SmtpMailer {
+    from: String,
+    addr: String,
+}
+
+impl SmtpMailer {
+    pub fn new(addr: &str, from: &str) -> Self {
+        Self {
+            from: from.to_string(),
+            addr: addr.to_string(),
+        }
+    }
+
+    fn expect_reply(reader: &mut BufReader<TcpStream>, code: &str) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).map_err(|e| AuthError::Mail(e.to_string()))?;
+
+            if !line.starts_with(code) {
+                return Err(AuthError::Mail(format!("Unexpected SMTP reply: {}", line.trim_end())));
+            }
+
+            // Multi-line replies use "250-" for every line but the last
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command(reader: &mut BufReader<TcpStream>, command: &str, code: &str) -> Result<()> {
+        reader.get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .map_err(|e| AuthError::Mail(e.to_string()))?;
+        Self::expect_reply(reader, code)
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<()> {
+        // Checked before connecting, so a bad address never reaches the relay
+        format_message(&self.from, email)?;
+
+        let stream = TcpStream::connect(&self.addr).map_err(|e| AuthError::Mail(e.to_string()))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10))).map_err(|e| AuthError::Mail(e.to_string()))?;
+        let mut reader = BufReader::new(stream);
+
+        Self::expect_reply(&mut reader, "220")?;
+        Self::command(&mut reader, "HELO spark", "250")?;
+        Self::command(&mut reader, &format!("MAIL FROM:<{}>", single_line(&self.from)?), "250")?;
+        Self::command(&mut reader, &format!("RCPT TO:<{}>", single_line(&email.to)?), "250")?;
+        Self::command(&mut reader, "DATA", "354")?;
+
+        // Dot-stuff lines beginning with '.' so they aren't read as the terminator
+        let data = format_message(&self.from, email)?
+            .lines()
+            .map(|line| if line.starts_with('.') { format!(".{}", line) } else { line.to_string() })
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        Self::command(&mut reader, &format!("{}\r\n.", data), "250")?;
+        Self::command(&mut reader, "QUIT", "221")?;
+        Ok(())
+    }
+}
+
+/// Refuses header fields and envelope addresses that span lines, which
+/// would let them add headers or SMTP commands of their own.
+fn single_line(value: &str) -> Result<&str> {
+    if value.contains(['\r', '\n']) {
+        return Err(AuthError::Mail("Header fields can't contain line breaks".to_string()));
+    }
+    Ok(value)
+}
+
+fn format_message(from: &str, email: &Email) -> Result<String> {
+    Ok(format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\r\n{}\r\n",
+        single_line(from)?,
+        single_line(&email.to)?,
+        single_line(&email.subject)?,
+        Utc::now().to_rfc2822(),
+        email.body,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_file_mailer_writes_outbox() {
+        let outbox = std::env::temp_dir().join(format!("spark-outbox-{}", Uuid::new_v4()));
+        let mailer = FileMailer::new(&outbox, "noreply@spark.local");
+
+        mailer.send(&Email {
+            to: "user@test.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "Body text".to_string(),
+        }).unwrap();
+
+        let entries: Vec<_> = fs::read_dir(&outbox).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        let contents = fs::read_to_string(entries[0].as_ref().unwrap().path()).unwrap();
+        assert!(contents.contains("To: user@test.com"));
+        assert!(contents.contains("Body text"));
+
+        fs::remove_dir_all(&outbox).unwrap();
+    }
+
+    #[test]
+    fn test_multi_line_header_fields_are_refused() {
+        let outbox = std::env::temp_dir().join(format!("spark-outbox-{}", Uuid::new_v4()));
+        let email = Email {
+            to: "user@test.com\r\nRCPT TO:<victim@test.com>".to_string(),
+            subject: "Hello".to_string(),
+            body: "Body text".to_string(),
+        };
+
+        assert!(matches!(FileMailer::new(&outbox, "noreply@spark.local").send(&email), Err(AuthError::Mail(_))));
+        assert!(!outbox.exists());
+        // Nothing listens here; the address is refused before connecting
+        assert!(matches!(SmtpMailer::new("127.0.0.1:1", "noreply@spark.local").send(&email), Err(AuthError::Mail(msg)) if msg.contains("line breaks")));
+
+        let email = Email { to: "user@test.com".to_string(), subject: "Hi\nBcc: victim@test.com".to_string(), body: String::new() };
+        assert!(format_message("noreply@spark.local", &email).is_err());
+    }
+
+    #[test]
+    fn test_smtp_mailer_against_local_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut transcript = Vec::new();
+            let mut in_data = false;
+
+            reader.get_mut().write_all(b"220 test ready\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 { break; }
+                let line = line.trim_end().to_string();
+                transcript.push(line.clone());
+
+                let reply: &[u8] = if in_data {
+                    if line != "." { continue; }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    reader.get_mut().write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                reader.get_mut().write_all(reply).unwrap();
+            }
+            transcript
+        });
+
+        let mailer = SmtpMailer::new(&addr, "noreply@spark.local");
+        mailer.send(&Email {
+            to: "user@test.com".to_string(),
+            subject: "Reset".to_string(),
+            body: ".leading dot".to_string(),
+        }).unwrap();
+
+        let transcript = server.join().unwrap();
+        assert!(transcript.contains(&"RCPT TO:<user@test.com>".to_string()));
+        assert!(transcript.contains(&"..leading dot".to_string()));
+    }
+}
@@ -1,32 +1,170 @@
-use spark_core::{Database, TcpServer, WebSocketServer};
+use spark_core::{Database, HttpServer, ServerConfig, TcpServer, WebSocketServer};
+use spark_core::auth_provider::recommend_argon2_params;
+use spark_core::filter::FilterPipeline;
+use spark_core::backplane::RedisBackplane;
+use spark_core::logging;
+use spark_core::metrics::metrics;
 use spark_core::network::{AuthService, MessageService};
+use spark_core::postgres::PostgresStorage;
+use spark_core::shutdown::{self, Shutdown};
+use spark_core::storage::Storage;
+use spark_core::users::Presence;
+use spark_core::webhooks::WebhookService;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let auth_db = Database::new("spark.db")?;
-    let msg_db = Database::new("spark.db")?;
-    let auth_service = Arc::new(Mutex::new(AuthService::new(auth_db)));
-    let message_service = Arc::new(Mutex::new(MessageService::new(msg_db)));
-    let tcp_server = TcpServer::new("spark.db", "127.0.0.1:8080".to_string())?;
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--bench-argon2") {
+        let target_ms = args.get(2).and_then(|ms| ms.parse().ok()).unwrap_or(250);
+        let params = recommend_argon2_params(Duration::from_millis(target_ms))?;
+
+        println!("Recommended Argon2id parameters for a {}ms login:", target_ms);
+        println!("SPARK_ARGON2_MEMORY_KIB={}", params.memory_kib);
+        println!("SPARK_ARGON2_ITERATIONS={}", params.iterations);
+        println!("SPARK_ARGON2_PARALLELISM={}", params.parallelism);
+        return Ok(());
+    }
+
+    let config = ServerConfig::from_env();
+    logging::init(&config.log)?;
+
+    let auth = AuthService::with_config(
+        open_storage(&config).await?,
+        config.auth.clone(),
+        config.mail.build(),
+    );
+    let webhooks = WebhookService::with_storage(open_storage(&config).await?)
+        .with_allowed_hosts(config.webhooks.allowed_hosts.clone());
+    let messages = MessageService::with_storage(open_storage(&config).await?)
+        .with_content_filter(FilterPipeline::from_config(&config.filter)?);
+    if config.storage.postgres_url.is_some() {
+        info!("connected to PostgreSQL storage");
+    }
+    for username in auth.bootstrap_admins()? {
+        info!(username = %username, "promoted configured admin");
+    }
+    let auth_service = Arc::new(Mutex::new(auth));
+    let message_service = Arc::new(Mutex::new(messages));
+    let webhook_service = Arc::new(Mutex::new(webhooks));
+    let shutdown = Shutdown::new();
+    let tcp_server = config.tcp_addr.clone()
+        .map(|addr| TcpServer::with_auth(Arc::clone(&auth_service), addr).with_shutdown(shutdown.clone()));
     let ws_server = WebSocketServer::new(
         Arc::clone(&auth_service), 
         Arc::clone(&message_service), 
-        "127.0.0.1:8081".to_string(),
+        config.ws_addr.clone().unwrap_or_default(),
+    )
+    .with_webhooks(Arc::clone(&webhook_service), config.webhooks.clone())
+    .with_shutdown(shutdown.clone(), config.shutdown.reconnect_after)
+    .with_heartbeat(config.heartbeat.clone())
+    .with_outbound_queue(config.outbound.clone());
+    let ws_server = match &config.backplane.url {
+        Some(url) => {
+            let backplane = RedisBackplane::connect(url).await?;
+            info!("connected to Redis backplane");
+            ws_server.with_backplane(Arc::new(backplane))
+        }
+        None => ws_server,
+    };
+    let http_server = HttpServer::new(
+        Arc::clone(&auth_service),
+        Arc::clone(&message_service),
+        ws_server.connections(),
+        webhook_service,
+        config.http_addr.clone(),
+    )
+    .with_websocket(ws_server.clone())
+    .with_shutdown(shutdown.clone());
+
+    metrics().expect_listener("http");
+    if config.tcp_addr.is_some() {
+        metrics().expect_listener("tcp");
+    }
+    if config.ws_addr.is_some() {
+        metrics().expect_listener("ws");
+    }
+
+    info!(
+        http = %config.http_addr,
+        tcp = config.tcp_addr.as_deref().unwrap_or("off"),
+        ws = config.ws_addr.as_deref().unwrap_or("off"),
+        "starting SpaRk server",
     );
 
-    println!("Starting SpaRk Server..");
-    println!("TCP Server (Auth): 127.0.0.1:8080");
-    println!("WebSocket Server (Chat): 127.0.0.1:8081");
+    let servers = async {
+        tokio::join!(
+            async {
+                if let Some(server) = &tcp_server {
+                    if let Err(e) = server.start().await {
+                        error!(error = %e, "TCP server stopped");
+                        shutdown.trigger();
+                    }
+                }
+            },
+            async {
+                if config.ws_addr.is_some() {
+                    if let Err(e) = ws_server.start().await {
+                        error!(error = %e, "WebSocket server stopped");
+                        shutdown.trigger();
+                    }
+                }
+            },
+            async {
+                if let Err(e) = http_server.start().await {
+                    error!(error = %e, "HTTP server stopped");
+                    shutdown.trigger();
+                }
+            },
+        );
+    };
 
-    tokio::select! {
-        result = tcp_server.start() => {
-            eprintln!("TCP server stopped: {:?}", result);
+    let connections = ws_server.connections();
+    let clients_gone = async {
+        shutdown.wait().await;
+        while connections.read().await.client_count() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
-        result = ws_server.start() => {
-            eprintln!("WebSocket server stopped {:?}", result);
+    };
+
+    // Starts counting down once a signal arrives or a listener fails
+    let deadline = async {
+        tokio::select! {
+            _ = shutdown::signal() => info!("shutdown signal received, draining connections"),
+            _ = shutdown.wait() => {}
+        }
+        shutdown.trigger();
+        tokio::time::sleep(config.shutdown.deadline).await;
+    };
+
+    tokio::select! {
+        _ = async { tokio::join!(servers, clients_gone) } => info!("all connections drained"),
+        _ = deadline => warn!(
+            clients = connections.read().await.client_count(),
+            "shutdown deadline passed, closing remaining connections",
+        ),
+    }
+
+    // Clients that didn't disconnect in time would otherwise stay Online
+    let stragglers = connections.read().await.connected_user_ids();
+    let msg_service = message_service.lock().await;
+    for user_id in stragglers {
+        if let Err(e) = msg_service.update_user_presence(&user_id, Presence::Offline) {
+            error!(user_id = %user_id, error = %e, "failed to mark user offline");
         }
     }
+
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Every service gets its own connection to the same store, so nothing is
+/// kept in SQLite when PostgreSQL is configured.
+async fn open_storage(config: &ServerConfig) -> Result<Box<dyn Storage>, Box<dyn std::error::Error>> {
+    Ok(match &config.storage.postgres_url {
+        Some(url) => Box::new(PostgresStorage::connect(url).await?),
+        None => Box::new(Database::new(&config.db_path)?),
+    })
+}
@@ -1,7 +1,8 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub enum MessageType {
     Room,
     Private,
@@ -26,15 +27,20 @@ pub struct Message {
     pub is_pinned: bool,
     pub pinned_at: Option<DateTime<Utc>>,
     pub pinned_by: Option<String>,
+    /// Name shown instead of the sender's username, set by incoming webhooks
+    pub display_name: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Room {
     pub id: String,
     pub name: String,
     pub desc: String,
+    pub topic: String,
     pub created_by: String,
     pub created_at: DateTime<Utc>,
+    /// Archived rooms are read-only and left out of the room list by default
+    pub archived_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,6 +50,126 @@ pub struct RoomMember {
     pub joined_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanctionKind {
+    /// Removed from the room and unable to rejoin
+    Ban,
+    /// Can read the room but not post or react
+    Mute,
+}
+
+impl SanctionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SanctionKind::Ban => "ban",
+            SanctionKind::Mute => "mute",
+        }
+    }
+}
+
+/// A ban or mute on one member of a room, in force until `expires_at`, or
+/// until lifted if that is unset.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoomSanction {
+    pub room_id: String,
+    pub user_id: String,
+    pub username: String,
+    pub reason: Option<String>,
+    pub issued_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportStatus {
+    /// Waiting in the moderation queue
+    Open,
+    Resolved,
+    Dismissed,
+}
+
+impl ReportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportStatus::Open => "open",
+            ReportStatus::Resolved => "resolved",
+            ReportStatus::Dismissed => "dismissed",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "resolved" => ReportStatus::Resolved,
+            "dismissed" => ReportStatus::Dismissed,
+            _ => ReportStatus::Open,
+        }
+    }
+}
+
+/// What a moderator did about a report when resolving it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportAction {
+    /// Closed the report without touching the message or its author
+    NoAction,
+    DeleteMessage,
+    Mute,
+    Ban,
+}
+
+impl ReportAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportAction::NoAction => "no_action",
+            ReportAction::DeleteMessage => "delete_message",
+            ReportAction::Mute => "mute",
+            ReportAction::Ban => "ban",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "no_action" => Some(ReportAction::NoAction),
+            "delete_message" => Some(ReportAction::DeleteMessage),
+            "mute" => Some(ReportAction::Mute),
+            "ban" => Some(ReportAction::Ban),
+            _ => None,
+        }
+    }
+}
+
+/// A member's report of a room message, queued for the room's moderators.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MessageReport {
+    pub id: String,
+    pub room_id: String,
+    pub message_id: String,
+    /// Author of the reported message
+    pub sender_id: String,
+    /// The message as it read when reported, kept if it is later edited or deleted
+    pub content: String,
+    pub reporter_id: String,
+    pub reason: String,
+    pub status: ReportStatus,
+    pub created_at: DateTime<Utc>,
+    pub resolved_by: Option<String>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    /// Set once resolved; dismissed reports have none
+    pub action: Option<ReportAction>,
+    pub resolution_note: Option<String>,
+}
+
+/// Who a server announcement goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnouncementTarget {
+    /// Posted as a `Server` message in every room that isn't archived
+    Rooms,
+    /// Shown to everyone connected right now, and not stored
+    Online,
+}
+
 // Requests
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,7 +179,7 @@ pub struct SendRoomMessageRequest {
     pub reply_to_message_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct SendPrivateMessageRequest {
     pub receiver_username: String,
     pub content: String,
@@ -76,7 +202,7 @@ pub struct GetPrivateMessagesRequest {
 
 // Responses
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct RoomMessageResponse {
     pub id: String,
     pub sender_username: String,
@@ -89,9 +215,25 @@ pub struct RoomMessageResponse {
     pub edited_at: Option<DateTime<Utc>>,
     pub mentions: Vec<String>,
     pub reply_to: Option<MessageReplyContext>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// Set for a user who has blocked the sender, on history they fetch
+    /// and on their live `NewMessage` events, so clients can collapse it.
+    #[serde(default)]
+    pub sender_blocked: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Who a newly posted room message needs special handling for when it goes
+/// out live.
+#[derive(Debug, Clone, Default)]
+pub struct MessageAudience {
+    /// Mentioned users to notify, leaving out those who blocked the sender
+    pub notify_user_ids: Vec<String>,
+    /// Users who blocked the sender
+    pub blocked_by: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct PrivateMessageResponse {
     pub id: String,
     pub sender_username: String,
@@ -104,7 +246,7 @@ pub struct PrivateMessageResponse {
     pub edited_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct MessageReplyContext {
     pub id: String,
     pub sender_username: String,
@@ -112,7 +254,7 @@ pub struct MessageReplyContext {
     pub sent_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ReactionSummary {
     pub emoji: String,
     pub count: usize,
@@ -120,6 +262,15 @@ pub struct ReactionSummary {
     pub usernames: Vec<String>,
 }
 
+/// One user's reaction to a message.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ReactionDetail {
+    pub emoji: String,
+    pub user_id: String,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+}
+
 impl Default for GetRoomMessagesRequest {
     fn default() -> Self {
         Self {
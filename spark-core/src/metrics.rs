@@ -0,0 +1,200 @@
+use crate::{error::AuthError, websocket::ConnectionManager};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Process-wide metrics. Global rather than passed around because SQLite
+/// reports statement timings through a plain function callback.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    connected_clients: IntGauge,
+    room_subscribers: IntGaugeVec,
+    messages_sent: IntCounterVec,
+    ws_request_duration: HistogramVec,
+    db_query_duration: HistogramVec,
+    errors: IntCounterVec,
+    messages_shed: IntCounter,
+    slow_consumer_disconnects: IntCounter,
+    listener_up: IntGaugeVec,
+    /// Listeners main has started, and whether each has bound yet
+    listeners: Mutex<HashMap<String, bool>>,
+}
+
+impl Metrics {
+    /// A registry of its own, for tests that can't share the global one.
+    pub(crate) fn new() -> Self {
+        let registry = Registry::new_custom(Some("spark".to_string()), None).unwrap();
+
+        let connected_clients = IntGauge::new("connected_clients", "Authenticated WebSocket clients").unwrap();
+        let room_subscribers = IntGaugeVec::new(
+            Opts::new("room_subscribers", "Connected clients subscribed to each room"),
+            &["room_id"],
+        ).unwrap();
+        let messages_sent = IntCounterVec::new(
+            Opts::new("messages_sent_total", "Messages sent, by room or direct"),
+            &["kind"],
+        ).unwrap();
+        let ws_request_duration = HistogramVec::new(
+            HistogramOpts::new("ws_request_duration_seconds", "Time spent handling each WebSocket request"),
+            &["type"],
+        ).unwrap();
+        let db_query_duration = HistogramVec::new(
+            HistogramOpts::new("db_query_duration_seconds", "SQLite statement execution time")
+                .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]),
+            &["statement"],
+        ).unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Errors returned to clients, by AuthError variant"),
+            &["kind"],
+        ).unwrap();
+        let messages_shed = IntCounter::new(
+            "ws_messages_shed_total",
+            "Typing and presence updates dropped because a client's queue was backed up",
+        ).unwrap();
+        let slow_consumer_disconnects = IntCounter::new(
+            "ws_slow_consumer_disconnects_total",
+            "Clients disconnected because their outbound queue filled up",
+        ).unwrap();
+        let listener_up = IntGaugeVec::new(
+            Opts::new("listener_up", "Whether each listener is accepting connections"),
+            &["listener"],
+        ).unwrap();
+
+        registry.register(Box::new(connected_clients.clone())).unwrap();
+        registry.register(Box::new(room_subscribers.clone())).unwrap();
+        registry.register(Box::new(messages_sent.clone())).unwrap();
+        registry.register(Box::new(ws_request_duration.clone())).unwrap();
+        registry.register(Box::new(db_query_duration.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(messages_shed.clone())).unwrap();
+        registry.register(Box::new(slow_consumer_disconnects.clone())).unwrap();
+        registry.register(Box::new(listener_up.clone())).unwrap();
+
+        Self {
+            registry,
+            connected_clients,
+            room_subscribers,
+            messages_sent,
+            ws_request_duration,
+            db_query_duration,
+            errors,
+            messages_shed,
+            slow_consumer_disconnects,
+            listener_up,
+            listeners: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Observes the request's duration when the returned timer is dropped.
+    pub fn time_ws_request(&self, kind: &str) -> HistogramTimer {
+        self.ws_request_duration.with_label_values(&[kind]).start_timer()
+    }
+
+    pub fn message_sent(&self, kind: &str) {
+        self.messages_sent.with_label_values(&[kind]).inc();
+    }
+
+    pub fn record_error(&self, error: &AuthError) {
+        self.errors.with_label_values(&[error.kind()]).inc();
+    }
+
+    pub fn message_shed(&self) {
+        self.messages_shed.inc();
+    }
+
+    pub fn slow_consumer_disconnect(&self) {
+        self.slow_consumer_disconnects.inc();
+    }
+
+    pub fn record_db_query(&self, sql: &str, duration: Duration) {
+        self.db_query_duration.with_label_values(&[&statement_label(sql)]).observe(duration.as_secs_f64());
+    }
+
+    /// Registers a listener that `/readyz` should wait for.
+    pub fn expect_listener(&self, name: &str) {
+        self.listeners.lock().unwrap().entry(name.to_string()).or_insert(false);
+        self.listener_up.with_label_values(&[name]).set(0);
+    }
+
+    pub fn listener_up(&self, name: &str) {
+        self.listeners.lock().unwrap().insert(name.to_string(), true);
+        self.listener_up.with_label_values(&[name]).set(1);
+    }
+
+    /// Names of expected listeners that haven't bound yet.
+    pub fn pending_listeners(&self) -> Vec<String> {
+        let mut pending: Vec<String> = self.listeners.lock().unwrap()
+            .iter()
+            .filter(|(_, up)| !**up)
+            .map(|(name, _)| name.clone())
+            .collect();
+        pending.sort();
+        pending
+    }
+
+    /// Renders everything in the Prometheus text format, refreshing the
+    /// connection gauges from the connection manager first.
+    pub fn render(&self, connections: &ConnectionManager) -> String {
+        self.connected_clients.set(connections.client_count() as i64);
+        self.room_subscribers.reset();
+        for (room_id, subscribers) in connections.room_subscriber_counts() {
+            self.room_subscribers.with_label_values(&[room_id]).set(subscribers as i64);
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// Reduces a statement to its verb and table, e.g. `select messages`, so the
+/// label stays low-cardinality.
+fn statement_label(sql: &str) -> String {
+    let words: Vec<String> = sql.split_whitespace().take(64).map(|w| w.to_lowercase()).collect();
+    let Some(verb) = words.first() else {
+        return "unknown".to_string();
+    };
+
+    let table_after = |keyword: &str| {
+        words.iter()
+            .position(|w| w == keyword)
+            .and_then(|i| words.get(i + 1))
+            .map(|t| t.trim_matches(|c: char| !c.is_alphanumeric() && c != '_').to_string())
+    };
+    let table = match verb.as_str() {
+        "select" => table_after("from"),
+        "insert" | "replace" => table_after("into"),
+        "update" => words.get(1).cloned(),
+        "delete" => table_after("from"),
+        _ => None,
+    };
+
+    match table {
+        Some(table) if !table.is_empty() => format!("{} {}", verb, table),
+        _ => verb.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statement_labels() {
+        assert_eq!(statement_label("SELECT u.id FROM users u WHERE u.id = ?1"), "select users");
+        assert_eq!(statement_label("INSERT OR IGNORE INTO room_members (room_id) VALUES (?1)"), "insert room_members");
+        assert_eq!(statement_label("UPDATE messages SET content = ?1"), "update messages");
+        assert_eq!(statement_label("DELETE FROM sessions WHERE token = ?1"), "delete sessions");
+        assert_eq!(statement_label("PRAGMA foreign_keys = ON"), "pragma");
+    }
+}
@@ -1,9 +1,12 @@
 use crate::{
+    audit::{to_json_lines, AuditAction, AuditEntry, AuditLogFilter},
+    filter::{ContentFilter, FilterOutcome, FilterPipeline, HeldMessage, RoomFilter, RoomFilterCache},
     error::{
         AuthError, 
         Result
     }, messages::{
         GetPrivateMessagesRequest, 
+        MessageAudience, 
         MessageType, 
         PrivateMessageResponse, 
         Room, 
@@ -11,45 +14,82 @@ use crate::{
         SendPrivateMessageRequest, 
         SendRoomMessageRequest,
         MessageReplyContext,
-        ReactionSummary,
+        ReactionDetail, ReactionSummary,
+        RoomSanction, SanctionKind,
+        MessageReport, ReportAction, ReportStatus,
     }, users::{
-        AuthResponse, CreateUserRequest, LoginRequest, Presence, User
-    }, Database
-};
-use argon2::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2
+        ApiScope, ApiToken, AuthResponse, ChangePasswordRequest, CreateUserRequest, InviteCode, LoginRequest, NewApiToken,
+        Presence, ResetPasswordRequest, User, UserAccount, UserBlock, UserRole, UserSearch
+    }, auth_provider::{
+        hash_password, verify_password, AuthProvider, AuthorizationRequest,
+        ExternalIdentity, Identity, LocalProvider, EXTERNAL_PASSWORD_HASH
+    }, config::{AuthConfig, MailConfig, RegistrationPolicy}, ldap::LdapProvider, mailer::{Email, Mailer},
+    metrics::metrics, oidc::OidcProvider, storage::Storage, Database
 };
 use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+use std::sync::Arc;
 use rand::{distributions::{Alphanumeric}, Rng};
 use regex::Regex;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// Prefix that tells API tokens apart from 64-char session tokens
+const API_TOKEN_PREFIX: &str = "spk_";
 
 pub struct AuthService {
-    db: Database,
+    db: Box<dyn Storage>,
+    config: AuthConfig,
+    mailer: Arc<dyn Mailer>,
+    providers: Vec<Box<dyn AuthProvider>>,
 }
 
 impl AuthService {
     pub fn new(db: Database) -> Self {
-        Self{ db }
+        Self::with_config(Box::new(db), AuthConfig::default(), MailConfig::default().build())
+    }
+
+    /// Password logins try the local provider first, then LDAP if configured.
+    pub fn with_config(db: Box<dyn Storage>, config: AuthConfig, mailer: Box<dyn Mailer>) -> Self {
+        let mut providers: Vec<Box<dyn AuthProvider>> = vec![Box::new(LocalProvider::new(config.argon2))];
+
+        if let Some(ldap) = &config.ldap {
+            providers.push(Box::new(LdapProvider::new(ldap.clone())));
+        }
+
+        if let Some(oidc) = &config.oidc {
+            providers.push(Box::new(OidcProvider::new(oidc.clone())));
+        }
+
+        Self { db, config, mailer: Arc::from(mailer), providers }
+    }
+
+    pub fn add_provider(&mut self, provider: Box<dyn AuthProvider>) {
+        self.providers.push(provider);
+    }
+
+    fn provider(&self, name: &str) -> Result<&dyn AuthProvider> {
+        self.providers.iter()
+            .find(|p| p.name() == name)
+            .map(|p| p.as_ref())
+            .ok_or(AuthError::InvalidInput(format!("Unknown authentication provider: {}", name)))
     }
 
     fn hash_password(&self, password: &str) -> Result<String> {
-        let salt = SaltString::generate(&mut rand::thread_rng());
-        let argon2 = Argon2::default();
+        hash_password(&self.config.argon2, password)
+    }
 
-        argon2
-            .hash_password(password.as_bytes(), &salt)
-            .map(|hash| hash.to_string())
-            .map_err(|e| AuthError::PasswordHash(e.to_string()))
+    #[cfg(test)]
+    fn needs_rehash(&self, hash: &str) -> Result<bool> {
+        crate::auth_provider::needs_rehash(&self.config.argon2, hash)
     }
 
     fn verify_password(&self, password: &str, hash: &str) -> Result<bool> {
-        let parsed_hash = PasswordHash::new(hash)
-            .map_err(|e| AuthError::PasswordHash(e.to_string()))?;
+        if hash == EXTERNAL_PASSWORD_HASH {
+            return Err(AuthError::InvalidInput("This account's password is managed by an external provider".to_string()));
+        }
 
-        Ok(Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .is_ok())
+        verify_password(password, hash)
     }
 
     fn generate_token(&self) -> String {
@@ -60,7 +100,7 @@ impl AuthService {
             .collect()
     }
 
-    fn validate_credentials(&self, username: &str, email: &str, password: &str) -> Result<()> {
+    fn validate_username(&self, username: &str) -> Result<()> {
         if username.is_empty() || username.len() < 3 {
             return Err(AuthError::InvalidInput("Username must be at least 3 characters long".to_string()))
         };
@@ -69,10 +109,30 @@ impl AuthService {
             return Err(AuthError::InvalidInput("Username must be less than 50 characters long".to_string()))
         };
 
-        if !email.contains('@') || email.len()< 5 {
+        Ok(())
+    }
+
+    fn validate_credentials(&self, username: &str, email: &str, password: &str) -> Result<()> {
+        self.validate_username(username)?;
+        self.validate_email(email)?;
+        self.validate_password(password)
+    }
+
+    /// Addresses go into SMTP commands and mail headers, so anything that
+    /// could break out of them is refused.
+    fn validate_email(&self, email: &str) -> Result<()> {
+        let well_formed = (5..=254).contains(&email.len())
+            && email.split_once('@').is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+            && !email.chars().any(|c| c.is_control() || c.is_whitespace() || c == '<' || c == '>');
+
+        if !well_formed {
             return Err(AuthError::InvalidInput("Invalid email format".to_string()));
         }
 
+        Ok(())
+    }
+
+    fn validate_password(&self, password: &str) -> Result<()> {
         if password.len() < 8 {
             return Err(AuthError::InvalidInput("Password must be at least 8 characters long".to_string()))
         }
@@ -80,7 +140,16 @@ impl AuthService {
         Ok(())
     }
 
+    /// Creates an account according to the configured `RegistrationPolicy`.
+    /// Under `AdminApproval` the account is stored but `PendingApproval` is
+    /// returned instead of a session. New accounts never start as admins;
+    /// see `bootstrap_admins`.
     pub fn register(&self, request: CreateUserRequest) -> Result<AuthResponse> {
+        let policy = self.config.registration_policy;
+        if policy == RegistrationPolicy::Disabled {
+            return Err(AuthError::RegistrationClosed);
+        }
+
         self.validate_credentials(&request.username, &request.email, &request.password)?;
 
         if self.db.get_user_by_username(&request.username)?.is_some() {
@@ -88,10 +157,37 @@ impl AuthService {
         }
 
         let password_hash = self.hash_password(&request.password)?;
-        let user = self.db.create_user(&request.username, &request.email, &password_hash)?;
+
+        let approved = policy != RegistrationPolicy::AdminApproval;
+        let email_verified = !self.config.require_email_verification;
+        let create_user = || self.db.create_user_with_status(&request.username, &request.email, &password_hash, approved, email_verified);
+
+        let user = if policy == RegistrationPolicy::InviteOnly {
+            let code = request.invite_code.as_deref().ok_or(AuthError::InvalidInviteCode)?;
+
+            // The redemption only commits once the account exists.
+            self.db.in_transaction(|| {
+                if !self.db.redeem_invite_code(code)? {
+                    return Err(AuthError::InvalidInviteCode);
+                }
+                let user = create_user()?;
+                self.db.set_user_invite_code(&user.id, code)?;
+                Ok(user)
+            })?
+        } else {
+            create_user()?
+        };
+
+        if !email_verified {
+            self.send_verification_email(&user)?;
+        }
+
+        if policy == RegistrationPolicy::AdminApproval {
+            return Err(AuthError::PendingApproval);
+        }
 
         let token = self.generate_token();
-        let expires_at = Utc::now() + Duration::days(30);
+        let expires_at = Utc::now() + self.config.session_ttl;
         self.db.create_session(user.id.clone(), &token, expires_at)?;
 
         self.db.update_last_login(user.id.clone())?;
@@ -100,22 +196,84 @@ impl AuthService {
     }
 
     pub fn login(&self, request: LoginRequest) -> Result<AuthResponse> {
-        let user = self.db
-            .get_user_by_username(&request.username)?
-            .ok_or(AuthError::InvalidCredentials)?;
+        let mut identity = None;
+        for provider in &self.providers {
+            if let Some(found) = provider.authenticate(self.db.as_ref(), &request.username, &request.password)? {
+                identity = Some(found);
+                break;
+            }
+        }
 
-        if !self.verify_password(&request.password, &user.password_hash)? {
-            return Err(AuthError::InvalidCredentials);
+        let user = match identity.ok_or(AuthError::InvalidCredentials)? {
+            Identity::Local(user) => user,
+            Identity::External(external) => self.provision_external_user(external)?,
+        };
+
+        self.start_session(user)
+    }
+
+    fn start_session(&self, user: User) -> Result<AuthResponse> {
+        if user.deactivated_at.is_some() {
+            return Err(AuthError::AccountDeactivated);
+        }
+        if !user.approved {
+            return Err(AuthError::PendingApproval);
         }
 
         let token = self.generate_token();
-        let expires_at = Utc::now() + Duration::days(30);
+        let expires_at = Utc::now() + self.config.session_ttl;
         self.db.create_session(user.id.clone(), &token, expires_at)?;
         self.db.update_last_login(user.id.clone())?;
 
         Ok(AuthResponse { user, token })
     }
 
+    /// Finds the account linked to an external identity, creating it on first
+    /// sign in. Existing local accounts are never linked automatically. New
+    /// accounts follow the `RegistrationPolicy`; there is no way to present
+    /// an invite code, so invite-only servers take no new external users.
+    /// Addresses the provider hasn't verified are replaced by a placeholder.
+    fn provision_external_user(&self, identity: ExternalIdentity) -> Result<User> {
+        if let Some(user) = self.db.get_user_by_identity(&identity.provider, &identity.subject)? {
+            return Ok(user);
+        }
+
+        let policy = self.config.registration_policy;
+        if matches!(policy, RegistrationPolicy::Disabled | RegistrationPolicy::InviteOnly) {
+            return Err(AuthError::RegistrationClosed);
+        }
+
+        self.validate_username(&identity.username)?;
+        let email = identity.email
+            .clone()
+            .filter(|email| identity.email_verified && self.validate_email(email).is_ok())
+            .unwrap_or_else(|| format!("{}@{}.invalid", identity.username, identity.provider));
+
+        if self.db.get_user_by_username(&identity.username)?.is_some() || self.db.get_user_by_email(&email)?.is_some() {
+            return Err(AuthError::UserExists);
+        }
+
+        self.db.in_transaction(|| {
+            let approved = policy != RegistrationPolicy::AdminApproval;
+            let user = self.db.create_user_with_status(&identity.username, &email, EXTERNAL_PASSWORD_HASH, approved, true)?;
+            self.db.link_user_identity(&user.id, &identity.provider, &identity.subject)?;
+            Ok(user)
+        })
+    }
+
+    /// Starts a redirect-based sign in (e.g. OpenID Connect).
+    pub fn begin_external_login(&self, provider: &str) -> Result<AuthorizationRequest> {
+        self.provider(provider)?.begin_authorization()
+    }
+
+    /// Finishes a redirect-based sign in with the `state` and `code` the
+    /// provider sent back, provisioning the account if needed.
+    pub fn complete_external_login(&self, provider: &str, state: &str, code: &str) -> Result<AuthResponse> {
+        let identity = self.provider(provider)?.complete_authorization(state, code)?;
+        let user = self.provision_external_user(identity)?;
+        self.start_session(user)
+    }
+
     pub fn validate_session(&self, token: &str) -> Result<User> {
         let session = self.db
             .get_session_by_token(token)?
@@ -129,6 +287,9 @@ impl AuthService {
         let user = self.db
             .get_user_by_id(session.user_id)?
             .ok_or(AuthError::UserNotFound)?;
+        if user.deactivated_at.is_some() {
+            return Err(AuthError::AccountDeactivated);
+        }
 
         Ok(user)
     }
@@ -138,19 +299,384 @@ impl AuthService {
         Ok(())
     }
 
+    /// Accepts either a session token or a bot's API token. Scopes are
+    /// `None` for sessions, which aren't restricted.
+    pub fn validate_token(&self, token: &str) -> Result<(User, Option<Vec<ApiScope>>)> {
+        if token.starts_with(API_TOKEN_PREFIX) {
+            let (user, scopes) = self.validate_api_token(token)?;
+            return Ok((user, Some(scopes)));
+        }
+
+        Ok((self.validate_session(token)?, None))
+    }
+
+    pub fn validate_api_token(&self, token: &str) -> Result<(User, Vec<ApiScope>)> {
+        let api_token = self.db
+            .get_api_token_by_hash(&Self::hash_token(token))?
+            .filter(|t| !t.revoked)
+            .ok_or(AuthError::InvalidToken)?;
+
+        let user = self.db
+            .get_user_by_id(api_token.user_id.clone())?
+            .ok_or(AuthError::UserNotFound)?;
+        if user.deactivated_at.is_some() {
+            return Err(AuthError::AccountDeactivated);
+        }
+
+        // A bot only acts for as long as its owner could
+        if let Some(owner_id) = &user.bot_owner_id {
+            let owner = self.db.get_user_by_id(owner_id.clone())?.ok_or(AuthError::InvalidToken)?;
+            if owner.deactivated_at.is_some() {
+                return Err(AuthError::AccountDeactivated);
+            }
+            if !owner.approved {
+                return Err(AuthError::PendingApproval);
+            }
+        }
+        self.db.touch_api_token(&api_token.id)?;
+
+        Ok((user, api_token.scopes))
+    }
+
+    /// What gets stored for API, reset and verification tokens, so a leaked
+    /// database doesn't hand out working tokens.
+    fn hash_token(token: &str) -> String {
+        Sha256::digest(token.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Promotes every existing account named in `admin_usernames`, returning
+    /// the usernames that were changed. Run once at startup; after that
+    /// `users.role` alone decides who is an admin.
+    pub fn bootstrap_admins(&self) -> Result<Vec<String>> {
+        let mut promoted = Vec::new();
+        for username in &self.config.admin_usernames {
+            if let Some(user) = self.db.get_user_by_username(username)? {
+                if user.role != UserRole::Admin {
+                    self.db.set_user_role(&user.id, UserRole::Admin)?;
+                    promoted.push(user.username);
+                }
+            }
+        }
+        Ok(promoted)
+    }
+
+    fn require_admin(&self, token: &str) -> Result<User> {
+        let user = self.validate_session(token)?;
+
+        if !user.is_admin() {
+            return Err(AuthError::PermissionDenied);
+        }
+
+        Ok(user)
+    }
+
+    /// Returns the caller and the bot if the session belongs to its owner or
+    /// an admin.
+    fn require_bot_owner(&self, owner_token: &str, bot_id: &str) -> Result<(User, User)> {
+        let owner = self.validate_session(owner_token)?;
+        let bot = self.db
+            .get_user_by_id(bot_id.to_string())?
+            .filter(|u| u.is_bot)
+            .ok_or(AuthError::UserNotFound)?;
+
+        if bot.bot_owner_id.as_deref() != Some(owner.id.as_str()) && !owner.is_admin() {
+            return Err(AuthError::PermissionDenied);
+        }
+
+        Ok((owner, bot))
+    }
+
+    /// Creates a bot account owned by the caller. Bots have no password and
+    /// can only authenticate with API tokens. Bots start verified and
+    /// approved, so their owner has to be both.
+    pub fn create_bot(&self, owner_token: &str, username: &str) -> Result<User> {
+        let owner = self.validate_session(owner_token)?;
+        if !owner.email_verified {
+            return Err(AuthError::EmailNotVerified);
+        }
+        if !owner.approved {
+            return Err(AuthError::PendingApproval);
+        }
+        self.validate_username(username)?;
+
+        let email = format!("{}@bots.invalid", username);
+        if self.db.get_user_by_username(username)?.is_some() || self.db.get_user_by_email(&email)?.is_some() {
+            return Err(AuthError::UserExists);
+        }
+
+        self.db.create_bot_user(username, &email, EXTERNAL_PASSWORD_HASH, &owner.id)
+    }
+
+    pub fn list_bots(&self, owner_token: &str) -> Result<Vec<User>> {
+        let owner = self.validate_session(owner_token)?;
+        self.db.get_bots_by_owner(&owner.id)
+    }
+
+    /// Issues a token for a bot. Tokens don't expire and stay valid until revoked.
+    pub fn create_api_token(&self, owner_token: &str, bot_id: &str, name: &str, scopes: Vec<ApiScope>) -> Result<NewApiToken> {
+        let (owner, bot) = self.require_bot_owner(owner_token, bot_id)?;
+
+        if scopes.is_empty() {
+            return Err(AuthError::InvalidInput("An API token needs at least one scope".to_string()));
+        }
+
+        let mut unique_scopes = Vec::new();
+        for scope in scopes {
+            if !unique_scopes.contains(&scope) {
+                unique_scopes.push(scope);
+            }
+        }
+
+        let token = format!("{}{}", API_TOKEN_PREFIX, self.generate_token());
+        let info = self.db.create_api_token(&bot.id, name, &Self::hash_token(&token), &unique_scopes)?;
+        self.db.append_audit_entry(&AuditEntry::new(&owner.id, AuditAction::ApiTokenCreated).with_target(&info.id))?;
+
+        Ok(NewApiToken { info, token })
+    }
+
+    pub fn list_api_tokens(&self, owner_token: &str, bot_id: &str) -> Result<Vec<ApiToken>> {
+        let (_, bot) = self.require_bot_owner(owner_token, bot_id)?;
+        self.db.get_api_tokens(&bot.id)
+    }
+
+    pub fn revoke_api_token(&self, owner_token: &str, token_id: &str) -> Result<()> {
+        let api_token = self.db.get_api_token(token_id)?.ok_or(AuthError::InvalidToken)?;
+        let (owner, _) = self.require_bot_owner(owner_token, &api_token.user_id)?;
+
+        self.db.revoke_api_token(&api_token.id)?;
+        self.db.append_audit_entry(&AuditEntry::new(&owner.id, AuditAction::ApiTokenRevoked).with_target(&api_token.id))
+    }
+
+    /// Creates an invite code. `max_uses` of `None` allows unlimited uses.
+    pub fn create_invite_code(&self, admin_token: &str, max_uses: Option<u32>, expires_in_hours: Option<i64>) -> Result<InviteCode> {
+        let admin = self.require_admin(admin_token)?;
+
+        if max_uses == Some(0) {
+            return Err(AuthError::InvalidInput("An invite code needs at least one use".to_string()));
+        }
+
+        let code: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        let expires_at = expires_in_hours.map(|hours| Utc::now() + Duration::hours(hours));
+
+        let invite = self.db.create_invite_code(&code, &admin.id, max_uses, expires_at)?;
+        self.db.append_audit_entry(&AuditEntry::new(&admin.id, AuditAction::InviteCreated).with_target(&invite.code))?;
+        Ok(invite)
+    }
+
+    pub fn list_invite_codes(&self, admin_token: &str) -> Result<Vec<InviteCode>> {
+        self.require_admin(admin_token)?;
+        self.db.get_invite_codes()
+    }
+
+    pub fn revoke_invite_code(&self, admin_token: &str, code: &str) -> Result<()> {
+        let admin = self.require_admin(admin_token)?;
+
+        if !self.db.revoke_invite_code(code)? {
+            return Err(AuthError::InvalidInviteCode);
+        }
+        self.db.append_audit_entry(&AuditEntry::new(&admin.id, AuditAction::InviteRevoked).with_target(code))
+    }
+
+    pub fn list_pending_users(&self, admin_token: &str) -> Result<Vec<User>> {
+        self.require_admin(admin_token)?;
+        self.db.get_pending_users()
+    }
+
+    pub fn approve_user(&self, admin_token: &str, user_id: &str) -> Result<User> {
+        let admin = self.require_admin(admin_token)?;
+
+        let user = self.db.get_user_by_id(user_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+        self.db.set_user_approved(&user.id, true)?;
+        self.db.append_audit_entry(&AuditEntry::new(&admin.id, AuditAction::UserApproved).with_target(&user.id))?;
+
+        Ok(User { approved: true, ..user })
+    }
+
+    /// Deletes an account that is still waiting for approval.
+    pub fn reject_user(&self, admin_token: &str, user_id: &str) -> Result<()> {
+        let admin = self.require_admin(admin_token)?;
+
+        let user = self.db.get_user_by_id(user_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+        if user.approved {
+            return Err(AuthError::InvalidInput("User has already been approved".to_string()));
+        }
+
+        self.db.delete_user(&user.id)?;
+        self.db.append_audit_entry(&AuditEntry::new(&admin.id, AuditAction::UserRejected).with_target(&user.id))
+    }
+
+    /// Sends on a blocking thread when running under Tokio, so a slow mail
+    /// server doesn't hold up everyone waiting on the `AuthService` lock.
+    /// Failures are logged rather than returned, which also keeps responses
+    /// the same whether or not an account exists.
+    fn deliver(&self, email: Email) {
+        let mailer = Arc::clone(&self.mailer);
+        let send = move || {
+            if let Err(e) = mailer.send(&email) {
+                warn!(error = %e, "failed to send account email");
+            }
+        };
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(send)),
+            Err(_) => send(),
+        }
+    }
+
+    fn send_verification_email(&self, user: &User) -> Result<()> {
+        let token = self.generate_token();
+        let expires_at = Utc::now() + self.config.verification_token_ttl;
+        self.db.create_email_verification(&user.id, &Self::hash_token(&token), expires_at)?;
+
+        self.deliver(Email {
+            to: user.email.clone(),
+            subject: "Verify your SpaRk email address".to_string(),
+            body: format!(
+                "Hi {},\r\n\r\nUse the following token to verify your email address:\r\n\r\n{}\r\n\r\nIt expires at {}.",
+                user.username, token, expires_at.to_rfc3339()
+            ),
+        });
+        Ok(())
+    }
+
+    /// Confirms the email address belonging to a verification token.
+    pub fn verify_email(&self, token: &str) -> Result<User> {
+        let token_hash = Self::hash_token(token);
+        let verification = self.db
+            .get_email_verification(&token_hash)?
+            .ok_or(AuthError::InvalidToken)?;
+
+        if verification.used_at.is_some() || verification.expires_at < Utc::now() {
+            return Err(AuthError::InvalidToken);
+        }
+
+        if !self.db.consume_email_verification(&token_hash)? {
+            return Err(AuthError::InvalidToken);
+        }
+
+        self.db.set_email_verified(&verification.user_id, true)?;
+
+        self.db
+            .get_user_by_id(verification.user_id)?
+            .ok_or(AuthError::UserNotFound)
+    }
+
+    /// Sends a fresh verification token to the session's user.
+    pub fn resend_verification(&self, session_token: &str) -> Result<()> {
+        let user = self.validate_session(session_token)?;
+
+        if user.email_verified {
+            return Err(AuthError::InvalidInput("Email address is already verified".to_string()));
+        }
+
+        self.send_verification_email(&user)
+    }
+
+    /// Changes the password of the session's user and revokes all of their
+    /// other sessions. The session used to make the request stays valid.
+    pub fn change_password(&self, request: ChangePasswordRequest) -> Result<()> {
+        let user = self.validate_session(&request.token)?;
+
+        if !self.verify_password(&request.current_password, &user.password_hash)? {
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        self.validate_password(&request.new_password)?;
+
+        let password_hash = self.hash_password(&request.new_password)?;
+        self.db.update_user_password(&user.id, &password_hash)?;
+        self.db.delete_user_sessions(&user.id, Some(&request.token))?;
+
+        Ok(())
+    }
+
+    /// Emails a single-use reset token to the account with this address.
+    /// Succeeds whether or not the address is registered so callers can't
+    /// probe for accounts.
+    pub fn request_password_reset(&self, email: &str) -> Result<()> {
+        let user = match self.db.get_user_by_email(email)? {
+            Some(user) if user.password_hash != EXTERNAL_PASSWORD_HASH => user,
+            _ => return Ok(()),
+        };
+
+        let token = self.generate_token();
+        let expires_at = Utc::now() + self.config.reset_token_ttl;
+        self.db.create_password_reset(&user.id, &Self::hash_token(&token), expires_at)?;
+
+        self.deliver(Email {
+            to: user.email,
+            subject: "SpaRk password reset".to_string(),
+            body: format!(
+                "Hi {},\r\n\r\nUse the following token to reset your password:\r\n\r\n{}\r\n\r\nIt expires at {}. If you didn't ask for a reset you can ignore this email.",
+                user.username, token, expires_at.to_rfc3339()
+            ),
+        });
+        Ok(())
+    }
+
+    /// Sets a new password using a reset token and signs the user out everywhere.
+    pub fn reset_password(&self, request: ResetPasswordRequest) -> Result<()> {
+        let token_hash = Self::hash_token(&request.token);
+        let reset = self.db
+            .get_password_reset(&token_hash)?
+            .ok_or(AuthError::InvalidToken)?;
+
+        if reset.used_at.is_some() || reset.expires_at < Utc::now() {
+            return Err(AuthError::InvalidToken);
+        }
+
+        self.validate_password(&request.new_password)?;
+
+        if !self.db.consume_password_reset(&token_hash)? {
+            return Err(AuthError::InvalidToken);
+        }
+
+        let password_hash = self.hash_password(&request.new_password)?;
+        self.db.update_user_password(&reset.user_id, &password_hash)?;
+        self.db.delete_user_sessions(&reset.user_id, None)?;
+
+        Ok(())
+    }
+
     pub fn cleanup_expired_sessions(&self) -> Result<()> {
         self.db.delete_expired_sessions()?;
+        self.db.delete_expired_password_resets()?;
+        self.db.delete_expired_email_verifications()?;
         Ok(())
     }
 }
 
 pub struct MessageService {
-    db: Database
+    db: Box<dyn Storage>,
+    /// Server-wide filters, run on every room message before the room's own
+    content_filter: FilterPipeline,
+    room_filters: RoomFilterCache,
 }
 
 impl MessageService {
     pub fn new(db: Database) -> Self {
-        Self { db }
+        Self::with_storage(Box::new(db))
+    }
+
+    pub fn with_storage(db: Box<dyn Storage>) -> Self {
+        Self { db, content_filter: FilterPipeline::new(), room_filters: RoomFilterCache::default() }
+    }
+
+    pub fn with_content_filter(mut self, content_filter: FilterPipeline) -> Self {
+        self.content_filter = content_filter;
+        self
+    }
+
+    /// Checks the database still answers, for readiness probes.
+    pub fn ping(&self) -> Result<()> {
+        self.db.ping()
     }
 
     fn validate_message_content(&self, content: &str) -> Result<()> {
@@ -165,21 +691,173 @@ impl MessageService {
         Ok(())
     }
 
-    pub fn send_room_message(&self, sender_id: &str, request: SendRoomMessageRequest) -> Result<(RoomMessageResponse, Vec<String>)> {
+    /// Runs a room message through the server's filters and the room's.
+    fn filter_room_message(&self, room_id: &str, content: &str) -> Result<FilterOutcome> {
+        let Some(filter) = self.db.get_room_filter(room_id)? else {
+            return Ok(self.content_filter.apply(content, &[]));
+        };
+        let room_filters = self.room_filters.get(room_id, &filter)?;
+        let extra: Vec<&dyn ContentFilter> = room_filters.iter().map(|f| f.as_ref()).collect();
+        Ok(self.content_filter.apply(content, &extra))
+    }
+
+    /// Unverified accounts can read but not post, react or create rooms.
+    fn ensure_verified(&self, user_id: &str) -> Result<User> {
+        let user = self.db.get_user_by_id(user_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+
+        if !user.email_verified {
+            return Err(AuthError::EmailNotVerified);
+        }
+
+        Ok(user)
+    }
+
+    fn get_room_or_err(&self, room_id: &str) -> Result<Room> {
+        self.db.get_room_by_id(room_id)?.ok_or(AuthError::InvalidInput("Room not found".to_string()))
+    }
+
+    /// Archived rooms keep their history but take no new messages,
+    /// reactions or pins.
+    fn ensure_room_writable(&self, room: &Room) -> Result<()> {
+        if room.archived_at.is_some() {
+            return Err(AuthError::InvalidInput("Room is archived".to_string()));
+        }
+        Ok(())
+    }
+
+    fn ensure_admin(&self, user_id: &str) -> Result<User> {
+        let user = self.db.get_user_by_id(user_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+
+        if !user.is_admin() {
+            return Err(AuthError::PermissionDenied);
+        }
+        Ok(user)
+    }
+
+    /// Only a room's creator or an admin may change or remove it.
+    fn ensure_room_manager(&self, room_id: &str, user_id: &str) -> Result<Room> {
+        let room = self.get_room_or_err(room_id)?;
+        let user = self.db.get_user_by_id(user_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+
+        if room.created_by != user.id && !user.is_admin() {
+            return Err(AuthError::PermissionDenied);
+        }
+        Ok(room)
+    }
+
+    fn ensure_not_sanctioned(&self, kind: SanctionKind, room_id: &str, user_id: &str) -> Result<()> {
+        let Some(sanction) = self.db.get_room_sanction(kind, room_id, user_id)? else {
+            return Ok(());
+        };
+
+        let what = match kind {
+            SanctionKind::Ban => "You are banned from this room",
+            SanctionKind::Mute => "You are muted in this room",
+        };
+        Err(AuthError::Forbidden(match sanction.expires_at {
+            Some(expires_at) => format!("{} until {}", what, expires_at.to_rfc3339()),
+            None => what.to_string(),
+        }))
+    }
+
+    /// Moderators can't act on themselves, the room's creator or an admin.
+    fn ensure_can_moderate(&self, room_id: &str, moderator_id: &str, target_id: &str) -> Result<User> {
+        let room = self.ensure_room_manager(room_id, moderator_id)?;
+        let target = self.db.get_user_by_id(target_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+
+        if target.id == moderator_id || target.id == room.created_by || target.is_admin() {
+            return Err(AuthError::Forbidden("This member can't be moderated".to_string()));
+        }
+        Ok(target)
+    }
+
+    fn sanction(&self, kind: SanctionKind, room_id: &str, moderator_id: &str, target_id: &str, reason: Option<&str>, duration_minutes: Option<i64>) -> Result<RoomSanction> {
+        let target = self.ensure_can_moderate(room_id, moderator_id, target_id)?;
+        if duration_minutes.is_some_and(|minutes| minutes <= 0) {
+            return Err(AuthError::InvalidInput("Duration must be a positive number of minutes".to_string()));
+        }
+
+        let now = Utc::now();
+        let sanction = RoomSanction {
+            room_id: room_id.to_string(),
+            user_id: target.id,
+            username: target.username,
+            reason: reason.map(str::trim).filter(|r| !r.is_empty()).map(str::to_string),
+            issued_by: moderator_id.to_string(),
+            created_at: now,
+            expires_at: duration_minutes.map(|minutes| now + Duration::minutes(minutes)),
+        };
+        self.db.add_room_sanction(kind, &sanction)?;
+
+        let action = match kind {
+            SanctionKind::Ban => AuditAction::MemberBanned,
+            SanctionKind::Mute => AuditAction::MemberMuted,
+        };
+        self.db.append_audit_entry(
+            &AuditEntry::new(moderator_id, action)
+                .with_room(room_id)
+                .with_target(&sanction.user_id)
+                .with_reason(sanction.reason.as_deref()),
+        )?;
+        Ok(sanction)
+    }
+
+    pub fn send_room_message(&self, sender_id: &str, request: SendRoomMessageRequest) -> Result<(RoomMessageResponse, MessageAudience)> {
         self.validate_message_content(&request.content)?;
 
-        if !self.db.is_user_in_room(&request.room_id, sender_id)? {
+        let sender = self.ensure_can_post(&request.room_id, sender_id)?;
+        let room = self.get_room_or_err(&request.room_id)?;
+        self.ensure_room_writable(&room)?;
+
+        let content = match self.filter_room_message(&room.id, &request.content)? {
+            FilterOutcome::Pass(content) => content,
+            FilterOutcome::Reject(reason) => return Err(AuthError::ContentRejected(reason)),
+            FilterOutcome::Hold(reason) => {
+                if let Some(reply_to_id) = &request.reply_to_message_id {
+                    self.db.get_message_by_id(reply_to_id)?
+                        .filter(|m| m.room_id.as_ref() == Some(&room.id))
+                        .ok_or(AuthError::InvalidInput("Reply message not found".to_string()))?;
+                }
+                let held = HeldMessage {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    room_id: room.id,
+                    sender_id: sender.id,
+                    content: request.content,
+                    reply_to_message_id: request.reply_to_message_id,
+                    reason: reason.clone(),
+                    created_at: Utc::now(),
+                };
+                self.db.hold_message(&held)?;
+                return Err(AuthError::MessageHeld { held_id: held.id, reason });
+            }
+        };
+
+        self.post_room_message(sender, room, &content, request.reply_to_message_id.as_deref())
+    }
+
+    /// What the sender must still satisfy for a room message to go out,
+    /// checked on sending and again when a held message is approved.
+    fn ensure_can_post(&self, room_id: &str, sender_id: &str) -> Result<User> {
+        if !self.db.is_user_in_room(room_id, sender_id)? {
             return Err(AuthError::InvalidInput("You are not a member of this room".to_string()));
         }
+        self.ensure_not_sanctioned(SanctionKind::Ban, room_id, sender_id)?;
+        self.ensure_not_sanctioned(SanctionKind::Mute, room_id, sender_id)?;
 
-        let room = self.db.get_room_by_id(&request.room_id)?.ok_or(AuthError::InvalidInput("Room not found".to_string()))?;
-        let sender = self.db.get_user_by_id(sender_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+        let sender = self.ensure_verified(sender_id)?;
+        if sender.deactivated_at.is_some() {
+            return Err(AuthError::AccountDeactivated);
+        }
+        Ok(sender)
+    }
 
+    /// Stores a room message that has passed every check and filter.
+    fn post_room_message(&self, sender: User, room: Room, content: &str, reply_to_message_id: Option<&str>) -> Result<(RoomMessageResponse, MessageAudience)> {
         let mut reply_context = None;
-        if let Some(reply_to_id) = &request.reply_to_message_id {
+        if let Some(reply_to_id) = reply_to_message_id {
             if let Some(reply_msg) = self.db.get_message_by_id(reply_to_id)? {
-                if reply_msg.room_id.as_ref() != Some(&request.room_id) {
-                    return Err(AuthError::InvalidInput("Reply message not in same room".to_string()).into());
+                if reply_msg.room_id.as_ref() != Some(&room.id) {
+                    return Err(AuthError::InvalidInput("Reply message not in same room".to_string()));
                 }
 
                 if let Ok(Some(reply_sender)) = self.db.get_user_by_id(reply_msg.sender_id) {
@@ -191,12 +869,23 @@ impl MessageService {
                     });
                 }
             } else {
-                return Err(AuthError::InvalidInput("Reply message not found".to_string()).into());
+                return Err(AuthError::InvalidInput("Reply message not found".to_string()));
             }
         }
 
-        let message = self.db.create_room_message(sender_id, &request.room_id, &request.content, request.reply_to_message_id.as_deref())?;
-        let mentioned_user_ids = self.db.save_message_mentions(&message.id, sender_id, &request.content, &request.room_id)?;
+        let message = self.db.create_room_message(&sender.id, &room.id, content, reply_to_message_id)?;
+        let mentioned_user_ids = self.db.save_message_mentions(&message.id, &sender.id, content, &room.id)?;
+
+        // Mentions from someone the user has blocked are kept but never notify
+        let blocked_by = self.db.get_blocker_ids(&sender.id)?;
+        let mut notify_user_ids = Vec::new();
+        for user_id in &mentioned_user_ids {
+            if blocked_by.contains(user_id) {
+                self.db.mark_mention_as_read(user_id, &message.id)?;
+            } else {
+                notify_user_ids.push(user_id.clone());
+            }
+        }
 
         let response = RoomMessageResponse {
             id: message.id,
@@ -208,16 +897,45 @@ impl MessageService {
             sent_at: message.sent_at,
             is_edited: message.is_edited,
             edited_at: message.edited_at,
-            mentions: mentioned_user_ids.clone(),
+            mentions: mentioned_user_ids,
             reply_to: reply_context,
+            display_name: None,
+            sender_blocked: false,
         };
 
-        Ok((response, mentioned_user_ids))
+        metrics().message_sent("room");
+        Ok((response, MessageAudience { notify_user_ids, blocked_by }))
     }
 
-    pub fn send_room_announcement(&self, sender_id: &str, request: SendRoomMessageRequest) -> Result<RoomMessageResponse> {
-        let room = self.db.get_room_by_id(&request.room_id)?.ok_or(AuthError::InvalidInput("Room not found".to_string()))?;
-        let message = self.db.room_announcement(&request.room_id, &request.content, sender_id)?;
+    /// Posts a message on behalf of an incoming webhook's bot, optionally
+    /// shown under a different name. It goes out live like any room message,
+    /// so who to notify is returned alongside it.
+    pub fn send_webhook_message(&self, sender_id: &str, room_id: &str, content: String, display_name: Option<String>) -> Result<(RoomMessageResponse, MessageAudience)> {
+        if let Some(name) = &display_name {
+            if name.trim().is_empty() || name.len() > 50 {
+                return Err(AuthError::InvalidInput("Username override must be between 1 and 50 characters".to_string()));
+            }
+        }
+
+        let request = SendRoomMessageRequest {
+            room_id: room_id.to_string(),
+            content,
+            reply_to_message_id: None,
+        };
+        let (mut response, audience) = self.send_room_message(sender_id, request)?;
+
+        if let Some(name) = display_name {
+            self.db.set_message_display_name(&response.id, name.trim())?;
+            response.display_name = Some(name.trim().to_string());
+        }
+
+        Ok((response, audience))
+    }
+
+    pub fn send_room_announcement(&self, sender_id: &str, request: SendRoomMessageRequest) -> Result<RoomMessageResponse> {
+        let room = self.get_room_or_err(&request.room_id)?;
+        self.ensure_room_writable(&room)?;
+        let message = self.db.room_announcement(&request.room_id, &request.content, sender_id)?;
 
         let reply_context = if let Some(reply_to_id) = &request.reply_to_message_id {
             if let Ok(Some(reply_msg)) = self.db.get_message_by_id(reply_to_id) {
@@ -250,10 +968,19 @@ impl MessageService {
             edited_at: message.edited_at,
             mentions: Vec::new(),
             reply_to: reply_context,
+            display_name: None,
+            sender_blocked: false,
         })
     }
 
-    pub fn get_room_messages(&self, room_id: &str, limit: usize, offset:usize) -> Result<Vec<RoomMessageResponse>> {
+    /// Ids of the users `user_id` has blocked.
+    fn blocked_user_ids(&self, user_id: &str) -> Result<HashSet<String>> {
+        Ok(self.db.get_blocked_users(user_id)?.into_iter().map(|block| block.user_id).collect())
+    }
+
+    /// `viewer_id` is the user asking, whose blocks decide `sender_blocked`.
+    pub fn get_room_messages(&self, room_id: &str, viewer_id: &str, limit: usize, offset:usize) -> Result<Vec<RoomMessageResponse>> {
+        let blocked = self.blocked_user_ids(viewer_id)?;
         let messages = self.db.get_room_messages(room_id, limit, offset)?;
         let room = self.db.get_room_by_id(room_id)?
             .ok_or(crate::error::AuthError::InvalidInput("Room not found".to_string()))?;
@@ -264,7 +991,7 @@ impl MessageService {
                 let mentions = self.db.get_message_mentions(&msg.id).unwrap_or_default();
 
                 let reply_context = if let Some(reply_to_id) = &msg.reply_to_message_id {
-                    if let Ok(Some(reply_msg)) = self.db.get_message_by_id(&reply_to_id) {
+                    if let Ok(Some(reply_msg)) = self.db.get_message_by_id(reply_to_id) {
                         if let Ok(Some(reply_sender)) = self.db.get_user_by_id(reply_msg.sender_id) {
                             Some(MessageReplyContext {
                                 id: reply_msg.id,
@@ -282,6 +1009,7 @@ impl MessageService {
                     None
                 };
 
+                let sender_blocked = !matches!(msg.message_type, MessageType::Server) && blocked.contains(&sender.id);
                 responses.push(RoomMessageResponse {
                     id: msg.id,
                     sender_username: match msg.message_type { MessageType::Server => "Server".to_string(), _=> sender.username},
@@ -294,6 +1022,8 @@ impl MessageService {
                     edited_at: msg.edited_at,
                     mentions,
                     reply_to: reply_context,
+                    display_name: msg.display_name,
+                    sender_blocked,
                 }); 
             }
         }
@@ -302,9 +1032,13 @@ impl MessageService {
     }
 
     pub fn send_private_message(&self, sender_id: &str, request: SendPrivateMessageRequest) -> Result<PrivateMessageResponse> {
+        let sender = self.ensure_verified(sender_id)?;
         let receiver = self.db.get_user_by_username(&request.receiver_username)?.ok_or(AuthError::UserNotFound)?;
-        let sender = self.db.get_user_by_id(sender_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+        if self.db.is_user_blocked(&receiver.id, sender_id)? {
+            return Err(AuthError::Forbidden("This user isn't accepting messages from you".to_string()));
+        }
         let message = self.db.create_private_message(sender_id, &receiver.id, &request.content)?;
+        metrics().message_sent("direct");
 
         Ok(PrivateMessageResponse { 
             id: message.id, 
@@ -352,6 +1086,7 @@ impl MessageService {
     }
 
     pub fn get_user_mentions(&self, user_id: &str, limit: usize, offset:usize) -> Result<Vec<RoomMessageResponse>> {
+        let blocked = self.blocked_user_ids(user_id)?;
         let messages = self.db.get_all_user_mentions(user_id, limit, offset)?;
 
         let mut responses = Vec::new();
@@ -362,7 +1097,7 @@ impl MessageService {
                         let mentions = self.db.get_message_mentions(&message.id).unwrap_or_default();
 
                         let reply_context = if let Some(reply_to_id) = &message.reply_to_message_id {
-                            if let Ok(Some(reply_msg)) = self.db.get_message_by_id(&reply_to_id) {
+                            if let Ok(Some(reply_msg)) = self.db.get_message_by_id(reply_to_id) {
                                 if let Ok(Some(reply_sender)) = self.db.get_user_by_id(reply_msg.sender_id) {
                                     Some(MessageReplyContext {
                                         id: reply_msg.id,
@@ -380,6 +1115,7 @@ impl MessageService {
                             None
                         };
 
+                        let sender_blocked = blocked.contains(&sender.id);
                         responses.push(RoomMessageResponse {
                             id: message.id,
                             sender_username: sender.username,
@@ -392,6 +1128,8 @@ impl MessageService {
                             edited_at: message.edited_at,
                             mentions,
                             reply_to: reply_context,
+                            display_name: message.display_name,
+                            sender_blocked,
                         })
                     }
                 }
@@ -412,7 +1150,11 @@ impl MessageService {
             return Err(AuthError::InvalidInput("Message does not belong to this room".to_string()));
         }
 
+        self.ensure_room_writable(&self.get_room_or_err(room_id)?)?;
+        self.ensure_not_sanctioned(SanctionKind::Mute, room_id, user_id)?;
+
         self.db.pin_message(message_id, user_id)?;
+        self.db.append_audit_entry(&AuditEntry::new(user_id, AuditAction::MessagePinned).with_room(room_id).with_target(message_id))?;
         Ok(Utc::now())
     }
 
@@ -428,12 +1170,18 @@ impl MessageService {
             return Err(AuthError::InvalidInput("Message does not belong to this room".to_string()));
         }
 
+        self.ensure_room_writable(&self.get_room_or_err(room_id)?)?;
+        self.ensure_not_sanctioned(SanctionKind::Mute, room_id, user_id)?;
+
         self.db.unpin_message(message_id)?;
+        self.db.append_audit_entry(&AuditEntry::new(user_id, AuditAction::MessageUnpinned).with_room(room_id).with_target(message_id))?;
         Ok(())
     }
 
-    pub fn get_pinned_messages(&self, room_id: &str) -> Result<Vec<RoomMessageResponse>> {
+    pub fn get_pinned_messages(&self, room_id: &str, viewer_id: &str) -> Result<Vec<RoomMessageResponse>> {
+        let blocked = self.blocked_user_ids(viewer_id)?;
         let messages = self.db.get_pinned_messages(room_id)?;
+        let mention_regex = Regex::new(r"@(\w+)").unwrap();
         let mut responses = Vec::new();
 
         for msg in messages {
@@ -461,13 +1209,13 @@ impl MessageService {
                 None
             };
 
-            let mention_regex = Regex::new(r"@(\w+)").unwrap();
             let mentions = mention_regex.captures_iter(&msg.content)
                 .map(|cap| cap[1].to_string())
                 .collect();
 
             responses.push(RoomMessageResponse {
                 id: msg.id,
+                sender_blocked: blocked.contains(&sender.id),
                 sender_username: sender.username,
                 message_type: msg.message_type,
                 room_id: room.id,
@@ -478,6 +1226,7 @@ impl MessageService {
                 edited_at: msg.edited_at,
                 mentions,
                 reply_to,
+                display_name: msg.display_name,
             })
         }
         Ok(responses)
@@ -499,12 +1248,37 @@ impl MessageService {
         self.db.get_unread_private_message_count(user_id)
     }
 
-    pub fn delete_message(&self, user_id: &str, message_id: &str) -> Result<()> {
-        self.db.delete_message(message_id, user_id)?;
-        Ok(())
+    /// Authors may delete their own messages and admins anyone's; the
+    /// latter is audited.
+    /// The room a message was posted in; `None` for DMs and unknown ids.
+    pub fn message_room_id(&self, message_id: &str) -> Result<Option<String>> {
+        Ok(self.db.get_message_by_id(message_id)?.and_then(|m| m.room_id))
+    }
+
+    /// Returns the deleted message's room, so callers notify that room
+    /// rather than one named by the client.
+    pub fn delete_message(&self, user_id: &str, message_id: &str) -> Result<Option<String>> {
+        let message = self.db
+            .get_message_by_id(message_id)?
+            .ok_or(AuthError::InvalidInput("Message not found".to_string()))?;
+
+        if message.sender_id != user_id {
+            self.ensure_admin(user_id)?;
+        }
+        self.db.delete_message(message_id, &message.sender_id)?;
+
+        if message.sender_id != user_id {
+            let mut entry = AuditEntry::new(user_id, AuditAction::MessageDeleted).with_target(message_id);
+            if let Some(room_id) = &message.room_id {
+                entry = entry.with_room(room_id);
+            }
+            self.db.append_audit_entry(&entry)?;
+        }
+        Ok(message.room_id)
     }
 
     pub fn join_room(&self, user_id: &str, room_id: &str) -> Result<()> {
+        self.ensure_not_sanctioned(SanctionKind::Ban, room_id, user_id)?;
         self.db.add_user_to_room(room_id, user_id)?;
         Ok(())
     }
@@ -519,19 +1293,413 @@ impl MessageService {
     }
 
     pub fn create_room(&self, creator_id: &str, name: &str, desc: &str) ->  Result<Room> {
+        self.ensure_verified(creator_id)?;
         self.db.create_room(name, desc, creator_id)
     }
 
-    pub fn get_all_rooms(&self) -> Result<Vec<Room>> {
-        self.db.get_all_rooms()
+    /// Archived rooms are only listed when asked for.
+    pub fn get_all_rooms(&self, include_archived: bool) -> Result<Vec<Room>> {
+        self.db.get_all_rooms(include_archived)
     }
 
-    pub fn edit_message(&self, message_id: &str, new_content: &str) -> Result<()> {
-        self.validate_message_content(new_content)?;
-        self.db.edit_message(message_id, new_content)?;
+    /// Changes whichever of the name, description and topic are given.
+    pub fn update_room(&self, room_id: &str, user_id: &str, name: Option<&str>, desc: Option<&str>, topic: Option<&str>) -> Result<Room> {
+        let mut room = self.ensure_room_manager(room_id, user_id)?;
+
+        if let Some(name) = name {
+            if name.trim().is_empty() {
+                return Err(AuthError::InvalidInput("Room name cannot be empty".to_string()));
+            }
+            room.name = name.trim().to_string();
+        }
+        if let Some(desc) = desc {
+            room.desc = desc.to_string();
+        }
+        if let Some(topic) = topic {
+            room.topic = topic.to_string();
+        }
+
+        self.db.update_room(room_id, &room.name, &room.desc, &room.topic)?;
+        self.db.append_audit_entry(&AuditEntry::new(user_id, AuditAction::RoomUpdated).with_room(room_id))?;
+        Ok(room)
+    }
+
+    pub fn archive_room(&self, room_id: &str, user_id: &str) -> Result<DateTime<Utc>> {
+        let room = self.ensure_room_manager(room_id, user_id)?;
+        if room.archived_at.is_some() {
+            return Err(AuthError::InvalidInput("Room is already archived".to_string()));
+        }
+
+        let archived_at = self.db.set_room_archived(room_id, true)?;
+        self.db.append_audit_entry(&AuditEntry::new(user_id, AuditAction::RoomArchived).with_room(room_id))?;
+        Ok(archived_at.unwrap_or_else(Utc::now))
+    }
+
+    pub fn unarchive_room(&self, room_id: &str, user_id: &str) -> Result<()> {
+        let room = self.ensure_room_manager(room_id, user_id)?;
+        if room.archived_at.is_none() {
+            return Err(AuthError::InvalidInput("Room is not archived".to_string()));
+        }
+
+        self.db.set_room_archived(room_id, false)?;
+        self.db.append_audit_entry(&AuditEntry::new(user_id, AuditAction::RoomUnarchived).with_room(room_id))?;
         Ok(())
     }
 
+    pub fn delete_room(&self, room_id: &str, user_id: &str) -> Result<()> {
+        self.ensure_room_manager(room_id, user_id)?;
+        self.db.delete_room(room_id)?;
+        self.db.append_audit_entry(&AuditEntry::new(user_id, AuditAction::RoomDeleted).with_room(room_id))
+    }
+
+    /// Removes a member, who is free to rejoin. Returns who was kicked.
+    pub fn kick_member(&self, room_id: &str, moderator_id: &str, target_id: &str, reason: Option<&str>) -> Result<User> {
+        let target = self.ensure_can_moderate(room_id, moderator_id, target_id)?;
+        if !self.db.is_user_in_room(room_id, target_id)? {
+            return Err(AuthError::InvalidInput("User is not a member of this room".to_string()));
+        }
+
+        self.db.remove_user_from_room(room_id, target_id)?;
+        self.db.append_audit_entry(
+            &AuditEntry::new(moderator_id, AuditAction::MemberKicked)
+                .with_room(room_id)
+                .with_target(target_id)
+                .with_reason(reason.map(str::trim).filter(|r| !r.is_empty())),
+        )?;
+        Ok(target)
+    }
+
+    /// Removes the user from the room and keeps them out until the ban
+    /// expires or is lifted. Without a duration the ban is permanent.
+    pub fn ban_member(&self, room_id: &str, moderator_id: &str, target_id: &str, reason: Option<&str>, duration_minutes: Option<i64>) -> Result<RoomSanction> {
+        let ban = self.sanction(SanctionKind::Ban, room_id, moderator_id, target_id, reason, duration_minutes)?;
+        self.db.remove_user_from_room(room_id, target_id)?;
+        Ok(ban)
+    }
+
+    /// Stops a member posting or reacting until the mute expires or is
+    /// lifted. They can still read the room.
+    pub fn mute_member(&self, room_id: &str, moderator_id: &str, target_id: &str, reason: Option<&str>, duration_minutes: Option<i64>) -> Result<RoomSanction> {
+        self.sanction(SanctionKind::Mute, room_id, moderator_id, target_id, reason, duration_minutes)
+    }
+
+    pub fn lift_sanction(&self, kind: SanctionKind, room_id: &str, moderator_id: &str, target_id: &str) -> Result<()> {
+        self.ensure_room_manager(room_id, moderator_id)?;
+        if !self.db.remove_room_sanction(kind, room_id, target_id)? {
+            return Err(AuthError::InvalidInput(match kind {
+                SanctionKind::Ban => "User is not banned from this room".to_string(),
+                SanctionKind::Mute => "User is not muted in this room".to_string(),
+            }));
+        }
+
+        let action = match kind {
+            SanctionKind::Ban => AuditAction::MemberUnbanned,
+            SanctionKind::Mute => AuditAction::MemberUnmuted,
+        };
+        self.db.append_audit_entry(&AuditEntry::new(moderator_id, action).with_room(room_id).with_target(target_id))
+    }
+
+    /// The room's bans or mutes still in force, for its moderators.
+    pub fn get_room_sanctions(&self, kind: SanctionKind, room_id: &str, user_id: &str) -> Result<Vec<RoomSanction>> {
+        self.ensure_room_manager(room_id, user_id)?;
+        self.db.get_room_sanctions(kind, room_id)
+    }
+
+    /// Room managers may read their room's entries; the whole log, or a
+    /// filter without a room, is for admins only.
+    fn ensure_can_read_audit_log(&self, user_id: &str, filter: &AuditLogFilter) -> Result<()> {
+        match &filter.room_id {
+            Some(room_id) => self.ensure_room_manager(room_id, user_id).map(|_| ()),
+            None => self.ensure_admin(user_id).map(|_| ()),
+        }
+    }
+
+    /// Newest first, at most 200 entries per page.
+    pub fn get_audit_log(&self, user_id: &str, filter: &AuditLogFilter, limit: usize, offset: usize) -> Result<Vec<AuditEntry>> {
+        self.ensure_can_read_audit_log(user_id, filter)?;
+        self.db.get_audit_log(filter, limit.min(200), offset)
+    }
+
+    /// Every matching entry, newest first, as JSON lines.
+    pub fn export_audit_log(&self, user_id: &str, filter: &AuditLogFilter) -> Result<String> {
+        const PAGE: usize = 500;
+        self.ensure_can_read_audit_log(user_id, filter)?;
+
+        let mut entries = Vec::new();
+        loop {
+            let page = self.db.get_audit_log(filter, PAGE, entries.len())?;
+            let done = page.len() < PAGE;
+            entries.extend(page);
+            if done {
+                break;
+            }
+        }
+        Ok(to_json_lines(&entries))
+    }
+
+    /// The room's creator and every active admin.
+    fn room_moderator_ids(&self, room: &Room) -> Result<Vec<String>> {
+        let mut ids = vec![room.created_by.clone()];
+        let admins = UserSearch { role: Some(UserRole::Admin), deactivated: Some(false), ..Default::default() };
+        for admin in self.db.search_users(&admins, 1000, 0)? {
+            if !ids.contains(&admin.id) {
+                ids.push(admin.id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Files a report on a room message, keeping a copy of its content.
+    /// Returns the report and the moderators to notify.
+    pub fn report_message(&self, reporter_id: &str, message_id: &str, reason: &str) -> Result<(MessageReport, Vec<String>)> {
+        let reason = reason.trim();
+        if reason.is_empty() {
+            return Err(AuthError::InvalidInput("A report needs a reason".to_string()));
+        }
+        if reason.chars().count() > 1000 {
+            return Err(AuthError::InvalidInput("Report reason too long (max 1,000 characters)".to_string()));
+        }
+
+        let message = self.db.get_message_by_id(message_id)?
+            .ok_or(AuthError::InvalidInput("Message not found".to_string()))?;
+        let room_id = match (&message.message_type, &message.room_id) {
+            (MessageType::Room, Some(room_id)) => room_id.clone(),
+            _ => return Err(AuthError::InvalidInput("Only room messages can be reported".to_string())),
+        };
+        if !self.db.is_user_in_room(&room_id, reporter_id)? {
+            return Err(AuthError::InvalidInput("You are not a member of this room".to_string()));
+        }
+        if message.sender_id == reporter_id {
+            return Err(AuthError::InvalidInput("You can't report your own message".to_string()));
+        }
+
+        let report = MessageReport {
+            id: uuid::Uuid::new_v4().to_string(),
+            room_id,
+            message_id: message.id,
+            sender_id: message.sender_id,
+            content: message.content,
+            reporter_id: reporter_id.to_string(),
+            reason: reason.to_string(),
+            status: ReportStatus::Open,
+            created_at: Utc::now(),
+            resolved_by: None,
+            resolved_at: None,
+            action: None,
+            resolution_note: None,
+        };
+        if !self.db.create_report(&report)? {
+            return Err(AuthError::InvalidInput("You have already reported this message".to_string()));
+        }
+
+        let moderators = self.room_moderator_ids(&self.get_room_or_err(&report.room_id)?)?;
+        Ok((report, moderators))
+    }
+
+    /// The moderation queue: one room's reports for its moderators, or
+    /// every room's for admins.
+    pub fn get_reports(&self, user_id: &str, room_id: Option<&str>, status: Option<ReportStatus>, limit: usize, offset: usize) -> Result<Vec<MessageReport>> {
+        match room_id {
+            Some(room_id) => self.ensure_room_manager(room_id, user_id).map(|_| ())?,
+            None => self.ensure_admin(user_id).map(|_| ())?,
+        }
+        self.db.get_reports(room_id, status, limit.min(200), offset)
+    }
+
+    fn get_open_report(&self, report_id: &str, moderator_id: &str) -> Result<MessageReport> {
+        let report = self.db.get_report(report_id)?.ok_or(AuthError::InvalidInput("Report not found".to_string()))?;
+        self.ensure_room_manager(&report.room_id, moderator_id)?;
+
+        if report.status != ReportStatus::Open {
+            return Err(AuthError::InvalidInput("Report has already been closed".to_string()));
+        }
+        Ok(report)
+    }
+
+    fn close_report(&self, report: MessageReport, moderator_id: &str, status: ReportStatus, action: Option<ReportAction>, note: Option<&str>) -> Result<MessageReport> {
+        let report = MessageReport {
+            status,
+            resolved_by: Some(moderator_id.to_string()),
+            resolved_at: Some(Utc::now()),
+            action,
+            resolution_note: note.map(str::trim).filter(|n| !n.is_empty()).map(str::to_string),
+            ..report
+        };
+        if !self.db.close_report(&report)? {
+            return Err(AuthError::InvalidInput("Report has already been closed".to_string()));
+        }
+
+        let audit_action = match status {
+            ReportStatus::Dismissed => AuditAction::ReportDismissed,
+            _ => AuditAction::ReportResolved,
+        };
+        self.db.append_audit_entry(
+            &AuditEntry::new(moderator_id, audit_action)
+                .with_room(&report.room_id)
+                .with_target(&report.id)
+                .with_reason(report.resolution_note.as_deref()),
+        )?;
+        Ok(report)
+    }
+
+    /// Acts on an open report and closes it: deletes the message, or mutes or
+    /// bans its author (for `duration_minutes`, if given). Returns the closed
+    /// report and any sanction issued.
+    pub fn resolve_report(&self, report_id: &str, moderator_id: &str, action: ReportAction, note: Option<&str>, duration_minutes: Option<i64>) -> Result<(MessageReport, Option<RoomSanction>)> {
+        let report = self.get_open_report(report_id, moderator_id)?;
+        let reason = note.or(Some(&report.reason));
+
+        let sanction = match action {
+            ReportAction::NoAction => None,
+            ReportAction::DeleteMessage => {
+                // The author may have deleted it already
+                if let Some(message) = self.db.get_message_by_id(&report.message_id)? {
+                    self.db.delete_message(&message.id, &message.sender_id)?;
+                    self.db.append_audit_entry(
+                        &AuditEntry::new(moderator_id, AuditAction::MessageDeleted)
+                            .with_room(&report.room_id)
+                            .with_target(&message.id)
+                            .with_reason(reason),
+                    )?;
+                }
+                None
+            }
+            ReportAction::Mute => Some(self.mute_member(&report.room_id, moderator_id, &report.sender_id, reason, duration_minutes)?),
+            ReportAction::Ban => Some(self.ban_member(&report.room_id, moderator_id, &report.sender_id, reason, duration_minutes)?),
+        };
+
+        let report = self.close_report(report, moderator_id, ReportStatus::Resolved, Some(action), note)?;
+        Ok((report, sanction))
+    }
+
+    /// Closes an open report without acting on it.
+    pub fn dismiss_report(&self, report_id: &str, moderator_id: &str, note: Option<&str>) -> Result<MessageReport> {
+        let report = self.get_open_report(report_id, moderator_id)?;
+        self.close_report(report, moderator_id, ReportStatus::Dismissed, None, note)
+    }
+
+    /// Only the sender can edit a message, and only through the room it was
+    /// posted in. Returns the content as stored, which filters may have
+    /// masked.
+    pub fn edit_message(&self, user_id: &str, room_id: &str, message_id: &str, new_content: &str) -> Result<String> {
+        self.validate_message_content(new_content)?;
+        let message = self.db.get_message_by_id(message_id)?
+            .filter(|m| m.room_id.as_deref() == Some(room_id))
+            .ok_or(AuthError::InvalidInput("Message not found".to_string()))?;
+        if message.sender_id != user_id {
+            return Err(AuthError::PermissionDenied);
+        }
+
+        self.ensure_room_writable(&self.get_room_or_err(room_id)?)?;
+        self.ensure_not_sanctioned(SanctionKind::Mute, room_id, user_id)?;
+        let content = match self.filter_room_message(room_id, new_content)? {
+            FilterOutcome::Pass(content) => content,
+            FilterOutcome::Reject(reason) => return Err(AuthError::ContentRejected(reason)),
+            FilterOutcome::Hold(reason) => {
+                return Err(AuthError::ContentRejected(format!("{}; edits can't be held for review", reason)));
+            }
+        };
+        self.db.edit_message(message_id, &content)?;
+        Ok(content)
+    }
+
+    /// Empty if the room has no filter of its own. Room managers only.
+    pub fn get_room_filter(&self, room_id: &str, user_id: &str) -> Result<RoomFilter> {
+        self.ensure_room_manager(room_id, user_id)?;
+        Ok(self.db.get_room_filter(room_id)?.unwrap_or_default())
+    }
+
+    /// Replaces the room's blocked words, link lists and the action taken
+    /// on them.
+    pub fn set_room_filter(&self, room_id: &str, user_id: &str, filter: RoomFilter) -> Result<RoomFilter> {
+        self.ensure_room_manager(room_id, user_id)?;
+
+        let clean = |entries: &[String]| -> Vec<String> {
+            entries.iter()
+                .map(|e| e.trim().to_string())
+                .filter(|e| !e.is_empty())
+                .collect()
+        };
+        let filter = RoomFilter {
+            blocked_words: clean(&filter.blocked_words),
+            allowed_link_domains: clean(&filter.allowed_link_domains),
+            denied_link_domains: clean(&filter.denied_link_domains),
+            ..filter
+        };
+        if filter.blocked_words.len() > 500 {
+            return Err(AuthError::InvalidInput("Too many blocked words (max 500)".to_string()));
+        }
+        if filter.blocked_words.iter().any(|w| w.chars().count() > 200) {
+            return Err(AuthError::InvalidInput("Blocked word too long (max 200 characters)".to_string()));
+        }
+        let domains = || filter.allowed_link_domains.iter().chain(&filter.denied_link_domains);
+        if filter.allowed_link_domains.len() > 500 || filter.denied_link_domains.len() > 500 {
+            return Err(AuthError::InvalidInput("Too many link domains (max 500)".to_string()));
+        }
+        if domains().any(|d| d.len() > 253) {
+            return Err(AuthError::InvalidInput("Link domain too long (max 253 characters)".to_string()));
+        }
+        filter.compile()?;
+
+        self.db.set_room_filter(room_id, &filter)?;
+        self.room_filters.invalidate(room_id);
+        self.db.append_audit_entry(&AuditEntry::new(user_id, AuditAction::RoomFilterUpdated).with_room(room_id))?;
+        Ok(filter)
+    }
+
+    /// The held message and the moderators to tell about it, for use right
+    /// after a send comes back with `AuthError::MessageHeld`.
+    pub fn held_message_moderators(&self, held_id: &str) -> Result<(HeldMessage, Vec<String>)> {
+        let held = self.db.get_held_message(held_id)?.ok_or(AuthError::InvalidInput("Held message not found".to_string()))?;
+        let moderators = self.room_moderator_ids(&self.get_room_or_err(&held.room_id)?)?;
+        Ok((held, moderators))
+    }
+
+    /// The room's review queue, oldest first. Room managers only.
+    pub fn get_held_messages(&self, room_id: &str, user_id: &str, limit: usize, offset: usize) -> Result<Vec<HeldMessage>> {
+        self.ensure_room_manager(room_id, user_id)?;
+        self.db.get_held_messages(room_id, limit.min(200), offset)
+    }
+
+    fn get_held_message_or_err(&self, held_id: &str, moderator_id: &str) -> Result<HeldMessage> {
+        let held = self.db.get_held_message(held_id)?.ok_or(AuthError::InvalidInput("Held message not found".to_string()))?;
+        self.ensure_room_manager(&held.room_id, moderator_id)?;
+        Ok(held)
+    }
+
+    /// Posts a held message as its sender, as written. Returns the posted
+    /// message and who to notify of it.
+    pub fn approve_held_message(&self, held_id: &str, moderator_id: &str) -> Result<(RoomMessageResponse, MessageAudience)> {
+        let held = self.get_held_message_or_err(held_id, moderator_id)?;
+        let room = self.get_room_or_err(&held.room_id)?;
+        self.ensure_room_writable(&room)?;
+        let sender = self.ensure_can_post(&held.room_id, &held.sender_id)?;
+
+        let posted = self.post_room_message(sender, room, &held.content, held.reply_to_message_id.as_deref())?;
+        self.db.remove_held_message(&held.id)?;
+        self.db.append_audit_entry(
+            &AuditEntry::new(moderator_id, AuditAction::HeldMessageApproved)
+                .with_room(&held.room_id)
+                .with_target(&held.id),
+        )?;
+        Ok(posted)
+    }
+
+    /// Discards a held message without posting it.
+    pub fn reject_held_message(&self, held_id: &str, moderator_id: &str, reason: Option<&str>) -> Result<HeldMessage> {
+        let held = self.get_held_message_or_err(held_id, moderator_id)?;
+        if !self.db.remove_held_message(&held.id)? {
+            return Err(AuthError::InvalidInput("Held message not found".to_string()));
+        }
+        self.db.append_audit_entry(
+            &AuditEntry::new(moderator_id, AuditAction::HeldMessageRejected)
+                .with_room(&held.room_id)
+                .with_target(&held.id)
+                .with_reason(reason.map(str::trim).filter(|r| !r.is_empty())),
+        )?;
+        Ok(held)
+    }
+
     pub fn get_user_rooms(&self, user_id: &str) -> Result<Vec<Room>> {
         self.db.get_user_rooms(user_id)
     }
@@ -541,6 +1709,139 @@ impl MessageService {
         Ok(())
     }
 
+    pub fn block_user(&self, user_id: &str, target_id: &str) -> Result<UserBlock> {
+        let target = self.db.get_user_by_id(target_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+        if target.id == user_id {
+            return Err(AuthError::InvalidInput("You can't block yourself".to_string()));
+        }
+        if !self.db.block_user(user_id, &target.id)? {
+            return Err(AuthError::InvalidInput("User is already blocked".to_string()));
+        }
+
+        Ok(UserBlock { user_id: target.id, username: target.username, created_at: Utc::now() })
+    }
+
+    pub fn unblock_user(&self, user_id: &str, target_id: &str) -> Result<()> {
+        if !self.db.unblock_user(user_id, target_id)? {
+            return Err(AuthError::InvalidInput("User is not blocked".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn get_blocked_users(&self, user_id: &str) -> Result<Vec<UserBlock>> {
+        self.db.get_blocked_users(user_id)
+    }
+
+    // Server administration
+
+    pub fn list_users(&self, admin_id: &str, search: &UserSearch, limit: usize, offset: usize) -> Result<Vec<UserAccount>> {
+        self.ensure_admin(admin_id)?;
+        let users = self.db.search_users(search, limit.min(200), offset)?;
+        Ok(users.into_iter().map(UserAccount::from).collect())
+    }
+
+    /// Admins can't act on themselves or on another active admin, who has
+    /// to be demoted first.
+    fn ensure_can_administer(&self, admin_id: &str, user_id: &str) -> Result<User> {
+        self.ensure_admin(admin_id)?;
+        let user = self.db.get_user_by_id(user_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+
+        if user.id == admin_id || user.is_admin() {
+            return Err(AuthError::PermissionDenied);
+        }
+        Ok(user)
+    }
+
+    /// Blocks sign in and ends every session; the account and its history
+    /// are kept.
+    pub fn deactivate_user(&self, admin_id: &str, user_id: &str, reason: Option<&str>) -> Result<UserAccount> {
+        let mut user = self.ensure_can_administer(admin_id, user_id)?;
+        if user.deactivated_at.is_some() {
+            return Err(AuthError::InvalidInput("User is already deactivated".to_string()));
+        }
+
+        let now = Utc::now();
+        self.db.set_user_deactivated(user_id, Some(now))?;
+        self.db.delete_user_sessions(user_id, None)?;
+        self.db.append_audit_entry(
+            &AuditEntry::new(admin_id, AuditAction::UserDeactivated)
+                .with_target(user_id)
+                .with_reason(reason),
+        )?;
+
+        user.deactivated_at = Some(now);
+        Ok(UserAccount::from(user))
+    }
+
+    pub fn reactivate_user(&self, admin_id: &str, user_id: &str) -> Result<UserAccount> {
+        self.ensure_admin(admin_id)?;
+        let mut user = self.db.get_user_by_id(user_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+        if user.deactivated_at.is_none() {
+            return Err(AuthError::InvalidInput("User is not deactivated".to_string()));
+        }
+
+        self.db.set_user_deactivated(user_id, None)?;
+        self.db.append_audit_entry(&AuditEntry::new(admin_id, AuditAction::UserReactivated).with_target(user_id))?;
+
+        user.deactivated_at = None;
+        Ok(UserAccount::from(user))
+    }
+
+    /// Ends every session the user has. Open connections are closed by the
+    /// caller.
+    pub fn force_logout(&self, admin_id: &str, user_id: &str) -> Result<()> {
+        self.ensure_can_administer(admin_id, user_id)?;
+        self.db.delete_user_sessions(user_id, None)?;
+        self.db.append_audit_entry(&AuditEntry::new(admin_id, AuditAction::UserLoggedOut).with_target(user_id))
+    }
+
+    /// Admins can't change their own role, so the server always keeps one.
+    pub fn set_user_role(&self, admin_id: &str, user_id: &str, role: UserRole) -> Result<UserAccount> {
+        self.ensure_admin(admin_id)?;
+        if user_id == admin_id {
+            return Err(AuthError::InvalidInput("You can't change your own role".to_string()));
+        }
+        let mut user = self.db.get_user_by_id(user_id.to_string())?.ok_or(AuthError::UserNotFound)?;
+        if user.role == role {
+            return Ok(UserAccount::from(user));
+        }
+
+        self.db.set_user_role(user_id, role)?;
+        self.db.append_audit_entry(
+            &AuditEntry::new(admin_id, AuditAction::UserRoleChanged)
+                .with_target(user_id)
+                .with_reason(Some(role.as_str())),
+        )?;
+
+        user.role = role;
+        Ok(UserAccount::from(user))
+    }
+
+    /// Posts a `Server` message to every room that isn't archived.
+    pub fn announce_to_rooms(&self, admin_id: &str, content: &str) -> Result<Vec<RoomMessageResponse>> {
+        self.ensure_admin(admin_id)?;
+        self.validate_message_content(content)?;
+
+        let mut messages = Vec::new();
+        for room in self.db.get_all_rooms(false)? {
+            messages.push(self.send_room_announcement(admin_id, SendRoomMessageRequest {
+                room_id: room.id,
+                content: content.to_string(),
+                reply_to_message_id: None,
+            })?);
+        }
+        self.db.append_audit_entry(&AuditEntry::new(admin_id, AuditAction::ServerAnnouncement).with_reason(Some(content)))?;
+        Ok(messages)
+    }
+
+    /// Checks and audits an announcement for everyone connected. Nothing is
+    /// stored; delivery is up to the caller.
+    pub fn announce_to_online_users(&self, admin_id: &str, content: &str) -> Result<()> {
+        self.ensure_admin(admin_id)?;
+        self.validate_message_content(content)?;
+        self.db.append_audit_entry(&AuditEntry::new(admin_id, AuditAction::ServerAnnouncement).with_reason(Some(content)))
+    }
+
     pub fn update_user_status(&self, user_id: &str, status: &str) -> Result<()> {
         self.db.update_user_status(user_id, Some(status))?;
         Ok(())
@@ -562,20 +1863,62 @@ impl MessageService {
         self.db.mark_room_mentions_as_read(user_id, room_id)
     }
 
-    pub fn add_reaction(&self, message_id: &str, user_id: &str, username: &str, emoji: &str) -> Result<Vec<ReactionSummary>> {
-        self.db.add_reaction(message_id, user_id, username, emoji)
+    pub fn add_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<Vec<ReactionSummary>> {
+        self.ensure_verified(user_id)?;
+        if emoji.trim().is_empty() {
+            return Err(AuthError::InvalidInput("Emoji cannot be empty".to_string()));
+        }
+        let message = self.db.get_message_by_id(message_id)?
+            .ok_or(AuthError::InvalidInput("Message not found".to_string()))?;
+        if let Some(room_id) = &message.room_id {
+            if !self.db.is_user_in_room(room_id, user_id)? {
+                return Err(AuthError::InvalidInput("You are not a member of this room".to_string()));
+            }
+            self.ensure_room_writable(&self.get_room_or_err(room_id)?)?;
+            self.ensure_not_sanctioned(SanctionKind::Ban, room_id, user_id)?;
+            self.ensure_not_sanctioned(SanctionKind::Mute, room_id, user_id)?;
+        } else {
+            // DMs: only the two participants, and not past a block
+            let other_id = if message.sender_id == user_id {
+                message.receiver_id.as_deref()
+            } else if message.receiver_id.as_deref() == Some(user_id) {
+                Some(message.sender_id.as_str())
+            } else {
+                None
+            }.ok_or(AuthError::InvalidInput("Message not found".to_string()))?;
+            if self.db.is_user_blocked(other_id, user_id)? {
+                return Err(AuthError::Forbidden("This user isn't accepting messages from you".to_string()));
+            }
+        }
+
+        self.db.add_reaction(message_id, user_id, emoji)
     }
 
     pub fn remove_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<Vec<ReactionSummary>> {
         self.db.remove_reaction(message_id, user_id, emoji)
     }
 
+    /// Who reacted to a message with what, oldest first.
+    pub fn get_reaction_details(&self, room_id: &str, message_id: &str, user_id: &str) -> Result<Vec<ReactionDetail>> {
+        if !self.db.is_user_in_room(room_id, user_id)? {
+            return Err(AuthError::InvalidInput("You are not a member of this room".to_string()));
+        }
+
+        let message = self.db.get_message_by_id(message_id)?
+            .ok_or(AuthError::InvalidInput("Message not found".to_string()))?;
+
+        if message.room_id != Some(room_id.to_string()) {
+            return Err(AuthError::InvalidInput("Message does not belong to this room".to_string()));
+        }
 
+        self.db.get_reaction_details(message_id)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Argon2Config;
     use crate::database::Database;
     //use crate::messages::SendRoomMessageRequest;
     use crate::users::CreateUserRequest;
@@ -601,6 +1944,7 @@ mod tests {
             username: "testuser".to_string(),
             email: "testemail@test.com".to_string(),
             password: "test_password_123".to_string(),
+            invite_code: None,
         };
 
         let register_response = auth.register(register_req).unwrap();
@@ -617,6 +1961,21 @@ mod tests {
         assert!(!login_response.token.is_empty());
     }
 
+    #[test]
+    fn test_register_rejects_malformed_emails() {
+        let auth = AuthService::new(Database::in_memory().unwrap());
+
+        for email in ["nope", "@test.com", "user@", "a b@test.com", "<user@test.com>", "user@test.com\r\nRCPT TO:<victim@test.com>"] {
+            let request = CreateUserRequest {
+                username: "testuser".to_string(),
+                email: email.to_string(),
+                password: "test_password_123".to_string(),
+                invite_code: None,
+            };
+            assert!(matches!(auth.register(request), Err(AuthError::InvalidInput(_))), "{:?}", email);
+        }
+    }
+
     #[test]
     fn test_session_validation() {
         let db = Database::in_memory().unwrap();
@@ -626,6 +1985,7 @@ mod tests {
             username: "testuser".to_string(),
             email: "test_eamail@test.com".to_string(),
             password: "test_password_123".to_string(),
+            invite_code: None,
         };
 
         let response = auth.register(register_req).unwrap();
@@ -637,6 +1997,645 @@ mod tests {
         assert!(auth.validate_session(&response.token).is_err());
     }
 
+    #[derive(Clone, Default)]
+    struct RecordingMailer {
+        sent: std::sync::Arc<std::sync::Mutex<Vec<Email>>>,
+    }
+
+    impl Mailer for RecordingMailer {
+        fn send(&self, email: &Email) -> Result<()> {
+            self.sent.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    struct FailingMailer;
+
+    impl Mailer for FailingMailer {
+        fn send(&self, _email: &Email) -> Result<()> {
+            Err(AuthError::Mail("relay unreachable".to_string()))
+        }
+    }
+
+    fn setup_auth_with_mailer() -> (AuthService, RecordingMailer) {
+        let mailer = RecordingMailer::default();
+        let auth = AuthService::with_config(
+            Box::new(Database::in_memory().unwrap()),
+            AuthConfig::default(),
+            Box::new(mailer.clone()),
+        );
+        (auth, mailer)
+    }
+
+    fn register_test_user(auth: &AuthService) -> AuthResponse {
+        auth.register(CreateUserRequest {
+            username: "testuser".to_string(),
+            email: "testuser@test.com".to_string(),
+            password: "test_password_123".to_string(),
+            invite_code: None,
+        }).unwrap()
+    }
+
+    fn last_mailed_token(mailer: &RecordingMailer) -> String {
+        let sent = mailer.sent.lock().unwrap();
+        let body = &sent.last().expect("no email sent").body;
+        body.split_whitespace()
+            .find(|word| word.len() == 64)
+            .expect("no token in email")
+            .to_string()
+    }
+
+    /// Waits for the `count`th email, which may be sent from a blocking task.
+    fn nth_mailed_token(mailer: &RecordingMailer, count: usize) -> String {
+        for _ in 0..250 {
+            if mailer.sent.lock().unwrap().len() >= count {
+                return last_mailed_token(mailer);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("email {} never sent", count);
+    }
+
+    /// Goes through sign-up, verification, bot tokens and a password reset
+    /// against whatever storage `auth` was built with.
+    fn exercise_auth_storage(auth: &AuthService, mailer: &RecordingMailer) {
+        let registered = register_test_user(auth);
+        assert!(!auth.validate_session(&registered.token).unwrap().email_verified);
+
+        let user = auth.verify_email(&nth_mailed_token(mailer, 1)).unwrap();
+        assert!(user.email_verified);
+
+        let session = auth.login(LoginRequest {
+            username: "testuser".to_string(),
+            password: "test_password_123".to_string(),
+        }).unwrap();
+        let bot = auth.create_bot(&session.token, "storage-bot").unwrap();
+        let api_token = auth.create_api_token(&session.token, &bot.id, "ci", vec![ApiScope::ReadRooms]).unwrap();
+        assert_eq!(auth.validate_api_token(&api_token.token).unwrap().0.id, bot.id);
+        assert_eq!(auth.list_api_tokens(&session.token, &bot.id).unwrap().len(), 1);
+
+        auth.request_password_reset("testuser@test.com").unwrap();
+        auth.reset_password(ResetPasswordRequest {
+            token: nth_mailed_token(mailer, 2),
+            new_password: "another_password_456".to_string(),
+        }).unwrap();
+        assert!(matches!(auth.validate_session(&session.token), Err(AuthError::InvalidSession)));
+    }
+
+    #[test]
+    fn test_auth_with_sqlite_storage() {
+        let mailer = RecordingMailer::default();
+        let config = AuthConfig { require_email_verification: true, ..AuthConfig::default() };
+        let auth = AuthService::with_config(Box::new(Database::in_memory().unwrap()), config, Box::new(mailer.clone()));
+
+        exercise_auth_storage(&auth, &mailer);
+    }
+
+    // Expects a running PostgreSQL the test user can create schemas in, e.g.
+    // SPARK_TEST_POSTGRES_URL=postgres://postgres@127.0.0.1/postgres
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "requires a local PostgreSQL"]
+    async fn test_auth_with_postgres_storage() {
+        let url = std::env::var("SPARK_TEST_POSTGRES_URL")
+            .unwrap_or_else(|_| "postgres://postgres@127.0.0.1/postgres".to_string());
+        let schema = format!("spark_test_{}", uuid::Uuid::new_v4().simple());
+
+        let (admin, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls).await.unwrap();
+        tokio::spawn(connection);
+        admin.batch_execute(&format!("CREATE SCHEMA {}", schema)).await.unwrap();
+
+        let mut config: tokio_postgres::Config = url.parse().unwrap();
+        config.options(format!("-c search_path={}", schema));
+        let storage = crate::postgres::PostgresStorage::from_config(&config).await.unwrap();
+
+        let mailer = RecordingMailer::default();
+        let auth_config = AuthConfig { require_email_verification: true, ..AuthConfig::default() };
+        let auth = AuthService::with_config(Box::new(storage), auth_config, Box::new(mailer.clone()));
+
+        exercise_auth_storage(&auth, &mailer);
+
+        admin.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema)).await.unwrap();
+    }
+
+    #[test]
+    fn test_reset_tokens_are_hashed_and_mail_failures_hidden() {
+        let (auth, mailer) = setup_auth_with_mailer();
+        register_test_user(&auth);
+
+        auth.request_password_reset("testuser@test.com").unwrap();
+        let token = last_mailed_token(&mailer);
+        assert!(auth.db.get_password_reset(&token).unwrap().is_none());
+        assert!(auth.db.get_password_reset(&AuthService::hash_token(&token)).unwrap().is_some());
+
+        // A broken relay looks the same as an unknown address
+        let auth = AuthService::with_config(Box::new(Database::in_memory().unwrap()), AuthConfig::default(), Box::new(FailingMailer));
+        register_test_user(&auth);
+        assert!(auth.request_password_reset("testuser@test.com").is_ok());
+        assert!(auth.request_password_reset("nobody@test.com").is_ok());
+    }
+
+    #[test]
+    fn test_change_password_revokes_other_sessions() {
+        let (auth, _) = setup_auth_with_mailer();
+        let first = register_test_user(&auth);
+        let second = auth.login(LoginRequest {
+            username: "testuser".to_string(),
+            password: "test_password_123".to_string(),
+        }).unwrap();
+
+        let wrong_current = auth.change_password(ChangePasswordRequest {
+            token: first.token.clone(),
+            current_password: "not_my_password".to_string(),
+            new_password: "new_password_456".to_string(),
+        });
+        assert!(matches!(wrong_current, Err(AuthError::InvalidCredentials)));
+
+        auth.change_password(ChangePasswordRequest {
+            token: first.token.clone(),
+            current_password: "test_password_123".to_string(),
+            new_password: "new_password_456".to_string(),
+        }).unwrap();
+
+        assert!(auth.validate_session(&first.token).is_ok());
+        assert!(auth.validate_session(&second.token).is_err());
+        assert!(auth.login(LoginRequest {
+            username: "testuser".to_string(),
+            password: "new_password_456".to_string(),
+        }).is_ok());
+    }
+
+    #[test]
+    fn test_password_reset_token_is_single_use() {
+        let (auth, mailer) = setup_auth_with_mailer();
+        let session = register_test_user(&auth);
+
+        auth.request_password_reset("unknown@test.com").unwrap();
+        assert!(mailer.sent.lock().unwrap().is_empty());
+
+        auth.request_password_reset("testuser@test.com").unwrap();
+        let token = last_mailed_token(&mailer);
+
+        auth.reset_password(ResetPasswordRequest {
+            token: token.clone(),
+            new_password: "reset_password_789".to_string(),
+        }).unwrap();
+
+        assert!(auth.validate_session(&session.token).is_err());
+        assert!(auth.login(LoginRequest {
+            username: "testuser".to_string(),
+            password: "reset_password_789".to_string(),
+        }).is_ok());
+
+        let reused = auth.reset_password(ResetPasswordRequest {
+            token,
+            new_password: "another_password_000".to_string(),
+        });
+        assert!(matches!(reused, Err(AuthError::InvalidToken)));
+    }
+
+    #[test]
+    fn test_password_reset_token_expires() {
+        let mailer = RecordingMailer::default();
+        let config = AuthConfig { reset_token_ttl: chrono::Duration::seconds(-1), ..AuthConfig::default() };
+        let auth = AuthService::with_config(Box::new(Database::in_memory().unwrap()), config, Box::new(mailer.clone()));
+        register_test_user(&auth);
+
+        auth.request_password_reset("testuser@test.com").unwrap();
+        let result = auth.reset_password(ResetPasswordRequest {
+            token: last_mailed_token(&mailer),
+            new_password: "reset_password_789".to_string(),
+        });
+        assert!(matches!(result, Err(AuthError::InvalidToken)));
+    }
+
+    #[test]
+    fn test_email_verification_flow() {
+        let mailer = RecordingMailer::default();
+        let config = AuthConfig { require_email_verification: true, ..AuthConfig::default() };
+        let auth = AuthService::with_config(Box::new(Database::in_memory().unwrap()), config, Box::new(mailer.clone()));
+
+        let response = register_test_user(&auth);
+        assert!(!response.user.email_verified);
+        assert!(!auth.validate_session(&response.token).unwrap().email_verified);
+        // Bots start verified, so an unverified owner can't make one to post through
+        assert!(matches!(auth.create_bot(&response.token, "proxybot"), Err(AuthError::EmailNotVerified)));
+
+        let token = last_mailed_token(&mailer);
+        let user = auth.verify_email(&token).unwrap();
+        assert!(user.email_verified);
+        assert!(auth.create_bot(&response.token, "proxybot").is_ok());
+        assert!(matches!(auth.verify_email(&token), Err(AuthError::InvalidToken)));
+        assert!(auth.resend_verification(&response.token).is_err());
+    }
+
+    #[test]
+    fn test_unverified_user_cannot_post() {
+        let (msg_service, user_id, room_id, _) = setup_message_service_with_user_and_room();
+        msg_service.db.set_email_verified(&user_id, false).unwrap();
+
+        let result = msg_service.send_room_message(&user_id, SendRoomMessageRequest {
+            room_id: room_id.clone(),
+            content: "Hello".to_string(),
+            reply_to_message_id: None,
+        });
+        assert!(matches!(result, Err(AuthError::EmailNotVerified)));
+        assert!(matches!(msg_service.create_room(&user_id, "Another", ""), Err(AuthError::EmailNotVerified)));
+        assert!(msg_service.get_room_messages(&room_id, &user_id, 10, 0).is_ok());
+    }
+
+    #[test]
+    fn test_blocked_user() {
+        let (msg_service, user_id, room_id, _) = setup_message_service_with_user_and_room();
+        let pest = msg_service.db.create_user("pest", "pest@example.com", "hash").unwrap();
+        msg_service.db.add_user_to_room(&room_id, &pest.id).unwrap();
+
+        assert!(msg_service.block_user(&user_id, &user_id).is_err());
+        let block = msg_service.block_user(&user_id, &pest.id).unwrap();
+        assert_eq!(block.username, "pest");
+        assert!(msg_service.block_user(&user_id, &pest.id).is_err());
+
+        // No DMs to the blocker, though the blocker can still write to them
+        let dm = |sender_id: &str, receiver_username: &str| msg_service.send_private_message(sender_id, SendPrivateMessageRequest {
+            receiver_username: receiver_username.to_string(),
+            content: "hi".to_string(),
+        });
+        assert!(matches!(dm(&pest.id, "testuser"), Err(AuthError::Forbidden(_))));
+        let sent = dm(&user_id, "pest").unwrap();
+
+        // Reactions on DMs follow the same rule, and stay between the two
+        assert!(matches!(msg_service.add_reaction(&sent.id, &pest.id, "👍"), Err(AuthError::Forbidden(_))));
+        assert_eq!(msg_service.add_reaction(&sent.id, &user_id, "👍").unwrap().len(), 1);
+        let outsider = msg_service.db.create_user("outsider", "outsider@example.com", "hash").unwrap();
+        assert!(matches!(msg_service.add_reaction(&sent.id, &outsider.id, "👍"), Err(AuthError::InvalidInput(_))));
+
+        // The mention is recorded but doesn't notify or count as unread
+        let (message, audience) = msg_service.send_room_message(&pest.id, SendRoomMessageRequest {
+            room_id: room_id.clone(),
+            content: "hey @testuser".to_string(),
+            reply_to_message_id: None,
+        }).unwrap();
+        assert_eq!(message.mentions, vec![user_id.clone()]);
+        assert!(audience.notify_user_ids.is_empty());
+        assert_eq!(audience.blocked_by, vec![user_id.clone()]);
+        assert_eq!(msg_service.get_unread_mentions_count(&user_id).unwrap(), 0);
+
+        // Flagged for the blocker only
+        assert!(msg_service.get_room_messages(&room_id, &user_id, 10, 0).unwrap()[0].sender_blocked);
+        assert!(!msg_service.get_room_messages(&room_id, &pest.id, 10, 0).unwrap()[0].sender_blocked);
+        assert!(msg_service.get_user_mentions(&user_id, 10, 0).unwrap()[0].sender_blocked);
+
+        msg_service.unblock_user(&user_id, &pest.id).unwrap();
+        assert!(msg_service.unblock_user(&user_id, &pest.id).is_err());
+        assert!(msg_service.get_blocked_users(&user_id).unwrap().is_empty());
+        assert!(dm(&pest.id, "testuser").is_ok());
+        assert!(!msg_service.get_room_messages(&room_id, &user_id, 10, 0).unwrap()[0].sender_blocked);
+    }
+
+    #[test]
+    fn test_server_admin_commands() {
+        let (msg_service, user_id, room_id, _) = setup_message_service_with_user_and_room();
+        let admin = msg_service.db.create_user("root", "root@example.com", "hash").unwrap();
+        msg_service.db.set_user_role(&admin.id, UserRole::Admin).unwrap();
+
+        let everyone = UserSearch::default();
+        assert!(matches!(msg_service.list_users(&user_id, &everyone, 10, 0), Err(AuthError::PermissionDenied)));
+        assert_eq!(msg_service.list_users(&admin.id, &everyone, 10, 0).unwrap().len(), 2);
+
+        // Admins may delete anyone's message; members only their own
+        let (message, _) = msg_service.send_room_message(&user_id, SendRoomMessageRequest {
+            room_id: room_id.clone(),
+            content: "hello".to_string(),
+            reply_to_message_id: None,
+        }).unwrap();
+        let announcements = msg_service.announce_to_rooms(&admin.id, "Maintenance tonight").unwrap();
+        assert_eq!(announcements.len(), 1);
+        assert_eq!(announcements[0].sender_username, "Server");
+        assert!(matches!(msg_service.delete_message(&user_id, &announcements[0].id), Err(AuthError::PermissionDenied)));
+        assert_eq!(msg_service.delete_message(&admin.id, &message.id).unwrap(), Some(room_id.clone()));
+        assert_eq!(msg_service.get_room_messages(&room_id, &user_id, 10, 0).unwrap().len(), 1);
+
+        // Deactivating ends every session
+        msg_service.db.create_session(user_id.clone(), "user-token", Utc::now() + Duration::hours(1)).unwrap();
+        assert!(matches!(msg_service.deactivate_user(&admin.id, &admin.id, None), Err(AuthError::PermissionDenied)));
+        let account = msg_service.deactivate_user(&admin.id, &user_id, Some("spam")).unwrap();
+        assert!(account.deactivated_at.is_some());
+        assert!(msg_service.db.get_session_by_token("user-token").unwrap().is_none());
+        let deactivated = UserSearch { deactivated: Some(true), ..Default::default() };
+        assert_eq!(msg_service.list_users(&admin.id, &deactivated, 10, 0).unwrap()[0].id, user_id);
+        assert!(msg_service.reactivate_user(&admin.id, &user_id).unwrap().deactivated_at.is_none());
+
+        // Other admins are out of reach until demoted, and nobody changes their own role
+        assert!(matches!(msg_service.set_user_role(&admin.id, &admin.id, UserRole::User), Err(AuthError::InvalidInput(_))));
+        assert_eq!(msg_service.set_user_role(&admin.id, &user_id, UserRole::Admin).unwrap().role, UserRole::Admin);
+        assert!(matches!(msg_service.force_logout(&admin.id, &user_id), Err(AuthError::PermissionDenied)));
+        msg_service.set_user_role(&admin.id, &user_id, UserRole::User).unwrap();
+        msg_service.force_logout(&admin.id, &user_id).unwrap();
+
+        assert!(matches!(msg_service.announce_to_online_users(&user_id, "hi"), Err(AuthError::PermissionDenied)));
+        assert!(msg_service.announce_to_online_users(&admin.id, " ").is_err());
+        msg_service.announce_to_online_users(&admin.id, "Back soon").unwrap();
+
+        let actions: Vec<AuditAction> = msg_service.get_audit_log(&admin.id, &AuditLogFilter::default(), 20, 0).unwrap()
+            .into_iter()
+            .map(|entry| entry.action)
+            .collect();
+        for action in [AuditAction::MessageDeleted, AuditAction::UserDeactivated, AuditAction::UserReactivated,
+            AuditAction::UserRoleChanged, AuditAction::UserLoggedOut, AuditAction::ServerAnnouncement] {
+            assert!(actions.contains(&action), "missing {:?}", action);
+        }
+    }
+
+    #[test]
+    fn test_login_rehashes_outdated_password_hash() {
+        let weak = Argon2Config { memory_kib: 1024, iterations: 1, parallelism: 1 };
+        let strong = Argon2Config { memory_kib: 2048, iterations: 2, parallelism: 1 };
+
+        let old_auth = AuthService::with_config(
+            Box::new(Database::in_memory().unwrap()),
+            AuthConfig { argon2: weak, ..AuthConfig::default() },
+            Box::new(RecordingMailer::default()),
+        );
+        let auth = AuthService::with_config(
+            Box::new(Database::in_memory().unwrap()),
+            AuthConfig { argon2: strong, ..AuthConfig::default() },
+            Box::new(RecordingMailer::default()),
+        );
+
+        let old_hash = old_auth.hash_password("test_password_123").unwrap();
+        let user = auth.db.create_user("testuser", "testuser@test.com", &old_hash).unwrap();
+        assert!(auth.needs_rehash(&old_hash).unwrap());
+
+        auth.login(LoginRequest {
+            username: "testuser".to_string(),
+            password: "test_password_123".to_string(),
+        }).unwrap();
+
+        let stored = auth.db.get_user_by_id(user.id).unwrap().unwrap().password_hash;
+        assert_ne!(stored, old_hash);
+        assert!(stored.starts_with("$argon2id$v=19$m=2048,t=2,p=1$"));
+        assert!(!auth.needs_rehash(&stored).unwrap());
+    }
+
+    /// Accepts any password for "sso.*" usernames, like a directory would
+    struct FakeDirectory;
+
+    impl AuthProvider for FakeDirectory {
+        fn name(&self) -> &str {
+            "directory"
+        }
+
+        fn authenticate(&self, _db: &dyn Storage, username: &str, password: &str) -> Result<Option<Identity>> {
+            if !username.starts_with("sso.") {
+                return Ok(None);
+            }
+            if password != "directory_password" {
+                return Err(AuthError::InvalidCredentials);
+            }
+
+            Ok(Some(Identity::External(ExternalIdentity {
+                provider: self.name().to_string(),
+                subject: format!("uid={}", username),
+                username: username.to_string(),
+                email: None,
+                email_verified: false,
+            })))
+        }
+    }
+
+    #[test]
+    fn test_external_provider_provisions_user_once() {
+        let mut auth = AuthService::new(Database::in_memory().unwrap());
+        auth.add_provider(Box::new(FakeDirectory));
+
+        let login = |password: &str| auth.login(LoginRequest {
+            username: "sso.alice".to_string(),
+            password: password.to_string(),
+        });
+
+        let first = login("directory_password").unwrap();
+        let second = login("directory_password").unwrap();
+        assert_eq!(first.user.id, second.user.id);
+        assert_eq!(first.user.email, "sso.alice@directory.invalid");
+        assert!(matches!(login("wrong"), Err(AuthError::InvalidCredentials)));
+
+        // Provisioned accounts have no local password
+        let result = auth.change_password(ChangePasswordRequest {
+            token: first.token,
+            current_password: "directory_password".to_string(),
+            new_password: "new_password_123".to_string(),
+        });
+        assert!(matches!(result, Err(AuthError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_external_provisioning_follows_registration_policy() {
+        let sign_in = |policy: RegistrationPolicy| {
+            let config = AuthConfig { registration_policy: policy, ..AuthConfig::default() };
+            let mut auth = AuthService::with_config(Box::new(Database::in_memory().unwrap()), config, Box::new(RecordingMailer::default()));
+            auth.add_provider(Box::new(FakeDirectory));
+            auth.login(LoginRequest { username: "sso.alice".to_string(), password: "directory_password".to_string() })
+        };
+
+        assert!(matches!(sign_in(RegistrationPolicy::Disabled), Err(AuthError::RegistrationClosed)));
+        assert!(matches!(sign_in(RegistrationPolicy::InviteOnly), Err(AuthError::RegistrationClosed)));
+        assert!(matches!(sign_in(RegistrationPolicy::AdminApproval), Err(AuthError::PendingApproval)));
+        assert!(sign_in(RegistrationPolicy::Open).is_ok());
+    }
+
+    #[test]
+    fn test_external_identities_are_checked_before_provisioning() {
+        let auth = AuthService::new(Database::in_memory().unwrap());
+        let identity = |username: &str, email: &str, email_verified: bool| ExternalIdentity {
+            provider: "oidc".to_string(),
+            subject: format!("sub-{}", username),
+            username: username.to_string(),
+            email: Some(email.to_string()),
+            email_verified,
+        };
+
+        let result = auth.provision_external_user(identity("ab", "ab@example.com", true));
+        assert!(matches!(result, Err(AuthError::InvalidInput(_))));
+
+        // Unverified or malformed addresses could belong to someone else
+        let user = auth.provision_external_user(identity("mallory", "victim@example.com", false)).unwrap();
+        assert_eq!(user.email, "mallory@oidc.invalid");
+        let user = auth.provision_external_user(identity("header", "x@example.com\r\nBcc: y@example.com", true)).unwrap();
+        assert_eq!(user.email, "header@oidc.invalid");
+
+        let user = auth.provision_external_user(identity("carol", "carol@example.com", true)).unwrap();
+        assert_eq!(user.email, "carol@example.com");
+        assert_eq!(auth.db.get_user_by_identity("oidc", "sub-carol").unwrap().unwrap().id, user.id);
+    }
+
+    #[test]
+    fn test_bot_api_tokens() {
+        let auth = AuthService::new(Database::in_memory().unwrap());
+        let owner = auth.register(registration("owner", None)).unwrap();
+        let other = auth.register(registration("other", None)).unwrap();
+
+        let bot = auth.create_bot(&owner.token, "helperbot").unwrap();
+        assert!(bot.is_bot);
+        assert_eq!(serde_json::to_value(&bot).unwrap()["is_bot"], true);
+        assert_eq!(auth.list_bots(&owner.token).unwrap().len(), 1);
+
+        let created = auth.create_api_token(&owner.token, &bot.id, "ci", vec![ApiScope::PostMessages, ApiScope::PostMessages]).unwrap();
+        assert!(created.token.starts_with(API_TOKEN_PREFIX));
+        assert_eq!(created.info.scopes, vec![ApiScope::PostMessages]);
+
+        let (user, scopes) = auth.validate_token(&created.token).unwrap();
+        assert_eq!(user.id, bot.id);
+        assert_eq!(scopes, Some(vec![ApiScope::PostMessages]));
+        assert!(auth.list_api_tokens(&owner.token, &bot.id).unwrap()[0].last_used_at.is_some());
+
+        // Sessions stay unscoped, and bots can't log in with a password
+        assert_eq!(auth.validate_token(&owner.token).unwrap().1, None);
+        let login = auth.login(LoginRequest { username: "helperbot".to_string(), password: "!external".to_string() });
+        assert!(matches!(login, Err(AuthError::InvalidCredentials)));
+
+        assert!(matches!(auth.list_api_tokens(&other.token, &bot.id), Err(AuthError::PermissionDenied)));
+        assert!(matches!(auth.revoke_api_token(&other.token, &created.info.id), Err(AuthError::PermissionDenied)));
+
+        auth.revoke_api_token(&owner.token, &created.info.id).unwrap();
+        assert!(matches!(auth.validate_token(&created.token), Err(AuthError::InvalidToken)));
+    }
+
+    #[test]
+    fn test_bot_tokens_follow_their_owner() {
+        let auth = AuthService::new(Database::in_memory().unwrap());
+        let owner = auth.register(registration("owner", None)).unwrap();
+        let bot = auth.create_bot(&owner.token, "helperbot").unwrap();
+        let created = auth.create_api_token(&owner.token, &bot.id, "ci", vec![ApiScope::ReadRooms]).unwrap();
+
+        auth.db.set_user_deactivated(&owner.user.id, Some(Utc::now())).unwrap();
+        assert!(matches!(auth.validate_token(&created.token), Err(AuthError::AccountDeactivated)));
+        auth.db.set_user_deactivated(&owner.user.id, None).unwrap();
+        assert_eq!(auth.validate_token(&created.token).unwrap().0.id, bot.id);
+
+        auth.db.set_user_approved(&owner.user.id, false).unwrap();
+        assert!(matches!(auth.validate_token(&created.token), Err(AuthError::PendingApproval)));
+    }
+
+    #[test]
+    fn test_external_login_with_unknown_provider() {
+        let auth = AuthService::new(Database::in_memory().unwrap());
+        assert!(matches!(auth.begin_external_login("nope"), Err(AuthError::InvalidInput(_))));
+        assert!(matches!(auth.begin_external_login("local"), Err(AuthError::InvalidInput(_))));
+    }
+
+    fn setup_auth_with_policy(policy: RegistrationPolicy) -> (AuthService, String) {
+        let config = AuthConfig {
+            registration_policy: policy,
+            admin_usernames: vec!["admin".to_string()],
+            ..AuthConfig::default()
+        };
+        let auth = AuthService::with_config(Box::new(Database::in_memory().unwrap()), config, Box::new(RecordingMailer::default()));
+
+        // Admins come from bootstrapping an existing account, never from registering
+        let password_hash = auth.hash_password("admin_password_123").unwrap();
+        auth.db.create_user("admin", "admin@test.com", &password_hash).unwrap();
+        auth.bootstrap_admins().unwrap();
+        let admin = auth.login(LoginRequest { username: "admin".to_string(), password: "admin_password_123".to_string() }).unwrap();
+
+        (auth, admin.token)
+    }
+
+    fn registration(username: &str, invite_code: Option<&str>) -> CreateUserRequest {
+        CreateUserRequest {
+            username: username.to_string(),
+            email: format!("{}@test.com", username),
+            password: "test_password_123".to_string(),
+            invite_code: invite_code.map(|c| c.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_invite_only_registration() {
+        let (auth, admin_token) = setup_auth_with_policy(RegistrationPolicy::InviteOnly);
+
+        assert!(matches!(auth.register(registration("nocode", None)), Err(AuthError::InvalidInviteCode)));
+        assert!(matches!(auth.register(registration("badcode", Some("nope"))), Err(AuthError::InvalidInviteCode)));
+
+        let invite = auth.create_invite_code(&admin_token, Some(1), None).unwrap();
+
+        // A failed insert leaves the code unused
+        let mut taken_email = registration("copycat", Some(&invite.code));
+        taken_email.email = "admin@test.com".to_string();
+        assert!(auth.register(taken_email).is_err());
+        assert_eq!(auth.list_invite_codes(&admin_token).unwrap()[0].uses, 0);
+
+        assert!(auth.register(registration("invited", Some(&invite.code))).is_ok());
+        assert!(matches!(auth.register(registration("latecomer", Some(&invite.code))), Err(AuthError::InvalidInviteCode)));
+
+        let codes = auth.list_invite_codes(&admin_token).unwrap();
+        assert_eq!(codes[0].uses, 1);
+        assert_eq!(codes[0].used_by, vec!["invited".to_string()]);
+
+        let user_token = auth.login(LoginRequest {
+            username: "invited".to_string(),
+            password: "test_password_123".to_string(),
+        }).unwrap().token;
+        assert!(matches!(auth.create_invite_code(&user_token, None, None), Err(AuthError::PermissionDenied)));
+    }
+
+    #[test]
+    fn test_admin_approval_registration() {
+        let (auth, admin_token) = setup_auth_with_policy(RegistrationPolicy::AdminApproval);
+
+        assert!(matches!(auth.register(registration("pending", None)), Err(AuthError::PendingApproval)));
+        let login = LoginRequest { username: "pending".to_string(), password: "test_password_123".to_string() };
+        assert!(matches!(auth.login(login), Err(AuthError::PendingApproval)));
+
+        let pending = auth.list_pending_users(&admin_token).unwrap();
+        assert_eq!(pending.len(), 1);
+        auth.approve_user(&admin_token, &pending[0].id).unwrap();
+
+        let login = LoginRequest { username: "pending".to_string(), password: "test_password_123".to_string() };
+        assert!(auth.login(login).is_ok());
+        assert!(auth.list_pending_users(&admin_token).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_admin_bootstrap_and_deactivation() {
+        let config = AuthConfig { admin_usernames: vec!["admin".to_string(), "boss".to_string()], ..AuthConfig::default() };
+        let auth = AuthService::with_config(Box::new(Database::in_memory().unwrap()), config, Box::new(RecordingMailer::default()));
+
+        let boss = auth.db.create_user("boss", "boss@test.com", "hash").unwrap();
+        assert_eq!(auth.bootstrap_admins().unwrap(), vec!["boss"]);
+        assert!(auth.bootstrap_admins().unwrap().is_empty());
+        assert!(auth.db.get_user_by_id(boss.id.clone()).unwrap().unwrap().is_admin());
+
+        // A demoted admin stays demoted when signing in again
+        let password_hash = auth.hash_password("boss_password_123").unwrap();
+        auth.db.update_user_password(&boss.id, &password_hash).unwrap();
+        auth.db.set_user_role(&boss.id, UserRole::User).unwrap();
+        let login = LoginRequest { username: "boss".to_string(), password: "boss_password_123".to_string() };
+        assert!(!auth.login(login).unwrap().user.is_admin());
+
+        // Registering a configured name later doesn't make an admin
+        let admin = auth.register(registration("admin", None)).unwrap();
+        assert!(!admin.user.is_admin());
+        let user = auth.register(registration("regular", None)).unwrap();
+        assert_eq!(user.user.role, UserRole::User);
+
+        // Deactivated accounts can neither use their sessions nor sign in again
+        auth.db.set_user_deactivated(&user.user.id, Some(Utc::now())).unwrap();
+        assert!(matches!(auth.validate_session(&user.token), Err(AuthError::AccountDeactivated)));
+        let login = LoginRequest { username: "regular".to_string(), password: "test_password_123".to_string() };
+        assert!(matches!(auth.login(login), Err(AuthError::AccountDeactivated)));
+
+        auth.db.set_user_deactivated(&user.user.id, None).unwrap();
+        let login = LoginRequest { username: "regular".to_string(), password: "test_password_123".to_string() };
+        assert!(auth.login(login).is_ok());
+    }
+
+    #[test]
+    fn test_disabled_registration() {
+        let (auth, _) = setup_auth_with_policy(RegistrationPolicy::Disabled);
+        assert!(matches!(auth.register(registration("someone", None)), Err(AuthError::RegistrationClosed)));
+        // Configured admin names get no way around the policy
+        assert!(matches!(auth.register(registration("admin", None)), Err(AuthError::RegistrationClosed)));
+    }
+
     fn setup_message_service_with_user_and_room() -> (MessageService, String, String, String) {
         let db = Database::new(":memory:").expect("Failed to create database");
         
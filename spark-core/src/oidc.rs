@@ -0,0 +1,292 @@
+use crate::{
+    auth_provider::{AuthProvider, AuthorizationRequest, ExternalIdentity},
+    config::OidcConfig,
+    error::{AuthError, Result},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// How long a user has to finish signing in at the IdP
+const AUTHORIZATION_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Deserialize)]
+struct Endpoints {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    preferred_username: Option<String>,
+}
+
+struct PendingAuthorization {
+    code_verifier: String,
+    started_at: Instant,
+}
+
+/// OpenID Connect authorization-code flow with PKCE. The user's identity is
+/// taken from the userinfo endpoint using the access token, so the ID token
+/// itself is never parsed.
+pub struct OidcProvider {
+    config: OidcConfig,
+    endpoints: Mutex<Option<Endpoints>>,
+    pending: Mutex<HashMap<String, PendingAuthorization>>,
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            endpoints: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the provider's endpoints from its discovery document on first use.
+    fn endpoints(&self) -> Result<Endpoints> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+
+        if endpoints.is_none() {
+            let url = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+            let discovered: Endpoints = Self::blocking(|| ureq::get(&url)
+                .call()
+                .map_err(|e| AuthError::Provider(e.to_string()))?
+                .into_json()
+                .map_err(|e| AuthError::Provider(e.to_string())))?;
+            *endpoints = Some(discovered);
+        }
+
+        Ok(endpoints.clone().unwrap())
+    }
+
+    /// ureq blocks, and callers hold the `AuthService` lock, so a runtime
+    /// worker hands its other tasks over before making the request.
+    fn blocking<T>(request: impl FnOnce() -> T) -> T {
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(request),
+            _ => request(),
+        }
+    }
+
+    fn random_string(len: usize) -> String {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(len)
+            .map(char::from)
+            .collect()
+    }
+}
+
+impl AuthProvider for OidcProvider {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn begin_authorization(&self) -> Result<AuthorizationRequest> {
+        let endpoints = self.endpoints()?;
+
+        let state = Self::random_string(32);
+        let code_verifier = Self::random_string(64);
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        let url = url::Url::parse_with_params(&endpoints.authorization_endpoint, &[
+            ("response_type", "code"),
+            ("client_id", self.config.client_id.as_str()),
+            ("redirect_uri", self.config.redirect_uri.as_str()),
+            ("scope", self.config.scopes.as_str()),
+            ("state", state.as_str()),
+            ("code_challenge", code_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ]).map_err(|e| AuthError::Provider(e.to_string()))?;
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.started_at.elapsed() < AUTHORIZATION_TTL);
+        pending.insert(state.clone(), PendingAuthorization {
+            code_verifier,
+            started_at: Instant::now(),
+        });
+
+        Ok(AuthorizationRequest {
+            provider: self.name().to_string(),
+            url: url.to_string(),
+            state,
+        })
+    }
+
+    fn complete_authorization(&self, state: &str, code: &str) -> Result<ExternalIdentity> {
+        let pending = self.pending.lock().unwrap()
+            .remove(state)
+            .filter(|p| p.started_at.elapsed() < AUTHORIZATION_TTL)
+            .ok_or(AuthError::InvalidToken)?;
+        let endpoints = self.endpoints()?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_uri.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", pending.code_verifier.as_str()),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+
+        let info: UserInfo = Self::blocking(|| {
+            let token: TokenResponse = ureq::post(&endpoints.token_endpoint)
+                .send_form(&form)
+                .map_err(|e| AuthError::Provider(e.to_string()))?
+                .into_json()
+                .map_err(|e| AuthError::Provider(e.to_string()))?;
+
+            ureq::get(&endpoints.userinfo_endpoint)
+                .set("Authorization", &format!("Bearer {}", token.access_token))
+                .call()
+                .map_err(|e| AuthError::Provider(e.to_string()))?
+                .into_json()
+                .map_err(|e| AuthError::Provider(e.to_string()))
+        })?;
+
+        let username = info.preferred_username
+            .or_else(|| info.email.as_ref().and_then(|e| e.split('@').next().map(str::to_string)))
+            .unwrap_or_else(|| info.sub.clone());
+
+        Ok(ExternalIdentity {
+            provider: self.name().to_string(),
+            subject: info.sub,
+            username,
+            email: info.email,
+            email_verified: info.email_verified,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Serves discovery, token and userinfo for one sign in. The token
+    /// endpoint only answers if the PKCE verifier matches the challenge.
+    fn spawn_mock_idp(expected_challenge: Arc<Mutex<Option<String>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let issuer = base.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().take(3) {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                let mut content_length = 0;
+                let mut authorization = String::new();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() { break; }
+                    let (name, value) = header.split_once(':').unwrap();
+                    match name.to_lowercase().as_str() {
+                        "content-length" => content_length = value.trim().parse().unwrap(),
+                        "authorization" => authorization = value.trim().to_string(),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let body = String::from_utf8(body).unwrap();
+
+                let path = request_line.split_whitespace().nth(1).unwrap().to_string();
+                let (status, json) = if path == "/.well-known/openid-configuration" {
+                    ("200 OK", serde_json::json!({
+                        "authorization_endpoint": format!("{}/authorize", base),
+                        "token_endpoint": format!("{}/token", base),
+                        "userinfo_endpoint": format!("{}/userinfo", base),
+                    }))
+                } else if path == "/token" {
+                    let verifier = url::form_urlencoded::parse(body.as_bytes())
+                        .find(|(k, _)| k == "code_verifier")
+                        .map(|(_, v)| v.to_string())
+                        .unwrap_or_default();
+                    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+                    if Some(challenge) == *expected_challenge.lock().unwrap() && body.contains("code=good-code") {
+                        ("200 OK", serde_json::json!({ "access_token": "mock-access-token", "token_type": "Bearer" }))
+                    } else {
+                        ("400 Bad Request", serde_json::json!({ "error": "invalid_grant" }))
+                    }
+                } else if path == "/userinfo" && authorization == "Bearer mock-access-token" {
+                    ("200 OK", serde_json::json!({
+                        "sub": "mock-subject-1",
+                        "email": "sso.user@example.com",
+                        "email_verified": true,
+                        "preferred_username": "ssouser",
+                    }))
+                } else {
+                    ("401 Unauthorized", serde_json::json!({}))
+                };
+
+                let payload = json.to_string();
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, payload.len(), payload
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        issuer
+    }
+
+    #[test]
+    fn test_authorization_code_flow_with_pkce() {
+        let expected_challenge = Arc::new(Mutex::new(None));
+        let issuer = spawn_mock_idp(Arc::clone(&expected_challenge));
+
+        let provider = OidcProvider::new(OidcConfig {
+            name: "mock".to_string(),
+            issuer,
+            client_id: "spark".to_string(),
+            client_secret: None,
+            redirect_uri: "http://127.0.0.1/callback".to_string(),
+            scopes: "openid email profile".to_string(),
+        });
+
+        let request = provider.begin_authorization().unwrap();
+        let url = url::Url::parse(&request.url).unwrap();
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], request.state);
+        assert_eq!(params["code_challenge_method"], "S256");
+        *expected_challenge.lock().unwrap() = Some(params["code_challenge"].clone());
+
+        assert!(matches!(provider.complete_authorization("unknown-state", "good-code"), Err(AuthError::InvalidToken)));
+
+        let identity = provider.complete_authorization(&request.state, "good-code").unwrap();
+        assert_eq!(identity.subject, "mock-subject-1");
+        assert_eq!(identity.username, "ssouser");
+        assert_eq!(identity.email.as_deref(), Some("sso.user@example.com"));
+        assert!(identity.email_verified);
+
+        // State is single use
+        assert!(matches!(provider.complete_authorization(&request.state, "good-code"), Err(AuthError::InvalidToken)));
+    }
+}
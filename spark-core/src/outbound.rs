@@ -0,0 +1,212 @@
+use crate::config::OutboundQueueConfig;
+use crate::metrics::metrics;
+use crate::websocket::WsServerMessage;
+use std::sync::Arc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+
+/// A server message encoded to JSON once. Cloning shares the buffer, so a
+/// room broadcast costs one serialization however many members it reaches.
+#[derive(Clone)]
+pub(crate) struct Encoded {
+    text: Utf8Bytes,
+    ephemeral: bool,
+}
+
+impl Encoded {
+    pub(crate) fn new(message: &WsServerMessage) -> Self {
+        Self {
+            text: serde_json::to_string(message).unwrap().into(),
+            ephemeral: message.is_ephemeral(),
+        }
+    }
+
+    /// Wraps JSON encoded elsewhere, e.g. on another node.
+    pub(crate) fn from_json(text: String, ephemeral: bool) -> Self {
+        Self { text: text.into(), ephemeral }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        self.text.as_str()
+    }
+
+    pub(crate) fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SendError {
+    /// The connection has already gone away
+    Closed,
+    /// A typing or presence update was dropped because the queue is backed up
+    Shed,
+    /// The queue is full and the connection is being closed
+    SlowConsumer,
+}
+
+/// Sending half of a connection's bounded outbound queue. Never blocks:
+/// callers are usually holding the connection manager lock.
+#[derive(Clone)]
+pub(crate) struct ClientSender {
+    queue: mpsc::Sender<Utf8Bytes>,
+    shed_ephemeral_at: usize,
+    slow_consumer: Arc<watch::Sender<bool>>,
+    /// Set when the server ends the connection, e.g. an admin logging the user out
+    disconnect: Arc<watch::Sender<bool>>,
+}
+
+/// Receiving half, drained by the connection's writer task.
+pub(crate) struct ClientReceiver {
+    queue: mpsc::Receiver<Utf8Bytes>,
+    slow_consumer: watch::Receiver<bool>,
+}
+
+pub(crate) fn channel(config: &OutboundQueueConfig) -> (ClientSender, ClientReceiver) {
+    let (queue_tx, queue_rx) = mpsc::channel(config.capacity.max(1));
+    let (slow_tx, slow_rx) = watch::channel(false);
+    let sender = ClientSender {
+        queue: queue_tx,
+        shed_ephemeral_at: config.shed_ephemeral_at,
+        slow_consumer: Arc::new(slow_tx),
+        disconnect: Arc::new(watch::channel(false).0),
+    };
+    (sender, ClientReceiver { queue: queue_rx, slow_consumer: slow_rx })
+}
+
+impl ClientSender {
+    pub(crate) fn send(&self, message: WsServerMessage) -> Result<(), SendError> {
+        self.send_encoded(&Encoded::new(&message))
+    }
+
+    pub(crate) fn send_encoded(&self, message: &Encoded) -> Result<(), SendError> {
+        if *self.slow_consumer.borrow() {
+            return Err(SendError::SlowConsumer);
+        }
+
+        let queued = self.queue.max_capacity() - self.queue.capacity();
+        if message.ephemeral && queued >= self.shed_ephemeral_at {
+            metrics().message_shed();
+            return Err(SendError::Shed);
+        }
+
+        match self.queue.try_send(message.text.clone()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Closed(_)) => Err(SendError::Closed),
+            Err(TrySendError::Full(_)) if message.ephemeral => {
+                metrics().message_shed();
+                Err(SendError::Shed)
+            }
+            Err(TrySendError::Full(_)) => {
+                if !self.slow_consumer.send_replace(true) {
+                    metrics().slow_consumer_disconnect();
+                }
+                Err(SendError::SlowConsumer)
+            }
+        }
+    }
+
+    /// Resolves once the queue has overflowed and the client should be dropped.
+    pub(crate) async fn overflowed(&self) {
+        let mut receiver = self.slow_consumer.subscribe();
+        let _ = receiver.wait_for(|slow| *slow).await;
+    }
+
+    /// Asks the connection to close once what's already queued is sent.
+    pub(crate) fn disconnect(&self) {
+        self.disconnect.send_replace(true);
+    }
+
+    /// Resolves once `disconnect` has been called on any clone.
+    pub(crate) async fn disconnected(&self) {
+        let mut receiver = self.disconnect.subscribe();
+        let _ = receiver.wait_for(|disconnect| *disconnect).await;
+    }
+}
+
+impl ClientReceiver {
+    /// Next message to write, or `None` once every sender is gone or the
+    /// client has been cut off as a slow consumer.
+    pub(crate) async fn recv(&mut self) -> Option<Utf8Bytes> {
+        tokio::select! {
+            biased;
+            Ok(_) = self.slow_consumer.wait_for(|slow| *slow) => None,
+            text = self.queue.recv() => text,
+        }
+    }
+
+    pub(crate) fn is_slow_consumer(&self) -> bool {
+        *self.slow_consumer.borrow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::Presence;
+
+    fn presence() -> WsServerMessage {
+        WsServerMessage::PresenceChanged {
+            user_id: "u1".to_string(),
+            username: "alice".to_string(),
+            presence: Presence::Online,
+        }
+    }
+
+    fn room_left() -> WsServerMessage {
+        WsServerMessage::RoomLeft { room_id: "r1".to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_updates_are_shed_first() {
+        let (tx, mut rx) = channel(&OutboundQueueConfig { capacity: 4, shed_ephemeral_at: 2 });
+
+        assert_eq!(tx.send(presence()), Ok(()));
+        assert_eq!(tx.send(room_left()), Ok(()));
+        assert_eq!(tx.send(presence()), Err(SendError::Shed));
+        assert_eq!(tx.send(room_left()), Ok(()));
+        assert_eq!(tx.send(room_left()), Ok(()));
+        assert!(!rx.is_slow_consumer());
+
+        let first: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_str()).unwrap();
+        assert_eq!(first["type"], "PresenceChanged");
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_marks_slow_consumer() {
+        let (tx, mut rx) = channel(&OutboundQueueConfig { capacity: 2, shed_ephemeral_at: 2 });
+
+        assert_eq!(tx.send(room_left()), Ok(()));
+        assert_eq!(tx.send(room_left()), Ok(()));
+        assert_eq!(tx.send(room_left()), Err(SendError::SlowConsumer));
+        assert_eq!(tx.send(room_left()), Err(SendError::SlowConsumer));
+
+        tx.overflowed().await;
+        assert!(rx.recv().await.is_none());
+        assert!(rx.is_slow_consumer());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_keeps_queued_messages() {
+        let (tx, mut rx) = channel(&OutboundQueueConfig::default());
+        tx.send(room_left()).unwrap();
+        tx.clone().disconnect();
+
+        tx.disconnected().await;
+        drop(tx);
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_receiver_ends_when_senders_are_dropped() {
+        let (tx, mut rx) = channel(&OutboundQueueConfig::default());
+        tx.send(room_left()).unwrap();
+        drop(tx);
+
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_none());
+        assert!(!rx.is_slow_consumer());
+    }
+}
@@ -1,17 +1,20 @@
-use crate::{network::{AuthService}, Database};
+use crate::{error::AuthError, metrics::metrics, network::{AuthService}, shutdown::Shutdown, users::ApiScope, Database};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
+use tracing::{debug, info, info_span, warn, Instrument};
 
-#[derive(Debug, Deserialize)]
+/// Deliberately not `Debug`: most requests carry a token or password.
+#[derive(Deserialize)]
 #[serde(tag = "type")]
 enum Request {
     Register {
         username: String,
         email: String,
         password: String,
+        invite_code: Option<String>,
     },
     Login {
         username: String,
@@ -23,6 +26,106 @@ enum Request {
     Logout {
         token: String,
     },
+    ChangePassword {
+        token: String,
+        current_password: String,
+        new_password: String,
+    },
+    ForgotPassword {
+        email: String,
+    },
+    ResetPassword {
+        token: String,
+        new_password: String,
+    },
+    VerifyEmail {
+        token: String,
+    },
+    ResendVerification {
+        token: String,
+    },
+    CreateInviteCode {
+        token: String,
+        max_uses: Option<u32>,
+        expires_in_hours: Option<i64>,
+    },
+    ListInviteCodes {
+        token: String,
+    },
+    RevokeInviteCode {
+        token: String,
+        code: String,
+    },
+    ListPendingUsers {
+        token: String,
+    },
+    ApproveUser {
+        token: String,
+        user_id: String,
+    },
+    RejectUser {
+        token: String,
+        user_id: String,
+    },
+    BeginExternalLogin {
+        provider: String,
+    },
+    CompleteExternalLogin {
+        provider: String,
+        state: String,
+        code: String,
+    },
+    CreateBot {
+        token: String,
+        username: String,
+    },
+    ListBots {
+        token: String,
+    },
+    CreateApiToken {
+        token: String,
+        bot_id: String,
+        name: String,
+        scopes: Vec<ApiScope>,
+    },
+    ListApiTokens {
+        token: String,
+        bot_id: String,
+    },
+    RevokeApiToken {
+        token: String,
+        token_id: String,
+    },
+}
+
+impl Request {
+    /// Variant name, matching the `type` tag clients send.
+    fn kind(&self) -> &'static str {
+        match self {
+            Request::Register { .. } => "Register",
+            Request::Login { .. } => "Login",
+            Request::ValidateSession { .. } => "ValidateSession",
+            Request::Logout { .. } => "Logout",
+            Request::ChangePassword { .. } => "ChangePassword",
+            Request::ForgotPassword { .. } => "ForgotPassword",
+            Request::ResetPassword { .. } => "ResetPassword",
+            Request::VerifyEmail { .. } => "VerifyEmail",
+            Request::ResendVerification { .. } => "ResendVerification",
+            Request::CreateInviteCode { .. } => "CreateInviteCode",
+            Request::ListInviteCodes { .. } => "ListInviteCodes",
+            Request::RevokeInviteCode { .. } => "RevokeInviteCode",
+            Request::ListPendingUsers { .. } => "ListPendingUsers",
+            Request::ApproveUser { .. } => "ApproveUser",
+            Request::RejectUser { .. } => "RejectUser",
+            Request::BeginExternalLogin { .. } => "BeginExternalLogin",
+            Request::CompleteExternalLogin { .. } => "CompleteExternalLogin",
+            Request::CreateBot { .. } => "CreateBot",
+            Request::ListBots { .. } => "ListBots",
+            Request::CreateApiToken { .. } => "CreateApiToken",
+            Request::ListApiTokens { .. } => "ListApiTokens",
+            Request::RevokeApiToken { .. } => "RevokeApiToken",
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -35,6 +138,7 @@ enum Response {
 pub struct TcpServer {
     auth: Arc<Mutex<AuthService>>,
     addr: String,
+    shutdown: Shutdown,
 }
 
 impl TcpServer {
@@ -45,23 +149,38 @@ impl TcpServer {
         Ok(Self {
             auth: Arc::new(Mutex::new(auth)),
             addr,
+            shutdown: Shutdown::new(),
         })
     }
 
+    pub fn with_auth(auth: Arc<Mutex<AuthService>>, addr: String) -> Self {
+        Self { auth, addr, shutdown: Shutdown::new() }
+    }
+
+    /// Stops accepting connections once `shutdown` is triggered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(&self.addr).await?;
-        println!("Server listening on {}", self.addr);
+        info!(addr = %self.addr, "TCP server listening");
+        metrics().listener_up("tcp");
 
         loop {
-            let (socket, addr) = listener.accept().await?;
-            println!("New connection from: {}", addr);
+            let (socket, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown.wait() => return Ok(()),
+            };
+            debug!(peer = %addr, "new TCP connection");
 
             let auth = Arc::clone(&self.auth);
             tokio::spawn(async move {
                 if let Err(e) = handle_client(socket, auth).await {
-                    eprintln!("Error handling clinet {}: {}", addr, e);
+                    warn!(error = %e, "TCP connection failed");
                 }
-            });
+            }.instrument(info_span!("tcp_connection", peer = %addr)));
         }
     }
 }
@@ -78,7 +197,10 @@ async fn handle_client(
 
         let request_str = String::from_utf8_lossy(&buffer[..n]);
         let response = match serde_json::from_str::<Request>(&request_str) {
-            Ok(request) => process_request(request, &auth).await,
+            Ok(request) => {
+                let span = info_span!("tcp_request", request_type = request.kind());
+                process_request(request, &auth).instrument(span).await
+            }
             Err(e) => Response::Error { message: format!("Invalid request format: {}", e) }
         };
 
@@ -88,15 +210,21 @@ async fn handle_client(
     }
 }
 
+fn error_response(error: AuthError) -> Response {
+    metrics().record_error(&error);
+    Response::Error { message: error.to_string() }
+}
+
 async fn process_request(request: Request, auth: &Arc<Mutex<AuthService>>) -> Response {
     let auth = auth.lock().await;
 
     match request {
-        Request::Register { username, email, password } => {
+        Request::Register { username, email, password, invite_code } => {
             let req = crate::users::CreateUserRequest {
                 username,
                 email,
-                password
+                password,
+                invite_code,
             };
 
             match auth.register(req) {
@@ -106,7 +234,7 @@ async fn process_request(request: Request, auth: &Arc<Mutex<AuthService>>) -> Re
                         Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
                     }
                 }
-                Err(e) => Response::Error { message: e.to_string() }
+                Err(e) => error_response(e)
             }
         }
         Request::Login { username, password } => {
@@ -122,13 +250,187 @@ async fn process_request(request: Request, auth: &Arc<Mutex<AuthService>>) -> Re
                         Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
                     }
                 }
-                Err(e) => Response::Error { message: e.to_string() }
+                Err(e) => error_response(e)
             }
         }
         Request::Logout { token } => {
             match auth.logout(&token) {
                 Ok(_) => Response::Success { data: serde_json::json!({"message": "Logged out successfully"}) },
-                Err(e) => Response::Error { message: e.to_string() }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ChangePassword { token, current_password, new_password } => {
+            let req = crate::users::ChangePasswordRequest {
+                token,
+                current_password,
+                new_password,
+            };
+
+            match auth.change_password(req) {
+                Ok(_) => Response::Success { data: serde_json::json!({"message": "Password changed successfully"}) },
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ForgotPassword { email } => {
+            match auth.request_password_reset(&email) {
+                Ok(_) => Response::Success { data: serde_json::json!({"message": "If the address is registered, a reset email has been sent"}) },
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ResetPassword { token, new_password } => {
+            let req = crate::users::ResetPasswordRequest {
+                token,
+                new_password,
+            };
+
+            match auth.reset_password(req) {
+                Ok(_) => Response::Success { data: serde_json::json!({"message": "Password reset successfully"}) },
+                Err(e) => error_response(e)
+            }
+        }
+        Request::VerifyEmail { token } => {
+            match auth.verify_email(&token) {
+                Ok(user) => {
+                    match serde_json::to_value(user) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ResendVerification { token } => {
+            match auth.resend_verification(&token) {
+                Ok(_) => Response::Success { data: serde_json::json!({"message": "Verification email sent"}) },
+                Err(e) => error_response(e)
+            }
+        }
+        Request::CreateInviteCode { token, max_uses, expires_in_hours } => {
+            match auth.create_invite_code(&token, max_uses, expires_in_hours) {
+                Ok(invite) => {
+                    match serde_json::to_value(invite) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ListInviteCodes { token } => {
+            match auth.list_invite_codes(&token) {
+                Ok(invites) => {
+                    match serde_json::to_value(invites) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::RevokeInviteCode { token, code } => {
+            match auth.revoke_invite_code(&token, &code) {
+                Ok(_) => Response::Success { data: serde_json::json!({"message": "Invite code revoked"}) },
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ListPendingUsers { token } => {
+            match auth.list_pending_users(&token) {
+                Ok(users) => {
+                    match serde_json::to_value(users) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ApproveUser { token, user_id } => {
+            match auth.approve_user(&token, &user_id) {
+                Ok(user) => {
+                    match serde_json::to_value(user) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::RejectUser { token, user_id } => {
+            match auth.reject_user(&token, &user_id) {
+                Ok(_) => Response::Success { data: serde_json::json!({"message": "User rejected"}) },
+                Err(e) => error_response(e)
+            }
+        }
+        Request::BeginExternalLogin { provider } => {
+            match auth.begin_external_login(&provider) {
+                Ok(request) => {
+                    match serde_json::to_value(request) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::CompleteExternalLogin { provider, state, code } => {
+            match auth.complete_external_login(&provider, &state, &code) {
+                Ok(response) => {
+                    match serde_json::to_value(response) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::CreateBot { token, username } => {
+            match auth.create_bot(&token, &username) {
+                Ok(bot) => {
+                    match serde_json::to_value(bot) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ListBots { token } => {
+            match auth.list_bots(&token) {
+                Ok(bots) => {
+                    match serde_json::to_value(bots) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::CreateApiToken { token, bot_id, name, scopes } => {
+            match auth.create_api_token(&token, &bot_id, &name, scopes) {
+                Ok(api_token) => {
+                    match serde_json::to_value(api_token) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::ListApiTokens { token, bot_id } => {
+            match auth.list_api_tokens(&token, &bot_id) {
+                Ok(api_tokens) => {
+                    match serde_json::to_value(api_tokens) {
+                        Ok(data) => Response::Success { data },
+                        Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
+                    }
+                }
+                Err(e) => error_response(e)
+            }
+        }
+        Request::RevokeApiToken { token, token_id } => {
+            match auth.revoke_api_token(&token, &token_id) {
+                Ok(_) => Response::Success { data: serde_json::json!({"message": "API token revoked"}) },
+                Err(e) => error_response(e)
             }
         }
         Request::ValidateSession { token } => {
@@ -139,7 +441,7 @@ async fn process_request(request: Request, auth: &Arc<Mutex<AuthService>>) -> Re
                         Err(e) => Response::Error { message: format!("Serialization error: {}", e) }
                     }
                 }
-                Err(e) => Response::Error { message: e.to_string() }
+                Err(e) => error_response(e)
             }
         }
     }
@@ -0,0 +1,1076 @@
+use crate::{
+    audit::{AuditEntry, AuditLogFilter},
+    error::Result,
+    filter::{HeldMessage, RoomFilter},
+    messages::{Message, MessageReport, ReactionDetail, ReactionSummary, ReportStatus, Room, RoomSanction, SanctionKind},
+    users::{ApiScope, ApiToken, EmailVerification, InviteCode, PasswordReset, Presence, Session, User, UserBlock, UserRole, UserSearch},
+    webhooks::{IncomingWebhook, Webhook, WebhookDeadLetter},
+};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+
+/// Persistence for everything the server keeps: users, sessions, invites,
+/// API and account email tokens, rooms and their members, room and private
+/// messages, mentions, reactions, pins, room filters and held messages,
+/// reports, the audit log and webhooks.
+/// `Database` (SQLite) is the default; `PostgresStorage` implements it for
+/// deployments that outgrow a single file. Both must pass the conformance
+/// suite in this module's tests.
+pub trait Storage: Send {
+    // Users
+
+    fn create_user(&self, username: &str, email: &str, password_hash: &str) -> Result<User> {
+        self.create_user_with_status(username, email, password_hash, true, true)
+    }
+
+    /// For accounts that start out waiting for approval or a verified email.
+    fn create_user_with_status(&self, username: &str, email: &str, password_hash: &str, approved: bool, email_verified: bool) -> Result<User>;
+
+    fn create_bot_user(&self, username: &str, email: &str, password_hash: &str, owner_id: &str) -> Result<User>;
+
+    fn get_user_by_username(&self, username: &str) -> Result<Option<User>>;
+
+    fn get_user_by_id(&self, user_id: String) -> Result<Option<User>>;
+
+    fn get_user_by_email(&self, email: &str) -> Result<Option<User>>;
+
+    fn get_user_by_identity(&self, provider: &str, subject: &str) -> Result<Option<User>>;
+
+    fn link_user_identity(&self, user_id: &str, provider: &str, subject: &str) -> Result<()>;
+
+    fn set_email_verified(&self, user_id: &str, verified: bool) -> Result<()>;
+
+    fn set_user_approved(&self, user_id: &str, approved: bool) -> Result<()>;
+
+    fn get_pending_users(&self) -> Result<Vec<User>>;
+
+    fn delete_user(&self, user_id: &str) -> Result<()>;
+
+    fn set_user_invite_code(&self, user_id: &str, code: &str) -> Result<()>;
+
+    fn update_user_password(&self, user_id: &str, password_hash: &str) -> Result<()>;
+
+    fn update_last_login(&self, user_id: String) -> Result<()>;
+
+    fn update_user_presence(&self, user_id: &str, presence: &Presence) -> Result<()>;
+
+    fn update_user_status(&self, user_id: &str, status: Option<&str>) -> Result<()>;
+
+    fn get_bots_by_owner(&self, owner_id: &str) -> Result<Vec<User>>;
+
+    fn set_user_role(&self, user_id: &str, role: UserRole) -> Result<()>;
+
+    /// `None` reactivates the account.
+    fn set_user_deactivated(&self, user_id: &str, deactivated_at: Option<DateTime<Utc>>) -> Result<()>;
+
+    /// Accounts matching the search, ordered by username.
+    fn search_users(&self, search: &UserSearch, limit: usize, offset: usize) -> Result<Vec<User>>;
+
+    // Blocks
+
+    /// Returns false if `blocker_id` had already blocked `blocked_id`.
+    fn block_user(&self, blocker_id: &str, blocked_id: &str) -> Result<bool>;
+
+    /// Returns whether there was a block to lift.
+    fn unblock_user(&self, blocker_id: &str, blocked_id: &str) -> Result<bool>;
+
+    fn is_user_blocked(&self, blocker_id: &str, blocked_id: &str) -> Result<bool>;
+
+    /// Newest first.
+    fn get_blocked_users(&self, blocker_id: &str) -> Result<Vec<UserBlock>>;
+
+    /// Ids of the users who have blocked `blocked_id`.
+    fn get_blocker_ids(&self, blocked_id: &str) -> Result<Vec<String>>;
+
+    // Sessions
+
+    fn create_session(&self, user_id: String, token: &str, expires_at: DateTime<Utc>) -> Result<Session>;
+
+    fn get_session_by_token(&self, token: &str) -> Result<Option<Session>>;
+
+    fn delete_session(&self, token: &str) -> Result<()>;
+
+    fn delete_expired_sessions(&self) -> Result<()>;
+
+    /// Deletes every session belonging to `user_id`, optionally keeping the one
+    /// identified by `keep_token`.
+    fn delete_user_sessions(&self, user_id: &str, keep_token: Option<&str>) -> Result<()>;
+
+    // Rooms and members
+
+    /// Creates the room and makes its creator a member.
+    fn create_room(&self, name: &str, desc: &str, created_by: &str) -> Result<Room>;
+
+    fn get_all_rooms(&self, include_archived: bool) -> Result<Vec<Room>>;
+
+    fn get_room_by_id(&self, room_id: &str) -> Result<Option<Room>>;
+
+    fn update_room(&self, room_id: &str, name: &str, desc: &str, topic: &str) -> Result<()>;
+
+    /// Archives the room as of now, or unarchives it when `archived` is false.
+    fn set_room_archived(&self, room_id: &str, archived: bool) -> Result<Option<DateTime<Utc>>>;
+
+    /// Deletes the room along with its members, messages, mentions and
+    /// reactions.
+    fn delete_room(&self, room_id: &str) -> Result<()>;
+
+    /// Joining a room twice is not an error.
+    fn add_user_to_room(&self, room_id: &str, user_id: &str) -> Result<()>;
+
+    fn remove_user_from_room(&self, room_id: &str, user_id: &str) -> Result<()>;
+
+    fn is_user_in_room(&self, room_id: &str, user_id: &str) -> Result<bool>;
+
+    fn get_user_rooms(&self, user_id: &str) -> Result<Vec<Room>>;
+
+    fn get_room_members(&self, room_id: &str) -> Result<Vec<User>>;
+
+    // Bans and mutes
+
+    /// Replaces any existing sanction of the same kind on the user.
+    fn add_room_sanction(&self, kind: SanctionKind, sanction: &RoomSanction) -> Result<()>;
+
+    /// Returns whether there was a sanction to lift.
+    fn remove_room_sanction(&self, kind: SanctionKind, room_id: &str, user_id: &str) -> Result<bool>;
+
+    /// The user's sanction of this kind, unless it has expired.
+    fn get_room_sanction(&self, kind: SanctionKind, room_id: &str, user_id: &str) -> Result<Option<RoomSanction>>;
+
+    /// Sanctions of this kind still in force, newest first.
+    fn get_room_sanctions(&self, kind: SanctionKind, room_id: &str) -> Result<Vec<RoomSanction>>;
+
+    // Room messages
+
+    fn create_room_message(&self, sender_id: &str, room_id: &str, content: &str, reply_to_message_id: Option<&str>) -> Result<Message>;
+
+    fn set_message_display_name(&self, message_id: &str, display_name: &str) -> Result<()>;
+
+    /// Newest first.
+    fn get_room_messages(&self, room_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>>;
+
+    fn get_message_by_id(&self, message_id: &str) -> Result<Option<Message>>;
+
+    fn edit_message(&self, message_id: &str, content: &str) -> Result<()>;
+
+    /// Only deletes the message if `user1_id` sent it.
+    fn delete_message(&self, message_id: &str, user1_id: &str) -> Result<()>;
+
+    fn room_announcement(&self, room_id: &str, content: &str, sender_id: &str) -> Result<Message>;
+
+    // Private messages
+
+    fn create_private_message(&self, sender_id: &str, receiver_id: &str, content: &str) -> Result<Message>;
+
+    fn get_private_messages_between_users(&self, user1_id: &str, user2_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>>;
+
+    fn get_received_private_messages(&self, receiver_id: &str, unread_only: bool, limit: usize, offset: usize) -> Result<Vec<Message>>;
+
+    fn mark_private_message_as_read(&self, message_id: &str) -> Result<()>;
+
+    fn mark_private_conversation_as_read(&self, receiver_id: &str, sender_id: &str) -> Result<()>;
+
+    fn get_unread_private_message_count(&self, user_id: &str) -> Result<i64>;
+
+    // Mentions
+
+    /// Records mentions of users named in `content`, or of every other room
+    /// member for `@everyone`, and returns the ids of the users to notify.
+    fn save_message_mentions(&self, message_id: &str, sender_id: &str, content: &str, room_id: &str) -> Result<Vec<String>>;
+
+    /// Ids of the users mentioned in a message.
+    fn get_message_mentions(&self, message_id: &str) -> Result<Vec<String>>;
+
+    fn get_unread_mentions_count(&self, user_id: &str) -> Result<i64>;
+
+    fn mark_mention_as_read(&self, user_id: &str, message_id: &str) -> Result<()>;
+
+    fn mark_room_mentions_as_read(&self, user_id: &str, room_id: &str) -> Result<()>;
+
+    fn get_all_user_mentions(&self, user_id: &str, limit: usize, offset: usize) -> Result<Vec<Message>>;
+
+    // Reactions and pins
+
+    /// Reacting twice with the same emoji is not an error. Returns the
+    /// message's reactions after the change.
+    fn add_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<Vec<ReactionSummary>>;
+
+    /// Returns the message's reactions after the change.
+    fn remove_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<Vec<ReactionSummary>>;
+
+    /// Reactions grouped by emoji, in the order each emoji was first used.
+    fn get_reactions(&self, message_id: &str) -> Result<Vec<ReactionSummary>> {
+        Ok(summarize_reactions(&self.get_reaction_details(message_id)?))
+    }
+
+    /// Every reaction to a message, oldest first.
+    fn get_reaction_details(&self, message_id: &str) -> Result<Vec<ReactionDetail>>;
+
+    fn pin_message(&self, message_id: &str, user_id: &str) -> Result<()>;
+
+    fn unpin_message(&self, message_id: &str) -> Result<()>;
+
+    fn get_pinned_messages(&self, room_id: &str) -> Result<Vec<Message>>;
+
+    // Content filters
+
+    fn get_room_filter(&self, room_id: &str) -> Result<Option<RoomFilter>>;
+
+    /// Replaces the room's filter.
+    fn set_room_filter(&self, room_id: &str, filter: &RoomFilter) -> Result<()>;
+
+    fn hold_message(&self, message: &HeldMessage) -> Result<()>;
+
+    fn get_held_message(&self, held_id: &str) -> Result<Option<HeldMessage>>;
+
+    /// Oldest first, the order moderators should review them in.
+    fn get_held_messages(&self, room_id: &str, limit: usize, offset: usize) -> Result<Vec<HeldMessage>>;
+
+    /// Returns whether there was a held message to remove.
+    fn remove_held_message(&self, held_id: &str) -> Result<bool>;
+
+    // Reports
+
+    /// Stores nothing and returns false if the reporter already has an open
+    /// report on the message.
+    fn create_report(&self, report: &MessageReport) -> Result<bool>;
+
+    fn get_report(&self, report_id: &str) -> Result<Option<MessageReport>>;
+
+    /// Newest first. Without a room, reports from every room.
+    fn get_reports(&self, room_id: Option<&str>, status: Option<ReportStatus>, limit: usize, offset: usize) -> Result<Vec<MessageReport>>;
+
+    /// Saves the report's status and resolution if it is still open. Returns
+    /// false if someone else closed it first.
+    fn close_report(&self, report: &MessageReport) -> Result<bool>;
+
+    // Audit log
+
+    /// Entries can never be changed or removed once appended.
+    fn append_audit_entry(&self, entry: &AuditEntry) -> Result<()>;
+
+    /// Entries matching the filter, newest first.
+    fn get_audit_log(&self, filter: &AuditLogFilter, limit: usize, offset: usize) -> Result<Vec<AuditEntry>>;
+
+    // Invites
+
+    fn create_invite_code(&self, code: &str, created_by: &str, max_uses: Option<u32>, expires_at: Option<DateTime<Utc>>) -> Result<InviteCode>;
+
+    /// Newest first, with the usernames registered with each.
+    fn get_invite_codes(&self) -> Result<Vec<InviteCode>>;
+
+    /// Atomically takes one use of an invite code. Returns false if the code
+    /// doesn't exist, is revoked, expired or has no uses left.
+    fn redeem_invite_code(&self, code: &str) -> Result<bool>;
+
+    fn revoke_invite_code(&self, code: &str) -> Result<bool>;
+
+    // API tokens
+
+    fn create_api_token(&self, user_id: &str, name: &str, token_hash: &str, scopes: &[ApiScope]) -> Result<ApiToken>;
+
+    fn get_api_token(&self, id: &str) -> Result<Option<ApiToken>>;
+
+    fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>>;
+
+    /// Oldest first.
+    fn get_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>>;
+
+    fn touch_api_token(&self, id: &str) -> Result<()>;
+
+    fn revoke_api_token(&self, id: &str) -> Result<bool>;
+
+    // Account emails
+    // Tokens are stored and looked up by their SHA-256 hash, like API tokens
+
+    fn create_email_verification(&self, user_id: &str, token: &str, expires_at: DateTime<Utc>) -> Result<EmailVerification>;
+
+    fn get_email_verification(&self, token: &str) -> Result<Option<EmailVerification>>;
+
+    /// Marks a verification token as used. Returns false if it was already consumed.
+    fn consume_email_verification(&self, token: &str) -> Result<bool>;
+
+    fn delete_expired_email_verifications(&self) -> Result<()>;
+
+    fn create_password_reset(&self, user_id: &str, token: &str, expires_at: DateTime<Utc>) -> Result<PasswordReset>;
+
+    fn get_password_reset(&self, token: &str) -> Result<Option<PasswordReset>>;
+
+    /// Marks a reset token as used. Returns false if it was already consumed.
+    fn consume_password_reset(&self, token: &str) -> Result<bool>;
+
+    fn delete_expired_password_resets(&self) -> Result<()>;
+
+    // Webhooks
+
+    fn create_webhook(&self, room_id: &str, url: &str, secret: &str, events: &[String], created_by: &str) -> Result<Webhook>;
+
+    fn get_webhook(&self, id: &str) -> Result<Option<Webhook>>;
+
+    /// Oldest first.
+    fn get_room_webhooks(&self, room_id: &str) -> Result<Vec<Webhook>>;
+
+    /// Also drops the webhook's dead letters.
+    fn delete_webhook(&self, id: &str) -> Result<()>;
+
+    fn create_webhook_dead_letter(&self, webhook_id: &str, event: &str, payload: &str, attempts: u32, last_error: &str) -> Result<()>;
+
+    /// Dead letters of every webhook in the room, newest first.
+    fn get_webhook_dead_letters(&self, room_id: &str) -> Result<Vec<WebhookDeadLetter>>;
+
+    fn create_incoming_webhook(&self, room_id: &str, name: &str, token_hash: &str, bot_user_id: &str, created_by: &str) -> Result<IncomingWebhook>;
+
+    fn get_incoming_webhook(&self, id: &str) -> Result<Option<IncomingWebhook>>;
+
+    fn get_incoming_webhook_by_token_hash(&self, token_hash: &str) -> Result<Option<IncomingWebhook>>;
+
+    /// Oldest first.
+    fn get_room_incoming_webhooks(&self, room_id: &str) -> Result<Vec<IncomingWebhook>>;
+
+    fn delete_incoming_webhook(&self, id: &str) -> Result<()>;
+
+    /// Runs `f` in a transaction, committing only if it succeeds. Whatever
+    /// `f` does through this storage is part of it. Transactions don't nest.
+    /// See `in_transaction` for one that returns a value.
+    fn transaction(&self, f: &mut dyn FnMut() -> Result<()>) -> Result<()>;
+
+    /// Cheap round trip used by readiness checks.
+    fn ping(&self) -> Result<()>;
+}
+
+impl dyn Storage + '_ {
+    /// `transaction` for a body that returns a value.
+    pub fn in_transaction<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let mut f = Some(f);
+        let mut value = None;
+        self.transaction(&mut || {
+            if let Some(f) = f.take() {
+                value = Some(f()?);
+            }
+            Ok(())
+        })?;
+        Ok(value.expect("transaction runs its body"))
+    }
+}
+
+/// A `LIKE` pattern matching `query` anywhere, with its wildcards escaped
+/// (use `ESCAPE '\'`).
+pub(crate) fn like_pattern(query: &str) -> String {
+    let escaped = query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// Usernames mentioned with `@name`, not counting `@everyone`.
+pub fn extract_mentions(content: &str) -> Vec<String> {
+    let re = Regex::new(r"@(\w+)").unwrap();
+    re.captures_iter(content)
+        .filter_map(|cap| cap.get(1).map(|m| m.as_str().to_string()))
+        .filter(|username| username.to_lowercase() != "everyone")
+        .collect()
+}
+
+pub fn everyone_mentioned(content: &str) -> bool {
+    content.to_lowercase().contains("@everyone")
+}
+
+/// Groups reactions, oldest first, into one summary per emoji.
+pub fn summarize_reactions(details: &[ReactionDetail]) -> Vec<ReactionSummary> {
+    let mut summaries: Vec<ReactionSummary> = Vec::new();
+    for detail in details {
+        let index = match summaries.iter().position(|s| s.emoji == detail.emoji) {
+            Some(index) => index,
+            None => {
+                summaries.push(ReactionSummary {
+                    emoji: detail.emoji.clone(),
+                    count: 0,
+                    user_ids: Vec::new(),
+                    usernames: Vec::new(),
+                });
+                summaries.len() - 1
+            }
+        };
+
+        let summary = &mut summaries[index];
+        summary.count += 1;
+        summary.user_ids.push(detail.user_id.clone());
+        summary.usernames.push(detail.username.clone());
+    }
+    summaries
+}
+
+/// Behaviour every `Storage` implementation must share. Each backend's tests
+/// run this against a fresh, empty store.
+#[cfg(test)]
+pub(crate) mod conformance {
+    use super::*;
+    use crate::audit::AuditAction;
+    use crate::error::AuthError;
+    use crate::filter::FilterAction;
+    use crate::messages::{MessageType, ReportAction};
+    use chrono::Duration;
+
+    pub(crate) fn run(storage: &dyn Storage) {
+        users(storage);
+        roles_and_search(storage);
+        blocks(storage);
+        sessions(storage);
+        rooms(storage);
+        sanctions(storage);
+        room_messages(storage);
+        private_messages(storage);
+        mentions(storage);
+        reactions_and_pins(storage);
+        filters_and_held_messages(storage);
+        reports(storage);
+        audit_log(storage);
+        invites_and_tokens(storage);
+        account_emails(storage);
+        webhooks(storage);
+        transactions(storage);
+        storage.ping().unwrap();
+    }
+
+    /// Keeps `sent_at` ordering unambiguous between consecutive inserts.
+    fn tick() {
+        std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+
+    fn users(storage: &dyn Storage) {
+        let alice = storage.create_user("alice", "alice@test.com", "hash").unwrap();
+        assert!(matches!(alice.presence, Presence::Offline));
+        assert!(storage.create_user("alice", "other@test.com", "hash").is_err());
+        assert!(storage.create_user("other", "alice@test.com", "hash").is_err());
+
+        assert_eq!(storage.get_user_by_username("alice").unwrap().unwrap().id, alice.id);
+        assert_eq!(storage.get_user_by_email("alice@test.com").unwrap().unwrap().id, alice.id);
+        assert!(storage.get_user_by_username("nobody").unwrap().is_none());
+
+        storage.update_user_password(&alice.id, "new-hash").unwrap();
+        storage.update_user_presence(&alice.id, &Presence::DoNotDisturb).unwrap();
+        storage.update_user_status(&alice.id, Some("lunch")).unwrap();
+        storage.update_last_login(alice.id.clone()).unwrap();
+        storage.set_user_invite_code(&alice.id, "INVITE").unwrap();
+        let reloaded = storage.get_user_by_id(alice.id.clone()).unwrap().unwrap();
+        assert_eq!(reloaded.password_hash, "new-hash");
+        assert!(matches!(reloaded.presence, Presence::DoNotDisturb));
+        assert_eq!(reloaded.status.as_deref(), Some("lunch"));
+        assert!(reloaded.last_login.is_some());
+        assert_eq!(reloaded.created_at.timestamp(), alice.created_at.timestamp());
+
+        storage.update_user_status(&alice.id, None).unwrap();
+        assert!(storage.get_user_by_id(alice.id.clone()).unwrap().unwrap().status.is_none());
+
+        let pending = storage.create_user_with_status("pending", "pending@test.com", "hash", false, false).unwrap();
+        assert!(!pending.approved && !pending.email_verified);
+        let pending_users = storage.get_pending_users().unwrap();
+        assert_eq!(pending_users.len(), 1);
+        assert!(!pending_users[0].email_verified);
+        storage.set_user_approved(&pending.id, true).unwrap();
+        storage.set_email_verified(&pending.id, true).unwrap();
+        assert!(storage.get_pending_users().unwrap().is_empty());
+        assert!(storage.get_user_by_id(pending.id.clone()).unwrap().unwrap().email_verified);
+
+        let bot = storage.create_bot_user("alice-bot", "bot@test.com", "!external", &alice.id).unwrap();
+        assert!(bot.is_bot);
+        let bots = storage.get_bots_by_owner(&alice.id).unwrap();
+        assert_eq!(bots.len(), 1);
+        assert_eq!(bots[0].bot_owner_id.as_deref(), Some(alice.id.as_str()));
+
+        storage.link_user_identity(&alice.id, "oidc", "subject-1").unwrap();
+        assert_eq!(storage.get_user_by_identity("oidc", "subject-1").unwrap().unwrap().id, alice.id);
+        assert!(storage.get_user_by_identity("oidc", "subject-2").unwrap().is_none());
+
+        storage.delete_user(&pending.id).unwrap();
+        assert!(storage.get_user_by_id(pending.id).unwrap().is_none());
+    }
+
+    fn sessions(storage: &dyn Storage) {
+        let user = storage.create_user("sessions", "sessions@test.com", "hash").unwrap();
+        let expires_at = Utc::now() + Duration::hours(1);
+
+        let first = storage.create_session(user.id.clone(), "token-1", expires_at).unwrap();
+        storage.create_session(user.id.clone(), "token-2", expires_at).unwrap();
+        storage.create_session(user.id.clone(), "token-expired", Utc::now() - Duration::hours(1)).unwrap();
+
+        let loaded = storage.get_session_by_token("token-1").unwrap().unwrap();
+        assert_eq!(loaded.id, first.id);
+        assert_eq!(loaded.user_id, user.id);
+        assert_eq!(loaded.expires_at.timestamp(), expires_at.timestamp());
+
+        storage.delete_expired_sessions().unwrap();
+        assert!(storage.get_session_by_token("token-expired").unwrap().is_none());
+
+        storage.delete_user_sessions(&user.id, Some("token-2")).unwrap();
+        assert!(storage.get_session_by_token("token-1").unwrap().is_none());
+        assert!(storage.get_session_by_token("token-2").unwrap().is_some());
+
+        storage.delete_session("token-2").unwrap();
+        assert!(storage.get_session_by_token("token-2").unwrap().is_none());
+    }
+
+    fn roles_and_search(storage: &dyn Storage) {
+        let root = storage.create_user("root_admin", "root@test.com", "hash").unwrap();
+        assert_eq!(root.role, UserRole::User);
+        storage.set_user_role(&root.id, UserRole::Admin).unwrap();
+        assert!(storage.get_user_by_id(root.id.clone()).unwrap().unwrap().is_admin());
+
+        let gone = storage.create_user("gone_user", "gone@test.com", "hash").unwrap();
+        let now = Utc::now();
+        storage.set_user_deactivated(&gone.id, Some(now)).unwrap();
+        let reloaded = storage.get_user_by_id(gone.id.clone()).unwrap().unwrap();
+        assert_eq!(reloaded.deactivated_at.map(|t| t.timestamp()), Some(now.timestamp()));
+
+        let search = |query: Option<&str>, role: Option<UserRole>, deactivated: Option<bool>| -> Vec<String> {
+            let search = UserSearch { query: query.map(str::to_string), role, deactivated };
+            storage.search_users(&search, 50, 0).unwrap().into_iter().map(|u| u.username).collect()
+        };
+        assert_eq!(search(Some("ROOT"), None, None), vec!["root_admin"]);
+        assert_eq!(search(Some("gone@"), None, None), vec!["gone_user"]);
+        assert_eq!(search(None, Some(UserRole::Admin), None), vec!["root_admin"]);
+        assert_eq!(search(None, None, Some(true)), vec!["gone_user"]);
+        assert!(!search(None, None, Some(false)).contains(&"gone_user".to_string()));
+        // Wildcards are matched literally
+        assert_eq!(search(Some("t_a"), None, None), vec!["root_admin"]);
+        assert!(search(Some("%"), None, None).is_empty());
+        assert_eq!(storage.search_users(&UserSearch::default(), 1, 1).unwrap().len(), 1);
+
+        storage.set_user_deactivated(&gone.id, None).unwrap();
+        assert!(storage.get_user_by_id(gone.id.clone()).unwrap().unwrap().deactivated_at.is_none());
+        storage.set_user_role(&root.id, UserRole::User).unwrap();
+        storage.delete_user(&root.id).unwrap();
+        storage.delete_user(&gone.id).unwrap();
+    }
+
+    fn blocks(storage: &dyn Storage) {
+        let blocker = storage.create_user("blocker", "blocker@test.com", "hash").unwrap();
+        let pest = storage.create_user("pest", "pest@test.com", "hash").unwrap();
+        let other = storage.create_user("other-pest", "other-pest@test.com", "hash").unwrap();
+
+        assert!(storage.block_user(&blocker.id, &pest.id).unwrap());
+        assert!(!storage.block_user(&blocker.id, &pest.id).unwrap());
+        tick();
+        assert!(storage.block_user(&blocker.id, &other.id).unwrap());
+        assert!(storage.is_user_blocked(&blocker.id, &pest.id).unwrap());
+        // Blocks are one-way
+        assert!(!storage.is_user_blocked(&pest.id, &blocker.id).unwrap());
+
+        let blocked: Vec<String> = storage.get_blocked_users(&blocker.id).unwrap().into_iter().map(|b| b.username).collect();
+        assert_eq!(blocked, vec!["other-pest", "pest"]);
+        assert_eq!(storage.get_blocker_ids(&pest.id).unwrap(), vec![blocker.id.clone()]);
+        assert!(storage.get_blocker_ids(&blocker.id).unwrap().is_empty());
+
+        assert!(storage.unblock_user(&blocker.id, &pest.id).unwrap());
+        assert!(!storage.unblock_user(&blocker.id, &pest.id).unwrap());
+        assert!(!storage.is_user_blocked(&blocker.id, &pest.id).unwrap());
+
+        storage.delete_user(&other.id).unwrap();
+        assert!(storage.get_blocked_users(&blocker.id).unwrap().is_empty());
+    }
+
+    fn rooms(storage: &dyn Storage) {
+        let owner = storage.create_user("owner", "owner@test.com", "hash").unwrap();
+        let member = storage.create_user("member", "member@test.com", "hash").unwrap();
+
+        let general = storage.create_room("general", "Everything", &owner.id).unwrap();
+        tick();
+        let random = storage.create_room("random", "", &owner.id).unwrap();
+        assert!(storage.is_user_in_room(&general.id, &owner.id).unwrap());
+
+        let all: Vec<String> = storage.get_all_rooms(false).unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(all, vec![random.id.clone(), general.id.clone()]);
+        let loaded = storage.get_room_by_id(&general.id).unwrap().unwrap();
+        assert_eq!(loaded.desc, "Everything");
+        assert_eq!(loaded.created_by, owner.id);
+        assert!(storage.get_room_by_id("missing").unwrap().is_none());
+
+        storage.add_user_to_room(&general.id, &member.id).unwrap();
+        storage.add_user_to_room(&general.id, &member.id).unwrap();
+        assert!(storage.is_user_in_room(&general.id, &member.id).unwrap());
+        let members: Vec<String> = storage.get_room_members(&general.id).unwrap().into_iter().map(|u| u.username).collect();
+        assert_eq!(members, vec!["member", "owner"]);
+        assert_eq!(storage.get_user_rooms(&member.id).unwrap().len(), 1);
+
+        storage.remove_user_from_room(&general.id, &member.id).unwrap();
+        assert!(!storage.is_user_in_room(&general.id, &member.id).unwrap());
+        assert!(storage.get_user_rooms(&member.id).unwrap().is_empty());
+
+        storage.update_room(&general.id, "lobby", "Say hi", "Welcome week").unwrap();
+        let loaded = storage.get_room_by_id(&general.id).unwrap().unwrap();
+        assert_eq!((loaded.name.as_str(), loaded.desc.as_str(), loaded.topic.as_str()), ("lobby", "Say hi", "Welcome week"));
+        assert!(loaded.archived_at.is_none());
+
+        let archived_at = storage.set_room_archived(&random.id, true).unwrap();
+        assert!(archived_at.is_some());
+        assert_eq!(storage.get_room_by_id(&random.id).unwrap().unwrap().archived_at.map(|t| t.timestamp()), archived_at.map(|t| t.timestamp()));
+        let visible: Vec<String> = storage.get_all_rooms(false).unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(visible, vec![general.id.clone()]);
+        assert_eq!(storage.get_all_rooms(true).unwrap().len(), 2);
+        assert!(storage.set_room_archived(&random.id, false).unwrap().is_none());
+        assert!(storage.get_room_by_id(&random.id).unwrap().unwrap().archived_at.is_none());
+
+        storage.add_user_to_room(&random.id, &member.id).unwrap();
+        let message = storage.create_room_message(&member.id, &random.id, "hi @owner", None).unwrap();
+        storage.save_message_mentions(&message.id, &member.id, &message.content, &random.id).unwrap();
+        storage.add_reaction(&message.id, &owner.id, "👋").unwrap();
+        storage.delete_room(&random.id).unwrap();
+        assert!(storage.get_room_by_id(&random.id).unwrap().is_none());
+        assert!(!storage.is_user_in_room(&random.id, &member.id).unwrap());
+        assert!(storage.get_message_by_id(&message.id).unwrap().is_none());
+        assert!(storage.get_reaction_details(&message.id).unwrap().is_empty());
+        assert_eq!(storage.get_unread_mentions_count(&owner.id).unwrap(), 0);
+    }
+
+    fn sanctions(storage: &dyn Storage) {
+        let moderator = storage.create_user("moderator", "moderator@test.com", "hash").unwrap();
+        let troll = storage.create_user("troll", "troll@test.com", "hash").unwrap();
+        let room = storage.create_room("moderated", "", &moderator.id).unwrap();
+        let sanction = |user_id: &str, expires_at| RoomSanction {
+            room_id: room.id.clone(),
+            user_id: user_id.to_string(),
+            username: String::new(),
+            reason: Some("spam".to_string()),
+            issued_by: moderator.id.clone(),
+            created_at: Utc::now(),
+            expires_at,
+        };
+
+        storage.add_room_sanction(SanctionKind::Ban, &sanction(&troll.id, None)).unwrap();
+        let ban = storage.get_room_sanction(SanctionKind::Ban, &room.id, &troll.id).unwrap().unwrap();
+        assert_eq!(ban.username, "troll");
+        assert_eq!(ban.reason.as_deref(), Some("spam"));
+        assert!(storage.get_room_sanction(SanctionKind::Mute, &room.id, &troll.id).unwrap().is_none());
+
+        // Re-banning replaces the old ban
+        let until = Utc::now() + Duration::hours(1);
+        storage.add_room_sanction(SanctionKind::Ban, &sanction(&troll.id, Some(until))).unwrap();
+        let bans = storage.get_room_sanctions(SanctionKind::Ban, &room.id).unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].expires_at.map(|t| t.timestamp()), Some(until.timestamp()));
+
+        assert!(storage.remove_room_sanction(SanctionKind::Ban, &room.id, &troll.id).unwrap());
+        assert!(!storage.remove_room_sanction(SanctionKind::Ban, &room.id, &troll.id).unwrap());
+        assert!(storage.get_room_sanctions(SanctionKind::Ban, &room.id).unwrap().is_empty());
+
+        storage.add_room_sanction(SanctionKind::Mute, &sanction(&troll.id, Some(Utc::now() - Duration::minutes(1)))).unwrap();
+        assert!(storage.get_room_sanction(SanctionKind::Mute, &room.id, &troll.id).unwrap().is_none());
+        assert!(storage.get_room_sanctions(SanctionKind::Mute, &room.id).unwrap().is_empty());
+    }
+
+    fn room_messages(storage: &dyn Storage) {
+        let sender = storage.create_user("sender", "sender@test.com", "hash").unwrap();
+        let other = storage.create_user("other_sender", "other_sender@test.com", "hash").unwrap();
+        let room = storage.create_room("messages", "", &sender.id).unwrap();
+
+        let first = storage.create_room_message(&sender.id, &room.id, "first", None).unwrap();
+        tick();
+        let reply = storage.create_room_message(&sender.id, &room.id, "reply", Some(&first.id)).unwrap();
+        tick();
+        let announcement = storage.room_announcement(&room.id, "welcome", &sender.id).unwrap();
+        assert!(matches!(announcement.message_type, MessageType::Server));
+
+        let history: Vec<String> = storage.get_room_messages(&room.id, 10, 0).unwrap().into_iter().map(|m| m.content).collect();
+        assert_eq!(history, vec!["welcome", "reply", "first"]);
+        let page: Vec<String> = storage.get_room_messages(&room.id, 1, 1).unwrap().into_iter().map(|m| m.content).collect();
+        assert_eq!(page, vec!["reply"]);
+
+        let loaded = storage.get_message_by_id(&reply.id).unwrap().unwrap();
+        assert!(matches!(loaded.message_type, MessageType::Room));
+        assert_eq!(loaded.room_id.as_deref(), Some(room.id.as_str()));
+        assert_eq!(loaded.reply_to_message_id.as_deref(), Some(first.id.as_str()));
+        assert!(!loaded.is_edited);
+
+        storage.edit_message(&first.id, "first, edited").unwrap();
+        storage.set_message_display_name(&first.id, "CI").unwrap();
+        let edited = storage.get_message_by_id(&first.id).unwrap().unwrap();
+        assert_eq!(edited.content, "first, edited");
+        assert!(edited.is_edited);
+        assert!(edited.edited_at.is_some());
+        assert_eq!(edited.display_name.as_deref(), Some("CI"));
+
+        storage.delete_message(&reply.id, &other.id).unwrap();
+        assert!(storage.get_message_by_id(&reply.id).unwrap().is_some());
+        storage.delete_message(&reply.id, &sender.id).unwrap();
+        assert!(storage.get_message_by_id(&reply.id).unwrap().is_none());
+        assert!(storage.get_message_by_id("missing").unwrap().is_none());
+    }
+
+    fn private_messages(storage: &dyn Storage) {
+        let ann = storage.create_user("ann", "ann@test.com", "hash").unwrap();
+        let ben = storage.create_user("ben", "ben@test.com", "hash").unwrap();
+
+        let hello = storage.create_private_message(&ann.id, &ben.id, "hello").unwrap();
+        tick();
+        storage.create_private_message(&ben.id, &ann.id, "hi").unwrap();
+        tick();
+        storage.create_private_message(&ann.id, &ben.id, "how are you?").unwrap();
+
+        let conversation: Vec<String> = storage.get_private_messages_between_users(&ben.id, &ann.id, 10, 0).unwrap()
+            .into_iter().map(|m| m.content).collect();
+        assert_eq!(conversation, vec!["how are you?", "hi", "hello"]);
+        assert_eq!(storage.get_unread_private_message_count(&ben.id).unwrap(), 2);
+
+        storage.mark_private_message_as_read(&hello.id).unwrap();
+        let unread = storage.get_received_private_messages(&ben.id, true, 10, 0).unwrap();
+        assert_eq!(unread.len(), 1);
+        assert_eq!(unread[0].content, "how are you?");
+        let read = storage.get_message_by_id(&hello.id).unwrap().unwrap();
+        assert!(read.is_read);
+        assert!(read.read_at.is_some());
+        assert_eq!(storage.get_received_private_messages(&ben.id, false, 10, 0).unwrap().len(), 2);
+
+        storage.mark_private_conversation_as_read(&ben.id, &ann.id).unwrap();
+        assert_eq!(storage.get_unread_private_message_count(&ben.id).unwrap(), 0);
+        assert_eq!(storage.get_unread_private_message_count(&ann.id).unwrap(), 1);
+    }
+
+    fn mentions(storage: &dyn Storage) {
+        let carol = storage.create_user("carol", "carol@test.com", "hash").unwrap();
+        let dave = storage.create_user("dave", "dave@test.com", "hash").unwrap();
+        let erin = storage.create_user("erin", "erin@test.com", "hash").unwrap();
+        let room = storage.create_room("mentions", "", &carol.id).unwrap();
+        storage.add_user_to_room(&room.id, &dave.id).unwrap();
+        storage.add_user_to_room(&room.id, &erin.id).unwrap();
+
+        let direct = storage.create_room_message(&carol.id, &room.id, "@dave @carol @nobody look", None).unwrap();
+        let notified = storage.save_message_mentions(&direct.id, &carol.id, &direct.content, &room.id).unwrap();
+        assert_eq!(notified, vec![dave.id.clone()]);
+        assert_eq!(storage.get_message_mentions(&direct.id).unwrap(), vec![dave.id.clone()]);
+
+        tick();
+        let everyone = storage.create_room_message(&dave.id, &room.id, "@everyone lunch", None).unwrap();
+        let mut notified = storage.save_message_mentions(&everyone.id, &dave.id, &everyone.content, &room.id).unwrap();
+        notified.sort();
+        let mut expected = vec![carol.id.clone(), erin.id.clone()];
+        expected.sort();
+        assert_eq!(notified, expected);
+
+        assert_eq!(storage.get_unread_mentions_count(&dave.id).unwrap(), 1);
+        let mentioned: Vec<String> = storage.get_all_user_mentions(&dave.id, 10, 0).unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(mentioned, vec![direct.id.clone()]);
+        storage.mark_mention_as_read(&dave.id, &direct.id).unwrap();
+        assert_eq!(storage.get_unread_mentions_count(&dave.id).unwrap(), 0);
+
+        assert_eq!(storage.get_unread_mentions_count(&erin.id).unwrap(), 1);
+        storage.mark_room_mentions_as_read(&erin.id, &room.id).unwrap();
+        assert_eq!(storage.get_unread_mentions_count(&erin.id).unwrap(), 0);
+    }
+
+    fn reactions_and_pins(storage: &dyn Storage) {
+        let fay = storage.create_user("fay", "fay@test.com", "hash").unwrap();
+        let gus = storage.create_user("gus", "gus@test.com", "hash").unwrap();
+        let room = storage.create_room("reactions", "", &fay.id).unwrap();
+        let message = storage.create_room_message(&fay.id, &room.id, "react to me", None).unwrap();
+
+        storage.add_reaction(&message.id, &fay.id, "👍").unwrap();
+        storage.add_reaction(&message.id, &fay.id, "👍").unwrap();
+        tick();
+        storage.add_reaction(&message.id, &gus.id, "🎉").unwrap();
+        tick();
+        let reactions = storage.add_reaction(&message.id, &gus.id, "👍").unwrap();
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions[0].emoji, "👍");
+        assert_eq!(reactions[0].count, 2);
+        assert_eq!(reactions[0].usernames, vec!["fay", "gus"]);
+        assert_eq!(reactions[1].user_ids, vec![gus.id.clone()]);
+
+        let loaded = storage.get_message_by_id(&message.id).unwrap().unwrap();
+        assert_eq!(loaded.reactions[0].count, 2);
+        assert_eq!(storage.get_room_messages(&room.id, 10, 0).unwrap()[0].reactions.len(), 2);
+
+        let details = storage.get_reaction_details(&message.id).unwrap();
+        let who: Vec<(&str, &str)> = details.iter().map(|d| (d.emoji.as_str(), d.username.as_str())).collect();
+        assert_eq!(who, vec![("👍", "fay"), ("🎉", "gus"), ("👍", "gus")]);
+
+        let reactions = storage.remove_reaction(&message.id, &gus.id, "🎉").unwrap();
+        assert_eq!(reactions.len(), 1);
+        storage.remove_reaction(&message.id, &gus.id, "🎉").unwrap();
+        let reactions = storage.remove_reaction(&message.id, &fay.id, "👍").unwrap();
+        assert_eq!(reactions[0].usernames, vec!["gus"]);
+        assert_eq!(storage.get_reactions(&message.id).unwrap()[0].count, 1);
+
+        tick();
+        let other = storage.create_room_message(&gus.id, &room.id, "pin me too", None).unwrap();
+        storage.pin_message(&message.id, &fay.id).unwrap();
+        tick();
+        storage.pin_message(&other.id, &fay.id).unwrap();
+        let pinned: Vec<String> = storage.get_pinned_messages(&room.id).unwrap().into_iter().map(|m| m.id).collect();
+        assert_eq!(pinned, vec![other.id.clone(), message.id.clone()]);
+        let loaded = storage.get_message_by_id(&message.id).unwrap().unwrap();
+        assert!(loaded.is_pinned);
+        assert_eq!(loaded.pinned_by.as_deref(), Some(fay.id.as_str()));
+
+        storage.unpin_message(&other.id).unwrap();
+        assert_eq!(storage.get_pinned_messages(&room.id).unwrap().len(), 1);
+        assert!(!storage.get_message_by_id(&other.id).unwrap().unwrap().is_pinned);
+    }
+
+    fn filters_and_held_messages(storage: &dyn Storage) {
+        let owner = storage.create_user("filterer", "filterer@test.com", "hash").unwrap();
+        let room = storage.create_room("filtered", "", &owner.id).unwrap();
+
+        assert!(storage.get_room_filter(&room.id).unwrap().is_none());
+        let filter = RoomFilter {
+            blocked_words: vec!["darn".to_string(), r"/a{2,}/".to_string()],
+            action: FilterAction::Hold,
+            allowed_link_domains: Vec::new(),
+            denied_link_domains: vec!["evil.example".to_string()],
+        };
+        storage.set_room_filter(&room.id, &filter).unwrap();
+        storage.set_room_filter(&room.id, &RoomFilter { action: FilterAction::Mask, ..filter }).unwrap();
+        let loaded = storage.get_room_filter(&room.id).unwrap().unwrap();
+        assert_eq!(loaded.blocked_words, vec!["darn", "/a{2,}/"]);
+        assert_eq!(loaded.action, FilterAction::Mask);
+        assert_eq!(loaded.denied_link_domains, vec!["evil.example"]);
+        assert!(loaded.allowed_link_domains.is_empty());
+
+        let held = |content: &str| HeldMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            room_id: room.id.clone(),
+            sender_id: owner.id.clone(),
+            content: content.to_string(),
+            reply_to_message_id: None,
+            reason: "contains a blocked word".to_string(),
+            created_at: Utc::now(),
+        };
+        let first = held("first");
+        storage.hold_message(&first).unwrap();
+        tick();
+        let second = held("second");
+        storage.hold_message(&second).unwrap();
+
+        let queue: Vec<String> = storage.get_held_messages(&room.id, 10, 0).unwrap().into_iter().map(|m| m.content).collect();
+        assert_eq!(queue, vec!["first", "second"]);
+        assert_eq!(storage.get_held_messages(&room.id, 10, 1).unwrap().len(), 1);
+        assert_eq!(storage.get_held_message(&first.id).unwrap().unwrap().reason, "contains a blocked word");
+
+        assert!(storage.remove_held_message(&first.id).unwrap());
+        assert!(!storage.remove_held_message(&first.id).unwrap());
+        assert!(storage.get_held_message(&first.id).unwrap().is_none());
+
+        storage.delete_room(&room.id).unwrap();
+        assert!(storage.get_room_filter(&room.id).unwrap().is_none());
+        assert!(storage.get_held_message(&second.id).unwrap().is_none());
+    }
+
+    fn reports(storage: &dyn Storage) {
+        let author = storage.create_user("author", "author@test.com", "hash").unwrap();
+        let reporter = storage.create_user("reporter", "reporter@test.com", "hash").unwrap();
+        let room = storage.create_room("reported", "", &author.id).unwrap();
+        let other_room = storage.create_room("quiet", "", &author.id).unwrap();
+        let message = storage.create_room_message(&author.id, &room.id, "rude words", None).unwrap();
+        let report = |room_id: &str, reason: &str| MessageReport {
+            id: uuid::Uuid::new_v4().to_string(),
+            room_id: room_id.to_string(),
+            message_id: message.id.clone(),
+            sender_id: author.id.clone(),
+            content: message.content.clone(),
+            reporter_id: reporter.id.clone(),
+            reason: reason.to_string(),
+            status: ReportStatus::Open,
+            created_at: Utc::now(),
+            resolved_by: None,
+            resolved_at: None,
+            action: None,
+            resolution_note: None,
+        };
+
+        let first = report(&room.id, "rude");
+        assert!(storage.create_report(&first).unwrap());
+        assert!(!storage.create_report(&report(&room.id, "still rude")).unwrap());
+        let loaded = storage.get_report(&first.id).unwrap().unwrap();
+        assert_eq!((loaded.content.as_str(), loaded.reason.as_str()), ("rude words", "rude"));
+        assert_eq!(loaded.status, ReportStatus::Open);
+        assert!(storage.get_report("missing").unwrap().is_none());
+
+        let closed = MessageReport {
+            status: ReportStatus::Resolved,
+            resolved_by: Some(author.id.clone()),
+            resolved_at: Some(Utc::now()),
+            action: Some(ReportAction::DeleteMessage),
+            resolution_note: Some("removed".to_string()),
+            ..loaded
+        };
+        assert!(storage.close_report(&closed).unwrap());
+        assert!(!storage.close_report(&closed).unwrap());
+        let loaded = storage.get_report(&first.id).unwrap().unwrap();
+        assert_eq!(loaded.status, ReportStatus::Resolved);
+        assert_eq!(loaded.action, Some(ReportAction::DeleteMessage));
+        assert_eq!(loaded.resolution_note.as_deref(), Some("removed"));
+
+        // Once the first is closed the same member can report again
+        tick();
+        let second = report(&room.id, "again");
+        assert!(storage.create_report(&second).unwrap());
+        tick();
+        let elsewhere = storage.create_room_message(&author.id, &other_room.id, "also rude", None).unwrap();
+        assert!(storage.create_report(&MessageReport { message_id: elsewhere.id, ..report(&other_room.id, "elsewhere") }).unwrap());
+
+        let ids = |reports: Vec<MessageReport>| -> Vec<String> { reports.into_iter().map(|r| r.id).collect() };
+        assert_eq!(ids(storage.get_reports(Some(&room.id), None, 10, 0).unwrap()), vec![second.id.clone(), first.id.clone()]);
+        assert_eq!(ids(storage.get_reports(Some(&room.id), Some(ReportStatus::Open), 10, 0).unwrap()), vec![second.id.clone()]);
+        assert_eq!(storage.get_reports(None, Some(ReportStatus::Open), 10, 0).unwrap().len(), 2);
+        assert_eq!(ids(storage.get_reports(Some(&room.id), None, 1, 1).unwrap()), vec![first.id.clone()]);
+
+        // Reports keep their snapshot after the message is gone
+        storage.delete_message(&message.id, &author.id).unwrap();
+        assert_eq!(storage.get_report(&second.id).unwrap().unwrap().content, "rude words");
+
+        storage.delete_room(&room.id).unwrap();
+        assert!(storage.get_report(&second.id).unwrap().is_none());
+    }
+
+    fn audit_log(storage: &dyn Storage) {
+        let start = Utc::now() - Duration::seconds(1);
+        let ban = AuditEntry::new("mod", AuditAction::MemberBanned)
+            .with_room("room-a")
+            .with_target("troll")
+            .with_reason(Some("spam"));
+        storage.append_audit_entry(&ban).unwrap();
+        tick();
+        storage.append_audit_entry(&AuditEntry::new("mod", AuditAction::MemberMuted).with_room("room-b").with_target("lurker")).unwrap();
+        tick();
+        let invite = AuditEntry::new("admin", AuditAction::InviteCreated).with_target("CODE");
+        storage.append_audit_entry(&invite).unwrap();
+        assert!(storage.append_audit_entry(&invite).is_err());
+
+        let all = storage.get_audit_log(&AuditLogFilter::default(), 10, 0).unwrap();
+        let actions: Vec<AuditAction> = all.iter().map(|e| e.action).collect();
+        assert_eq!(actions, vec![AuditAction::InviteCreated, AuditAction::MemberMuted, AuditAction::MemberBanned]);
+        assert_eq!(all[2].reason.as_deref(), Some("spam"));
+        assert_eq!(all[2].target_id.as_deref(), Some("troll"));
+        assert!(all[0].room_id.is_none());
+
+        let filtered = |filter: AuditLogFilter| -> Vec<String> {
+            storage.get_audit_log(&filter, 10, 0).unwrap().into_iter().map(|e| e.id).collect()
+        };
+        assert_eq!(filtered(AuditLogFilter { room_id: Some("room-a".into()), ..Default::default() }), vec![ban.id.clone()]);
+        assert_eq!(filtered(AuditLogFilter { actor_id: Some("admin".into()), ..Default::default() }), vec![invite.id.clone()]);
+        assert_eq!(filtered(AuditLogFilter { target_id: Some("troll".into()), ..Default::default() }), vec![ban.id.clone()]);
+        assert_eq!(filtered(AuditLogFilter { action: Some(AuditAction::InviteCreated), ..Default::default() }), vec![invite.id.clone()]);
+        // Compare against stored times, which a backend may round
+        let invited_at = all[0].created_at;
+        assert_eq!(filtered(AuditLogFilter { since: Some(invited_at), ..Default::default() }), vec![invite.id.clone()]);
+        assert_eq!(filtered(AuditLogFilter { since: Some(start), until: Some(invited_at), ..Default::default() }).len(), 2);
+
+        let page = storage.get_audit_log(&AuditLogFilter::default(), 1, 1).unwrap();
+        assert_eq!(page[0].action, AuditAction::MemberMuted);
+    }
+
+    fn invites_and_tokens(storage: &dyn Storage) {
+        let admin = storage.create_user("inviter", "inviter@test.com", "hash").unwrap();
+        let invite = storage.create_invite_code("TWICE", &admin.id, Some(2), None).unwrap();
+        assert_eq!(invite.uses, 0);
+        assert!(storage.redeem_invite_code("TWICE").unwrap());
+        let guest = storage.create_user("invitee", "invitee@test.com", "hash").unwrap();
+        storage.set_user_invite_code(&guest.id, "TWICE").unwrap();
+        assert!(storage.redeem_invite_code("TWICE").unwrap());
+        assert!(!storage.redeem_invite_code("TWICE").unwrap());
+        assert!(!storage.redeem_invite_code("MISSING").unwrap());
+
+        storage.create_invite_code("EXPIRED", &admin.id, None, Some(Utc::now() - Duration::minutes(1))).unwrap();
+        assert!(!storage.redeem_invite_code("EXPIRED").unwrap());
+        tick();
+        storage.create_invite_code("OPEN", &admin.id, None, None).unwrap();
+        assert!(storage.revoke_invite_code("OPEN").unwrap());
+        assert!(!storage.revoke_invite_code("MISSING").unwrap());
+        assert!(!storage.redeem_invite_code("OPEN").unwrap());
+
+        let codes = storage.get_invite_codes().unwrap();
+        let listed: Vec<&str> = codes.iter().map(|c| c.code.as_str()).collect();
+        assert_eq!(listed, vec!["OPEN", "EXPIRED", "TWICE"]);
+        assert!(codes[0].revoked);
+        assert_eq!((codes[2].max_uses, codes[2].uses), (Some(2), 2));
+        assert_eq!(codes[2].used_by, vec!["invitee".to_string()]);
+
+        let bot = storage.create_bot_user("token-bot", "token-bot@test.com", "hash", &admin.id).unwrap();
+        let token = storage.create_api_token(&bot.id, "ci", "token-hash", &[ApiScope::ReadRooms, ApiScope::React]).unwrap();
+        assert!(storage.create_api_token(&bot.id, "dup", "token-hash", &[]).is_err());
+
+        let found = storage.get_api_token_by_hash("token-hash").unwrap().unwrap();
+        assert_eq!(found.id, token.id);
+        assert_eq!(found.scopes, vec![ApiScope::ReadRooms, ApiScope::React]);
+        assert!(found.last_used_at.is_none());
+        storage.touch_api_token(&token.id).unwrap();
+        assert!(storage.get_api_token(&token.id).unwrap().unwrap().last_used_at.is_some());
+        assert_eq!(storage.get_api_tokens(&bot.id).unwrap().len(), 1);
+
+        assert!(storage.revoke_api_token(&token.id).unwrap());
+        assert!(storage.get_api_token(&token.id).unwrap().unwrap().revoked);
+        assert!(!storage.revoke_api_token("missing").unwrap());
+    }
+
+    fn account_emails(storage: &dyn Storage) {
+        let user = storage.create_user("mailbox", "mailbox@test.com", "hash").unwrap();
+        let later = Utc::now() + Duration::hours(1);
+        let earlier = Utc::now() - Duration::hours(1);
+
+        storage.create_email_verification(&user.id, "verify", later).unwrap();
+        storage.create_email_verification(&user.id, "stale-verify", earlier).unwrap();
+        assert_eq!(storage.get_email_verification("verify").unwrap().unwrap().user_id, user.id);
+        assert!(storage.consume_email_verification("verify").unwrap());
+        assert!(!storage.consume_email_verification("verify").unwrap());
+        assert!(storage.get_email_verification("verify").unwrap().unwrap().used_at.is_some());
+        storage.delete_expired_email_verifications().unwrap();
+        assert!(storage.get_email_verification("stale-verify").unwrap().is_none());
+        assert!(storage.get_email_verification("verify").unwrap().is_some());
+
+        storage.create_password_reset(&user.id, "reset", later).unwrap();
+        storage.create_password_reset(&user.id, "stale-reset", earlier).unwrap();
+        assert_eq!(storage.get_password_reset("reset").unwrap().unwrap().user_id, user.id);
+        assert!(storage.consume_password_reset("reset").unwrap());
+        assert!(!storage.consume_password_reset("reset").unwrap());
+        storage.delete_expired_password_resets().unwrap();
+        assert!(storage.get_password_reset("stale-reset").unwrap().is_none());
+        assert!(storage.get_password_reset("reset").unwrap().is_some());
+    }
+
+    fn webhooks(storage: &dyn Storage) {
+        let owner = storage.create_user("hook-owner", "hook-owner@test.com", "hash").unwrap();
+        let room = storage.create_room("Hooks", "", &owner.id).unwrap();
+        let events = vec!["message.created".to_string(), "member.joined".to_string()];
+
+        let hook = storage.create_webhook(&room.id, "https://example.com/hook", "secret", &events, &owner.id).unwrap();
+        assert_eq!(storage.get_webhook(&hook.id).unwrap().unwrap().events, events);
+        assert_eq!(storage.get_room_webhooks(&room.id).unwrap().len(), 1);
+
+        storage.create_webhook_dead_letter(&hook.id, "message.created", "{}", 3, "timed out").unwrap();
+        let dead = storage.get_webhook_dead_letters(&room.id).unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!((dead[0].attempts, dead[0].last_error.as_str()), (3, "timed out"));
+
+        storage.delete_webhook(&hook.id).unwrap();
+        assert!(storage.get_webhook(&hook.id).unwrap().is_none());
+        assert!(storage.get_webhook_dead_letters(&room.id).unwrap().is_empty());
+
+        let bot = storage.create_bot_user("hook-bot", "hook-bot@test.com", "hash", &owner.id).unwrap();
+        let incoming = storage.create_incoming_webhook(&room.id, "CI", "incoming-hash", &bot.id, &owner.id).unwrap();
+        assert!(storage.create_incoming_webhook(&room.id, "Dup", "incoming-hash", &bot.id, &owner.id).is_err());
+        assert_eq!(storage.get_incoming_webhook_by_token_hash("incoming-hash").unwrap().unwrap().id, incoming.id);
+        assert_eq!(storage.get_incoming_webhook(&incoming.id).unwrap().unwrap().bot_user_id, bot.id);
+        assert_eq!(storage.get_room_incoming_webhooks(&room.id).unwrap().len(), 1);
+
+        storage.delete_incoming_webhook(&incoming.id).unwrap();
+        assert!(storage.get_incoming_webhook(&incoming.id).unwrap().is_none());
+    }
+
+    fn transactions(storage: &dyn Storage) {
+        let failed: Result<()> = storage.in_transaction(|| {
+            storage.create_user("rolled-back", "rolled-back@test.com", "hash")?;
+            Err(AuthError::InvalidInviteCode)
+        });
+        assert!(matches!(failed, Err(AuthError::InvalidInviteCode)));
+        assert!(storage.get_user_by_username("rolled-back").unwrap().is_none());
+
+        let user = storage.in_transaction(|| storage.create_user("committed", "committed@test.com", "hash")).unwrap();
+        assert_eq!(storage.get_user_by_username("committed").unwrap().unwrap().id, user.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Database;
+
+    #[test]
+    fn test_sqlite_conformance() {
+        super::conformance::run(&Database::in_memory().unwrap());
+    }
+}
@@ -1,8 +1,11 @@
 //use crate::error::Result;
+use crate::logging::REDACTED;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub struct User {
     pub id: String,
     pub username: String,
@@ -16,6 +19,112 @@ pub struct User {
     pub last_login: Option<DateTime<Utc>>,
     pub presence: Presence,
     pub status: Option<String>,
+    pub email_verified: bool,
+    #[serde(skip_serializing)]
+    pub approved: bool,
+    /// Shown to clients so automated accounts can be marked as "BOT"
+    pub is_bot: bool,
+    /// The user who created and manages this bot
+    #[serde(skip_serializing)]
+    pub bot_owner_id: Option<String>,
+    #[serde(default)]
+    pub role: UserRole,
+    /// Deactivated accounts can't sign in and their tokens stop working
+    #[serde(default, skip_serializing)]
+    pub deactivated_at: Option<DateTime<Utc>>,
+}
+
+impl User {
+    /// Deactivated admins keep the role but none of its powers.
+    pub fn is_admin(&self) -> bool {
+        self.role == UserRole::Admin && self.deactivated_at.is_none()
+    }
+}
+
+/// Server-wide role. Room-level powers come from creating the room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UserRole {
+    #[default]
+    User,
+    /// Manages every room and account, and can post server announcements
+    Admin,
+}
+
+impl UserRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::User => "user",
+            UserRole::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "admin" => UserRole::Admin,
+            _ => UserRole::User,
+        }
+    }
+}
+
+/// An account as admins see it, including the fields `User` keeps private.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UserAccount {
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub role: UserRole,
+    pub presence: Presence,
+    pub is_bot: bool,
+    pub email_verified: bool,
+    pub approved: bool,
+    pub created_at: DateTime<Utc>,
+    pub last_login: Option<DateTime<Utc>>,
+    pub deactivated_at: Option<DateTime<Utc>>,
+}
+
+impl From<User> for UserAccount {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            role: user.role,
+            presence: user.presence,
+            is_bot: user.is_bot,
+            email_verified: user.email_verified,
+            approved: user.approved,
+            created_at: user.created_at,
+            last_login: user.last_login,
+            deactivated_at: user.deactivated_at,
+        }
+    }
+}
+
+/// Every field is optional; unset fields match everyone.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserSearch {
+    /// Part of a username or email address, case-insensitive
+    #[serde(default)]
+    pub query: Option<String>,
+    #[serde(default)]
+    pub role: Option<UserRole>,
+    #[serde(default)]
+    pub deactivated: Option<bool>,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("email", &self.email)
+            .field("password_hash", &REDACTED)
+            .field("presence", &self.presence)
+            .field("is_bot", &self.is_bot)
+            .field("role", &self.role)
+            .finish_non_exhaustive()
+    }
 }
 
 pub struct Session {
@@ -26,26 +135,185 @@ pub struct Session {
     pub expires_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailVerification {
+    pub token: String,
+    pub user_id: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+pub struct PasswordReset {
+    pub token: String,
+    pub user_id: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub struct CreateUserRequest {
     pub username: String,
     pub email: String,
     pub password: String,
+    #[serde(default)]
+    pub invite_code: Option<String>,
+}
+
+impl fmt::Debug for CreateUserRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CreateUserRequest")
+            .field("username", &self.username)
+            .field("email", &self.email)
+            .field("password", &REDACTED)
+            .field("invite_code", &self.invite_code)
+            .finish()
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteCode {
+    pub code: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// `None` means the code can be used any number of times
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub revoked: bool,
+    /// Usernames of the accounts registered with this code
+    pub used_by: Vec<String>,
+}
+
+/// Someone a user has blocked: they can't DM the blocker, their mentions
+/// don't notify them and their room messages come flagged.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UserBlock {
+    pub user_id: String,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
 }
 
-#[derive(Debug, Serialize)]
+impl fmt::Debug for LoginRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoginRequest")
+            .field("username", &self.username)
+            .field("password", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub token: String,
+    pub current_password: String,
+    pub new_password: String,
+}
+
+impl fmt::Debug for ChangePasswordRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangePasswordRequest")
+            .field("token", &REDACTED)
+            .field("current_password", &REDACTED)
+            .field("new_password", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+impl fmt::Debug for ResetPasswordRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResetPasswordRequest")
+            .field("token", &REDACTED)
+            .field("new_password", &REDACTED)
+            .finish()
+    }
+}
+
+/// What a bot's API token is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+    ReadRooms,
+    PostMessages,
+    React,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::ReadRooms => "read_rooms",
+            ApiScope::PostMessages => "post_messages",
+            ApiScope::React => "react",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "read_rooms" => Some(ApiScope::ReadRooms),
+            "post_messages" => Some(ApiScope::PostMessages),
+            "react" => Some(ApiScope::React),
+            _ => None,
+        }
+    }
+}
+
+/// A long-lived token for a bot account. Only a hash of the token is stored,
+/// the plaintext is returned once on creation.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked: bool,
+}
+
+#[derive(Serialize)]
+pub struct NewApiToken {
+    #[serde(flatten)]
+    pub info: ApiToken,
+    pub token: String,
+}
+
+impl fmt::Debug for NewApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewApiToken")
+            .field("info", &self.info)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct AuthResponse {
     pub user: User,
     pub token: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl fmt::Debug for AuthResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthResponse")
+            .field("user", &self.user)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(tag = "type")]
 pub enum Presence {
     Offline,
@@ -0,0 +1,681 @@
+use crate::{
+    auth_provider::EXTERNAL_PASSWORD_HASH,
+    config::WebhookConfig,
+    error::{AuthError, Result},
+    logging::REDACTED,
+    storage::Storage,
+    websocket::WsServerMessage,
+    Database,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+/// Events a webhook can subscribe to. An empty subscription list means all of them.
+pub const WEBHOOK_EVENTS: &[&str] = &[
+    "message.created",
+    "message.edited",
+    "message.deleted",
+    "member.joined",
+    "member.left",
+    "reaction.added",
+    "reaction.removed",
+    "message.pinned",
+    "message.unpinned",
+];
+
+#[derive(Clone, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub room_id: String,
+    pub url: String,
+    /// Used to sign deliveries; only shown to the room owner
+    pub secret: String,
+    pub events: Vec<String>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("id", &self.id)
+            .field("room_id", &self.room_id)
+            .field("url", &self.url)
+            .field("secret", &REDACTED)
+            .field("events", &self.events)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Webhook {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+}
+
+/// A delivery that failed every attempt.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDeadLetter {
+    pub id: String,
+    pub webhook_id: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: u32,
+    pub last_error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Lets external systems post into a room through a secret URL,
+/// `POST /hooks/{token}`. Messages are sent by a bot account created for the
+/// webhook. Only a hash of the token is stored.
+#[derive(Debug, Clone, Serialize)]
+pub struct IncomingWebhook {
+    pub id: String,
+    pub room_id: String,
+    pub name: String,
+    pub bot_user_id: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A newly created incoming webhook. The token is only ever shown here.
+#[derive(Clone, Serialize)]
+pub struct NewIncomingWebhook {
+    #[serde(flatten)]
+    pub info: IncomingWebhook,
+    pub token: String,
+}
+
+impl fmt::Debug for NewIncomingWebhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewIncomingWebhook")
+            .field("info", &self.info)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+/// What gets stored for incoming webhook tokens.
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct IncomingWebhookPayload {
+    pub text: String,
+    /// Shown instead of the webhook's bot username
+    pub username: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<WebhookAttachment>,
+}
+
+/// Attachments are rendered as links below the text.
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct WebhookAttachment {
+    pub url: String,
+    pub title: Option<String>,
+}
+
+impl IncomingWebhookPayload {
+    /// The message content: the text followed by one line per attachment.
+    pub fn render(&self) -> Result<String> {
+        let mut lines = Vec::new();
+        if !self.text.trim().is_empty() {
+            lines.push(self.text.trim().to_string());
+        }
+
+        for attachment in &self.attachments {
+            let url = url::Url::parse(&attachment.url)
+                .map_err(|e| AuthError::InvalidInput(format!("Invalid attachment URL: {}", e)))?;
+            if url.scheme() != "http" && url.scheme() != "https" {
+                return Err(AuthError::InvalidInput("Attachment URLs must be http or https".to_string()));
+            }
+
+            match &attachment.title {
+                Some(title) => lines.push(format!("{}: {}", title, url)),
+                None => lines.push(url.to_string()),
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+}
+
+/// Maps a room broadcast to its webhook event name. Typing and presence
+/// updates aren't delivered.
+pub fn event_name(message: &WsServerMessage) -> Option<&'static str> {
+    match message {
+        WsServerMessage::NewMessage { .. } => Some("message.created"),
+        WsServerMessage::MessageEdited { .. } => Some("message.edited"),
+        WsServerMessage::MessageDeleted { .. } => Some("message.deleted"),
+        WsServerMessage::UserJoined { .. } => Some("member.joined"),
+        WsServerMessage::UserLeft { .. } => Some("member.left"),
+        WsServerMessage::ReactionAdded { .. } => Some("reaction.added"),
+        WsServerMessage::ReactionRemoved { .. } => Some("reaction.removed"),
+        WsServerMessage::MessagePinned { .. } => Some("message.pinned"),
+        WsServerMessage::MessageUnpinned { .. } => Some("message.unpinned"),
+        _ => None,
+    }
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{body}`, sent as
+/// `X-Spark-Signature: sha256=<hex>`. Receivers should recompute it and
+/// reject stale timestamps.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Whether deliveries may go to `ip`. Loopback, private, link-local and
+/// other internal ranges are refused so webhooks can't reach the server's
+/// own network or cloud metadata endpoints.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            let shared = a == 100 && (b & 0xc0) == 64;
+            !(v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified()
+                || v4.is_broadcast() || v4.is_documentation() || v4.is_multicast() || shared || a == 0)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                let first = v6.segments()[0];
+                let unique_local = (first & 0xfe00) == 0xfc00;
+                let link_local = (first & 0xffc0) == 0xfe80;
+                !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast() || unique_local || link_local)
+            }
+        },
+    }
+}
+
+/// Resolves a `host:port` webhook destination, failing if any address isn't
+/// public unless the host is allow-listed. Used both when a webhook is
+/// created and as the delivery resolver, so DNS changes are caught too.
+fn resolve_destination(netloc: &str, allowed_hosts: &[String]) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+    let host = netloc.rsplit_once(':').map_or(netloc, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    if allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+        return Ok(addrs);
+    }
+    if addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} resolves to a non-public address", host)));
+    }
+    Ok(addrs)
+}
+
+pub struct WebhookService {
+    db: Box<dyn Storage>,
+    allowed_hosts: Vec<String>,
+}
+
+impl WebhookService {
+    pub fn new(db: Database) -> Self {
+        Self::with_storage(Box::new(db))
+    }
+
+    pub fn with_storage(db: Box<dyn Storage>) -> Self {
+        Self { db, allowed_hosts: Vec::new() }
+    }
+
+    /// Lets webhooks be created for these hosts even though they resolve to
+    /// internal addresses. Should match `WebhookConfig::allowed_hosts`.
+    pub fn with_allowed_hosts(mut self, hosts: Vec<String>) -> Self {
+        self.allowed_hosts = hosts;
+        self
+    }
+
+    /// Only the room's creator manages its webhooks.
+    fn require_room_owner(&self, user_id: &str, room_id: &str) -> Result<()> {
+        let room = self.db.get_room_by_id(room_id)?
+            .ok_or(AuthError::InvalidInput("Room not found".to_string()))?;
+
+        if room.created_by != user_id {
+            return Err(AuthError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    pub fn create_webhook(&self, user_id: &str, room_id: &str, url: &str, events: Vec<String>) -> Result<Webhook> {
+        self.require_room_owner(user_id, room_id)?;
+
+        let parsed = url::Url::parse(url).map_err(|e| AuthError::InvalidInput(format!("Invalid webhook URL: {}", e)))?;
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err(AuthError::InvalidInput("Webhook URL must be http or https".to_string()));
+        }
+
+        let host = parsed.host_str().ok_or(AuthError::InvalidInput("Webhook URL must have a host".to_string()))?;
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        resolve_destination(&format!("{}:{}", host, port), &self.allowed_hosts)
+            .map_err(|e| AuthError::InvalidInput(format!("Invalid webhook URL: {}", e)))?;
+
+        if let Some(unknown) = events.iter().find(|e| !WEBHOOK_EVENTS.contains(&e.as_str())) {
+            return Err(AuthError::InvalidInput(format!("Unknown webhook event: {}", unknown)));
+        }
+
+        let secret: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        self.db.create_webhook(room_id, url, &secret, &events, user_id)
+    }
+
+    pub fn list_webhooks(&self, user_id: &str, room_id: &str) -> Result<Vec<Webhook>> {
+        self.require_room_owner(user_id, room_id)?;
+        self.db.get_room_webhooks(room_id)
+    }
+
+    pub fn delete_webhook(&self, user_id: &str, webhook_id: &str) -> Result<()> {
+        let webhook = self.db.get_webhook(webhook_id)?
+            .ok_or(AuthError::InvalidInput("Webhook not found".to_string()))?;
+        self.require_room_owner(user_id, &webhook.room_id)?;
+
+        self.db.delete_webhook(webhook_id)
+    }
+
+    pub fn list_dead_letters(&self, user_id: &str, room_id: &str) -> Result<Vec<WebhookDeadLetter>> {
+        self.require_room_owner(user_id, room_id)?;
+        self.db.get_webhook_dead_letters(room_id)
+    }
+
+    /// Creates an incoming webhook and the bot account it posts as. The bot
+    /// is added to the room so it passes the usual membership checks.
+    pub fn create_incoming_webhook(&self, user_id: &str, room_id: &str, name: &str) -> Result<NewIncomingWebhook> {
+        self.require_room_owner(user_id, room_id)?;
+
+        if name.trim().is_empty() || name.len() > 50 {
+            return Err(AuthError::InvalidInput("Webhook name must be between 1 and 50 characters".to_string()));
+        }
+
+        let suffix: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(|c| char::from(c).to_ascii_lowercase())
+            .collect();
+        let username = format!("webhook-{}", suffix);
+        let bot = self.db.create_bot_user(&username, &format!("{}@webhooks.invalid", username), EXTERNAL_PASSWORD_HASH, user_id)?;
+        self.db.add_user_to_room(room_id, &bot.id)?;
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(48)
+            .map(char::from)
+            .collect();
+
+        let info = self.db.create_incoming_webhook(room_id, name.trim(), &hash_token(&token), &bot.id, user_id)?;
+        Ok(NewIncomingWebhook { info, token })
+    }
+
+    pub fn list_incoming_webhooks(&self, user_id: &str, room_id: &str) -> Result<Vec<IncomingWebhook>> {
+        self.require_room_owner(user_id, room_id)?;
+        self.db.get_room_incoming_webhooks(room_id)
+    }
+
+    /// Invalidates the URL. The bot account is kept so its past messages
+    /// stay attributed, but it leaves the room.
+    pub fn delete_incoming_webhook(&self, user_id: &str, webhook_id: &str) -> Result<()> {
+        let webhook = self.db.get_incoming_webhook(webhook_id)?
+            .ok_or(AuthError::InvalidInput("Webhook not found".to_string()))?;
+        self.require_room_owner(user_id, &webhook.room_id)?;
+
+        self.db.delete_incoming_webhook(&webhook.id)?;
+        self.db.remove_user_from_room(&webhook.room_id, &webhook.bot_user_id)
+    }
+
+    pub fn find_incoming_webhook(&self, token: &str) -> Result<IncomingWebhook> {
+        self.db.get_incoming_webhook_by_token_hash(&hash_token(token))?.ok_or(AuthError::InvalidToken)
+    }
+
+    fn subscriptions(&self, room_id: &str, event: &str) -> Result<Vec<Webhook>> {
+        Ok(self.db.get_room_webhooks(room_id)?
+            .into_iter()
+            .filter(|w| w.wants(event))
+            .collect())
+    }
+}
+
+struct RoomEvent {
+    room_id: String,
+    event: &'static str,
+    data: serde_json::Value,
+}
+
+/// Hands room events to a background task that delivers them to subscribed
+/// webhooks, so broadcasting never waits on HTTP.
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    sender: mpsc::UnboundedSender<RoomEvent>,
+}
+
+impl WebhookDispatcher {
+    /// Spawns the delivery task on the current Tokio runtime.
+    pub fn start(service: Arc<Mutex<WebhookService>>, config: WebhookConfig) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<RoomEvent>();
+
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let webhooks = match service.lock().await.subscriptions(&event.room_id, event.event) {
+                    Ok(webhooks) => webhooks,
+                    Err(e) => {
+                        error!(room_id = %event.room_id, error = %e, "failed to load webhooks");
+                        continue;
+                    }
+                };
+
+                for webhook in webhooks {
+                    let delivery_id = uuid::Uuid::new_v4().to_string();
+                    let payload = serde_json::json!({
+                        "id": delivery_id,
+                        "event": event.event,
+                        "room_id": event.room_id,
+                        "timestamp": Utc::now().to_rfc3339(),
+                        "data": event.data,
+                    }).to_string();
+
+                    tokio::spawn(deliver(Arc::clone(&service), config.clone(), webhook, event.event, delivery_id, payload));
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    pub fn dispatch(&self, room_id: &str, message: &WsServerMessage) {
+        let Some(event) = event_name(message) else {
+            return;
+        };
+
+        if let Ok(data) = serde_json::to_value(message) {
+            let _ = self.sender.send(RoomEvent { room_id: room_id.to_string(), event, data });
+        }
+    }
+}
+
+async fn deliver(
+    service: Arc<Mutex<WebhookService>>,
+    config: WebhookConfig,
+    webhook: Webhook,
+    event: &'static str,
+    delivery_id: String,
+    payload: String,
+) {
+    let mut backoff = config.initial_backoff;
+    let mut last_error = String::new();
+
+    for attempt in 1..=config.max_attempts {
+        let request = (webhook.url.clone(), webhook.secret.clone(), delivery_id.clone(), payload.clone(), config.clone());
+        let result = tokio::task::spawn_blocking(move || {
+            let (url, secret, delivery_id, payload, config) = request;
+            let timestamp = Utc::now().timestamp();
+
+            // Redirects could lead to an internal host the resolver never saw
+            let allowed_hosts = config.allowed_hosts;
+            let agent = ureq::AgentBuilder::new()
+                .timeout(config.timeout)
+                .redirects(0)
+                .resolver(move |netloc: &str| resolve_destination(netloc, &allowed_hosts))
+                .build();
+
+            agent.post(&url)
+                .set("Content-Type", "application/json")
+                .set("X-Spark-Event", event)
+                .set("X-Spark-Delivery", &delivery_id)
+                .set("X-Spark-Timestamp", &timestamp.to_string())
+                .set("X-Spark-Signature", &format!("sha256={}", sign(&secret, timestamp, &payload)))
+                .send_string(&payload)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }).await;
+
+        match result {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => last_error = e,
+            Err(e) => last_error = e.to_string(),
+        }
+        warn!(webhook_id = %webhook.id, delivery_id = %delivery_id, attempt, error = %last_error, "webhook delivery failed");
+
+        if attempt < config.max_attempts {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(config.max_backoff);
+        }
+    }
+
+    let service = service.lock().await;
+    if let Err(e) = service.db.create_webhook_dead_letter(&webhook.id, event, &payload, config.max_attempts, &last_error) {
+        error!(delivery_id = %delivery_id, error = %e, "failed to dead-letter webhook delivery");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{MessageType, RoomMessageResponse};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> &str {
+            self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str()).unwrap()
+        }
+    }
+
+    /// Answers every request with `status` and forwards what it received.
+    fn spawn_receiver(status: &'static str) -> (String, std::sync::mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+
+                let mut headers = Vec::new();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() { break; }
+                    let (name, value) = header.split_once(':').unwrap();
+                    headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+                }
+
+                let length: usize = headers.iter()
+                    .find(|(n, _)| n == "content-length")
+                    .map(|(_, v)| v.parse().unwrap())
+                    .unwrap_or(0);
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                let _ = tx.send(Received { headers, body: String::from_utf8(body).unwrap() });
+            }
+        });
+
+        (url, rx)
+    }
+
+    fn setup(url: &str, events: Vec<String>) -> (Arc<Mutex<WebhookService>>, Webhook, String) {
+        let db = Database::in_memory().unwrap();
+        let owner = db.create_user("owner", "owner@test.com", "hash").unwrap();
+        let room = db.create_room("Room", "", &owner.id).unwrap();
+        let service = WebhookService::new(db).with_allowed_hosts(vec!["127.0.0.1".to_string()]);
+        let webhook = service.create_webhook(&owner.id, &room.id, url, events).unwrap();
+
+        (Arc::new(Mutex::new(service)), webhook, room.id)
+    }
+
+    fn fast_retries() -> WebhookConfig {
+        WebhookConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            timeout: Duration::from_secs(2),
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_delivers_signed_events() {
+        let (url, received) = spawn_receiver("204 No Content");
+        let (service, webhook, room_id) = setup(&url, vec!["message.deleted".to_string()]);
+        let dispatcher = WebhookDispatcher::start(service, fast_retries());
+
+        // Not subscribed, so only the delete should arrive
+        dispatcher.dispatch(&room_id, &WsServerMessage::MessageUnpinned { room_id: room_id.clone(), message_id: "m1".to_string() });
+        dispatcher.dispatch(&room_id, &WsServerMessage::MessageDeleted { room_id: room_id.clone(), message_id: "m1".to_string() });
+
+        let delivery = tokio::task::spawn_blocking(move || received.recv_timeout(Duration::from_secs(5)).unwrap()).await.unwrap();
+        assert_eq!(delivery.header("x-spark-event"), "message.deleted");
+
+        let timestamp: i64 = delivery.header("x-spark-timestamp").parse().unwrap();
+        let expected = format!("sha256={}", sign(&webhook.secret, timestamp, &delivery.body));
+        assert_eq!(delivery.header("x-spark-signature"), expected);
+
+        let payload: serde_json::Value = serde_json::from_str(&delivery.body).unwrap();
+        assert_eq!(payload["room_id"], room_id);
+        assert_eq!(payload["data"]["type"], "MessageDeleted");
+        assert_eq!(payload["data"]["message_id"], "m1");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_deliveries_are_dead_lettered() {
+        let (url, received) = spawn_receiver("500 Internal Server Error");
+        let (service, webhook, room_id) = setup(&url, Vec::new());
+        let dispatcher = WebhookDispatcher::start(Arc::clone(&service), fast_retries());
+
+        let message = RoomMessageResponse {
+            id: "m1".to_string(),
+            sender_username: "owner".to_string(),
+            message_type: MessageType::Room,
+            room_id: room_id.clone(),
+            room_name: "Room".to_string(),
+            content: "hello".to_string(),
+            sent_at: Utc::now(),
+            is_edited: false,
+            edited_at: None,
+            mentions: Vec::new(),
+            reply_to: None,
+            display_name: None,
+            sender_blocked: false,
+        };
+        dispatcher.dispatch(&room_id, &WsServerMessage::NewMessage { room_id: room_id.clone(), message });
+
+        let attempts: Vec<Received> = tokio::task::spawn_blocking(move || {
+            (0..3).map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap()).collect()
+        }).await.unwrap();
+        assert_eq!(attempts[0].header("x-spark-delivery"), attempts[2].header("x-spark-delivery"));
+
+        let mut dead_letters = Vec::new();
+        for _ in 0..50 {
+            dead_letters = service.lock().await.list_dead_letters(&webhook.created_by, &room_id).unwrap();
+            if !dead_letters.is_empty() { break; }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].event, "message.created");
+        assert_eq!(dead_letters[0].attempts, 3);
+        assert!(dead_letters[0].last_error.contains("500"));
+    }
+
+    #[test]
+    fn test_only_room_owner_manages_webhooks() {
+        let db = Database::in_memory().unwrap();
+        let owner = db.create_user("owner", "owner@test.com", "hash").unwrap();
+        let other = db.create_user("other", "other@test.com", "hash").unwrap();
+        let room = db.create_room("Room", "", &owner.id).unwrap();
+        let service = WebhookService::new(db).with_allowed_hosts(vec!["127.0.0.1".to_string()]);
+
+        let result = service.create_webhook(&other.id, &room.id, "http://127.0.0.1/hook", Vec::new());
+        assert!(matches!(result, Err(AuthError::PermissionDenied)));
+
+        let result = service.create_webhook(&owner.id, &room.id, "ftp://127.0.0.1/hook", Vec::new());
+        assert!(matches!(result, Err(AuthError::InvalidInput(_))));
+
+        let result = service.create_webhook(&owner.id, &room.id, "http://127.0.0.1/hook", vec!["typing".to_string()]);
+        assert!(matches!(result, Err(AuthError::InvalidInput(_))));
+
+        let webhook = service.create_webhook(&owner.id, &room.id, "http://127.0.0.1/hook", Vec::new()).unwrap();
+        assert!(matches!(service.delete_webhook(&other.id, &webhook.id), Err(AuthError::PermissionDenied)));
+        service.delete_webhook(&owner.id, &webhook.id).unwrap();
+        assert!(service.list_webhooks(&owner.id, &room.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_internal_webhook_urls_are_rejected() {
+        let db = Database::in_memory().unwrap();
+        let owner = db.create_user("owner", "owner@test.com", "hash").unwrap();
+        let room = db.create_room("Room", "", &owner.id).unwrap();
+        let service = WebhookService::new(db);
+
+        for url in ["http://127.0.0.1/hook", "http://localhost:8080/hook", "http://10.0.0.5/hook",
+                    "http://169.254.169.254/latest/meta-data", "http://[::1]/hook", "http://[::ffff:192.168.1.1]/hook"] {
+            let result = service.create_webhook(&owner.id, &room.id, url, Vec::new());
+            assert!(matches!(result, Err(AuthError::InvalidInput(_))), "{} was accepted", url);
+        }
+
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:4700::1111".parse().unwrap()));
+        assert!(!is_public("100.64.0.1".parse().unwrap()));
+        assert!(!is_public("fd00::1".parse().unwrap()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deliveries_to_internal_hosts_are_refused() {
+        let (url, received) = spawn_receiver("204 No Content");
+        let (service, webhook, room_id) = setup(&url, Vec::new());
+        // The host was allowed when the webhook was created but not any more
+        let config = WebhookConfig { allowed_hosts: Vec::new(), ..fast_retries() };
+        let dispatcher = WebhookDispatcher::start(Arc::clone(&service), config);
+
+        dispatcher.dispatch(&room_id, &WsServerMessage::MessageDeleted { room_id: room_id.clone(), message_id: "m1".to_string() });
+
+        let mut dead_letters = Vec::new();
+        for _ in 0..100 {
+            dead_letters = service.lock().await.list_dead_letters(&webhook.created_by, &room_id).unwrap();
+            if !dead_letters.is_empty() { break; }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(dead_letters.len(), 1);
+        assert!(dead_letters[0].last_error.contains("non-public"));
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_incoming_webhooks_with_boxed_storage() {
+        let storage = Database::in_memory().unwrap();
+        let owner = storage.create_user("owner", "owner@test.com", "hash").unwrap();
+        let room = storage.create_room("Room", "", &owner.id).unwrap();
+        let service = WebhookService::with_storage(Box::new(storage));
+
+        let hook = service.create_incoming_webhook(&owner.id, &room.id, "CI").unwrap();
+        assert_eq!(service.find_incoming_webhook(&hook.token).unwrap().bot_user_id, hook.info.bot_user_id);
+        assert!(service.db.is_user_in_room(&room.id, &hook.info.bot_user_id).unwrap());
+    }
+}
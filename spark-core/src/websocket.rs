@@ -1,21 +1,73 @@
+use crate::audit::{AuditEntry, AuditLogFilter};
+use crate::error::AuthError;
+use crate::filter::{HeldMessage, RoomFilter};
 use crate::network::{AuthService, MessageService};
-use crate::messages::{ReactionSummary, RoomMessageResponse, SendRoomMessageRequest};
-use crate::users::{Presence, User};
+use crate::messages::{
+    AnnouncementTarget, MessageAudience, MessageReport, ReactionDetail, ReactionSummary, ReportAction, ReportStatus, Room, RoomMessageResponse, RoomSanction, SanctionKind,
+    SendRoomMessageRequest,
+};
+use crate::users::{ApiScope, Presence, User, UserAccount, UserBlock, UserRole, UserSearch};
+use crate::webhooks::{IncomingWebhook, NewIncomingWebhook, Webhook, WebhookDeadLetter, WebhookDispatcher, WebhookService};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex, RwLock};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, RwLock};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
 use chrono::Utc;
-
-#[derive(Debug, Deserialize)]
+use crate::config::{HeartbeatConfig, OutboundQueueConfig, ShutdownConfig, WebhookConfig};
+use crate::outbound::{self, ClientSender, Encoded};
+use crate::backplane::{self, Backplane, BackplaneEvent};
+use crate::shutdown::Shutdown;
+use crate::logging::fingerprint;
+use crate::metrics::metrics;
+use std::ops::ControlFlow;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+/// How long a closing connection may spend writing out queued messages
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Deliberately not `Debug`: `Authenticate` carries a token. Log `kind()`.
+#[derive(Deserialize)]
 #[serde(tag = "type")]
 pub enum WsClientMessage {
     Authenticate { token: String },
+    /// Keepalive for clients that can't see WebSocket control frames
+    Ping { #[serde(default)] nonce: Option<String> },
     CreateRoom {name: String, desc: String},
-    GetAllRooms,
+    /// Archived rooms are only listed with `include_archived`
+    GetAllRooms { #[serde(default)] include_archived: bool },
+    /// Fields left out are unchanged
+    UpdateRoom { room_id: String, name: Option<String>, desc: Option<String>, topic: Option<String> },
+    ArchiveRoom { room_id: String },
+    UnarchiveRoom { room_id: String },
+    DeleteRoom { room_id: String },
+    KickMember { room_id: String, user_id: String, reason: Option<String> },
+    /// Without a duration the ban lasts until lifted
+    BanMember { room_id: String, user_id: String, reason: Option<String>, duration_minutes: Option<i64> },
+    UnbanMember { room_id: String, user_id: String },
+    /// Without a duration the mute lasts until lifted
+    MuteMember { room_id: String, user_id: String, reason: Option<String>, duration_minutes: Option<i64> },
+    UnmuteMember { room_id: String, user_id: String },
+    GetRoomBans { room_id: String },
+    GetRoomMutes { room_id: String },
+    /// Room managers must filter by one of their rooms; admins may omit it
+    GetAuditLog { #[serde(flatten)] filter: AuditLogFilter, limit: Option<usize>, offset: Option<usize> },
+    ReportMessage { message_id: String, reason: String },
+    /// Room managers must pass one of their rooms; admins may omit it
+    GetReports { room_id: Option<String>, status: Option<ReportStatus>, limit: Option<usize>, offset: Option<usize> },
+    /// `duration_minutes` applies to mutes and bans
+    ResolveReport { report_id: String, action: ReportAction, note: Option<String>, duration_minutes: Option<i64> },
+    DismissReport { report_id: String, note: Option<String> },
+    GetRoomFilter { room_id: String },
+    SetRoomFilter { room_id: String, filter: RoomFilter },
+    GetHeldMessages { room_id: String, limit: Option<usize>, offset: Option<usize> },
+    ApproveHeldMessage { held_id: String },
+    RejectHeldMessage { held_id: String, reason: Option<String> },
     JoinRoom { room_id: String },
     LeaveRoom { room_id: String },
     SendMessage { room_id: String, content: String , reply_to_message_id: Option<String> },
@@ -27,15 +79,173 @@ pub enum WsClientMessage {
     UpdatePresence { user_id: String, presence: Presence },
     UpdateStatus { user_id: String, status: String },
     UpdateTyping { room_id: String, is_typing: bool },
+    BlockUser { user_id: String },
+    UnblockUser { user_id: String },
+    GetBlockedUsers,
     GetUnreadMentionsCount { user_id: String },
     MarkMentionsRead { message_id: String },
     MarkRoomMentionsRead { room_id: String },
     GetUserMentions { limit: Option<usize>, offset: Option<usize> },
     AddReaction { room_id: String, message_id: String, emoji: String },
     RemoveReaction { room_id: String, message_id: String, emoji: String },
+    GetReactionDetails { room_id: String, message_id: String },
     PinMessage { room_id: String, message_id: String },
     UnpinMessage { room_id: String, message_id: String },
     GetPinnedMessages { room_id: String },
+    CreateWebhook { room_id: String, url: String, #[serde(default)] events: Vec<String> },
+    ListWebhooks { room_id: String },
+    DeleteWebhook { webhook_id: String },
+    GetWebhookFailures { room_id: String },
+    CreateIncomingWebhook { room_id: String, name: String },
+    ListIncomingWebhooks { room_id: String },
+    DeleteIncomingWebhook { webhook_id: String },
+    // Server administration, for admins only
+    ListUsers { #[serde(flatten)] search: UserSearch, limit: Option<usize>, offset: Option<usize> },
+    /// Also closes the user's connections
+    DeactivateUser { user_id: String, reason: Option<String> },
+    ReactivateUser { user_id: String },
+    ForceLogout { user_id: String },
+    SetUserRole { user_id: String, role: UserRole },
+    SendServerAnnouncement { content: String, target: AnnouncementTarget },
+}
+
+impl WsClientMessage {
+    /// The API token scope needed to send this message. `None` means bots
+    /// can't use it at all.
+    fn required_scope(&self) -> Option<ApiScope> {
+        match self {
+            WsClientMessage::GetAllRooms { .. }
+            | WsClientMessage::JoinRoom { .. }
+            | WsClientMessage::LeaveRoom { .. }
+            | WsClientMessage::GetRoomHistory { .. }
+            | WsClientMessage::GetUserRooms { .. }
+            | WsClientMessage::GetRoomMembers { .. }
+            | WsClientMessage::GetUnreadMentionsCount { .. }
+            | WsClientMessage::MarkMentionsRead { .. }
+            | WsClientMessage::MarkRoomMentionsRead { .. }
+            | WsClientMessage::GetUserMentions { .. }
+            | WsClientMessage::GetPinnedMessages { .. }
+            | WsClientMessage::GetReactionDetails { .. } => Some(ApiScope::ReadRooms),
+            WsClientMessage::SendMessage { .. }
+            | WsClientMessage::EditMessage { .. }
+            | WsClientMessage::DeleteMessage { .. }
+            | WsClientMessage::UpdateTyping { .. } => Some(ApiScope::PostMessages),
+            WsClientMessage::AddReaction { .. }
+            | WsClientMessage::RemoveReaction { .. } => Some(ApiScope::React),
+            _ => None,
+        }
+    }
+
+    /// The room the request is about, if any, for log context.
+    pub(crate) fn room_id(&self) -> Option<&str> {
+        match self {
+            WsClientMessage::UpdateRoom { room_id, .. }
+            | WsClientMessage::ArchiveRoom { room_id, .. }
+            | WsClientMessage::UnarchiveRoom { room_id, .. }
+            | WsClientMessage::DeleteRoom { room_id, .. }
+            | WsClientMessage::KickMember { room_id, .. }
+            | WsClientMessage::BanMember { room_id, .. }
+            | WsClientMessage::UnbanMember { room_id, .. }
+            | WsClientMessage::MuteMember { room_id, .. }
+            | WsClientMessage::UnmuteMember { room_id, .. }
+            | WsClientMessage::GetRoomBans { room_id, .. }
+            | WsClientMessage::GetRoomMutes { room_id, .. }
+            | WsClientMessage::JoinRoom { room_id, .. }
+            | WsClientMessage::LeaveRoom { room_id, .. }
+            | WsClientMessage::SendMessage { room_id, .. }
+            | WsClientMessage::GetRoomHistory { room_id, .. }
+            | WsClientMessage::EditMessage { room_id, .. }
+            | WsClientMessage::DeleteMessage { room_id, .. }
+            | WsClientMessage::GetRoomMembers { room_id, .. }
+            | WsClientMessage::UpdateTyping { room_id, .. }
+            | WsClientMessage::MarkRoomMentionsRead { room_id, .. }
+            | WsClientMessage::AddReaction { room_id, .. }
+            | WsClientMessage::RemoveReaction { room_id, .. }
+            | WsClientMessage::GetReactionDetails { room_id, .. }
+            | WsClientMessage::PinMessage { room_id, .. }
+            | WsClientMessage::UnpinMessage { room_id, .. }
+            | WsClientMessage::GetPinnedMessages { room_id, .. }
+            | WsClientMessage::CreateWebhook { room_id, .. }
+            | WsClientMessage::ListWebhooks { room_id, .. }
+            | WsClientMessage::GetWebhookFailures { room_id, .. }
+            | WsClientMessage::CreateIncomingWebhook { room_id, .. }
+            | WsClientMessage::ListIncomingWebhooks { room_id, .. }
+            | WsClientMessage::GetRoomFilter { room_id, .. }
+            | WsClientMessage::SetRoomFilter { room_id, .. }
+            | WsClientMessage::GetHeldMessages { room_id, .. } => Some(room_id),
+            WsClientMessage::GetAuditLog { filter, .. } => filter.room_id.as_deref(),
+            WsClientMessage::GetReports { room_id, .. } => room_id.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Variant name, matching the `type` tag clients send.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            WsClientMessage::Authenticate { .. } => "Authenticate",
+            WsClientMessage::Ping { .. } => "Ping",
+            WsClientMessage::CreateRoom { .. } => "CreateRoom",
+            WsClientMessage::GetAllRooms { .. } => "GetAllRooms",
+            WsClientMessage::UpdateRoom { .. } => "UpdateRoom",
+            WsClientMessage::ArchiveRoom { .. } => "ArchiveRoom",
+            WsClientMessage::UnarchiveRoom { .. } => "UnarchiveRoom",
+            WsClientMessage::DeleteRoom { .. } => "DeleteRoom",
+            WsClientMessage::KickMember { .. } => "KickMember",
+            WsClientMessage::BanMember { .. } => "BanMember",
+            WsClientMessage::UnbanMember { .. } => "UnbanMember",
+            WsClientMessage::MuteMember { .. } => "MuteMember",
+            WsClientMessage::UnmuteMember { .. } => "UnmuteMember",
+            WsClientMessage::GetRoomBans { .. } => "GetRoomBans",
+            WsClientMessage::GetRoomMutes { .. } => "GetRoomMutes",
+            WsClientMessage::GetAuditLog { .. } => "GetAuditLog",
+            WsClientMessage::ReportMessage { .. } => "ReportMessage",
+            WsClientMessage::GetReports { .. } => "GetReports",
+            WsClientMessage::ResolveReport { .. } => "ResolveReport",
+            WsClientMessage::DismissReport { .. } => "DismissReport",
+            WsClientMessage::GetRoomFilter { .. } => "GetRoomFilter",
+            WsClientMessage::SetRoomFilter { .. } => "SetRoomFilter",
+            WsClientMessage::GetHeldMessages { .. } => "GetHeldMessages",
+            WsClientMessage::ApproveHeldMessage { .. } => "ApproveHeldMessage",
+            WsClientMessage::RejectHeldMessage { .. } => "RejectHeldMessage",
+            WsClientMessage::JoinRoom { .. } => "JoinRoom",
+            WsClientMessage::LeaveRoom { .. } => "LeaveRoom",
+            WsClientMessage::SendMessage { .. } => "SendMessage",
+            WsClientMessage::GetRoomHistory { .. } => "GetRoomHistory",
+            WsClientMessage::EditMessage { .. } => "EditMessage",
+            WsClientMessage::DeleteMessage { .. } => "DeleteMessage",
+            WsClientMessage::GetUserRooms { .. } => "GetUserRooms",
+            WsClientMessage::GetRoomMembers { .. } => "GetRoomMembers",
+            WsClientMessage::UpdatePresence { .. } => "UpdatePresence",
+            WsClientMessage::UpdateStatus { .. } => "UpdateStatus",
+            WsClientMessage::BlockUser { .. } => "BlockUser",
+            WsClientMessage::UnblockUser { .. } => "UnblockUser",
+            WsClientMessage::GetBlockedUsers => "GetBlockedUsers",
+            WsClientMessage::UpdateTyping { .. } => "UpdateTyping",
+            WsClientMessage::GetUnreadMentionsCount { .. } => "GetUnreadMentionsCount",
+            WsClientMessage::MarkMentionsRead { .. } => "MarkMentionsRead",
+            WsClientMessage::MarkRoomMentionsRead { .. } => "MarkRoomMentionsRead",
+            WsClientMessage::GetUserMentions { .. } => "GetUserMentions",
+            WsClientMessage::AddReaction { .. } => "AddReaction",
+            WsClientMessage::RemoveReaction { .. } => "RemoveReaction",
+            WsClientMessage::GetReactionDetails { .. } => "GetReactionDetails",
+            WsClientMessage::PinMessage { .. } => "PinMessage",
+            WsClientMessage::UnpinMessage { .. } => "UnpinMessage",
+            WsClientMessage::GetPinnedMessages { .. } => "GetPinnedMessages",
+            WsClientMessage::CreateWebhook { .. } => "CreateWebhook",
+            WsClientMessage::ListWebhooks { .. } => "ListWebhooks",
+            WsClientMessage::DeleteWebhook { .. } => "DeleteWebhook",
+            WsClientMessage::GetWebhookFailures { .. } => "GetWebhookFailures",
+            WsClientMessage::CreateIncomingWebhook { .. } => "CreateIncomingWebhook",
+            WsClientMessage::ListIncomingWebhooks { .. } => "ListIncomingWebhooks",
+            WsClientMessage::DeleteIncomingWebhook { .. } => "DeleteIncomingWebhook",
+            WsClientMessage::ListUsers { .. } => "ListUsers",
+            WsClientMessage::DeactivateUser { .. } => "DeactivateUser",
+            WsClientMessage::ReactivateUser { .. } => "ReactivateUser",
+            WsClientMessage::ForceLogout { .. } => "ForceLogout",
+            WsClientMessage::SetUserRole { .. } => "SetUserRole",
+            WsClientMessage::SendServerAnnouncement { .. } => "SendServerAnnouncement",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -43,8 +253,42 @@ pub enum WsClientMessage {
 pub enum WsServerMessage {
     Authenticated { user_id: String, username: String },
     Error { message: String },
+    /// The server is going away; reconnect after the given delay
+    ServerShuttingDown { reconnect_after_ms: u64 },
+    /// Reply to `Ping`, echoing its nonce
+    Pong { nonce: Option<String> },
     RoomCreated { room_id: String, room_name: String },
     RoomList { rooms: Vec<RoomInfo> },
+    RoomUpdated { room: RoomInfo },
+    RoomArchived { room_id: String, archived_at: String },
+    RoomUnarchived { room_id: String },
+    /// Members are unsubscribed from the room after this
+    RoomDeleted { room_id: String },
+    /// The kicked member is unsubscribed from the room after this
+    MemberKicked { room_id: String, user_id: String, username: String, kicked_by: String, reason: Option<String> },
+    /// The banned member is unsubscribed from the room after this
+    MemberBanned { room_id: String, ban: RoomSanction },
+    MemberUnbanned { room_id: String, user_id: String },
+    MemberMuted { room_id: String, mute: RoomSanction },
+    MemberUnmuted { room_id: String, user_id: String },
+    RoomBans { room_id: String, bans: Vec<RoomSanction> },
+    RoomMutes { room_id: String, mutes: Vec<RoomSanction> },
+    AuditLog { entries: Vec<AuditEntry> },
+    /// Acknowledges a report to the member who filed it
+    MessageReported { report_id: String, message_id: String },
+    /// Sent to the room's moderators when a report arrives
+    ReportCreated { report: MessageReport },
+    Reports { reports: Vec<MessageReport> },
+    ReportClosed { report: MessageReport },
+    RoomFilter { room_id: String, filter: RoomFilter },
+    /// Tells the sender a filter kept their message back for review
+    MessageHeld { room_id: String, held_id: String, reason: String },
+    /// Sent to the room's moderators when a message is held
+    HeldMessageCreated { message: HeldMessage },
+    HeldMessages { room_id: String, messages: Vec<HeldMessage> },
+    HeldMessageApproved { held_id: String, message_id: String },
+    /// Sent to the moderator and the message's sender
+    HeldMessageRejected { held_id: String, room_id: String },
     RoomJoined { room_id: String, room_name: String },
     RoomLeft { room_id: String },
     NewMessage { room_id: String, message: RoomMessageResponse },
@@ -58,6 +302,9 @@ pub enum WsServerMessage {
     RoomMembers { room_id: String, members: Vec<User> },
     PresenceChanged { user_id: String, username: String, presence: Presence },
     StatusChanged { user_id: String, username: String, status: String },
+    UserBlocked { block: UserBlock },
+    UserUnblocked { user_id: String },
+    BlockedUsers { blocks: Vec<UserBlock> },
     TypingStatusChanged { room_id: String, typing_users: Vec<TypingUser> },
     MentionNotification {
         message_id: String,
@@ -83,6 +330,7 @@ pub enum WsServerMessage {
         user_id: String,
         reactions: Vec<ReactionSummary>,
     },
+    ReactionDetails { room_id: String, message_id: String, reactions: Vec<ReactionDetail> },
     MessagePinned {
         room_id: String,
         message_id: String,
@@ -91,6 +339,32 @@ pub enum WsServerMessage {
     },
     MessageUnpinned { room_id: String, message_id: String },
     PinnedMessages { room_id: String, messages: Vec<RoomMessageResponse> },
+    WebhookCreated { webhook: Webhook },
+    Webhooks { room_id: String, webhooks: Vec<Webhook> },
+    WebhookDeleted { webhook_id: String },
+    WebhookFailures { room_id: String, failures: Vec<WebhookDeadLetter> },
+    IncomingWebhookCreated { webhook: NewIncomingWebhook },
+    IncomingWebhooks { room_id: String, webhooks: Vec<IncomingWebhook> },
+    IncomingWebhookDeleted { webhook_id: String },
+    Users { users: Vec<UserAccount> },
+    UserDeactivated { user: UserAccount },
+    UserReactivated { user: UserAccount },
+    UserLoggedOut { user_id: String },
+    UserRoleChanged { user: UserAccount },
+    /// A notice from an admin to everyone connected
+    ServerAnnouncement { content: String, sent_at: String },
+    /// Acknowledges an announcement to the admin who sent it
+    AnnouncementSent { target: AnnouncementTarget },
+    /// The session was ended by the server; the connection closes after this
+    SessionRevoked { reason: String },
+}
+
+impl WsServerMessage {
+    /// Updates that are superseded by the next one, so can be dropped for a
+    /// client that is falling behind.
+    pub(crate) fn is_ephemeral(&self) -> bool {
+        matches!(self, WsServerMessage::TypingStatusChanged { .. } | WsServerMessage::PresenceChanged { .. })
+    }
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -98,13 +372,27 @@ pub struct RoomInfo {
     pub id: String,
     pub name: String,
     pub desc: String,
+    pub topic: String,
+    pub archived: bool,
+}
+
+impl From<Room> for RoomInfo {
+    fn from(room: Room) -> Self {
+        Self {
+            id: room.id,
+            name: room.name,
+            desc: room.desc,
+            topic: room.topic,
+            archived: room.archived_at.is_some(),
+        }
+    }
 }
 
 #[allow(dead_code)]
 struct Client {
     user_id: String,
     username: String,
-    sender: mpsc::UnboundedSender<WsServerMessage>,
+    sender: ClientSender,
     rooms: HashSet<String>,
 }
 
@@ -112,6 +400,12 @@ pub struct ConnectionManager {
     clients: HashMap<String, Client>,
     rooms: HashMap<String, HashSet<String>>,
     typing_users: HashMap<String, HashSet<String>>,
+    /// Users typing on other nodes, by room then user id, with their usernames
+    remote_typing: HashMap<String, HashMap<String, String>>,
+    webhooks: Option<WebhookDispatcher>,
+    backplane: Option<Arc<dyn Backplane>>,
+    /// Identifies this node's events on the backplane
+    node_id: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -121,15 +415,74 @@ pub struct TypingUser {
 }
 
 impl ConnectionManager {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             clients: HashMap::new(),
             rooms: HashMap::new(),
             typing_users: HashMap::new(),
+            remote_typing: HashMap::new(),
+            webhooks: None,
+            backplane: None,
+            node_id: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub(crate) fn set_backplane(&mut self, backplane: Arc<dyn Backplane>) {
+        self.backplane = Some(backplane);
+    }
+
+    fn publish_typing(&self, room_id: &str, user_id: &str, is_typing: bool) {
+        let (Some(backplane), Some(client)) = (&self.backplane, self.clients.get(user_id)) else {
+            return;
+        };
+        backplane.publish(BackplaneEvent::Typing {
+            origin: self.node_id.clone(),
+            room_id: room_id.to_string(),
+            user_id: user_id.to_string(),
+            username: client.username.clone(),
+            is_typing,
+        });
+    }
+
+    /// Applies an event published by another node to this node's clients.
+    pub(crate) fn apply_remote(&mut self, event: BackplaneEvent) {
+        if event.origin() == self.node_id {
+            return;
+        }
+
+        match event {
+            BackplaneEvent::RoomBroadcast { room_id, except, ephemeral, message, .. } => {
+                self.deliver_to_room(&room_id, &Encoded::from_json(message, ephemeral), &except);
+            }
+            BackplaneEvent::MembersBroadcast { room_id, user_ids, ephemeral, message, .. } => {
+                self.deliver_to_members(&room_id, &user_ids, &Encoded::from_json(message, ephemeral));
+            }
+            BackplaneEvent::Typing { room_id, user_id, username, is_typing, .. } => {
+                let typing = self.remote_typing.entry(room_id.clone()).or_default();
+                if is_typing {
+                    typing.insert(user_id, username);
+                } else {
+                    typing.remove(&user_id);
+                }
+
+                let typing_users = self.get_typing_users(&room_id).into_iter()
+                    .map(|(user_id, username)| TypingUser { user_id, username })
+                    .collect();
+                let message = WsServerMessage::TypingStatusChanged { room_id: room_id.clone(), typing_users };
+                self.deliver_to_room(&room_id, &Encoded::new(&message), &[]);
+            }
+            BackplaneEvent::RoomClosed { room_id, .. } => self.forget_room(&room_id),
+            BackplaneEvent::MemberRemoved { room_id, user_id, .. } => {
+                let _ = self.leave_room(&user_id, room_id);
+            }
+            BackplaneEvent::UserDisconnected { user_id, reason, .. } => self.close_connection(&user_id, reason),
+            BackplaneEvent::GlobalBroadcast { ephemeral, message, .. } => {
+                self.deliver_to_all(&Encoded::from_json(message, ephemeral));
+            }
         }
     }
 
-    fn add_client(&mut self, user_id: String, username: String, sender: mpsc::UnboundedSender<WsServerMessage>) {
+    pub(crate) fn add_client(&mut self, user_id: String, username: String, sender: ClientSender) {
         self.clients.insert(user_id.clone(), Client {
             user_id,
             username,
@@ -138,11 +491,28 @@ impl ConnectionManager {
         });
     }
 
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn connected_user_ids(&self) -> Vec<String> {
+        self.clients.keys().cloned().collect()
+    }
+
+    /// Connected subscribers per room, for rooms with at least one.
+    pub(crate) fn room_subscriber_counts(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.rooms.iter()
+            .filter(|(_, members)| !members.is_empty())
+            .map(|(room_id, members)| (room_id.as_str(), members.len()))
+    }
+
     fn remove_client(&mut self, user_id: &str) {
         if let Some(client) = self.clients.get(user_id) {
             for room_id in &client.rooms {
                 if let Some(typing_set) = self.typing_users.get_mut(room_id) {
-                    typing_set.remove(user_id);
+                    if typing_set.remove(user_id) {
+                        self.publish_typing(room_id, user_id, false);
+                    }
                 }
             }
         }
@@ -150,16 +520,16 @@ impl ConnectionManager {
         self.clients.remove(user_id);
     }
 
-    fn join_room(&mut self, user_id: &str, room_id: String) -> Result<(), String> {
+    pub(crate) fn join_room(&mut self, user_id: &str, room_id: String) -> Result<(), String> {
         let client = self.clients.get_mut(user_id).ok_or("Client not found")?;
 
         client.rooms.insert(room_id.clone());
-        self.rooms.entry(room_id).or_insert_with(HashSet::new).insert(user_id.to_string());
+        self.rooms.entry(room_id).or_default().insert(user_id.to_string());
 
         Ok(())
     }
 
-    fn leave_room(&mut self, user_id: &str, room_id: String) -> Result<(), String> {
+    pub(crate) fn leave_room(&mut self, user_id: &str, room_id: String) -> Result<(), String> {
         let client = self.clients.get_mut(user_id).ok_or("Client not found")?;
 
         client.rooms.remove(&room_id);
@@ -174,11 +544,197 @@ impl ConnectionManager {
         Ok(())
     }
 
-    fn broadcast_to_room(&self, room_id: &str, message: WsServerMessage) {
+    pub(crate) fn broadcast_to_room(&self, room_id: &str, message: WsServerMessage) {
+        self.broadcast_to_room_except(room_id, message, &[]);
+    }
+
+    /// Broadcasts to the room, skipping members in `except`.
+    fn broadcast_to_room_except(&self, room_id: &str, message: WsServerMessage, except: &[String]) {
+        let encoded = Encoded::new(&message);
+        self.deliver_to_room(room_id, &encoded, except);
+
+        // Typing travels as its own event, since other nodes merge in their own typists
+        if let Some(backplane) = &self.backplane {
+            if !matches!(message, WsServerMessage::TypingStatusChanged { .. }) {
+                backplane.publish(BackplaneEvent::RoomBroadcast {
+                    origin: self.node_id.clone(),
+                    room_id: room_id.to_string(),
+                    except: except.to_vec(),
+                    ephemeral: encoded.is_ephemeral(),
+                    message: encoded.as_str().to_string(),
+                });
+            }
+        }
+
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.dispatch(room_id, &message);
+        }
+    }
+
+    /// Broadcasts to the room, and to `user_id` as well if they aren't
+    /// subscribed to it, e.g. an admin managing a room they aren't in.
+    pub(crate) fn broadcast_to_room_and_user(&self, room_id: &str, user_id: &str, message: WsServerMessage) {
+        if !self.clients.get(user_id).is_some_and(|client| client.rooms.contains(room_id)) {
+            let _ = self.send_to_user(user_id, message.clone());
+        }
+        self.broadcast_to_room(room_id, message);
+    }
+
+    /// Unsubscribes everyone from a deleted room, on every node.
+    pub(crate) fn close_room(&mut self, room_id: &str) {
+        self.forget_room(room_id);
+
+        if let Some(backplane) = &self.backplane {
+            backplane.publish(BackplaneEvent::RoomClosed {
+                origin: self.node_id.clone(),
+                room_id: room_id.to_string(),
+            });
+        }
+    }
+
+    /// Unsubscribes a member a moderator removed, on whichever node they
+    /// are connected to.
+    pub(crate) fn remove_member(&mut self, room_id: &str, user_id: &str) {
+        let _ = self.leave_room(user_id, room_id.to_string());
+
+        if let Some(backplane) = &self.backplane {
+            backplane.publish(BackplaneEvent::MemberRemoved {
+                origin: self.node_id.clone(),
+                room_id: room_id.to_string(),
+                user_id: user_id.to_string(),
+            });
+        }
+    }
+
+    /// Tells the room what resolving a report changed: a deleted message, or
+    /// a muted or banned author, who is also unsubscribed if banned.
+    pub(crate) fn broadcast_report_outcome(&mut self, moderator_id: &str, report: &MessageReport, sanction: Option<RoomSanction>) {
+        let room_id = report.room_id.clone();
+        match (report.action, sanction) {
+            (Some(ReportAction::DeleteMessage), _) => {
+                self.broadcast_to_room(&room_id, WsServerMessage::MessageDeleted {
+                    room_id: room_id.clone(),
+                    message_id: report.message_id.clone(),
+                });
+            }
+            (Some(ReportAction::Mute), Some(mute)) => {
+                self.broadcast_to_room_and_user(&room_id, moderator_id, WsServerMessage::MemberMuted { room_id: room_id.clone(), mute });
+            }
+            (Some(ReportAction::Ban), Some(ban)) => {
+                self.broadcast_to_room_and_user(&room_id, moderator_id, WsServerMessage::MemberBanned { room_id: room_id.clone(), ban });
+                self.remove_member(&room_id, &report.sender_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Delivers a newly posted message to the room and notifies the users it
+    /// mentions. Members who blocked the sender get it with `sender_blocked`
+    /// set.
+    pub(crate) fn broadcast_new_message(&self, message: &RoomMessageResponse, audience: &MessageAudience) {
+        self.broadcast_to_room_except(&message.room_id, WsServerMessage::NewMessage {
+            room_id: message.room_id.clone(),
+            message: message.clone(),
+        }, &audience.blocked_by);
+
+        if !audience.blocked_by.is_empty() {
+            let flagged = Encoded::new(&WsServerMessage::NewMessage {
+                room_id: message.room_id.clone(),
+                message: RoomMessageResponse { sender_blocked: true, ..message.clone() },
+            });
+            self.deliver_to_members(&message.room_id, &audience.blocked_by, &flagged);
+
+            if let Some(backplane) = &self.backplane {
+                backplane.publish(BackplaneEvent::MembersBroadcast {
+                    origin: self.node_id.clone(),
+                    room_id: message.room_id.clone(),
+                    user_ids: audience.blocked_by.clone(),
+                    ephemeral: flagged.is_ephemeral(),
+                    message: flagged.as_str().to_string(),
+                });
+            }
+        }
+
+        for mentioned_user_id in &audience.notify_user_ids {
+            let _ = self.send_to_user(mentioned_user_id, WsServerMessage::MentionNotification {
+                message_id: message.id.clone(),
+                room_id: message.room_id.clone(),
+                room_name: message.room_name.clone(),
+                sender_username: message.sender_username.clone(),
+                content: message.content.clone(),
+                sent_at: message.sent_at.to_rfc3339(),
+            });
+        }
+    }
+
+    /// Sends to every connected client, on every node.
+    pub(crate) fn broadcast_to_all(&self, message: WsServerMessage) {
+        let encoded = Encoded::new(&message);
+        self.deliver_to_all(&encoded);
+
+        if let Some(backplane) = &self.backplane {
+            backplane.publish(BackplaneEvent::GlobalBroadcast {
+                origin: self.node_id.clone(),
+                ephemeral: encoded.is_ephemeral(),
+                message: encoded.as_str().to_string(),
+            });
+        }
+    }
+
+    /// Tells the user why and closes their connection, on whichever node
+    /// they are connected to.
+    pub(crate) fn disconnect_user(&self, user_id: &str, reason: &str) {
+        self.close_connection(user_id, reason.to_string());
+
+        if let Some(backplane) = &self.backplane {
+            backplane.publish(BackplaneEvent::UserDisconnected {
+                origin: self.node_id.clone(),
+                user_id: user_id.to_string(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    fn close_connection(&self, user_id: &str, reason: String) {
+        if let Some(client) = self.clients.get(user_id) {
+            let _ = client.sender.send(WsServerMessage::SessionRevoked { reason });
+            client.sender.disconnect();
+        }
+    }
+
+    fn deliver_to_all(&self, message: &Encoded) {
+        for client in self.clients.values() {
+            let _ = client.sender.send_encoded(message);
+        }
+    }
+
+    fn forget_room(&mut self, room_id: &str) {
+        for user_id in self.rooms.remove(room_id).unwrap_or_default() {
+            if let Some(client) = self.clients.get_mut(&user_id) {
+                client.rooms.remove(room_id);
+            }
+        }
+        self.typing_users.remove(room_id);
+        self.remote_typing.remove(room_id);
+    }
+
+    /// Sends to this node's clients in the room only, other than `except`.
+    fn deliver_to_room(&self, room_id: &str, message: &Encoded, except: &[String]) {
         if let Some(user_ids) = self.rooms.get(room_id) {
-            for user_id in user_ids {
+            for user_id in user_ids.iter().filter(|user_id| !except.contains(user_id)) {
+                if let Some(client) = self.clients.get(user_id) {
+                    let _ = client.sender.send_encoded(message);
+                }
+            }
+        }
+    }
+
+    /// Sends to those of `user_ids` subscribed to the room on this node.
+    fn deliver_to_members(&self, room_id: &str, user_ids: &[String], message: &Encoded) {
+        if let Some(members) = self.rooms.get(room_id) {
+            for user_id in user_ids.iter().filter(|user_id| members.contains(*user_id)) {
                 if let Some(client) = self.clients.get(user_id) {
-                    let _ = client.sender.send(message.clone());
+                    let _ = client.sender.send_encoded(message);
                 }
             }
         }
@@ -188,12 +744,12 @@ impl ConnectionManager {
         if let Some(client) = self.clients.get_mut(user_id) {
             for room_id in room_ids {
                 client.rooms.insert(room_id.clone());
-                self.rooms.entry(room_id).or_insert_with(HashSet::new).insert(user_id.to_string());
+                self.rooms.entry(room_id).or_default().insert(user_id.to_string());
             }
         }
     }
 
-    fn set_typing(&mut self, user_id: &str, room_id: &str, is_typing: bool) -> Result<(), String> {
+    pub(crate) fn set_typing(&mut self, user_id: &str, room_id: &str, is_typing: bool) -> Result<(), String> {
         if let Some(client) = self.clients.get(user_id) {
             if !client.rooms.contains(room_id) {
                 return Err("User not in room".to_string());
@@ -202,19 +758,20 @@ impl ConnectionManager {
             return Err("User not found".to_string());
         }
 
-        let typing_set = self.typing_users.entry(room_id.to_string()).or_insert_with(HashSet::new);
+        let typing_set = self.typing_users.entry(room_id.to_string()).or_default();
 
         if is_typing {
             typing_set.insert(user_id.to_string());
         } else {
             typing_set.remove(user_id);
         }
+        self.publish_typing(room_id, user_id, is_typing);
 
         Ok(())
     }
 
-    fn get_typing_users(&mut self, room_id: &str) -> Vec<(String, String)> {
-        if let Some(typing_set) = self.typing_users.get(room_id) {
+    pub(crate) fn get_typing_users(&mut self, room_id: &str) -> Vec<(String, String)> {
+        let mut typing: Vec<(String, String)> = if let Some(typing_set) = self.typing_users.get(room_id) {
             typing_set.iter()
                 .filter_map(|user_id| {
                     self.clients.get(user_id).map(|client| {
@@ -223,26 +780,37 @@ impl ConnectionManager {
                 }).collect()
         } else {
             Vec::new()
+        };
+
+        if let Some(remote) = self.remote_typing.get(room_id) {
+            typing.extend(remote.iter().map(|(user_id, username)| (user_id.clone(), username.clone())));
         }
+        typing
     }
 
-    /*
-    fn send_to_user(&self, user_id: &str, message: WsServerMessage) -> Result<(), String> {
+    pub(crate) fn send_to_user(&self, user_id: &str, message: WsServerMessage) -> Result<(), String> {
         let client = self.clients.get(user_id).ok_or("Client not found")?;
         client.sender.send(message).map_err(|_| "Failed to send message".to_string())
     }
 
+    /*
     fn get_username(&self, user_id: &str) -> Option<String> {
         self.clients.get(user_id).map(|c| c.username.clone())
     }
     */
 }
 
+#[derive(Clone)]
 pub struct WebSocketServer {
     auth: Arc<Mutex<AuthService>>,
     message_service: Arc<Mutex<MessageService>>,
     connections: Arc<RwLock<ConnectionManager>>,
+    webhooks: Option<Arc<Mutex<WebhookService>>>,
     addr: String,
+    shutdown: Shutdown,
+    reconnect_after: Duration,
+    heartbeat: HeartbeatConfig,
+    outbound: OutboundQueueConfig,
 }
 
 impl WebSocketServer {
@@ -251,58 +819,165 @@ impl WebSocketServer {
             auth,
             message_service,
             connections: Arc::new(RwLock::new(ConnectionManager::new())),
+            webhooks: None,
             addr,
+            shutdown: Shutdown::new(),
+            reconnect_after: ShutdownConfig::default().reconnect_after,
+            heartbeat: HeartbeatConfig::default(),
+            outbound: OutboundQueueConfig::default(),
         }
     }
 
+    /// Bounds each connection's send queue; see `OutboundQueueConfig`.
+    pub fn with_outbound_queue(mut self, outbound: OutboundQueueConfig) -> Self {
+        self.outbound = outbound;
+        self
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// On shutdown, stops accepting connections and tells connected clients
+    /// to come back after `reconnect_after` before closing them.
+    pub fn with_shutdown(mut self, shutdown: Shutdown, reconnect_after: Duration) -> Self {
+        self.shutdown = shutdown;
+        self.reconnect_after = reconnect_after;
+        self
+    }
+
+    /// Delivers room events to webhooks and lets room owners manage them.
+    /// Must be called from within a Tokio runtime, before `start`.
+    pub fn with_webhooks(mut self, service: Arc<Mutex<WebhookService>>, config: WebhookConfig) -> Self {
+        self.connections.try_write()
+            .expect("webhooks must be configured before the server starts")
+            .webhooks = Some(WebhookDispatcher::start(Arc::clone(&service), config));
+        self.webhooks = Some(service);
+        self
+    }
+
+    /// Fans room broadcasts, presence and typing out to the other nodes
+    /// sharing `backplane`. Must be called from within a Tokio runtime,
+    /// before `start`.
+    pub fn with_backplane(self, backplane: Arc<dyn Backplane>) -> Self {
+        backplane::attach(&self.connections, backplane);
+        self
+    }
+
+    /// Shared with the HTTP server so it can broadcast to connected clients.
+    pub fn connections(&self) -> Arc<RwLock<ConnectionManager>> {
+        Arc::clone(&self.connections)
+    }
+
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(&self.addr).await?;
-        println!("WebSocket server listening on {}", self.addr);
+        info!(addr = %self.addr, "WebSocket server listening");
+        metrics().listener_up("ws");
 
         loop {
-            let (stream, addr) = listener.accept().await?;
-            println!("New WebSocket connection from: {}", addr);
-
-            let auth = Arc::clone(&self.auth);
-            let message_service = Arc::clone(&self.message_service);
-            let connections = Arc::clone(&self.connections);
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown.wait() => return Ok(()),
+            };
+            debug!(peer = %addr, "new WebSocket connection");
 
+            let server = self.clone();
             tokio::spawn(async move {
-                if let Err(e) = handle_websocket_connections(stream, auth, message_service, connections).await {
-                    eprintln!(" {} - WebSocket error: {}", addr, e);
+                let result = match accept_async(stream).await {
+                    Ok(ws_stream) => server.serve(ws_stream).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    warn!(error = %e, "WebSocket connection failed");
                 }
-            });
+            }.instrument(info_span!("ws_accept", peer = %addr)));
         }
     }
+
+    /// Runs the chat protocol over an already upgraded connection, whether
+    /// it came from this server's listener or the HTTP server's `/ws` route.
+    pub async fn serve<S>(&self, ws_stream: WebSocketStream<S>) -> Result<(), Box<dyn std::error::Error>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        handle_websocket_connections(ws_stream, self.clone()).instrument(info_span!("ws_connection", user_id = tracing::field::Empty)).await
+    }
 }
 
-async fn handle_websocket_connections(
-    stream: TcpStream,
-    auth: Arc<Mutex<AuthService>>,
-    message_service: Arc<Mutex<MessageService>>,
-    connections: Arc<RwLock<ConnectionManager>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ws_stream = accept_async(stream).await?;
+async fn handle_websocket_connections<S>(
+    ws_stream: WebSocketStream<S>,
+    server: WebSocketServer,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let WebSocketServer { auth, message_service, connections, webhooks, shutdown, reconnect_after, heartbeat, outbound, .. } = server;
+    let reconnect_after_ms = reconnect_after.as_millis() as u64;
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-    let (tx, mut rx) = mpsc::unbounded_channel::<WsServerMessage>();
+    let (tx, mut rx) = outbound::channel(&outbound);
 
     let mut authenticated_user_id: Option<String> = None;
     let mut authenticated_username: Option<String> = None;
+    // Set when a bot authenticated with an API token
+    let mut token_scopes: Option<Vec<ApiScope>> = None;
+    let connection_span = Span::current();
 
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await{
-            let json = serde_json::to_string(&msg).unwrap();
-            if ws_sender.send(Message::Text(json.into())).await.is_err() {
-                break;
+    let mut send_task = tokio::spawn(async move {
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat.interval, heartbeat.interval);
+        loop {
+            let frame = tokio::select! {
+                text = rx.recv() => match text {
+                    Some(text) => Message::Text(text),
+                    None => break,
+                },
+                _ = ping.tick() => Message::Ping(Default::default()),
+            };
+            if ws_sender.send(frame).await.is_err() {
+                return;
             }
         }
+        if rx.is_slow_consumer() {
+            let reason = CloseFrame { code: CloseCode::Policy, reason: "SlowConsumer".into() };
+            let _ = ws_sender.send(Message::Close(Some(reason))).await;
+        } else {
+            let _ = ws_sender.close().await;
+        }
     });
 
-    while let Some(msg) = ws_receiver.next().await {
+    // Any frame from the client, including pongs to our pings, counts as a heartbeat
+    let mut last_heard = tokio::time::Instant::now();
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => match msg {
+                Some(msg) => {
+                    last_heard = tokio::time::Instant::now();
+                    msg
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep_until(last_heard + heartbeat.timeout) => {
+                info!(timeout_secs = heartbeat.timeout.as_secs_f64(), "client missed heartbeats, disconnecting");
+                break;
+            }
+            _ = tx.overflowed() => {
+                warn!("client is not keeping up with its messages, disconnecting as a slow consumer");
+                break;
+            }
+            _ = tx.disconnected() => {
+                info!("session revoked, disconnecting");
+                break;
+            }
+            _ = shutdown.wait() => {
+                let _ = tx.send(WsServerMessage::ServerShuttingDown { reconnect_after_ms });
+                break;
+            }
+        };
         let msg = match msg {
             Ok(msg) => msg,
             Err(e) => {
-                eprintln!("WebSocket received error: {}", e);
+                debug!(error = %e, "WebSocket receive failed");
                 break;
             }
         };
@@ -318,626 +993,1272 @@ async fn handle_websocket_connections(
                 }
             };
 
-            match client_msg {
-                WsClientMessage::Authenticate { token } => {
-                    let auth = auth.lock().await;
-                    match auth.validate_session(&token) {
-                        Ok(user) => {
-                            authenticated_user_id = Some(user.id.clone());
-                            authenticated_username = Some(user.username.clone());
-
-                            connections.write().await.add_client(
-                                user.id.clone(),
-                                user.username.clone(),
-                                tx.clone()
-                            );
-
-                            let _ = tx.send(WsServerMessage::Authenticated { 
-                                user_id: user.id.clone(), 
-                                username: user.username.clone() 
-                            });
+            let _timer = metrics().time_ws_request(client_msg.kind());
+            let request_span = info_span!("ws_request", request_type = client_msg.kind(), room_id = client_msg.room_id());
+            let flow = async {
+                match client_msg {
+                    WsClientMessage::Authenticate { token } => {
+                        let auth = auth.lock().await;
+                        match auth.validate_token(&token) {
+                            Ok((user, scopes)) => {
+                                connection_span.record("user_id", user.id.as_str());
+                                info!(username = %user.username, bot = user.is_bot, "authenticated");
+
+                                authenticated_user_id = Some(user.id.clone());
+                                authenticated_username = Some(user.username.clone());
+                                token_scopes = scopes;
+
+                                connections.write().await.add_client(
+                                    user.id.clone(),
+                                    user.username.clone(),
+                                    tx.clone()
+                                );
+
+                                let _ = tx.send(WsServerMessage::Authenticated { 
+                                    user_id: user.id.clone(), 
+                                    username: user.username.clone() 
+                                });
 
-                            let msg_service = message_service.lock().await;
-                            let _ = msg_service.update_user_presence(&user.id, Presence::Online);
+                                let msg_service = message_service.lock().await;
+                                let _ = msg_service.update_user_presence(&user.id, Presence::Online);
 
 
-                            if let Ok(user_rooms) = msg_service.get_user_rooms(&user.id) {
-                                let room_ids: Vec<String> = user_rooms.iter().map(|r| r.id.clone()).collect();
+                                if let Ok(user_rooms) = msg_service.get_user_rooms(&user.id) {
+                                    let room_ids: Vec<String> = user_rooms.iter().map(|r| r.id.clone()).collect();
 
-                                drop(msg_service);
-                                connections.write().await.restore_user_rooms(&user.id, room_ids);
-                                let conns = connections.read().await;
+                                    drop(msg_service);
+                                    connections.write().await.restore_user_rooms(&user.id, room_ids);
+                                    let conns = connections.read().await;
 
-                                for room in user_rooms {
-                                    let _ = tx.send(WsServerMessage::RoomJoined { room_id: room.id.clone(), room_name: room.name });
+                                    for room in user_rooms {
+                                        let _ = tx.send(WsServerMessage::RoomJoined { room_id: room.id.clone(), room_name: room.name });
                                     
-                                    conns.broadcast_to_room(&room.id, WsServerMessage::PresenceChanged { 
-                                        user_id: user.id.clone(), 
-                                        username: user.username.clone(), 
-                                        presence: Presence::Online 
-                                    });
+                                        conns.broadcast_to_room(&room.id, WsServerMessage::PresenceChanged { 
+                                            user_id: user.id.clone(), 
+                                            username: user.username.clone(), 
+                                            presence: Presence::Online 
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        Err(e) => {
-                            let _ = tx.send(WsServerMessage::Error { 
-                                message: format!("Authentication failed: {}", e) 
-                            });
-                            break;
+                            Err(e) => {
+                                metrics().record_error(&e);
+                                warn!(token = %fingerprint(&token), error = %e, "authentication failed");
+                                let _ = tx.send(WsServerMessage::Error { 
+                                    message: format!("Authentication failed: {}", e) 
+                                });
+                                return ControlFlow::Break(());
+                            }
                         }
                     }
-                }
-                _ => {
-                    let user_id = match &authenticated_user_id {
-                        Some(id) => id,
-                        None => {
-                            let _ = tx.send(WsServerMessage::Error { 
-                                message: "User not authenticated".to_string(),
-                            });
-                            continue;
-                        }
-                    };
-
-                    match client_msg {
-                        WsClientMessage::JoinRoom { room_id } => {
-                            let msg_service = message_service.lock().await;
-                            match msg_service.get_room(&room_id) {
-                                Ok(Some(room)) => {
-                                    if let Err(e) = msg_service.join_room(user_id, &room_id) {
-                                        let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Failed to join room: {}", e) 
-                                        });
-                                        continue;
-                                    }
+                    WsClientMessage::Ping { nonce } => {
+                        let _ = tx.send(WsServerMessage::Pong { nonce });
+                    }
+                    _ => {
+                        let user_id = match &authenticated_user_id {
+                            Some(id) => id,
+                            None => {
+                                let _ = tx.send(WsServerMessage::Error { 
+                                    message: "User not authenticated".to_string(),
+                                });
+                                return ControlFlow::Continue(());
+                            }
+                        };
 
-                                    if let Err(e) = connections.write().await.join_room(user_id, room_id.clone()) {
-                                        let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Failed to join room: {}", e) 
-                                        });
-                                        continue;
-                                    }
+                        if let Some(scopes) = &token_scopes {
+                            if !client_msg.required_scope().is_some_and(|scope| scopes.contains(&scope)) {
+                                let _ = tx.send(WsServerMessage::Error {
+                                    message: "API token is not allowed to do this".to_string(),
+                                });
+                                return ControlFlow::Continue(());
+                            }
+                        }
 
-                                    let _ = tx.send(WsServerMessage::RoomJoined { 
-                                        room_id: room_id.clone(), 
-                                        room_name: room.name.clone() 
-                                    });
+                        match client_msg {
+                            WsClientMessage::JoinRoom { room_id } => {
+                                let msg_service = message_service.lock().await;
+                                match msg_service.get_room(&room_id) {
+                                    Ok(Some(room)) => {
+                                        if let Err(e) = msg_service.join_room(user_id, &room_id) {
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Failed to join room: {}", e) 
+                                            });
+                                            return ControlFlow::Continue(());
+                                        }
 
-                                    if let Some(username) = &authenticated_username {
-                                        connections.read().await.broadcast_to_room(
-                                            &room_id, 
-                                            WsServerMessage::UserJoined { 
-                                                room_id: room_id.clone(), 
-                                                user_id: user_id.clone(), 
-                                                username: username.clone() 
-                                            },
-                                        );
+                                        if let Err(e) = connections.write().await.join_room(user_id, room_id.clone()) {
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Failed to join room: {}", e) 
+                                            });
+                                            return ControlFlow::Continue(());
+                                        }
 
-                                        let announcement_content = format!("{} has joined the room", username);
-                                        let announcment_request = SendRoomMessageRequest {
-                                            room_id: room_id.clone(),
-                                            content: announcement_content,
-                                            reply_to_message_id: None,
-                                        };
+                                        let _ = tx.send(WsServerMessage::RoomJoined { 
+                                            room_id: room_id.clone(), 
+                                            room_name: room.name.clone() 
+                                        });
 
-                                        if let Ok(announcement_response) = msg_service.send_room_announcement(user_id, announcment_request) {
+                                        if let Some(username) = &authenticated_username {
                                             connections.read().await.broadcast_to_room(
                                                 &room_id, 
-                                                WsServerMessage::NewMessage {
-                                                    room_id: room_id.clone(),
-                                                    message: announcement_response
-                                                }
+                                                WsServerMessage::UserJoined { 
+                                                    room_id: room_id.clone(), 
+                                                    user_id: user_id.clone(), 
+                                                    username: username.clone() 
+                                                },
                                             );
-                                        }
 
-                                        match msg_service.get_room_members(&room_id) {
-                                            Ok(members) => {
-                                                let _ = tx.send(WsServerMessage::RoomMembers { room_id: room_id.clone(), members });
+                                            let announcement_content = format!("{} has joined the room", username);
+                                            let announcment_request = SendRoomMessageRequest {
+                                                room_id: room_id.clone(),
+                                                content: announcement_content,
+                                                reply_to_message_id: None,
+                                            };
+
+                                            if let Ok(announcement_response) = msg_service.send_room_announcement(user_id, announcment_request) {
+                                                connections.read().await.broadcast_to_room(
+                                                    &room_id, 
+                                                    WsServerMessage::NewMessage {
+                                                        room_id: room_id.clone(),
+                                                        message: announcement_response
+                                                    }
+                                                );
                                             }
-                                            Err(e) => {
-                                                let _ = tx.send(WsServerMessage::Error { message: format!("Failed to get room members: {}", e) });
+
+                                            match msg_service.get_room_members(&room_id) {
+                                                Ok(members) => {
+                                                    let _ = tx.send(WsServerMessage::RoomMembers { room_id: room_id.clone(), members });
+                                                }
+                                                Err(e) => {
+                                                    metrics().record_error(&e);
+                                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to get room members: {}", e) });
+                                                }
                                             }
                                         }
                                     }
+                                    Ok(None) => {
+                                        let _ = tx.send(WsServerMessage::Error { 
+                                            message: "Room not found".to_string() 
+                                        });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { 
+                                            message: format!("Failed to get room: {}", e), 
+                                        });
+                                    }
                                 }
-                                Ok(None) => {
-                                    let _ = tx.send(WsServerMessage::Error { 
-                                        message: "Room not found".to_string() 
-                                    });
+                            }
+                            WsClientMessage::LeaveRoom { room_id } => {
+                                let msg_service = message_service.lock().await;
+                                if let Err(e) = connections.write().await.leave_room(user_id, room_id.clone()) {
+                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to leave room: {}", e) });
+                                    return ControlFlow::Continue(());
                                 }
-                                Err(e) => {
+
+                                if let Err(e) = msg_service.leave_room(user_id, &room_id) {
                                     let _ = tx.send(WsServerMessage::Error { 
-                                        message: format!("Failed to get room: {}", e), 
+                                        message: format!("Failed to leave room: {}", e) 
                                     });
+                                    return ControlFlow::Continue(());
                                 }
-                            }
-                        }
-                        WsClientMessage::LeaveRoom { room_id } => {
-                            let msg_service = message_service.lock().await;
-                            if let Err(e) = connections.write().await.leave_room(user_id, room_id.clone()) {
-                                let _ = tx.send(WsServerMessage::Error { message: format!("Failed to leave room: {}", e) });
-                                continue;
-                            }
-
-                            if let Err(e) = msg_service.leave_room(user_id, &room_id) {
-                                let _ = tx.send(WsServerMessage::Error { 
-                                    message: format!("Failed to leave room: {}", e) 
-                                });
-                                continue;
-                            }
 
-                            let _ = tx.send(WsServerMessage::RoomLeft { room_id: room_id.clone() });
+                                let _ = tx.send(WsServerMessage::RoomLeft { room_id: room_id.clone() });
 
-                            if let Some(username) = &authenticated_username {
-                                connections.read().await.broadcast_to_room(&room_id, WsServerMessage::UserLeft { 
-                                    room_id: room_id.clone(), 
-                                    user_id: user_id.clone(), 
-                                    username: username.clone() 
-                                });
-
-                                let announcement_content = format!("{} has left the room", username);
-                                let announcement_request = SendRoomMessageRequest {
-                                    room_id: room_id.clone(),
-                                    content: announcement_content,
-                                    reply_to_message_id: None,
-                                };
-
-                                if let Ok(announcement_response) = msg_service.send_room_announcement(user_id, announcement_request) {
-                                    connections.read().await.broadcast_to_room(
-                                        &room_id,
-                                        WsServerMessage::NewMessage { room_id: room_id.clone(), 
-                                            message: announcement_response } 
-                                    );
-                                }
-                            }
-                        }
-                        WsClientMessage::SendMessage { room_id, content , reply_to_message_id} => {
-                            if let (Some(user_id), Some(_username)) = (&authenticated_user_id, &authenticated_username) {
-                                let msg_service = message_service.lock().await;
-                                let request = SendRoomMessageRequest {
-                                    room_id: room_id.clone(),
-                                    content,
-                                    reply_to_message_id,
-                                };
+                                if let Some(username) = &authenticated_username {
+                                    connections.read().await.broadcast_to_room(&room_id, WsServerMessage::UserLeft { 
+                                        room_id: room_id.clone(), 
+                                        user_id: user_id.clone(), 
+                                        username: username.clone() 
+                                    });
 
-                                match msg_service.send_room_message(user_id, request) {
-                                    Ok((message_response, mentioned_user_ids)) => {
-                                        let _ = tx.send(WsServerMessage::MessageSent { message_id: message_response.id.clone() });
+                                    let announcement_content = format!("{} has left the room", username);
+                                    let announcement_request = SendRoomMessageRequest {
+                                        room_id: room_id.clone(),
+                                        content: announcement_content,
+                                        reply_to_message_id: None,
+                                    };
 
+                                    if let Ok(announcement_response) = msg_service.send_room_announcement(user_id, announcement_request) {
                                         connections.read().await.broadcast_to_room(
                                             &room_id,
-                                            WsServerMessage::NewMessage { 
-                                                room_id: room_id.clone(), 
-                                                message: message_response.clone()
-                                            } 
+                                            WsServerMessage::NewMessage { room_id: room_id.clone(), 
+                                                message: announcement_response } 
                                         );
-
-                                        if !mentioned_user_ids.is_empty() {
-                                            let conns = connections.read().await;
-                                            for mentioned_user_id in &mentioned_user_ids {
-                                                if let Some(client) = conns.clients.get(mentioned_user_id) {
-                                                    let _ = client.sender.send(WsServerMessage::MentionNotification { 
-                                                        message_id: message_response.id.clone(), 
-                                                        room_id: message_response.room_id.clone(), 
-                                                        room_name: message_response.room_name.clone(), 
-                                                        sender_username: message_response.sender_username.clone(), 
-                                                        content: message_response.content.clone(), 
-                                                        sent_at: message_response.sent_at.to_rfc3339(),
-                                                    });
+                                    }
+                                }
+                            }
+                            WsClientMessage::SendMessage { room_id, content , reply_to_message_id} => {
+                                if let (Some(user_id), Some(_username)) = (&authenticated_user_id, &authenticated_username) {
+                                    let msg_service = message_service.lock().await;
+                                    let request = SendRoomMessageRequest {
+                                        room_id: room_id.clone(),
+                                        content,
+                                        reply_to_message_id,
+                                    };
+
+                                    match msg_service.send_room_message(user_id, request) {
+                                        Ok((message_response, audience)) => {
+                                            let _ = tx.send(WsServerMessage::MessageSent { message_id: message_response.id.clone() });
+                                            connections.read().await.broadcast_new_message(&message_response, &audience);
+                                        }
+                                        Err(AuthError::MessageHeld { held_id, reason }) => {
+                                            let _ = tx.send(WsServerMessage::MessageHeld { room_id, held_id: held_id.clone(), reason });
+                                            if let Ok((held, moderators)) = msg_service.held_message_moderators(&held_id) {
+                                                let conns = connections.read().await;
+                                                for moderator_id in moderators {
+                                                    let _ = conns.send_to_user(&moderator_id, WsServerMessage::HeldMessageCreated { message: held.clone() });
                                                 }
                                             }
                                         }
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Failed to send message: {}", e) 
+                                            });
+                                        }
+                                    }
+                                } else {
+                                    let _ = tx.send(WsServerMessage::Error { message: "Not Authenticated".to_string() });
+                                }
+                            }
+                            WsClientMessage::GetRoomHistory { room_id, limit, offset } => { 
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.get_room_messages(
+                                    &room_id,
+                                    user_id,
+                                    limit.unwrap_or(50),
+                                    offset.unwrap_or(0)) {
+
+                                    Ok(messages) => {
+                                        let _ = tx.send(WsServerMessage::RoomHistory { room_id, messages });
                                     }
                                     Err(e) => {
+                                        metrics().record_error(&e);
                                         let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Failed to send message: {}", e) 
+                                            message: format!("Failed to get history: {}", e) 
                                         });
                                     }
                                 }
-                            } else {
-                                let _ = tx.send(WsServerMessage::Error { message: "Not Authenticated".to_string() });
                             }
-                        }
-                        WsClientMessage::GetRoomHistory { room_id, limit, offset } => { 
-                            let msg_service = message_service.lock().await;
-
-                            match msg_service.get_room_messages(
-                                &room_id,
-                                limit.unwrap_or(50),
-                                offset.unwrap_or(0)) {
-
-                                Ok(messages) => {
-                                    let _ = tx.send(WsServerMessage::RoomHistory { room_id, messages });
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { 
-                                        message: format!("Failed to get history: {}", e) 
-                                    });
-                                }
+                            #[allow(unused_variables)]
+                            WsClientMessage::Authenticate { token } => {
+                                //already handled, leaving here just to satistfy the compiler
                             }
-                        }
-                        #[allow(unused_variables)]
-                        WsClientMessage::Authenticate { token } => {
-                            //already handled, leaving here just to satistfy the compiler
-                        }
-                        WsClientMessage::CreateRoom { name, desc } => {
-                            let msg_service = message_service.lock().await;
-
-                            match msg_service.create_room(user_id, &name, &desc) {
-                                Ok(room) => {
-                                    let _ = tx.send(WsServerMessage::RoomCreated { 
-                                        room_id: room.id.clone(), 
-                                        room_name: room.name.clone() 
-                                    });
+                            WsClientMessage::Ping { .. } => {}
+                            WsClientMessage::CreateRoom { name, desc } => {
+                                let msg_service = message_service.lock().await;
 
-                                    if let Err(e) = connections.write().await.join_room(user_id, room.id.clone()) {
-                                        let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Error joining created room: {}", e)  
+                                match msg_service.create_room(user_id, &name, &desc) {
+                                    Ok(room) => {
+                                        let _ = tx.send(WsServerMessage::RoomCreated { 
+                                            room_id: room.id.clone(), 
+                                            room_name: room.name.clone() 
                                         });
-                                    }
 
-                                    if let Err(e) = msg_service.join_room(user_id, &room.id) {
+                                        if let Err(e) = connections.write().await.join_room(user_id, room.id.clone()) {
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Error joining created room: {}", e)  
+                                            });
+                                        }
+
+                                        if let Err(e) = msg_service.join_room(user_id, &room.id) {
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Error joining created room: {}", e)  
+                                            });
+                                        }
+
+                                        let _ = tx.send(WsServerMessage::RoomJoined { 
+                                            room_id: room.id.clone(), 
+                                            room_name: room.name.clone()
+                                        });
+
+                                        let _ = tx.send(WsServerMessage::RoomHistory { 
+                                            room_id: room.id.clone(), 
+                                            messages: vec![] 
+                                        });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
                                         let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Error joining created room: {}", e)  
+                                            message: format!("Error creating room: {}", e) 
                                         });
                                     }
+                                }
+                            }
+                            WsClientMessage::GetAllRooms { include_archived } => {
+                                let msg_service = message_service.lock().await;
 
-                                    let _ = tx.send(WsServerMessage::RoomJoined { 
-                                        room_id: room.id.clone(), 
-                                        room_name: room.name.clone()
-                                    });
+                                match msg_service.get_all_rooms(include_archived) {
+                                    Ok(rooms) => {
+                                        let rooms_info: Vec<RoomInfo> = rooms.into_iter().map(RoomInfo::from).collect();
 
-                                    let _ = tx.send(WsServerMessage::RoomHistory { 
-                                        room_id: room.id.clone(), 
-                                        messages: vec![] 
-                                    });
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { 
-                                        message: format!("Error creating room: {}", e) 
-                                    });
+                                        let _ = tx.send(WsServerMessage::RoomList { rooms: rooms_info });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { 
+                                            message: format!("Error getting room list: {}", e)  
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::GetAllRooms => {
-                            let msg_service = message_service.lock().await;
-
-                            match msg_service.get_all_rooms() {
-                                Ok(rooms) => {
-                                    let rooms_info: Vec<RoomInfo> = rooms.into_iter()
-                                        .map(|r| RoomInfo {
-                                            id: r.id,
-                                            name: r.name,
-                                            desc: r.desc
-                                        })
-                                        .collect();
-
-                                    let _ = tx.send(WsServerMessage::RoomList { rooms: rooms_info });
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { 
-                                        message: format!("Error getting room list: {}", e)  
-                                    });
+                            WsClientMessage::UpdateRoom { room_id, name, desc, topic } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.update_room(&room_id, user_id, name.as_deref(), desc.as_deref(), topic.as_deref()) {
+                                    Ok(room) => {
+                                        connections.read().await.broadcast_to_room_and_user(
+                                            &room_id,
+                                            user_id,
+                                            WsServerMessage::RoomUpdated { room: room.into() },
+                                        );
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to update room: {}", e)
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::EditMessage { room_id, message_id, new_content }  => {
-                            let msg_service = message_service.lock().await;
-
-                            match msg_service.edit_message(&message_id, &new_content) {
-                                Ok(()) => {
-                                    let edited_at = Utc::now().to_rfc3339();
-                                    connections.read().await.broadcast_to_room(
-                                        &room_id, 
-                                        WsServerMessage::MessageEdited { 
-                                            room_id: room_id.clone(), 
-                                            message_id, 
-                                            new_content, 
-                                            edited_at 
-                                        }
-                                    );
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to edit message: {}", e) });
+                            WsClientMessage::ArchiveRoom { room_id } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.archive_room(&room_id, user_id) {
+                                    Ok(archived_at) => {
+                                        connections.read().await.broadcast_to_room_and_user(&room_id, user_id, WsServerMessage::RoomArchived {
+                                            room_id: room_id.clone(),
+                                            archived_at: archived_at.to_rfc3339(),
+                                        });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to archive room: {}", e)
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::DeleteMessage { room_id, message_id } => {
-                            let msg_server = message_service.lock().await;
-
-                            match msg_server.delete_message(user_id, &message_id) {
-                                Ok(()) => {
-                                    connections.read().await.broadcast_to_room(
-                                        &room_id, 
-                                        WsServerMessage::MessageDeleted { 
-                                            room_id: room_id.clone(), 
-                                            message_id 
-                                        }
-                                    );
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { 
-                                        message: format!("Failed to delete message: {}", e) 
-                                    });
+                            WsClientMessage::UnarchiveRoom { room_id } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.unarchive_room(&room_id, user_id) {
+                                    Ok(()) => {
+                                        connections.read().await.broadcast_to_room_and_user(
+                                            &room_id,
+                                            user_id,
+                                            WsServerMessage::RoomUnarchived { room_id: room_id.clone() },
+                                        );
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to unarchive room: {}", e)
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::GetUserRooms { user_id } => {
-                            let msg_service = message_service.lock().await;
-
-                            match msg_service.get_user_rooms(&user_id) {
-                                Ok(rooms) => {
-                                    let rooms_info = rooms.into_iter().map(|r| {RoomInfo {
-                                        id: r.id,
-                                        name: r.name,
-                                        desc: r.desc,
-                                    }}).collect();
+                            WsClientMessage::DeleteRoom { room_id } => {
+                                let msg_service = message_service.lock().await;
 
-                                    let _ = tx.send(WsServerMessage::UserRoomList { rooms: rooms_info });
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to get user rooms: {}", e) });
+                                match msg_service.delete_room(&room_id, user_id) {
+                                    Ok(()) => {
+                                        let mut conns = connections.write().await;
+                                        conns.broadcast_to_room_and_user(
+                                            &room_id,
+                                            user_id,
+                                            WsServerMessage::RoomDeleted { room_id: room_id.clone() },
+                                        );
+                                        conns.close_room(&room_id);
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to delete room: {}", e)
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::UpdatePresence { user_id, presence } => {
-                            let msg_service = message_service.lock().await;
+                            WsClientMessage::KickMember { room_id, user_id: target_id, reason } => {
+                                let msg_service = message_service.lock().await;
 
-                            if let Err(e) = msg_service.update_user_presence(&user_id, presence.clone()) {
-                                let _ = tx.send(WsServerMessage::Error { message: format!("Failed to update presence: {}", e) });
+                                match msg_service.kick_member(&room_id, user_id, &target_id, reason.as_deref()) {
+                                    Ok(target) => {
+                                        let mut conns = connections.write().await;
+                                        conns.broadcast_to_room_and_user(&room_id, user_id, WsServerMessage::MemberKicked {
+                                            room_id: room_id.clone(),
+                                            user_id: target.id.clone(),
+                                            username: target.username,
+                                            kicked_by: user_id.clone(),
+                                            reason,
+                                        });
+                                        conns.remove_member(&room_id, &target.id);
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to kick member: {}", e)
+                                        });
+                                    }
+                                }
                             }
+                            WsClientMessage::BanMember { room_id, user_id: target_id, reason, duration_minutes } => {
+                                let msg_service = message_service.lock().await;
 
-                            match msg_service.get_user_rooms(&user_id) {
-                                Ok(rooms) => {
-                                    let conns = connections.read().await;
-                                    for room in rooms {
-                                        conns.broadcast_to_room(&room.id, WsServerMessage::PresenceChanged { 
-                                            user_id: user_id.clone(), 
-                                            username: authenticated_username.clone().unwrap_or_default(), 
-                                            presence: presence.clone() 
+                                match msg_service.ban_member(&room_id, user_id, &target_id, reason.as_deref(), duration_minutes) {
+                                    Ok(ban) => {
+                                        let mut conns = connections.write().await;
+                                        conns.broadcast_to_room_and_user(&room_id, user_id, WsServerMessage::MemberBanned {
+                                            room_id: room_id.clone(),
+                                            ban,
+                                        });
+                                        conns.remove_member(&room_id, &target_id);
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to ban member: {}", e)
                                         });
                                     }
                                 }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to broadcase presence: {}", e) });
+                            }
+                            WsClientMessage::MuteMember { room_id, user_id: target_id, reason, duration_minutes } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.mute_member(&room_id, user_id, &target_id, reason.as_deref(), duration_minutes) {
+                                    Ok(mute) => {
+                                        connections.read().await.broadcast_to_room_and_user(&room_id, user_id, WsServerMessage::MemberMuted {
+                                            room_id: room_id.clone(),
+                                            mute,
+                                        });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to mute member: {}", e)
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::UpdateStatus { user_id, status } => {
-                            let msg_service = message_service.lock().await;
+                            WsClientMessage::UnbanMember { room_id, user_id: target_id } => {
+                                let msg_service = message_service.lock().await;
 
-                            if let Err(e) = msg_service.update_user_status(&user_id, &status) {
-                                let _ = tx.send(WsServerMessage::Error { message: format!("Failed to update status: {}", e) });
+                                match msg_service.lift_sanction(SanctionKind::Ban, &room_id, user_id, &target_id) {
+                                    Ok(()) => {
+                                        let _ = tx.send(WsServerMessage::MemberUnbanned { room_id, user_id: target_id });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to unban member: {}", e)
+                                        });
+                                    }
+                                }
                             }
+                            WsClientMessage::UnmuteMember { room_id, user_id: target_id } => {
+                                let msg_service = message_service.lock().await;
 
-                            match msg_service.get_user_rooms(&user_id) {
-                                Ok(rooms) => {
-                                    let conns = connections.read().await;
-                                    for room in rooms {
-                                        conns.broadcast_to_room(&room.id, WsServerMessage::StatusChanged { 
-                                            user_id: user_id.clone(), 
-                                            username: authenticated_username.clone().unwrap_or_default(), 
-                                            status: status.clone() 
+                                match msg_service.lift_sanction(SanctionKind::Mute, &room_id, user_id, &target_id) {
+                                    Ok(()) => {
+                                        connections.read().await.broadcast_to_room_and_user(&room_id, user_id, WsServerMessage::MemberUnmuted {
+                                            room_id: room_id.clone(),
+                                            user_id: target_id,
+                                        });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to unmute member: {}", e)
                                         });
                                     }
                                 }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to broadcast status: {}", e) });
+                            }
+                            WsClientMessage::GetRoomBans { room_id } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.get_room_sanctions(SanctionKind::Ban, &room_id, user_id) {
+                                    Ok(bans) => {
+                                        let _ = tx.send(WsServerMessage::RoomBans { room_id, bans });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to get bans: {}", e)
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::GetRoomMembers { room_id } => {
-                            let msg_service = message_service.lock().await;
-
-                            match msg_service.get_room_members(&room_id) {
-                                Ok(members) => {
-                                    let _ = tx.send(WsServerMessage::RoomMembers { 
-                                        room_id, 
-                                        members, 
-                                    });
+                            WsClientMessage::GetAuditLog { filter, limit, offset } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.get_audit_log(user_id, &filter, limit.unwrap_or(50), offset.unwrap_or(0)) {
+                                    Ok(entries) => {
+                                        let _ = tx.send(WsServerMessage::AuditLog { entries });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to get audit log: {}", e)
+                                        });
+                                    }
                                 }
-                                Err(e) => {
-                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to get room members: {}", e) });
+                            }
+                            WsClientMessage::ReportMessage { message_id, reason } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.report_message(user_id, &message_id, &reason) {
+                                    Ok((report, moderators)) => {
+                                        let _ = tx.send(WsServerMessage::MessageReported { report_id: report.id.clone(), message_id });
+                                        let conns = connections.read().await;
+                                        for moderator_id in moderators {
+                                            let _ = conns.send_to_user(&moderator_id, WsServerMessage::ReportCreated { report: report.clone() });
+                                        }
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to report message: {}", e)
+                                        });
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::UpdateTyping { room_id, is_typing } => {
-                            if let Err(e) = connections.write().await.set_typing(user_id, &room_id, is_typing) {
-                                let _ = tx.send(WsServerMessage::Error { message: format!("Failed to update typing status : {}", e) });
-                                continue;
+                            WsClientMessage::GetReports { room_id, status, limit, offset } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.get_reports(user_id, room_id.as_deref(), status, limit.unwrap_or(50), offset.unwrap_or(0)) {
+                                    Ok(reports) => {
+                                        let _ = tx.send(WsServerMessage::Reports { reports });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to get reports: {}", e)
+                                        });
+                                    }
+                                }
                             }
+                            WsClientMessage::ResolveReport { report_id, action, note, duration_minutes } => {
+                                let msg_service = message_service.lock().await;
 
-                            let typing_users_list = connections.write().await.get_typing_users(&room_id);
-                            let typing_users: Vec<TypingUser> = typing_users_list.into_iter()
-                                .map(|(user_id, username)| TypingUser { user_id, username,})
-                                .collect();
+                                match msg_service.resolve_report(&report_id, user_id, action, note.as_deref(), duration_minutes) {
+                                    Ok((report, sanction)) => {
+                                        let mut conns = connections.write().await;
+                                        conns.broadcast_report_outcome(user_id, &report, sanction);
+                                        let _ = tx.send(WsServerMessage::ReportClosed { report });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to resolve report: {}", e)
+                                        });
+                                    }
+                                }
+                            }
+                            WsClientMessage::DismissReport { report_id, note } => {
+                                let msg_service = message_service.lock().await;
 
-                            connections.read().await.broadcast_to_room(
-                                &room_id, 
-                                WsServerMessage::TypingStatusChanged { 
-                                    room_id: room_id.clone(), 
-                                    typing_users 
+                                match msg_service.dismiss_report(&report_id, user_id, note.as_deref()) {
+                                    Ok(report) => {
+                                        let _ = tx.send(WsServerMessage::ReportClosed { report });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to dismiss report: {}", e)
+                                        });
+                                    }
                                 }
-                            );
-                        }
-                        WsClientMessage::GetUnreadMentionsCount { user_id } => {
-                            if let Some(auth_user_id) = &authenticated_user_id {
-                                if auth_user_id == &user_id {
-                                    let msg_service = message_service.lock().await;
-                                    match msg_service.get_unread_mentions_count(&user_id) {
-                                        Ok(count) => {
-                                            let _ = tx.send(WsServerMessage::UnreadMentionsCount { count });
-                                        }
-                                        Err(e) => {
-                                            let _ = tx.send(WsServerMessage::Error { message: format!("Error getting unread mentions count: {}", e) });
-                                        }
+                            }
+                            WsClientMessage::GetRoomFilter { room_id } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.get_room_filter(&room_id, user_id) {
+                                    Ok(filter) => {
+                                        let _ = tx.send(WsServerMessage::RoomFilter { room_id, filter });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to get room filter: {}", e)
+                                        });
                                     }
                                 }
                             }
-                        }
-                        WsClientMessage::MarkMentionsRead { message_id } => {
-                            if let Some(user_id) = &authenticated_user_id {
+                            WsClientMessage::SetRoomFilter { room_id, filter } => {
                                 let msg_service = message_service.lock().await;
-                                match msg_service.mark_mention_as_read(user_id, &message_id) {
-                                    Ok(()) => {
-                                        if let Ok(count) = msg_service.get_unread_mentions_count(user_id) {
-                                            let _ = tx.send(WsServerMessage::UnreadMentionsCount { count });
-                                        }
+
+                                match msg_service.set_room_filter(&room_id, user_id, filter) {
+                                    Ok(filter) => {
+                                        let _ = tx.send(WsServerMessage::RoomFilter { room_id, filter });
                                     }
                                     Err(e) => {
-                                        let _ = tx.send(WsServerMessage::Error { message: format!("Error marking mentions as read: {}", e) });
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to set room filter: {}", e)
+                                        });
                                     }
                                 }
                             }
-                        }
-                        WsClientMessage::MarkRoomMentionsRead { room_id } => {
-                            if let Some(user_id) = &authenticated_user_id {
+                            WsClientMessage::GetHeldMessages { room_id, limit, offset } => {
                                 let msg_service = message_service.lock().await;
-                                match msg_service.mark_room_mentions_as_read(user_id, &room_id) {
-                                    Ok(()) => {
-                                        if let Ok(count) = msg_service.get_unread_mentions_count(user_id) {
-                                            let _ = tx.send(WsServerMessage::UnreadMentionsCount { count });
-                                        }
+
+                                match msg_service.get_held_messages(&room_id, user_id, limit.unwrap_or(50), offset.unwrap_or(0)) {
+                                    Ok(messages) => {
+                                        let _ = tx.send(WsServerMessage::HeldMessages { room_id, messages });
                                     }
                                     Err(e) => {
-                                        let _ = tx.send(WsServerMessage::Error { message: format!("Error marking room mentions as read: {}", e) });
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to get held messages: {}", e)
+                                        });
                                     }
                                 }
                             }
-                        }
-                        WsClientMessage::GetUserMentions { limit, offset } => {
-                            if let Some(user_id) = &authenticated_user_id {
+                            WsClientMessage::ApproveHeldMessage { held_id } => {
                                 let msg_service = message_service.lock().await;
-                                match msg_service.get_user_mentions(user_id, limit.unwrap_or(100), offset.unwrap_or(0)) {
-                                    Ok(mentions) => {
-                                        let _ = tx.send(WsServerMessage::RoomHistory { 
-                                            room_id: "mentions".to_string(),
-                                            messages: mentions,
+
+                                match msg_service.approve_held_message(&held_id, user_id) {
+                                    Ok((message, audience)) => {
+                                        let _ = tx.send(WsServerMessage::HeldMessageApproved { held_id, message_id: message.id.clone() });
+                                        connections.read().await.broadcast_new_message(&message, &audience);
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to approve held message: {}", e)
                                         });
                                     }
+                                }
+                            }
+                            WsClientMessage::RejectHeldMessage { held_id, reason } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.reject_held_message(&held_id, user_id, reason.as_deref()) {
+                                    Ok(held) => {
+                                        let rejected = WsServerMessage::HeldMessageRejected { held_id, room_id: held.room_id };
+                                        if held.sender_id != *user_id {
+                                            let _ = connections.read().await.send_to_user(&held.sender_id, rejected.clone());
+                                        }
+                                        let _ = tx.send(rejected);
+                                    }
                                     Err(e) => {
-                                        let _ = tx.send(WsServerMessage::Error { message: format!("Error fetching mentions: {}", e) });
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to reject held message: {}", e)
+                                        });
                                     }
                                 }
                             }
-                        }
-                        WsClientMessage::AddReaction { room_id, message_id, emoji } => {
-                            if let (Some(user_id), Some(username)) = (&authenticated_user_id, &authenticated_username) {
+                            WsClientMessage::GetRoomMutes { room_id } => {
                                 let msg_service = message_service.lock().await;
 
-                                match msg_service.add_reaction(&message_id, user_id, username, &emoji) {
-                                    Ok(reactions) => {
-                                        connections.read().await.broadcast_to_room(
-                                            &room_id,
-                                            WsServerMessage::ReactionAdded { 
-                                                room_id: room_id.clone(), 
-                                                message_id: message_id.clone(), 
-                                                emoji: emoji.clone(), 
-                                                user_id: user_id.clone(), 
-                                                username: username.clone(), 
-                                                reactions 
-                                            }
-                                        );
+                                match msg_service.get_room_sanctions(SanctionKind::Mute, &room_id, user_id) {
+                                    Ok(mutes) => {
+                                        let _ = tx.send(WsServerMessage::RoomMutes { room_id, mutes });
                                     }
                                     Err(e) => {
-                                        let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Failed to add reaction: {}", e) 
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to get mutes: {}", e)
                                         });
                                     }
                                 }
                             }
-                        }
-                        WsClientMessage::RemoveReaction { room_id, message_id, emoji } => {
-                            if let Some(user_id) = &authenticated_user_id {
+                            WsClientMessage::EditMessage { room_id, message_id, new_content }  => {
                                 let msg_service = message_service.lock().await;
 
-                                match msg_service.remove_reaction(&message_id, &user_id.clone(), &emoji) {
-                                    Ok(reactions) => {
+                                match msg_service.edit_message(user_id, &room_id, &message_id, &new_content) {
+                                    Ok(new_content) => {
+                                        let edited_at = Utc::now().to_rfc3339();
                                         connections.read().await.broadcast_to_room(
                                             &room_id, 
-                                            WsServerMessage::ReactionRemoved { 
+                                            WsServerMessage::MessageEdited { 
                                                 room_id: room_id.clone(), 
-                                                message_id: message_id.clone(), 
-                                                emoji: emoji.clone(), 
-                                                user_id: user_id.clone(), 
-                                                reactions, 
+                                                message_id, 
+                                                new_content, 
+                                                edited_at 
                                             }
                                         );
                                     }
                                     Err(e) => {
-                                        let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Unable to remove reaction: {}", e) 
-                                        });
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to edit message: {}", e) });
                                     }
                                 }
                             }
-                        }
-                        WsClientMessage::PinMessage { room_id, message_id } => {
-                            if let Some(user_id) = &authenticated_user_id {
-                                let msg_service = message_service.lock().await;
+                            WsClientMessage::DeleteMessage { message_id, .. } => {
+                                let msg_server = message_service.lock().await;
 
-                                match msg_service.pin_message(&room_id, &message_id, user_id) {
-                                    Ok(pinned_at) => {
+                                match msg_server.delete_message(user_id, &message_id) {
+                                    Ok(Some(room_id)) => {
                                         connections.read().await.broadcast_to_room(
                                             &room_id, 
-                                            WsServerMessage::MessagePinned { 
+                                            WsServerMessage::MessageDeleted { 
                                                 room_id: room_id.clone(), 
-                                                message_id: message_id.clone(), 
-                                                pinned_by: user_id.clone(), 
-                                                pinned_at: pinned_at.to_rfc3339(), 
+                                                message_id 
                                             }
                                         );
-                                    },
+                                    }
+                                    Ok(None) => {}
                                     Err(e) => {
+                                        metrics().record_error(&e);
                                         let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Failed to pin message: {}", e) 
+                                            message: format!("Failed to delete message: {}", e) 
                                         });
                                     }
                                 }
                             }
-                        }
-                        WsClientMessage::UnpinMessage { room_id, message_id } => {
-                            if let Some(user_id) = &authenticated_user_id {
+                            WsClientMessage::GetUserRooms { user_id } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.get_user_rooms(&user_id) {
+                                    Ok(rooms) => {
+                                        let rooms_info = rooms.into_iter().map(RoomInfo::from).collect();
+
+                                        let _ = tx.send(WsServerMessage::UserRoomList { rooms: rooms_info });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to get user rooms: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::UpdatePresence { user_id, presence } => {
+                                let msg_service = message_service.lock().await;
+
+                                if let Err(e) = msg_service.update_user_presence(&user_id, presence.clone()) {
+                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to update presence: {}", e) });
+                                }
+
+                                match msg_service.get_user_rooms(&user_id) {
+                                    Ok(rooms) => {
+                                        let conns = connections.read().await;
+                                        for room in rooms {
+                                            conns.broadcast_to_room(&room.id, WsServerMessage::PresenceChanged { 
+                                                user_id: user_id.clone(), 
+                                                username: authenticated_username.clone().unwrap_or_default(), 
+                                                presence: presence.clone() 
+                                            });
+                                        }
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to broadcase presence: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::UpdateStatus { user_id, status } => {
                                 let msg_service = message_service.lock().await;
 
-                                match msg_service.unpin_message(&room_id, &message_id, user_id) {
+                                if let Err(e) = msg_service.update_user_status(&user_id, &status) {
+                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to update status: {}", e) });
+                                }
+
+                                match msg_service.get_user_rooms(&user_id) {
+                                    Ok(rooms) => {
+                                        let conns = connections.read().await;
+                                        for room in rooms {
+                                            conns.broadcast_to_room(&room.id, WsServerMessage::StatusChanged { 
+                                                user_id: user_id.clone(), 
+                                                username: authenticated_username.clone().unwrap_or_default(), 
+                                                status: status.clone() 
+                                            });
+                                        }
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to broadcast status: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::BlockUser { user_id: target_id } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.block_user(user_id, &target_id) {
+                                    Ok(block) => {
+                                        let _ = tx.send(WsServerMessage::UserBlocked { block });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to block user: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::UnblockUser { user_id: target_id } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.unblock_user(user_id, &target_id) {
                                     Ok(()) => {
-                                        connections.read().await.broadcast_to_room(
-                                            &room_id, 
-                                            WsServerMessage::MessageUnpinned { 
-                                                room_id: room_id.clone(), 
-                                                message_id: message_id.clone(), 
+                                        let _ = tx.send(WsServerMessage::UserUnblocked { user_id: target_id });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to unblock user: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::GetBlockedUsers => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.get_blocked_users(user_id) {
+                                    Ok(blocks) => {
+                                        let _ = tx.send(WsServerMessage::BlockedUsers { blocks });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to get blocked users: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::GetRoomMembers { room_id } => {
+                                let msg_service = message_service.lock().await;
+
+                                match msg_service.get_room_members(&room_id) {
+                                    Ok(members) => {
+                                        let _ = tx.send(WsServerMessage::RoomMembers { 
+                                            room_id, 
+                                            members, 
+                                        });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to get room members: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::UpdateTyping { room_id, is_typing } => {
+                                if let Err(e) = connections.write().await.set_typing(user_id, &room_id, is_typing) {
+                                    let _ = tx.send(WsServerMessage::Error { message: format!("Failed to update typing status : {}", e) });
+                                    return ControlFlow::Continue(());
+                                }
+
+                                let typing_users_list = connections.write().await.get_typing_users(&room_id);
+                                let typing_users: Vec<TypingUser> = typing_users_list.into_iter()
+                                    .map(|(user_id, username)| TypingUser { user_id, username,})
+                                    .collect();
+
+                                connections.read().await.broadcast_to_room(
+                                    &room_id, 
+                                    WsServerMessage::TypingStatusChanged { 
+                                        room_id: room_id.clone(), 
+                                        typing_users 
+                                    }
+                                );
+                            }
+                            WsClientMessage::GetUnreadMentionsCount { user_id } => {
+                                if let Some(auth_user_id) = &authenticated_user_id {
+                                    if auth_user_id == &user_id {
+                                        let msg_service = message_service.lock().await;
+                                        match msg_service.get_unread_mentions_count(&user_id) {
+                                            Ok(count) => {
+                                                let _ = tx.send(WsServerMessage::UnreadMentionsCount { count });
                                             }
-                                        );
+                                            Err(e) => {
+                                                metrics().record_error(&e);
+                                                let _ = tx.send(WsServerMessage::Error { message: format!("Error getting unread mentions count: {}", e) });
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            WsClientMessage::MarkMentionsRead { message_id } => {
+                                if let Some(user_id) = &authenticated_user_id {
+                                    let msg_service = message_service.lock().await;
+                                    match msg_service.mark_mention_as_read(user_id, &message_id) {
+                                        Ok(()) => {
+                                            if let Ok(count) = msg_service.get_unread_mentions_count(user_id) {
+                                                let _ = tx.send(WsServerMessage::UnreadMentionsCount { count });
+                                            }
+                                        }
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { message: format!("Error marking mentions as read: {}", e) });
+                                        }
+                                    }
+                                }
+                            }
+                            WsClientMessage::MarkRoomMentionsRead { room_id } => {
+                                if let Some(user_id) = &authenticated_user_id {
+                                    let msg_service = message_service.lock().await;
+                                    match msg_service.mark_room_mentions_as_read(user_id, &room_id) {
+                                        Ok(()) => {
+                                            if let Ok(count) = msg_service.get_unread_mentions_count(user_id) {
+                                                let _ = tx.send(WsServerMessage::UnreadMentionsCount { count });
+                                            }
+                                        }
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { message: format!("Error marking room mentions as read: {}", e) });
+                                        }
+                                    }
+                                }
+                            }
+                            WsClientMessage::GetUserMentions { limit, offset } => {
+                                if let Some(user_id) = &authenticated_user_id {
+                                    let msg_service = message_service.lock().await;
+                                    match msg_service.get_user_mentions(user_id, limit.unwrap_or(100), offset.unwrap_or(0)) {
+                                        Ok(mentions) => {
+                                            let _ = tx.send(WsServerMessage::RoomHistory { 
+                                                room_id: "mentions".to_string(),
+                                                messages: mentions,
+                                            });
+                                        }
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { message: format!("Error fetching mentions: {}", e) });
+                                        }
+                                    }
+                                }
+                            }
+                            WsClientMessage::AddReaction { room_id, message_id, emoji } => {
+                                if let (Some(user_id), Some(username)) = (&authenticated_user_id, &authenticated_username) {
+                                    let msg_service = message_service.lock().await;
+
+                                    match msg_service.add_reaction(&message_id, user_id, &emoji) {
+                                        Ok(reactions) => {
+                                            connections.read().await.broadcast_to_room(
+                                                &room_id,
+                                                WsServerMessage::ReactionAdded { 
+                                                    room_id: room_id.clone(), 
+                                                    message_id: message_id.clone(), 
+                                                    emoji: emoji.clone(), 
+                                                    user_id: user_id.clone(), 
+                                                    username: username.clone(), 
+                                                    reactions 
+                                                }
+                                            );
+                                        }
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Failed to add reaction: {}", e) 
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                            WsClientMessage::RemoveReaction { room_id, message_id, emoji } => {
+                                if let Some(user_id) = &authenticated_user_id {
+                                    let msg_service = message_service.lock().await;
+
+                                    match msg_service.remove_reaction(&message_id, &user_id.clone(), &emoji) {
+                                        Ok(reactions) => {
+                                            connections.read().await.broadcast_to_room(
+                                                &room_id, 
+                                                WsServerMessage::ReactionRemoved { 
+                                                    room_id: room_id.clone(), 
+                                                    message_id: message_id.clone(), 
+                                                    emoji: emoji.clone(), 
+                                                    user_id: user_id.clone(), 
+                                                    reactions, 
+                                                }
+                                            );
+                                        }
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Unable to remove reaction: {}", e) 
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                            WsClientMessage::GetReactionDetails { room_id, message_id } => {
+                                match message_service.lock().await.get_reaction_details(&room_id, &message_id, user_id) {
+                                    Ok(reactions) => {
+                                        let _ = tx.send(WsServerMessage::ReactionDetails { room_id, message_id, reactions });
                                     }
                                     Err(e) => {
-                                        let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Failed to unpin message: {}", e)  
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error {
+                                            message: format!("Failed to get reactions: {}", e)
                                         });
-                                    } 
+                                    }
                                 }
                             }
-                        }
-                        WsClientMessage::GetPinnedMessages { room_id } => {
-                            if let Some(_user_id) = &authenticated_user_id {
+                            WsClientMessage::PinMessage { room_id, message_id } => {
+                                if let Some(user_id) = &authenticated_user_id {
+                                    let msg_service = message_service.lock().await;
+
+                                    match msg_service.pin_message(&room_id, &message_id, user_id) {
+                                        Ok(pinned_at) => {
+                                            connections.read().await.broadcast_to_room(
+                                                &room_id, 
+                                                WsServerMessage::MessagePinned { 
+                                                    room_id: room_id.clone(), 
+                                                    message_id: message_id.clone(), 
+                                                    pinned_by: user_id.clone(), 
+                                                    pinned_at: pinned_at.to_rfc3339(), 
+                                                }
+                                            );
+                                        },
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Failed to pin message: {}", e) 
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                            WsClientMessage::UnpinMessage { room_id, message_id } => {
+                                if let Some(user_id) = &authenticated_user_id {
+                                    let msg_service = message_service.lock().await;
+
+                                    match msg_service.unpin_message(&room_id, &message_id, user_id) {
+                                        Ok(()) => {
+                                            connections.read().await.broadcast_to_room(
+                                                &room_id, 
+                                                WsServerMessage::MessageUnpinned { 
+                                                    room_id: room_id.clone(), 
+                                                    message_id: message_id.clone(), 
+                                                }
+                                            );
+                                        }
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Failed to unpin message: {}", e)  
+                                            });
+                                        } 
+                                    }
+                                }
+                            }
+                            WsClientMessage::GetPinnedMessages { room_id } => {
+                                if let Some(user_id) = &authenticated_user_id {
+                                    let msg_service = message_service.lock().await;
+
+                                    match msg_service.get_pinned_messages(&room_id, user_id) {
+                                        Ok(messages) => {
+                                            let _ = tx.send(WsServerMessage::PinnedMessages { 
+                                                room_id: room_id.clone(), 
+                                                messages, 
+                                            });
+                                        }
+                                        Err(e) => {
+                                            metrics().record_error(&e);
+                                            let _ = tx.send(WsServerMessage::Error { 
+                                                message: format!("Failed to get pinned messages: {}", e) 
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                            WsClientMessage::CreateWebhook { room_id, url, events } => {
+                                let Some(webhooks) = &webhooks else {
+                                    let _ = tx.send(WsServerMessage::Error { message: "Webhooks are not enabled".to_string() });
+                                    return ControlFlow::Continue(());
+                                };
+
+                                match webhooks.lock().await.create_webhook(user_id, &room_id, &url, events) {
+                                    Ok(webhook) => {
+                                        let _ = tx.send(WsServerMessage::WebhookCreated { webhook });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to create webhook: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::ListWebhooks { room_id } => {
+                                let Some(webhooks) = &webhooks else {
+                                    let _ = tx.send(WsServerMessage::Error { message: "Webhooks are not enabled".to_string() });
+                                    return ControlFlow::Continue(());
+                                };
+
+                                match webhooks.lock().await.list_webhooks(user_id, &room_id) {
+                                    Ok(list) => {
+                                        let _ = tx.send(WsServerMessage::Webhooks { room_id, webhooks: list });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to list webhooks: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::DeleteWebhook { webhook_id } => {
+                                let Some(webhooks) = &webhooks else {
+                                    let _ = tx.send(WsServerMessage::Error { message: "Webhooks are not enabled".to_string() });
+                                    return ControlFlow::Continue(());
+                                };
+
+                                match webhooks.lock().await.delete_webhook(user_id, &webhook_id) {
+                                    Ok(()) => {
+                                        let _ = tx.send(WsServerMessage::WebhookDeleted { webhook_id });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to delete webhook: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::GetWebhookFailures { room_id } => {
+                                let Some(webhooks) = &webhooks else {
+                                    let _ = tx.send(WsServerMessage::Error { message: "Webhooks are not enabled".to_string() });
+                                    return ControlFlow::Continue(());
+                                };
+
+                                match webhooks.lock().await.list_dead_letters(user_id, &room_id) {
+                                    Ok(failures) => {
+                                        let _ = tx.send(WsServerMessage::WebhookFailures { room_id, failures });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to get webhook failures: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::CreateIncomingWebhook { room_id, name } => {
+                                let Some(webhooks) = &webhooks else {
+                                    let _ = tx.send(WsServerMessage::Error { message: "Webhooks are not enabled".to_string() });
+                                    return ControlFlow::Continue(());
+                                };
+
+                                match webhooks.lock().await.create_incoming_webhook(user_id, &room_id, &name) {
+                                    Ok(webhook) => {
+                                        let _ = tx.send(WsServerMessage::IncomingWebhookCreated { webhook });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to create webhook: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::ListIncomingWebhooks { room_id } => {
+                                let Some(webhooks) = &webhooks else {
+                                    let _ = tx.send(WsServerMessage::Error { message: "Webhooks are not enabled".to_string() });
+                                    return ControlFlow::Continue(());
+                                };
+
+                                match webhooks.lock().await.list_incoming_webhooks(user_id, &room_id) {
+                                    Ok(list) => {
+                                        let _ = tx.send(WsServerMessage::IncomingWebhooks { room_id, webhooks: list });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to list webhooks: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::DeleteIncomingWebhook { webhook_id } => {
+                                let Some(webhooks) = &webhooks else {
+                                    let _ = tx.send(WsServerMessage::Error { message: "Webhooks are not enabled".to_string() });
+                                    return ControlFlow::Continue(());
+                                };
+
+                                match webhooks.lock().await.delete_incoming_webhook(user_id, &webhook_id) {
+                                    Ok(()) => {
+                                        let _ = tx.send(WsServerMessage::IncomingWebhookDeleted { webhook_id });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to delete webhook: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::ListUsers { search, limit, offset } => {
+                                let msg_service = message_service.lock().await;
+                                match msg_service.list_users(user_id, &search, limit.unwrap_or(50), offset.unwrap_or(0)) {
+                                    Ok(users) => {
+                                        let _ = tx.send(WsServerMessage::Users { users });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to list users: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::DeactivateUser { user_id: target_id, reason } => {
+                                let msg_service = message_service.lock().await;
+                                match msg_service.deactivate_user(user_id, &target_id, reason.as_deref()) {
+                                    Ok(user) => {
+                                        drop(msg_service);
+                                        connections.read().await.disconnect_user(&user.id, "Your account has been deactivated");
+                                        let _ = tx.send(WsServerMessage::UserDeactivated { user });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to deactivate user: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::ReactivateUser { user_id: target_id } => {
+                                let msg_service = message_service.lock().await;
+                                match msg_service.reactivate_user(user_id, &target_id) {
+                                    Ok(user) => {
+                                        let _ = tx.send(WsServerMessage::UserReactivated { user });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to reactivate user: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::ForceLogout { user_id: target_id } => {
+                                let msg_service = message_service.lock().await;
+                                match msg_service.force_logout(user_id, &target_id) {
+                                    Ok(()) => {
+                                        drop(msg_service);
+                                        connections.read().await.disconnect_user(&target_id, "You have been logged out by an administrator");
+                                        let _ = tx.send(WsServerMessage::UserLoggedOut { user_id: target_id });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to log out user: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::SetUserRole { user_id: target_id, role } => {
+                                let msg_service = message_service.lock().await;
+                                match msg_service.set_user_role(user_id, &target_id, role) {
+                                    Ok(user) => {
+                                        let _ = tx.send(WsServerMessage::UserRoleChanged { user });
+                                    }
+                                    Err(e) => {
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to change role: {}", e) });
+                                    }
+                                }
+                            }
+                            WsClientMessage::SendServerAnnouncement { content, target } => {
                                 let msg_service = message_service.lock().await;
+                                let sent = match target {
+                                    AnnouncementTarget::Rooms => msg_service.announce_to_rooms(user_id, &content),
+                                    AnnouncementTarget::Online => msg_service.announce_to_online_users(user_id, &content).map(|()| Vec::new()),
+                                };
+                                drop(msg_service);
 
-                                match msg_service.get_pinned_messages(&room_id) {
+                                match sent {
                                     Ok(messages) => {
-                                        let _ = tx.send(WsServerMessage::PinnedMessages { 
-                                            room_id: room_id.clone(), 
-                                            messages, 
-                                        });
+                                        let conns = connections.read().await;
+                                        for message in &messages {
+                                            conns.broadcast_new_message(message, &MessageAudience::default());
+                                        }
+                                        if target == AnnouncementTarget::Online {
+                                            conns.broadcast_to_all(WsServerMessage::ServerAnnouncement {
+                                                content,
+                                                sent_at: Utc::now().to_rfc3339(),
+                                            });
+                                        }
+                                        let _ = tx.send(WsServerMessage::AnnouncementSent { target });
                                     }
                                     Err(e) => {
-                                        let _ = tx.send(WsServerMessage::Error { 
-                                            message: format!("Failed to get pinned messages: {}", e) 
-                                        });
+                                        metrics().record_error(&e);
+                                        let _ = tx.send(WsServerMessage::Error { message: format!("Failed to send announcement: {}", e) });
                                     }
                                 }
                             }
                         }
                     }
                 }
+
+                ControlFlow::Continue(())
+            }.instrument(request_span).await;
+
+            if flow.is_break() {
+                break;
             }
         }
     }
@@ -946,7 +2267,7 @@ async fn handle_websocket_connections(
         let msg_service = message_service.lock().await;
 
         if let Err(e) = msg_service.update_user_presence(user_id, Presence::Offline) {
-            eprintln!("Failed to update presence on disconnect: {}", e);
+            error!(error = %e, "failed to update presence on disconnect");
         }
 
         if let Ok(rooms) = msg_service.get_user_rooms(user_id) {
@@ -968,7 +2289,12 @@ async fn handle_websocket_connections(
         connections.write().await.remove_client(&user_id);
     }
 
-    send_task.abort();
+    // Once every sender is gone the send task drains what's queued, including
+    // any shutdown notice, and closes the socket
+    drop(tx);
+    if tokio::time::timeout(FLUSH_TIMEOUT, &mut send_task).await.is_err() {
+        send_task.abort();
+    }
     Ok(())
 }
 